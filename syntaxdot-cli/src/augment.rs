@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use clap::{App, Arg, ArgMatches};
+use syntaxdot::dataset::Augmentation;
+
+use crate::traits::SyntaxDotOption;
+
+const AUGMENT_CASE: &str = "AUGMENT_CASE";
+const AUGMENT_CHAR_NOISE: &str = "AUGMENT_CHAR_NOISE";
+const AUGMENT_DIACRITICS: &str = "AUGMENT_DIACRITICS";
+const AUGMENT_PIECE_DROPOUT: &str = "AUGMENT_PIECE_DROPOUT";
+
+pub struct AugmentationOption;
+
+impl AugmentationOption {
+    fn parse_prob(matches: &ArgMatches, name: &str) -> Result<f64> {
+        let prob = matches.value_of(name).unwrap();
+        prob.parse()
+            .context(format!("Cannot parse augmentation probability: {}", prob))
+    }
+}
+
+impl SyntaxDotOption for AugmentationOption {
+    type Value = Augmentation;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(AUGMENT_CASE)
+                .long("augment-case")
+                .value_name("P")
+                .help("Change the case of training tokens with probability P")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name(AUGMENT_CHAR_NOISE)
+                .long("augment-char-noise")
+                .value_name("P")
+                .help("Edit a character of training tokens with probability P")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name(AUGMENT_DIACRITICS)
+                .long("augment-diacritics")
+                .value_name("P")
+                .help("Strip diacritics from training tokens with probability P")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name(AUGMENT_PIECE_DROPOUT)
+                .long("augment-piece-dropout")
+                .value_name("P")
+                .help("Drop non-initial pieces of training tokens with probability P")
+                .default_value("0"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        Augmentation::new(
+            Self::parse_prob(matches, AUGMENT_CASE)?,
+            Self::parse_prob(matches, AUGMENT_DIACRITICS)?,
+            Self::parse_prob(matches, AUGMENT_CHAR_NOISE)?,
+            Self::parse_prob(matches, AUGMENT_PIECE_DROPOUT)?,
+        )
+        .context("Cannot construct data augmentation")
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use syntaxdot::optimizers::OptimizerState;
+use tch::nn::VarStore;
+use tch::{Device, Tensor};
+
+const OPTIMIZER_FILENAME: &str = "optimizer.ot";
+const PARAMETERS_FILENAME: &str = "parameters.ot";
+const STATE_FILENAME: &str = "state.yaml";
+
+/// Training checkpoint.
+///
+/// A checkpoint is a directory that contains everything that is
+/// necessary to resume training: the model parameters, the optimizer
+/// state and the training state (e.g. counters and learning rate
+/// schedules). The training state is stored in YAML, so that it can
+/// be inspected.
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    /// Construct a checkpoint that is stored in the directory `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Checkpoint { path: path.into() }
+    }
+
+    /// Check whether the checkpoint exists.
+    pub fn exists(&self) -> bool {
+        self.existing_path().is_some()
+    }
+
+    /// Get the directory that holds the most recent complete checkpoint.
+    ///
+    /// If saving was interrupted after the previous checkpoint was moved
+    /// aside, but before the new checkpoint was moved into place, the
+    /// previous checkpoint is used.
+    fn existing_path(&self) -> Option<PathBuf> {
+        let old_path = self.path.with_extension("old");
+        vec![self.path.clone(), old_path]
+            .into_iter()
+            .find(|path| path.join(STATE_FILENAME).exists())
+    }
+
+    /// Load the checkpoint.
+    ///
+    /// The model parameters are loaded into `vs` and the optimizer
+    /// state into `optimizer`. Returns the training state.
+    pub fn load<S>(
+        &self,
+        vs: &mut VarStore,
+        optimizer: &mut impl OptimizerState,
+        device: Device,
+    ) -> Result<S>
+    where
+        S: DeserializeOwned,
+    {
+        let path = self.existing_path().ok_or_else(|| {
+            anyhow::anyhow!("Cannot find checkpoint: {}", self.path.to_string_lossy())
+        })?;
+
+        vs.load(path.join(PARAMETERS_FILENAME))
+            .context("Cannot load checkpoint parameters")?;
+
+        let optimizer_state: HashMap<_, _> =
+            Tensor::load_multi_with_device(path.join(OPTIMIZER_FILENAME), device)
+                .context("Cannot load checkpoint optimizer state")?
+                .into_iter()
+                .collect();
+        optimizer
+            .load_state(&optimizer_state)
+            .context("Cannot restore optimizer state")?;
+
+        let state_file = File::open(path.join(STATE_FILENAME))
+            .context("Cannot open checkpoint training state")?;
+        serde_yaml::from_reader(state_file).context("Cannot deserialize checkpoint training state")
+    }
+
+    /// Save the checkpoint.
+    ///
+    /// The checkpoint is first written to a temporary directory, which
+    /// then replaces the previous checkpoint. The previous checkpoint is
+    /// only removed after the new checkpoint is in place. So, a crash
+    /// during saving does not corrupt the previous checkpoint.
+    pub fn save<S>(&self, vs: &VarStore, optimizer: &impl OptimizerState, state: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let tmp_path = self.path.with_extension("tmp");
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path).context(format!(
+                "Cannot remove temporary checkpoint directory: {}",
+                tmp_path.to_string_lossy()
+            ))?;
+        }
+
+        fs::create_dir_all(&tmp_path).context(format!(
+            "Cannot create temporary checkpoint directory: {}",
+            tmp_path.to_string_lossy()
+        ))?;
+
+        vs.save(tmp_path.join(PARAMETERS_FILENAME))
+            .context("Cannot save checkpoint parameters")?;
+        Tensor::save_multi(&optimizer.state(), tmp_path.join(OPTIMIZER_FILENAME))
+            .context("Cannot save checkpoint optimizer state")?;
+        let state_file = File::create(tmp_path.join(STATE_FILENAME))
+            .context("Cannot create checkpoint training state file")?;
+        serde_yaml::to_writer(state_file, state)
+            .context("Cannot serialize checkpoint training state")?;
+
+        Self::replace_dir(&tmp_path, &self.path)
+    }
+
+    /// Replace the directory `to` by `from`.
+    ///
+    /// `to` is first moved aside, so that there is a complete checkpoint
+    /// on disk at any point.
+    fn replace_dir(from: &Path, to: &Path) -> Result<()> {
+        let old_path = to.with_extension("old");
+
+        if to.exists() {
+            if old_path.exists() {
+                fs::remove_dir_all(&old_path).context(format!(
+                    "Cannot remove stale checkpoint: {}",
+                    old_path.to_string_lossy()
+                ))?;
+            }
+
+            fs::rename(to, &old_path).context(format!(
+                "Cannot move previous checkpoint to: {}",
+                old_path.to_string_lossy()
+            ))?;
+        }
+
+        fs::rename(from, to).context(format!(
+            "Cannot move checkpoint to: {}",
+            to.to_string_lossy()
+        ))?;
+
+        if old_path.exists() {
+            fs::remove_dir_all(&old_path).context(format!(
+                "Cannot remove previous checkpoint: {}",
+                old_path.to_string_lossy()
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Generate a seed for the random number generators.
+pub fn random_seed() -> i64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the UNIX epoch")
+        .subsec_nanos();
+    i64::from(nanos)
+}
+
+/// Seed the Torch random number generator.
+///
+/// The generator is seeded from the run's seed and the training
+/// progress. Reseeding at every point where a checkpoint can be
+/// stored ensures that a resumed run uses the same random numbers
+/// (e.g. for dropout) as an uninterrupted run.
+pub fn seed_torch(seed: i64, progress: usize) {
+    tch::manual_seed(seed.wrapping_add(progress as i64));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{Checkpoint, STATE_FILENAME};
+
+    fn checkpoint_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("syntaxdot-{}-{}", name, std::process::id()));
+        for dir in &[
+            path.clone(),
+            path.with_extension("old"),
+            path.with_extension("tmp"),
+        ] {
+            let _ = fs::remove_dir_all(dir);
+        }
+        path
+    }
+
+    fn write_state(path: &Path, state: &str) {
+        fs::create_dir_all(path).unwrap();
+        fs::write(path.join(STATE_FILENAME), state).unwrap();
+    }
+
+    #[test]
+    fn replace_dir_removes_previous_checkpoint() {
+        let path = checkpoint_dir("replace-checkpoint");
+        let tmp_path = path.with_extension("tmp");
+        write_state(&path, "old");
+        write_state(&tmp_path, "new");
+
+        Checkpoint::replace_dir(&tmp_path, &path).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(path.join(STATE_FILENAME)).unwrap(),
+            "new"
+        );
+        assert!(!tmp_path.exists());
+        assert!(!path.with_extension("old").exists());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_falls_back_to_previous_checkpoint() {
+        let path = checkpoint_dir("fallback-checkpoint");
+        let checkpoint = Checkpoint::new(&path);
+        assert!(!checkpoint.exists());
+
+        // Interrupted save: the previous checkpoint was moved aside,
+        // the new checkpoint was not moved into place yet.
+        let old_path = path.with_extension("old");
+        write_state(&old_path, "old");
+
+        assert!(checkpoint.exists());
+        assert_eq!(checkpoint.existing_path(), Some(old_path.clone()));
+
+        fs::remove_dir_all(old_path).unwrap();
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use syntaxdot::dataset::SourceFiles;
+
+/// Magic number of gzip streams.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic number of zstd frames.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A corpus that consists of one or more files.
+///
+/// The files of a corpus are called shards. Shards can be compressed
+/// with gzip or zstd, compression is detected from the file contents.
+pub struct Corpus {
+    shards: Vec<PathBuf>,
+}
+
+impl Corpus {
+    /// Get the corpus that is described by `spec`.
+    ///
+    /// `spec` is either the path of a file, the path of a directory, or
+    /// a glob pattern such as `train/*.conllu.gz`. The shards of a
+    /// directory are the non-hidden files in the directory. Shards are
+    /// sorted by their paths.
+    pub fn new(spec: &str) -> Result<Self> {
+        let path = Path::new(spec);
+
+        let mut shards = if path.is_dir() {
+            fs::read_dir(path)
+                .context(format!("Cannot read corpus directory: {}", spec))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .context(format!("Cannot read corpus directory: {}", spec))?
+                .into_iter()
+                .filter(|path| path.is_file() && !is_hidden(path))
+                .collect()
+        } else if !path.exists() && spec.contains(&['*', '?', '['][..]) {
+            glob::glob(spec)
+                .context(format!("Invalid corpus pattern: {}", spec))?
+                .collect::<Result<Vec<_>, _>>()
+                .context(format!("Cannot read corpus: {}", spec))?
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect()
+        } else {
+            vec![path.to_owned()]
+        };
+
+        if shards.is_empty() {
+            bail!("Corpus does not contain any files: {}", spec);
+        }
+
+        shards.sort();
+
+        Ok(Corpus { shards })
+    }
+
+    /// Get the paths of the shards.
+    pub fn shards(&self) -> &[PathBuf] {
+        &self.shards
+    }
+
+    /// Open the corpus for reading.
+    ///
+    /// The reader returns the decompressed shards one after another.
+    /// Shards are separated by an empty line, so that the last sentence
+    /// of a shard does not run into the first sentence of the next
+    /// shard.
+    pub fn open(&self) -> Result<CorpusReader> {
+        let size = self
+            .shards
+            .iter()
+            .map(|shard| {
+                fs::metadata(shard)
+                    .map(|metadata| metadata.len())
+                    .context(format!("Cannot open corpus file: {}", shard.display()))
+            })
+            .sum::<Result<u64>>()?;
+
+        let mut reader = CorpusReader {
+            completed_size: 0,
+            current: None,
+            lines: 0,
+            next_shard: 0,
+            separator: &[],
+            shards: self.shards.clone(),
+            size,
+            source_files: SourceFiles::default(),
+            started: false,
+            trailing_newlines: 0,
+        };
+        reader.rewind().context(format!(
+            "Cannot open corpus file: {}",
+            self.shards[0].display()
+        ))?;
+
+        Ok(reader)
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// Open a possibly compressed file for reading.
+pub fn open_decompressed(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    Ok(ShardReader::open(path)?.read)
+}
+
+/// Create a file for writing.
+///
+/// The file is compressed with gzip when its name ends with `.gz` and
+/// with zstd when its name ends with `.zst`.
+pub fn create_compressed(path: &Path) -> io::Result<CompressedWriter> {
+    let write = BufWriter::new(File::create(path)?);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("gz") => Ok(CompressedWriter::Gzip(GzEncoder::new(
+            write,
+            Compression::default(),
+        ))),
+        Some("zst") => Ok(CompressedWriter::Zstd(zstd::Encoder::new(write, 0)?)),
+        _ => Ok(CompressedWriter::Plain(write)),
+    }
+}
+
+/// Writer for a possibly compressed file.
+///
+/// `finish` must be called after writing. It writes the end of the
+/// compressed stream and flushes the file.
+pub enum CompressedWriter {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Plain(BufWriter<File>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl CompressedWriter {
+    /// Finish writing the file.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(write) => write.finish()?.flush(),
+            CompressedWriter::Plain(mut write) => write.flush(),
+            CompressedWriter::Zstd(write) => write.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Gzip(write) => write.write(buf),
+            CompressedWriter::Plain(write) => write.write(buf),
+            CompressedWriter::Zstd(write) => write.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(write) => write.flush(),
+            CompressedWriter::Plain(write) => write.flush(),
+            CompressedWriter::Zstd(write) => write.flush(),
+        }
+    }
+}
+
+/// Get the output paths that mirror the shards of a corpus.
+///
+/// The output of a shard is stored in `output_dir` under the file name
+/// of the shard. Returns an error if an output path is one of the
+/// shards, since annotating would then overwrite the input.
+pub fn mirror_shards(corpus: &Corpus, output_dir: &Path) -> Result<Vec<PathBuf>> {
+    let shards = corpus
+        .shards()
+        .iter()
+        .map(|shard| {
+            shard
+                .canonicalize()
+                .context(format!("Cannot resolve shard path: {}", shard.display()))
+        })
+        .collect::<Result<HashSet<_>>>()?;
+
+    let mut file_names = HashSet::new();
+    corpus
+        .shards()
+        .iter()
+        .map(|shard| {
+            let file_name = shard.file_name().context(format!(
+                "Shard does not have a file name: {}",
+                shard.display()
+            ))?;
+            if !file_names.insert(file_name) {
+                bail!(
+                    "Cannot mirror shards, multiple shards are named: {}",
+                    file_name.to_string_lossy()
+                );
+            }
+
+            let output_path = output_dir.join(file_name);
+            // An output path that does not exist cannot be a shard.
+            if let Ok(canonical_output_path) = output_path.canonicalize() {
+                if shards.contains(&canonical_output_path) {
+                    bail!(
+                        "Cannot mirror shards, output would overwrite input: {}",
+                        output_path.display()
+                    );
+                }
+            }
+
+            Ok(output_path)
+        })
+        .collect()
+}
+
+/// Reader for a single, possibly compressed, shard.
+struct ShardReader {
+    /// The number of (compressed) bytes read from the shard.
+    bytes_read: Arc<AtomicU64>,
+    read: Box<dyn BufRead>,
+}
+
+impl ShardReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+
+        let mut magic = [0u8; 4];
+        let mut n_read = 0;
+        while n_read < magic.len() {
+            match file.read(&mut magic[n_read..])? {
+                0 => break,
+                n => n_read += n,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let file = CountingRead {
+            bytes_read: bytes_read.clone(),
+            inner: file,
+        };
+
+        let magic = &magic[..n_read];
+        let read: Box<dyn BufRead> = if magic.starts_with(&GZIP_MAGIC) {
+            Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(file))))
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Box::new(BufReader::new(zstd::Decoder::new(file)?))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+
+        Ok(ShardReader { bytes_read, read })
+    }
+}
+
+/// Reader that counts the number of bytes that were read.
+struct CountingRead<R> {
+    bytes_read: Arc<AtomicU64>,
+    inner: R,
+}
+
+impl<R> Read for CountingRead<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(n_read as u64, Ordering::Relaxed);
+        Ok(n_read)
+    }
+}
+
+/// Reader of the shards of a corpus.
+///
+/// The reader only supports seeking to the start of the corpus, which
+/// is sufficient for multiple passes over a data set. Seeking to the
+/// end and querying the current position are supported for progress
+/// reporting. Positions are offsets in the (compressed) shards.
+///
+/// The reader records on which line each shard starts, so that lines
+/// can be mapped to shards (see [`CorpusReader::source_files`]).
+pub struct CorpusReader {
+    completed_size: u64,
+    current: Option<ShardReader>,
+
+    /// The number of lines that were read.
+    lines: usize,
+
+    next_shard: usize,
+    separator: &'static [u8],
+    shards: Vec<PathBuf>,
+    size: u64,
+    source_files: SourceFiles,
+
+    /// Whether data was read from the shards.
+    started: bool,
+
+    /// The number of trailing newlines of the data that was read, at most 2.
+    trailing_newlines: usize,
+}
+
+impl CorpusReader {
+    /// Get the shards of the corpus with the lines on which they start.
+    ///
+    /// A shard is only recorded once the reader has reached it.
+    pub fn source_files(&self) -> SourceFiles {
+        self.source_files.clone()
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.completed_size = 0;
+        self.current = None;
+        self.lines = 0;
+        self.next_shard = 0;
+        self.separator = &[];
+        self.started = false;
+        self.trailing_newlines = 0;
+        self.open_next_shard()
+    }
+
+    fn open_next_shard(&mut self) -> io::Result<()> {
+        if let Some(shard) = self.next_shard.checked_sub(1) {
+            self.completed_size += fs::metadata(&self.shards[shard])?.len();
+        }
+
+        self.current = match self.shards.get(self.next_shard) {
+            Some(shard) => Some(ShardReader::open(shard)?),
+            None => None,
+        };
+        self.next_shard += 1;
+
+        // Separate shards by an empty line.
+        self.separator = match (self.started, self.trailing_newlines) {
+            _ if self.current.is_none() => &[],
+            (false, _) => &[],
+            (true, 0) => b"\n\n",
+            (true, 1) => b"\n",
+            (true, _) => &[],
+        };
+
+        if self.current.is_some() {
+            let shard = self.next_shard - 1;
+            let separator_lines = bytecount::count(self.separator, b'\n');
+            self.source_files.add_file(
+                shard,
+                self.shards[shard].display().to_string(),
+                self.lines + separator_lines,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for CorpusReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let n_read = data.len().min(buf.len());
+        buf[..n_read].copy_from_slice(&data[..n_read]);
+        self.consume(n_read);
+        Ok(n_read)
+    }
+}
+
+impl BufRead for CorpusReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if !self.separator.is_empty() {
+            return Ok(self.separator);
+        }
+
+        // Find the next shard with remaining data. We cannot return the
+        // buffer of the current shard directly in the loop, because the
+        // borrow would outlive the loop iteration.
+        loop {
+            match self.current.as_mut() {
+                Some(current) => {
+                    if current.read.fill_buf()?.is_empty() {
+                        self.open_next_shard()?;
+                        if !self.separator.is_empty() {
+                            return Ok(self.separator);
+                        }
+                    } else {
+                        break;
+                    }
+                }
+                None => return Ok(&[]),
+            }
+        }
+
+        self.current
+            .as_mut()
+            .expect("No current shard")
+            .read
+            .fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt == 0 {
+            return;
+        }
+
+        if !self.separator.is_empty() {
+            let separator = self.separator;
+            self.track_newlines(&separator[..amt]);
+            self.separator = &separator[amt..];
+            return;
+        }
+
+        if let Some(mut current) = self.current.take() {
+            if let Ok(data) = current.read.fill_buf() {
+                let consumed = &data[..amt.min(data.len())];
+                self.track_newlines(consumed);
+            }
+            current.read.consume(amt);
+            self.current = Some(current);
+        }
+    }
+}
+
+impl CorpusReader {
+    /// Track the lines and the trailing newlines of the data that was read.
+    fn track_newlines(&mut self, data: &[u8]) {
+        self.lines += bytecount::count(data, b'\n');
+
+        let newlines = data.iter().rev().take_while(|&&b| b == b'\n').count();
+        self.trailing_newlines = if newlines == data.len() {
+            (self.trailing_newlines + newlines).min(2)
+        } else {
+            newlines.min(2)
+        };
+        self.started = true;
+    }
+}
+
+impl Seek for CorpusReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(0) => {
+                self.rewind()?;
+                Ok(0)
+            }
+            SeekFrom::End(0) => {
+                self.completed_size = self.size;
+                self.current = None;
+                self.next_shard = self.shards.len() + 1;
+                self.separator = &[];
+                Ok(self.size)
+            }
+            SeekFrom::Current(0) => Ok(self.completed_size
+                + self
+                    .current
+                    .as_ref()
+                    .map(|current| current.bytes_read.load(Ordering::Relaxed))
+                    .unwrap_or(0)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A corpus can only be rewound",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use syntaxdot::dataset::{source_file, source_line, ConlluSentences};
+
+    use super::{create_compressed, mirror_shards, open_decompressed, Corpus};
+
+    fn corpus_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("syntaxdot-corpus-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_shards(dir: &Path) {
+        fs::write(dir.join("a.conllu"), "1\tDit\n2\tis\n").unwrap();
+
+        let mut gz = GzEncoder::new(
+            File::create(dir.join("b.conllu.gz")).unwrap(),
+            Compression::default(),
+        );
+        gz.write_all(b"1\tDat\n\n").unwrap();
+        gz.finish().unwrap();
+
+        let zst = zstd::encode_all(&b"1\tZin"[..], 0).unwrap();
+        fs::write(dir.join("c.conllu.zst"), zst).unwrap();
+
+        fs::write(dir.join(".hidden"), "1\tVerborgen\n").unwrap();
+    }
+
+    #[test]
+    fn reads_decompressed_shards() {
+        let dir = corpus_dir("directory");
+        write_shards(&dir);
+
+        let corpus = Corpus::new(dir.to_str().unwrap()).unwrap();
+        assert_eq!(corpus.shards().len(), 3);
+
+        let mut reader = corpus.open().unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "1\tDit\n2\tis\n\n1\tDat\n\n1\tZin");
+
+        // Rewinding reads the corpus again.
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let mut again = String::new();
+        reader.read_to_string(&mut again).unwrap();
+        assert_eq!(again, data);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn maps_lines_to_shards() {
+        let dir = corpus_dir("lines");
+        write_shards(&dir);
+
+        let corpus = Corpus::new(dir.to_str().unwrap()).unwrap();
+        let mut reader = corpus.open().unwrap();
+        let source_files = reader.source_files();
+
+        for _ in 0..2 {
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let positions = ConlluSentences::new(&mut reader)
+                .map(|sentence| {
+                    let mut sentence = sentence.unwrap();
+                    assert!(source_line(&sentence).is_some());
+                    source_files.set_source_file(&mut sentence);
+                    (
+                        source_file(&sentence).unwrap().to_string(),
+                        source_line(&sentence).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                positions,
+                vec![
+                    (dir.join("a.conllu").display().to_string(), 1),
+                    (dir.join("b.conllu.gz").display().to_string(), 1),
+                    (dir.join("c.conllu.zst").display().to_string(), 1),
+                ]
+            );
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expands_glob_patterns() {
+        let dir = corpus_dir("glob");
+        write_shards(&dir);
+
+        let corpus = Corpus::new(dir.join("*.conllu*").to_str().unwrap()).unwrap();
+        assert_eq!(
+            corpus.shards(),
+            &[
+                dir.join("a.conllu"),
+                dir.join("b.conllu.gz"),
+                dir.join("c.conllu.zst")
+            ]
+        );
+
+        assert!(Corpus::new(dir.join("*.txt").to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mirrored_shards_do_not_overwrite_input() {
+        let dir = corpus_dir("mirror");
+        write_shards(&dir);
+
+        let corpus = Corpus::new(dir.to_str().unwrap()).unwrap();
+        assert!(mirror_shards(&corpus, &dir).is_err());
+        assert!(mirror_shards(&corpus, &dir.join(".")).is_err());
+
+        let output_dir = dir.join("output");
+        assert_eq!(
+            mirror_shards(&corpus, &output_dir).unwrap(),
+            vec![
+                output_dir.join("a.conllu"),
+                output_dir.join("b.conllu.gz"),
+                output_dir.join("c.conllu.zst")
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_output_is_finished() {
+        let dir = corpus_dir("compressed");
+
+        for name in &["plain.conllu", "gzip.conllu.gz", "zstd.conllu.zst"] {
+            let path = dir.join(name);
+            let mut write = create_compressed(&path).unwrap();
+            write.write_all(b"1\tDit\n").unwrap();
+            write.finish().unwrap();
+
+            let mut data = String::new();
+            open_decompressed(&path)
+                .unwrap()
+                .read_to_string(&mut data)
+                .unwrap();
+            assert_eq!(data, "1\tDit\n");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+use anyhow::{Context, Result};
+use clap::{App, Arg, ArgMatches};
+use syntaxdot::dataset::SentenceFormat;
+
+use crate::traits::SyntaxDotOption;
+
+const FORMAT: &str = "FORMAT";
+
+pub struct SentenceFormatOption;
+
+impl SyntaxDotOption for SentenceFormatOption {
+    type Value = SentenceFormat;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(FORMAT)
+                .long("format")
+                .value_name("FORMAT")
+                .help("Data format: conllu, conll2003 or columns (e.g. form,xpos,_,misc:ner)")
+                .default_value("conllu"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let format = matches.value_of(FORMAT).unwrap();
+        format
+            .parse()
+            .context(format!("Cannot parse data format: {}", format))
+    }
+}
@@ -96,9 +96,13 @@ impl Model {
                 .map(ImmutableDependencyEncoder::n_relations)
                 .unwrap_or(0),
             &encoders,
+            &config.labeler.encoders,
             config.model.pooler,
-            0.0,
+            config.model.pooling_position,
+            &config.model.regularization,
             config.model.position_embeddings,
+            config.model.task_weighting,
+            config.model.treebanks.len(),
         )
         .context("Cannot construct model")?;
 
@@ -145,7 +149,7 @@ pub fn load_config(config_path: &str) -> Result<Config> {
     Ok(config)
 }
 
-fn load_biaffine_decoder(config: &BiaffineParserConfig) -> Result<ImmutableDependencyEncoder> {
+pub fn load_biaffine_decoder(config: &BiaffineParserConfig) -> Result<ImmutableDependencyEncoder> {
     let f = File::open(&config.labels).context(format!(
         "Cannot open dependency label file: {}",
         config.labels
@@ -161,7 +165,7 @@ fn load_biaffine_decoder(config: &BiaffineParserConfig) -> Result<ImmutableDepen
     Ok(encoder)
 }
 
-fn load_encoders(config: &Config) -> Result<Encoders> {
+pub fn load_encoders(config: &Config) -> Result<Encoders> {
     let f = File::open(&config.labeler.labels)
         .context(format!("Cannot open label file: {}", config.labeler.labels))?;
     let encoders: Encoders = serde_yaml::from_reader(&f).context(format!(
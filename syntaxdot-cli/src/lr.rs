@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use ordered_float::NotNan;
+use syntaxdot::lr::{CosineDecay, DecaySchedule, ExponentialDecay, InverseSqrtDecay, LinearDecay};
+
+use crate::traits::SyntaxDotOption;
+
+const LAYER_LR_DECAY: &str = "LAYER_LR_DECAY";
+const LR_SCHEDULE: &str = "LR_SCHEDULE";
+const LR_SCHEDULE_STEPS: &str = "LR_SCHEDULE_STEPS";
+
+/// Learning rate schedule types.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LrScheduleType {
+    Cosine,
+    CosineRestarts,
+    Exponential,
+    InverseSqrt,
+    Linear,
+}
+
+/// Learning rate schedule selected on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct LrScheduleSelection {
+    pub schedule_type: LrScheduleType,
+    pub schedule_steps: Option<usize>,
+}
+
+impl LrScheduleSelection {
+    /// Construct the selected decay schedule.
+    ///
+    /// `decay_rate` and `decay_steps` are only used by the exponential
+    /// schedule. Linear and cosine decay take `schedule_steps` steps when
+    /// it is specified and `default_schedule_steps` otherwise.
+    pub fn decay_schedule(
+        &self,
+        initial_lr: f32,
+        decay_rate: f32,
+        decay_steps: usize,
+        warmup_steps: usize,
+        default_schedule_steps: Option<usize>,
+    ) -> Result<DecaySchedule> {
+        let schedule_steps = || {
+            self.schedule_steps
+                .or(default_schedule_steps)
+                .context("The number of learning rate schedule steps is not specified")
+        };
+
+        Ok(match self.schedule_type {
+            LrScheduleType::Cosine => DecaySchedule::Cosine(CosineDecay::new(
+                initial_lr,
+                schedule_steps()?,
+                false,
+                warmup_steps,
+            )),
+            LrScheduleType::CosineRestarts => DecaySchedule::Cosine(CosineDecay::new(
+                initial_lr,
+                schedule_steps()?,
+                true,
+                warmup_steps,
+            )),
+            LrScheduleType::Exponential => DecaySchedule::Exponential(ExponentialDecay::new(
+                initial_lr,
+                decay_rate,
+                decay_steps,
+                false,
+                warmup_steps,
+            )),
+            LrScheduleType::InverseSqrt => {
+                DecaySchedule::InverseSqrt(InverseSqrtDecay::new(initial_lr, warmup_steps))
+            }
+            LrScheduleType::Linear => DecaySchedule::Linear(LinearDecay::new(
+                initial_lr,
+                schedule_steps()?,
+                warmup_steps,
+            )),
+        })
+    }
+}
+
+pub struct LrScheduleOption;
+
+impl SyntaxDotOption for LrScheduleOption {
+    type Value = LrScheduleSelection;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(LR_SCHEDULE)
+                .long("lr-schedule")
+                .value_name("SCHEDULE")
+                .possible_values(&[
+                    "cosine",
+                    "cosine-restarts",
+                    "exponential",
+                    "inverse-sqrt",
+                    "linear",
+                ])
+                .help("Learning rate schedule")
+                .default_value("exponential"),
+        )
+        .arg(
+            Arg::with_name(LR_SCHEDULE_STEPS)
+                .long("lr-schedule-steps")
+                .value_name("N")
+                .takes_value(true)
+                .help("Decay the learning rate in N steps (linear, cosine) or restart every N steps (cosine-restarts)"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let schedule_type = match matches.value_of(LR_SCHEDULE).unwrap() {
+            "cosine" => LrScheduleType::Cosine,
+            "cosine-restarts" => LrScheduleType::CosineRestarts,
+            "exponential" => LrScheduleType::Exponential,
+            "inverse-sqrt" => LrScheduleType::InverseSqrt,
+            "linear" => LrScheduleType::Linear,
+            schedule => bail!("Unknown learning rate schedule: {}", schedule),
+        };
+
+        let schedule_steps = matches
+            .value_of(LR_SCHEDULE_STEPS)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse learning rate schedule steps: {}", v))
+            })
+            .transpose()?;
+        if schedule_steps == Some(0) {
+            bail!("The number of learning rate schedule steps must be at least 1")
+        }
+
+        Ok(LrScheduleSelection {
+            schedule_type,
+            schedule_steps,
+        })
+    }
+}
+
+pub struct LayerLrDecayOption;
+
+impl SyntaxDotOption for LayerLrDecayOption {
+    type Value = NotNan<f32>;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(LAYER_LR_DECAY)
+                .long("layer-lr-decay")
+                .value_name("D")
+                .help("Multiply the learning rate by D for every encoder layer below the top layer")
+                .default_value("1.0"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let layer_lr_decay: NotNan<f32> = matches
+            .value_of(LAYER_LR_DECAY)
+            .unwrap()
+            .parse()
+            .context("Cannot parse layer-wise learning rate decay")?;
+        if layer_lr_decay.into_inner() <= 0. || layer_lr_decay.into_inner() > 1. {
+            bail!("The layer-wise learning rate decay must be in (0, 1]")
+        }
+
+        Ok(layer_lr_decay)
+    }
+}
@@ -3,8 +3,24 @@ use std::io::stdout;
 use anyhow::Result;
 use clap::{crate_version, App, AppSettings, Arg, Shell, SubCommand};
 
+pub mod augment;
+
+pub mod checkpoint;
+
+pub mod corpus;
+
+pub mod format;
+
 pub mod io;
 
+pub mod lr;
+
+pub mod malformed;
+
+pub mod metric;
+
+pub mod optimizer;
+
 pub mod progress;
 
 pub mod save;
@@ -30,10 +46,12 @@ fn main() -> Result<()> {
     // Known subapplications.
     let apps = vec![
         subcommands::AnnotateApp::app(),
+        subcommands::AverageApp::app(),
         subcommands::DistillApp::app(),
         subcommands::FilterLenApp::app(),
         subcommands::FinetuneApp::app(),
         subcommands::PrepareApp::app(),
+        subcommands::PreprocessApp::app(),
     ];
 
     env_logger::init();
@@ -55,6 +73,9 @@ fn main() -> Result<()> {
         "annotate" => {
             subcommands::AnnotateApp::parse(matches.subcommand_matches("annotate").unwrap())?.run()
         }
+        "average" => {
+            subcommands::AverageApp::parse(matches.subcommand_matches("average").unwrap())?.run()
+        }
         "completions" => {
             let shell = matches
                 .subcommand_matches("completions")
@@ -77,6 +98,10 @@ fn main() -> Result<()> {
         "prepare" => {
             subcommands::PrepareApp::parse(matches.subcommand_matches("prepare").unwrap())?.run()
         }
+        "preprocess" => {
+            subcommands::PreprocessApp::parse(matches.subcommand_matches("preprocess").unwrap())?
+                .run()
+        }
         _unknown => unreachable!(),
     }
 }
@@ -0,0 +1,32 @@
+use anyhow::{Context, Result};
+use clap::{App, Arg, ArgMatches};
+use syntaxdot::dataset::MalformedSentences;
+
+use crate::traits::SyntaxDotOption;
+
+const MALFORMED: &str = "MALFORMED";
+
+pub struct MalformedSentencesOption;
+
+impl SyntaxDotOption for MalformedSentencesOption {
+    type Value = MalformedSentences;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(MALFORMED)
+                .long("malformed")
+                .value_name("POLICY")
+                .possible_values(&["fail", "skip-with-warning", "mask-labels"])
+                .help("Handling of sentences with annotations that cannot be encoded")
+                .default_value("fail"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let malformed = matches.value_of(MALFORMED).unwrap();
+        malformed.parse().context(format!(
+            "Cannot parse malformed sentence policy: {}",
+            malformed
+        ))
+    }
+}
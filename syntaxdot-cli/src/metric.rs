@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{App, Arg, ArgMatches};
+
+use crate::traits::SyntaxDotOption;
+
+const SELECTION_METRIC: &str = "SELECTION_METRIC";
+
+/// Metric name of the average accuracy of all encoders.
+pub const AVERAGE_METRIC: &str = "avg";
+
+/// Scores of a validation set, indexed by metric name.
+pub type ValidationScores = BTreeMap<String, f32>;
+
+/// Metric that is used for model selection.
+///
+/// The metric is a weighted combination of validation scores, such as
+/// `las` or `0.7*las+0.3*ood:upos`. Each term is a metric name, which
+/// can be prefixed by a weight and by the name of a validation set.
+/// Terms without a validation set use the first validation set. The
+/// weighted sum of the scores is normalized by the sum of the weights.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectionMetric {
+    terms: Vec<MetricTerm>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct MetricTerm {
+    metric: String,
+    validation_set: Option<String>,
+    weight: f32,
+}
+
+impl SelectionMetric {
+    /// Check that the metric only uses known validation sets and metrics.
+    pub fn check(&self, validation_sets: &[String], metrics: &[String]) -> Result<()> {
+        for term in &self.terms {
+            if let Some(validation_set) = &term.validation_set {
+                if !validation_sets.contains(validation_set) {
+                    bail!(
+                        "Selection metric uses unknown validation set: {}",
+                        validation_set
+                    );
+                }
+            }
+
+            if !metrics.contains(&term.metric) {
+                bail!(
+                    "Selection metric uses unknown metric: {}, possible metrics: {}",
+                    term.metric,
+                    metrics.join(", ")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compute the metric.
+    ///
+    /// `scores` contains the name and the scores of each validation set,
+    /// in the order in which the validation sets were given.
+    pub fn compute(&self, scores: &[(String, ValidationScores)]) -> Result<f32> {
+        let mut weighted_sum = 0.;
+        let mut weight_sum = 0.;
+
+        for term in &self.terms {
+            let (validation_set, set_scores) = match &term.validation_set {
+                Some(name) => scores
+                    .iter()
+                    .find(|(set_name, _)| set_name == name)
+                    .ok_or_else(|| anyhow!("Unknown validation set: {}", name))?,
+                None => scores
+                    .first()
+                    .ok_or_else(|| anyhow!("No validation scores"))?,
+            };
+
+            let score = set_scores.get(&term.metric).ok_or_else(|| {
+                anyhow!(
+                    "Validation set {} does not have metric: {}",
+                    validation_set,
+                    term.metric
+                )
+            })?;
+
+            weighted_sum += term.weight * score;
+            weight_sum += term.weight;
+        }
+
+        Ok(weighted_sum / weight_sum)
+    }
+}
+
+impl FromStr for SelectionMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let terms = split_terms(s)
+            .into_iter()
+            .map(|term| {
+                let term = term.trim();
+
+                let mut parts = term.rsplitn(2, '*');
+                let term_metric = parts.next().unwrap().trim();
+                let weight = match parts.next() {
+                    Some(weight) => weight
+                        .trim()
+                        .parse()
+                        .context(format!("Cannot parse metric weight: {}", weight))?,
+                    None => 1.,
+                };
+                if !weight.is_finite() || weight <= 0. {
+                    bail!(
+                        "Metric weights must be positive and finite, was: {}",
+                        weight
+                    );
+                }
+
+                let mut parts = term_metric.rsplitn(2, ':');
+                let metric = parts.next().unwrap().trim();
+                let validation_set = parts.next().map(|set| set.trim().to_string());
+                if metric.is_empty() {
+                    bail!("Missing metric name in: {}", term);
+                }
+
+                Ok(MetricTerm {
+                    metric: metric.to_string(),
+                    validation_set,
+                    weight,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SelectionMetric { terms })
+    }
+}
+
+/// Split a selection metric into its terms.
+///
+/// A `+` that is the exponent sign of a weight (e.g. `1e+1*las`) does
+/// not separate terms.
+fn split_terms(s: &str) -> Vec<&str> {
+    let mut terms = Vec::new();
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if c == '+' && !is_exponent_prefix(s[start..idx].trim_start()) {
+            terms.push(&s[start..idx]);
+            start = idx + 1;
+        }
+    }
+    terms.push(&s[start..]);
+    terms
+}
+
+/// Check whether a term prefix is a number up to its exponent.
+fn is_exponent_prefix(prefix: &str) -> bool {
+    match prefix.strip_suffix(|c| c == 'e' || c == 'E') {
+        Some(mantissa) => {
+            let mantissa = mantissa.trim_start_matches(|c| c == '-' || c == '+');
+            !mantissa.is_empty() && mantissa.chars().all(|c| c.is_ascii_digit() || c == '.')
+        }
+        None => false,
+    }
+}
+
+pub struct SelectionMetricOption;
+
+impl SyntaxDotOption for SelectionMetricOption {
+    type Value = SelectionMetric;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(SELECTION_METRIC)
+                .long("selection-metric")
+                .value_name("METRIC")
+                .help("Validation metric for model selection (e.g. avg, las, 0.5*las+0.5*upos)")
+                .default_value(AVERAGE_METRIC),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let metric = matches.value_of(SELECTION_METRIC).unwrap();
+        metric
+            .parse()
+            .context(format!("Cannot parse selection metric: {}", metric))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::{SelectionMetric, ValidationScores};
+
+    fn scores() -> Vec<(String, ValidationScores)> {
+        vec![
+            (
+                "in".to_string(),
+                vec![("las".to_string(), 0.9), ("upos".to_string(), 0.96)]
+                    .into_iter()
+                    .collect(),
+            ),
+            (
+                "ood".to_string(),
+                vec![("las".to_string(), 0.8), ("upos".to_string(), 0.92)]
+                    .into_iter()
+                    .collect(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn unqualified_metric_uses_first_validation_set() {
+        let metric: SelectionMetric = "las".parse().unwrap();
+        assert_eq!(metric.compute(&scores()).unwrap(), 0.9);
+    }
+
+    #[test]
+    fn weighted_metric_is_normalized() {
+        let metric: SelectionMetric = "3*las + ood:las".parse().unwrap();
+        assert_abs_diff_eq!(metric.compute(&scores()).unwrap(), 0.875, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn weights_can_use_exponents() {
+        let metric: SelectionMetric = "1e+1*las+ood:las".parse().unwrap();
+        assert_abs_diff_eq!(
+            metric.compute(&scores()).unwrap(),
+            9.8 / 11.,
+            epsilon = 1e-6
+        );
+
+        let metric: SelectionMetric = "2.5E-1*upos + 7.5e-1*las".parse().unwrap();
+        assert_abs_diff_eq!(metric.compute(&scores()).unwrap(), 0.915, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn non_finite_and_negative_weights_are_rejected() {
+        assert!("inf*las".parse::<SelectionMetric>().is_err());
+        assert!("NaN*las".parse::<SelectionMetric>().is_err());
+        assert!("-1e+1*las".parse::<SelectionMetric>().is_err());
+        assert!("0*las+upos".parse::<SelectionMetric>().is_err());
+    }
+
+    #[test]
+    fn unknown_metrics_are_rejected() {
+        let metric: SelectionMetric = "0.5*dev:las+xpos".parse().unwrap();
+        let validation_sets = &["in".to_string(), "ood".to_string()];
+        let metrics = &["las".to_string(), "upos".to_string()];
+        assert!(metric.check(validation_sets, metrics).is_err());
+        assert!(metric.compute(&scores()).is_err());
+        assert!("las+".parse::<SelectionMetric>().is_err());
+        assert!("-1*las".parse::<SelectionMetric>().is_err());
+    }
+}
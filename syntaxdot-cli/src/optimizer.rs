@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use syntaxdot::optimizers::{
+    Adafactor, AdafactorConfig, AdamW, AdamWConfig, AnyOptimizer, Lamb, LambConfig, Sgd, SgdConfig,
+};
+use tch::nn::VarStore;
+
+use crate::traits::SyntaxDotOption;
+
+const EMA_DECAY: &str = "EMA_DECAY";
+const MOMENTUM: &str = "MOMENTUM";
+const OPTIMIZER: &str = "OPTIMIZER";
+const SWA_EPOCHS: &str = "SWA_EPOCHS";
+
+/// Optimizer selected on the command line.
+#[derive(Clone, Copy, Debug)]
+pub enum OptimizerSelection {
+    Adafactor,
+    AdamW,
+    Lamb,
+    Sgd { momentum: f64 },
+}
+
+impl OptimizerSelection {
+    /// Construct the selected optimizer for the trainable variables of `vs`.
+    pub fn build(&self, vs: &VarStore, weight_decay: f64) -> Result<AnyOptimizer> {
+        Ok(match *self {
+            OptimizerSelection::Adafactor => AnyOptimizer::Adafactor(Adafactor::new(
+                vs,
+                AdafactorConfig {
+                    weight_decay,
+                    ..AdafactorConfig::default()
+                },
+            )?),
+            OptimizerSelection::AdamW => AnyOptimizer::AdamW(AdamW::new(
+                vs,
+                AdamWConfig {
+                    weight_decay,
+                    ..AdamWConfig::default()
+                },
+            )?),
+            OptimizerSelection::Lamb => AnyOptimizer::Lamb(Lamb::new(
+                vs,
+                LambConfig {
+                    weight_decay,
+                    ..LambConfig::default()
+                },
+            )?),
+            OptimizerSelection::Sgd { momentum } => AnyOptimizer::Sgd(Sgd::new(
+                vs,
+                SgdConfig {
+                    momentum,
+                    weight_decay,
+                    ..SgdConfig::default()
+                },
+            )?),
+        })
+    }
+}
+
+pub struct OptimizerOption;
+
+impl SyntaxDotOption for OptimizerOption {
+    type Value = OptimizerSelection;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(OPTIMIZER)
+                .long("optimizer")
+                .value_name("OPTIMIZER")
+                .possible_values(&["adafactor", "adamw", "lamb", "sgd"])
+                .help("Optimizer")
+                .default_value("adamw"),
+        )
+        .arg(
+            Arg::with_name(MOMENTUM)
+                .long("momentum")
+                .value_name("M")
+                .help("Momentum of the SGD optimizer")
+                .default_value("0.9"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        Ok(match matches.value_of(OPTIMIZER).unwrap() {
+            "adafactor" => OptimizerSelection::Adafactor,
+            "adamw" => OptimizerSelection::AdamW,
+            "lamb" => OptimizerSelection::Lamb,
+            "sgd" => {
+                let momentum: f64 = matches
+                    .value_of(MOMENTUM)
+                    .unwrap()
+                    .parse()
+                    .context("Cannot parse momentum")?;
+                if !momentum.is_finite() || momentum < 0. {
+                    bail!("The momentum must be a non-negative number")
+                }
+                OptimizerSelection::Sgd { momentum }
+            }
+            optimizer => bail!("Unknown optimizer: {}", optimizer),
+        })
+    }
+}
+
+/// Parameter averaging selected on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct Averaging {
+    /// Decay of the exponential moving average of the parameters.
+    pub ema_decay: Option<f64>,
+
+    /// Number of epochs to use in stochastic weight averaging.
+    pub swa_epochs: Option<usize>,
+}
+
+pub struct AveragingOption;
+
+impl SyntaxDotOption for AveragingOption {
+    type Value = Averaging;
+
+    fn add_to_app(app: App<'static, 'static>) -> App<'static, 'static> {
+        app.arg(
+            Arg::with_name(EMA_DECAY)
+                .long("ema-decay")
+                .value_name("D")
+                .help(
+                "Validate and save an exponential moving average of the parameters with decay D",
+            ),
+        )
+        .arg(
+            Arg::with_name(SWA_EPOCHS)
+                .long("swa-epochs")
+                .value_name("N")
+                .help("Average the parameters of the last N epochs after training"),
+        )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self::Value> {
+        let ema_decay = matches
+            .value_of(EMA_DECAY)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse moving average decay: {}", v))
+            })
+            .transpose()?;
+        if ema_decay
+            .map(|decay: f64| decay <= 0. || decay >= 1.)
+            .unwrap_or(false)
+        {
+            bail!("The moving average decay must be in (0, 1)")
+        }
+
+        let swa_epochs = matches
+            .value_of(SWA_EPOCHS)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse number of epochs to average: {}", v))
+            })
+            .transpose()?;
+        if swa_epochs == Some(0) {
+            bail!("Refusing to average zero epochs")
+        }
+
+        Ok(Averaging {
+            ema_decay,
+            swa_epochs,
+        })
+    }
+}
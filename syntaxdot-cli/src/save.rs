@@ -1,8 +1,11 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
+use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use tch::nn::VarStore;
+use tch::{Device, Kind, Tensor};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum CompletedUnit<P> {
@@ -14,6 +17,12 @@ pub enum CompletedUnit<P> {
     /// The performance is of an epoch is typically evaluated against
     /// a validation set.
     Epoch(P),
+
+    /// Training steps are completed with the given performance.
+    ///
+    /// The first field is the global step after which the performance
+    /// was evaluated.
+    Step(usize, P),
 }
 
 /// Trait for model savers.
@@ -33,8 +42,8 @@ pub trait Save<P> {
     fn save(&mut self, vs: &VarStore, completed: CompletedUnit<P>) -> Result<()>;
 }
 
-/// Save best epochs with the best performance so far.
-#[derive(Clone)]
+/// Save epochs or steps with the best performance so far.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct BestEpochSaver<P> {
     best_epoch_performance: Option<P>,
     best_epoch_paths: Option<VecDeque<String>>,
@@ -73,35 +82,285 @@ where
     P: PartialOrd,
 {
     fn save(&mut self, vs: &VarStore, completed: CompletedUnit<P>) -> Result<()> {
-        if let CompletedUnit::Epoch(perf) = completed {
-            let improvement = match self.best_epoch_performance {
-                Some(ref mut best) => {
-                    if perf > *best {
-                        *best = perf;
-                        true
-                    } else {
-                        false
-                    }
-                }
-                None => {
-                    self.best_epoch_performance = Some(perf);
+        let (perf, unit) = match completed {
+            CompletedUnit::Batch(_) => return Ok(()),
+            CompletedUnit::Epoch(perf) => {
+                self.epoch += 1;
+                (perf, format!("epoch-{}", self.epoch - 1))
+            }
+            CompletedUnit::Step(step, perf) => (perf, format!("step-{}", step)),
+        };
+
+        let improvement = match self.best_epoch_performance {
+            Some(ref mut best) => {
+                if perf > *best {
+                    *best = perf;
                     true
+                } else {
+                    false
                 }
-            };
+            }
+            None => {
+                self.best_epoch_performance = Some(perf);
+                true
+            }
+        };
+
+        if improvement {
+            let path = format!("{}{}", self.prefix, unit);
+            vs.save(&path)
+                .context(format!("Cannot save variable store for {}", unit))?;
 
-            if improvement {
-                let path = format!("{}epoch-{}", self.prefix, self.epoch);
-                vs.save(&path).context(format!(
-                    "Cannot save variable store for epoch {}",
-                    self.epoch
-                ))?;
+            self.cleanup_old_best_steps(path)
+        }
 
-                self.cleanup_old_best_steps(path)
+        Ok(())
+    }
+}
+
+/// Saver for stochastic weight averaging (SWA).
+///
+/// This saver keeps the parameters of the last N epochs. After
+/// training, `average` averages these parameters.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SwaSaver {
+    epoch_paths: VecDeque<String>,
+    n_epochs: usize,
+    prefix: String,
+}
+
+impl SwaSaver {
+    pub fn new(prefix: impl Into<String>, n_epochs: usize) -> Self {
+        SwaSaver {
+            epoch_paths: VecDeque::with_capacity(n_epochs),
+            n_epochs,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Average the parameters of the saved epochs.
+    ///
+    /// The averaged parameters are stored in `vs` and saved with the
+    /// suffix `swa`. The parameters of the individual epochs are
+    /// removed.
+    pub fn average(&mut self, vs: &mut VarStore) -> Result<()> {
+        if self.epoch_paths.is_empty() {
+            return Ok(());
+        }
+
+        let weights = vec![1.; self.epoch_paths.len()];
+        let averages =
+            average_parameter_files(self.epoch_paths.make_contiguous(), &weights, vs.device())?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+
+        tch::no_grad(|| {
+            for (name, mut variable) in vs.variables() {
+                let average = averages.get(&name).ok_or_else(|| {
+                    anyhow!("Variable {} is missing in the epoch parameters", name)
+                })?;
+                variable.f_copy_(average)?;
+            }
+
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        let path = format!("{}swa", self.prefix);
+        vs.save(&path)
+            .context(format!("Cannot save averaged parameters to {}", path))?;
+
+        log::info!(
+            "Saved average of the last {} epochs to {}",
+            self.epoch_paths.len(),
+            path
+        );
+
+        for epoch_path in self.epoch_paths.drain(..) {
+            if let Err(err) = fs::remove_file(&epoch_path) {
+                log::error!("Cannot remove epoch parameters {}: {}", epoch_path, err);
             }
+        }
+
+        Ok(())
+    }
 
-            self.epoch += 1;
+    /// Save the parameters of an epoch.
+    ///
+    /// The parameters of the oldest epoch are removed when the
+    /// parameters of more than N epochs are stored.
+    pub fn save_epoch(&mut self, vs: &VarStore, epoch: usize) -> Result<()> {
+        let path = format!("{}swa-epoch-{}", self.prefix, epoch);
+        vs.save(&path)
+            .context(format!("Cannot save variable store for epoch {}", epoch))?;
+
+        if self.epoch_paths.len() == self.n_epochs {
+            let cleanup_epoch = self.epoch_paths.pop_front().expect("No epochs?");
+            if let Err(err) = fs::remove_file(&cleanup_epoch) {
+                log::error!("Cannot remove epoch parameters {}: {}", cleanup_epoch, err);
+            }
         }
 
+        self.epoch_paths.push_back(path);
+
         Ok(())
     }
 }
+
+/// Average the parameters stored in the given files.
+///
+/// The parameters of each file are weighted by the corresponding
+/// element of `weights`. The weights are normalized to sum to one. An
+/// error is returned when the files do not contain the same variables
+/// or when the shapes of a variable differ between files.
+pub fn average_parameter_files(
+    paths: &[impl AsRef<Path>],
+    weights: &[f64],
+    device: Device,
+) -> Result<Vec<(String, Tensor)>> {
+    if paths.is_empty() {
+        bail!("Cannot average zero parameter files");
+    }
+
+    if paths.len() != weights.len() {
+        bail!(
+            "Got {} parameter files, but {} weights",
+            paths.len(),
+            weights.len()
+        );
+    }
+
+    if weights.iter().any(|&weight| weight < 0.) {
+        bail!("Parameter file weights cannot be negative");
+    }
+
+    let weight_sum: f64 = weights.iter().sum();
+    if weight_sum <= 0. {
+        bail!("The sum of the parameter file weights must be positive");
+    }
+
+    // Variable name -> (original kind, weighted sum).
+    let mut sums: BTreeMap<String, (Kind, Tensor)> = BTreeMap::new();
+
+    for (idx, (path, &weight)) in paths.iter().zip(weights).enumerate() {
+        let path = path.as_ref().to_string_lossy();
+        let parameters = Tensor::load_multi_with_device(path.as_ref(), device)
+            .context(format!("Cannot load parameters from {}", path))?;
+
+        if idx == 0 {
+            for (name, tensor) in parameters {
+                let kind = tensor.kind();
+                let sum = tensor.f_to_kind(Kind::Float)?.f_mul_scalar(weight)?;
+                sums.insert(name, (kind, sum));
+            }
+
+            continue;
+        }
+
+        if parameters.len() != sums.len() {
+            bail!(
+                "{} contains {} variables, expected {}",
+                path,
+                parameters.len(),
+                sums.len()
+            );
+        }
+
+        for (name, tensor) in parameters {
+            let (_, sum) = sums
+                .get_mut(&name)
+                .ok_or_else(|| anyhow!("Unexpected variable {} in {}", name, path))?;
+
+            if tensor.size() != sum.size() {
+                bail!(
+                    "Variable {} has shape {:?} in {}, expected {:?}",
+                    name,
+                    tensor.size(),
+                    path,
+                    sum.size()
+                );
+            }
+
+            let _ = sum.f_add_(&tensor.f_to_kind(Kind::Float)?.f_mul_scalar(weight)?)?;
+        }
+    }
+
+    sums.into_iter()
+        .map(|(name, (kind, sum))| Ok((name, sum.f_div_scalar(weight_sum)?.f_to_kind(kind)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use tch::{Device, Tensor};
+
+    use super::average_parameter_files;
+
+    fn parameter_file(name: &str, variables: &[(&str, &[f32])]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "syntaxdot-average-{}-{}.pt",
+            name,
+            std::process::id()
+        ));
+        let tensors = variables
+            .iter()
+            .map(|&(name, values)| (name, Tensor::of_slice(values)))
+            .collect::<Vec<_>>();
+        Tensor::save_multi(&tensors, &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn parameters_are_averaged_by_weight() {
+        let paths = vec![
+            parameter_file("weighted-a", &[("w", &[1., 2.]), ("b", &[4.])]),
+            parameter_file("weighted-b", &[("w", &[5., 6.]), ("b", &[0.])]),
+        ];
+
+        let averages = average_parameter_files(&paths, &[1., 3.], Device::Cpu).unwrap();
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+
+        let averages = averages
+            .into_iter()
+            .map(|(name, tensor)| (name, Vec::<f32>::from(&tensor)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            averages,
+            vec![("b".to_string(), vec![1.]), ("w".to_string(), vec![4., 5.])]
+        );
+    }
+
+    #[test]
+    fn mismatching_names_are_rejected() {
+        let paths = vec![
+            parameter_file("names-a", &[("w", &[1., 2.])]),
+            parameter_file("names-b", &[("v", &[1., 2.])]),
+        ];
+
+        let averages = average_parameter_files(&paths, &[1., 1.], Device::Cpu);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert!(averages.is_err());
+    }
+
+    #[test]
+    fn mismatching_shapes_are_rejected() {
+        let paths = vec![
+            parameter_file("shapes-a", &[("w", &[1., 2.])]),
+            parameter_file("shapes-b", &[("w", &[1., 2., 3.])]),
+        ];
+
+        let averages = average_parameter_files(&paths, &[1., 1.], Device::Cpu);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert!(averages.is_err());
+    }
+}
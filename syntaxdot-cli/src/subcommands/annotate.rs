@@ -1,17 +1,23 @@
-use std::io::BufWriter;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{App, Arg, ArgMatches};
 use conllu::io::{ReadSentence, Reader, WriteSentence, Writer};
-use stdinout::{Input, Output};
+use syntaxdot::dataset::{ColumnSentences, ColumnWriter, SentenceFormat};
 use syntaxdot::tagger::Tagger;
 use syntaxdot_tokenizers::Tokenize;
 use tch::{self, Device};
+use udgraph::graph::Sentence;
 
-use crate::io::Model;
+use crate::corpus::{create_compressed, mirror_shards, open_decompressed, Corpus};
+use crate::format::SentenceFormatOption;
+use crate::io::{load_config, Model};
 use crate::progress::TaggerSpeed;
 use crate::sent_proc::SentProcessor;
-use crate::traits::{SyntaxDotApp, DEFAULT_CLAP_SETTINGS};
+use crate::traits::{SyntaxDotApp, SyntaxDotOption, DEFAULT_CLAP_SETTINGS};
 
 const CONFIG: &str = "CONFIG";
 const GPU: &str = "GPU";
@@ -22,11 +28,14 @@ const NUM_ANNOTATION_THREADS: &str = "NUM_ANNOTATION_THREADS";
 const NUM_INTEROP_THREADS: &str = "NUM_INTEROP_THREADS";
 const NUM_INTRAOP_THREADS: &str = "NUM_INTRAOP_THREADS";
 const OUTPUT: &str = "OUTPUT";
+const OUTPUT_DIR: &str = "OUTPUT_DIR";
 const READ_AHEAD: &str = "READ_AHEAD";
+const TREEBANK: &str = "TREEBANK";
 
 pub struct AnnotateApp {
     config: String,
     device: Device,
+    format: SentenceFormat,
     input: Option<String>,
     max_batch_pieces: usize,
     max_len: Option<usize>,
@@ -34,32 +43,61 @@ pub struct AnnotateApp {
     num_interop_threads: usize,
     num_intraop_threads: usize,
     output: Option<String>,
+    output_dir: Option<String>,
     read_ahead: usize,
+    treebank: Option<String>,
 }
 
 impl AnnotateApp {
-    fn process<R, W>(
+    /// Annotate the sentences in `read`, writing them to `write`.
+    ///
+    /// Sentences are read and written in the data format of the app.
+    fn process_format(
         &self,
         tokenizer: &dyn Tokenize,
-        tagger: Tagger,
-        read: R,
+        tagger: &Tagger,
+        read: Box<dyn BufRead>,
+        write: &mut dyn Write,
+    ) -> Result<()> {
+        match &self.format {
+            SentenceFormat::Conllu => self.process(
+                tokenizer,
+                tagger,
+                Reader::new(read).sentences(),
+                Writer::new(write),
+            ),
+            SentenceFormat::Column(format) => self.process(
+                tokenizer,
+                tagger,
+                ColumnSentences::new(read, format.clone()),
+                ColumnWriter::new(write, format.clone()),
+            ),
+        }
+    }
+
+    fn process<S, E, W>(
+        &self,
+        tokenizer: &dyn Tokenize,
+        tagger: &Tagger,
+        sentences: S,
         write: W,
     ) -> Result<()>
     where
-        R: ReadSentence,
+        S: Iterator<Item = Result<Sentence, E>>,
+        E: Error + Send + Sync + 'static,
         W: WriteSentence,
     {
         let mut speed = TaggerSpeed::new();
 
         let mut sent_proc = SentProcessor::new(
-            &tagger,
+            tagger,
             write,
             self.max_batch_pieces,
             self.max_len,
             self.read_ahead,
         );
 
-        for sentence in read.sentences() {
+        for sentence in sentences {
             let sentence = sentence.context("Cannot parse sentence")?;
 
             let tokenized_sentence = tokenizer.tokenize(sentence);
@@ -77,7 +115,7 @@ impl AnnotateApp {
 
 impl SyntaxDotApp for AnnotateApp {
     fn app() -> App<'static, 'static> {
-        App::new("annotate")
+        let app = App::new("annotate")
             .settings(DEFAULT_CLAP_SETTINGS)
             .about("Annotate a corpus")
             .arg(
@@ -86,13 +124,26 @@ impl SyntaxDotApp for AnnotateApp {
                     .index(1)
                     .required(true),
             )
-            .arg(Arg::with_name(INPUT).help("Input data").index(2))
+            .arg(
+                Arg::with_name(INPUT)
+                    .help("Input data (file, directory or glob pattern)")
+                    .index(2),
+            )
             .arg(
                 Arg::with_name(OUTPUT)
                     .help("Output data")
                     .index(3)
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name(OUTPUT_DIR)
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .takes_value(true)
+                    .requires(INPUT)
+                    .conflicts_with(OUTPUT)
+                    .help("Write the annotations of each input file to a file in DIR"),
+            )
             .arg(
                 Arg::with_name(GPU)
                     .long("gpu")
@@ -140,6 +191,14 @@ impl SyntaxDotApp for AnnotateApp {
                     .long("readahead")
                     .default_value("5000"),
             )
+            .arg(
+                Arg::with_name(TREEBANK)
+                    .long("treebank")
+                    .value_name("NAME")
+                    .help("Annotate sentences as sentences from the given treebank"),
+            );
+
+        SentenceFormatOption::add_to_app(app)
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
@@ -151,6 +210,7 @@ impl SyntaxDotApp for AnnotateApp {
             ),
             None => Device::Cpu,
         };
+        let format = SentenceFormatOption::parse(matches)?;
         let input = matches.value_of(INPUT).map(ToOwned::to_owned);
         let max_batch_pieces = matches
             .value_of(MAX_BATCH_PIECES)
@@ -177,15 +237,18 @@ impl SyntaxDotApp for AnnotateApp {
             .map(|v| v.parse().context("Cannot parse maximum sentence length"))
             .transpose()?;
         let output = matches.value_of(OUTPUT).map(ToOwned::to_owned);
+        let output_dir = matches.value_of(OUTPUT_DIR).map(ToOwned::to_owned);
         let read_ahead = matches
             .value_of(READ_AHEAD)
             .unwrap()
             .parse()
             .context("Cannot parse number of sentences to read ahead")?;
+        let treebank = matches.value_of(TREEBANK).map(ToOwned::to_owned);
 
         Ok(AnnotateApp {
             config,
             device,
+            format,
             input,
             max_batch_pieces,
             max_len,
@@ -193,7 +256,9 @@ impl SyntaxDotApp for AnnotateApp {
             num_interop_threads,
             num_intraop_threads,
             output,
+            output_dir,
             read_ahead,
+            treebank,
         })
     }
 
@@ -208,22 +273,80 @@ impl SyntaxDotApp for AnnotateApp {
             .build_global()
             .unwrap();
 
+        let treebank_id = match &self.treebank {
+            Some(treebank) => {
+                let config = load_config(&self.config)?;
+                Some(config.model.treebank_id(treebank).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown treebank: {}, treebanks: {}",
+                        treebank,
+                        config.model.treebanks.join(", ")
+                    )
+                })?)
+            }
+            None => None,
+        };
+
         let model = Model::load(&self.config, self.device, true, false, |_| 0)?;
         let tagger = Tagger::new(
             self.device,
             model.model,
             model.biaffine_encoder,
             model.encoders,
+            treebank_id,
         );
 
-        let input = Input::from(self.input.as_ref());
-        let reader = Reader::new(input.buf_read().context("Cannot open input for reading")?);
+        let corpus = self.input.as_deref().map(Corpus::new).transpose()?;
+
+        // Mirror the files of the input corpus in the output directory.
+        if let (Some(corpus), Some(output_dir)) = (&corpus, &self.output_dir) {
+            let output_dir = Path::new(output_dir);
+            fs::create_dir_all(output_dir).context(format!(
+                "Cannot create output directory: {}",
+                output_dir.display()
+            ))?;
+
+            for (shard, output_path) in corpus
+                .shards()
+                .iter()
+                .zip(mirror_shards(corpus, output_dir)?)
+            {
+                log::info!("Annotating {}", shard.display());
+
+                let read = open_decompressed(shard)
+                    .context(format!("Cannot open input: {}", shard.display()))?;
+                let mut write = create_compressed(&output_path)
+                    .context(format!("Cannot open output: {}", output_path.display()))?;
 
-        let output = Output::from(self.output.as_ref());
-        let writer = Writer::new(BufWriter::new(
-            output.write().context("Cannot open output for writing")?,
-        ));
+                self.process_format(&*model.tokenizer, &tagger, read, &mut write)?;
 
-        self.process(&*model.tokenizer, tagger, reader, writer)
+                write
+                    .finish()
+                    .context(format!("Cannot finish output: {}", output_path.display()))?;
+            }
+
+            return Ok(());
+        }
+
+        let read: Box<dyn BufRead> = match &corpus {
+            Some(corpus) => Box::new(corpus.open().context("Cannot open input for reading")?),
+            None => Box::new(BufReader::new(io::stdin())),
+        };
+
+        match &self.output {
+            Some(output) => {
+                let mut write = create_compressed(Path::new(output))
+                    .context(format!("Cannot open output for writing: {}", output))?;
+                self.process_format(&*model.tokenizer, &tagger, read, &mut write)?;
+                write
+                    .finish()
+                    .context(format!("Cannot finish output: {}", output))
+            }
+            None => {
+                let mut write = BufWriter::new(io::stdout());
+                self.process_format(&*model.tokenizer, &tagger, read, &mut write)?;
+                write.flush().context("Cannot write output")
+            }
+        }
     }
 }
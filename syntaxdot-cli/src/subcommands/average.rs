@@ -0,0 +1,96 @@
+use anyhow::{bail, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use tch::{Device, Tensor};
+
+use crate::save::average_parameter_files;
+use crate::traits::{SyntaxDotApp, DEFAULT_CLAP_SETTINGS};
+
+const OUTPUT: &str = "OUTPUT";
+const PARAMETERS: &str = "PARAMETERS";
+const WEIGHTS: &str = "WEIGHTS";
+
+pub struct AverageApp {
+    output: String,
+    parameters: Vec<String>,
+    weights: Vec<f64>,
+}
+
+impl SyntaxDotApp for AverageApp {
+    fn app() -> App<'static, 'static> {
+        App::new("average")
+            .settings(DEFAULT_CLAP_SETTINGS)
+            .about("Average the parameters of several epochs or steps")
+            .arg(
+                Arg::with_name(WEIGHTS)
+                    .long("weights")
+                    .value_name("W1,W2,...")
+                    .help("Comma-separated weights of the parameter files (default: uniform)"),
+            )
+            .arg(
+                Arg::with_name(OUTPUT)
+                    .help("Output file for the averaged parameters")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(PARAMETERS)
+                    .help("Parameter files to average")
+                    .index(2)
+                    .multiple(true)
+                    .required(true),
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self> {
+        let output = matches.value_of(OUTPUT).unwrap().into();
+        let parameters: Vec<String> = matches
+            .values_of(PARAMETERS)
+            .unwrap()
+            .map(ToOwned::to_owned)
+            .collect();
+
+        let weights = match matches.value_of(WEIGHTS) {
+            Some(weights) => weights
+                .split(',')
+                .map(|weight| {
+                    weight
+                        .trim()
+                        .parse()
+                        .context(format!("Cannot parse parameter file weight: {}", weight))
+                })
+                .collect::<Result<Vec<f64>>>()?,
+            None => vec![1.; parameters.len()],
+        };
+
+        if weights.len() != parameters.len() {
+            bail!(
+                "The number of weights ({}) differs from the number of parameter files ({})",
+                weights.len(),
+                parameters.len()
+            );
+        }
+
+        Ok(AverageApp {
+            output,
+            parameters,
+            weights,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let averages = average_parameter_files(&self.parameters, &self.weights, Device::Cpu)?;
+
+        Tensor::save_multi(&averages, &self.output).context(format!(
+            "Cannot save averaged parameters to {}",
+            self.output
+        ))?;
+
+        log::info!(
+            "Saved average of {} parameter files to {}",
+            self.parameters.len(),
+            self.output
+        );
+
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
-use std::cell::RefCell;
 use std::collections::btree_map::{BTreeMap, Entry};
 use std::collections::{HashMap, VecDeque};
-use std::fs::{self, File};
+use std::fs;
 use std::io::{BufRead, BufReader, Seek};
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -9,47 +8,62 @@ use clap::{App, Arg, ArgMatches};
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use ordered_float::NotNan;
-use syntaxdot::config::{Config, PretrainConfig};
+use serde::{Deserialize, Serialize};
+use syntaxdot::config::{Config, PretrainConfig, Regularization};
 use syntaxdot::dataset::{
-    BatchedTensors, ConlluDataSet, DataSet, PlainTextDataSet, SentenceIterTools, SequenceLength,
+    batch_tensors, is_cached_data_set, BatchSize, BatchedTensors, DataSet, MalformedSentences,
+    PlainTextDataSet, SentenceFormat, SentenceIterTools, SequenceLength,
 };
 use syntaxdot::encoders::Encoders;
 use syntaxdot::error::SyntaxDotError;
-use syntaxdot::lr::{ExponentialDecay, LearningRateSchedule};
+use syntaxdot::lr::{DecaySchedule, LearningRateSchedule};
 use syntaxdot::model::bert::{BertModel, FreezeLayers, PretrainBertConfig};
 use syntaxdot::model::biaffine_dependency_layer::BiaffineScoreLogits;
-use syntaxdot::optimizers::{GradScaler, Optimizer};
+use syntaxdot::optimizers::{ExponentialMovingAverage, GradScaler, Optimizer, OptimizerState};
 use syntaxdot::tensor::{Tensors, TokenMask};
 use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
 use syntaxdot_tch_ext::RootExt;
-use syntaxdot_tokenizers::Tokenize;
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
 use syntaxdot_transformers::models::LayerOutput;
 use tch::nn::{Init, VarStore};
 use tch::{self, Device, Kind, Reduction, Tensor};
 
+use crate::checkpoint::{random_seed, seed_torch, Checkpoint};
+use crate::corpus::Corpus;
+use crate::format::SentenceFormatOption;
 use crate::io::{load_config, load_pretrain_config, load_tokenizer, Model};
+use crate::lr::{LayerLrDecayOption, LrScheduleOption, LrScheduleSelection};
+use crate::malformed::MalformedSentencesOption;
+use crate::optimizer::{Averaging, AveragingOption, OptimizerOption, OptimizerSelection};
 use crate::progress::ReadProgress;
+use crate::save::SwaSaver;
 use crate::summary::{ScalarWriter, SummaryOption};
 use crate::traits::{
     ParameterGroup, SyntaxDotApp, SyntaxDotOption, SyntaxDotTrainApp, DEFAULT_CLAP_SETTINGS,
 };
-use crate::util::{autocast_or_preserve, count_sentences};
+use crate::util::{autocast_or_preserve, count_sentences, TrainDuration};
 
 const ATTENTION_LOSS: &str = "ATTENTION_LOSS";
 const BATCH_SIZE: &str = "BATCH_SIZE";
+const CHECKPOINT: &str = "CHECKPOINT";
 const EPOCHS: &str = "EPOCHS";
 const EVAL_STEPS: &str = "EVAL_STEPS";
 const HIDDEN_LOSS: &str = "HIDDEN_LOSS";
 const TEACHER_CONFIG: &str = "TEACHER_CONFIG";
 const STUDENT_CONFIG: &str = "STUDENT_CONFIG";
 const GPU: &str = "GPU";
+const GRAD_ACCUMULATION_STEPS: &str = "GRAD_ACCUMULATION_STEPS";
 const INITIAL_LR_CLASSIFIER: &str = "INITIAL_LR_CLASSIFIER";
 const INITIAL_LR_ENCODER: &str = "INITIAL_LR_ENCODER";
 const KEEP_BEST_STEPS: &str = "KEEP_BEST_STEPS";
 const LR_DECAY_RATE: &str = "LR_DECAY_RATE";
 const LR_DECAY_STEPS: &str = "LR_DECAY_STEPS";
+const MAX_BATCH_PIECES: &str = "MAX_BATCH_PIECES";
+const MAX_GRAD_NORM: &str = "MAX_GRAD_NORM";
 const MAX_LEN: &str = "MAX_LEN";
 const MIXED_PRECISION: &str = "MIXED_PRECISION";
+const RESUME: &str = "RESUME";
+const SHUFFLE_BUFFER_SIZE: &str = "SHUFFLE_BUFFER_SIZE";
 const STEPS: &str = "N_STEPS";
 const TRAIN_DATA: &str = "TRAIN_DATA";
 const VALIDATION_DATA: &str = "VALIDATION_DATA";
@@ -101,14 +115,23 @@ struct DistillLoss {
 
 pub struct DistillApp {
     attention_loss: bool,
-    batch_size: usize,
+    averaging: Averaging,
+    batch_size: BatchSize,
+    checkpoint: String,
     device: Device,
     eval_steps: usize,
+    format: SentenceFormat,
+    grad_accumulation_steps: usize,
     hidden_loss: Option<Vec<(usize, usize)>>,
     keep_best_steps: Option<usize>,
+    malformed: MalformedSentences,
+    max_grad_norm: Option<f64>,
     max_len: SequenceLength,
     mixed_precision: bool,
-    lr_schedules: RefCell<LearningRateSchedules>,
+    optimizer: OptimizerSelection,
+    lr_schedule: LrSchedule,
+    resume: bool,
+    shuffle_buffer_size: Option<usize>,
     student_config: String,
     summary_writer: Box<dyn ScalarWriter>,
     teacher_config: String,
@@ -118,9 +141,39 @@ pub struct DistillApp {
     weight_decay: f64,
 }
 
+pub struct LrSchedule {
+    pub initial_lr_classifier: NotNan<f32>,
+    pub initial_lr_encoder: NotNan<f32>,
+    pub layer_lr_decay: NotNan<f32>,
+    pub lr_decay_rate: NotNan<f32>,
+    pub lr_decay_steps: usize,
+    pub schedule: LrScheduleSelection,
+    pub warmup_steps: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct LearningRateSchedules {
-    pub classifier: ExponentialDecay,
-    pub encoder: ExponentialDecay,
+    pub classifier: DecaySchedule,
+    pub encoder: DecaySchedule,
+}
+
+/// Distillation state that is stored in checkpoints.
+#[derive(Deserialize, Serialize)]
+struct DistillState {
+    best_acc: f32,
+    best_step: usize,
+    best_step_paths: Option<VecDeque<String>>,
+
+    /// The number of completed passes over the training data.
+    epoch: usize,
+
+    /// The number of batches in the current pass over the training data.
+    epoch_step: usize,
+
+    global_step: usize,
+    lr_schedules: LearningRateSchedules,
+    seed: i64,
+    swa_saver: Option<SwaSaver>,
 }
 
 struct StudentModel {
@@ -135,6 +188,9 @@ struct EpochStats {
     encoder_accuracy: BTreeMap<String, f32>,
     encoder_loss: BTreeMap<String, f32>,
     n_tokens: i64,
+    sentence_accuracy: BTreeMap<String, f32>,
+    sentence_loss: BTreeMap<String, f32>,
+    n_sentences: i64,
 }
 
 impl DistillApp {
@@ -300,102 +356,144 @@ impl DistillApp {
     #[allow(clippy::too_many_arguments)]
     fn distill_model(
         &self,
-        grad_scaler: &mut GradScaler<impl Optimizer>,
+        grad_scaler: &mut GradScaler<ExponentialMovingAverage<impl Optimizer + OptimizerState>>,
         auxiliary_params: &AuxiliaryParameters,
         teacher: &Model,
-        student: &StudentModel,
-        teacher_train_file: &File,
-        student_train_file: &File,
-        validation_file: &mut File,
+        student: &mut StudentModel,
+        train_corpus: &Corpus,
+        validation_corpus: &Corpus,
+        checkpoint: &Checkpoint,
+        n_steps: usize,
+        mut state: DistillState,
     ) -> Result<()> {
-        let mut best_step = 0;
-        let mut best_acc = 0.0;
-
-        let mut global_step = 0;
-
-        let mut best_step_paths = self.keep_best_steps.map(VecDeque::with_capacity);
-
-        let n_steps = self
-            .train_duration
-            .as_steps(teacher_train_file, self.batch_size)
-            .context("Cannot determine number of training steps")?;
-
         let train_progress = ProgressBar::new(n_steps as u64);
         train_progress.set_style(ProgressStyle::default_bar().template(
             "[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}% train {msg}",
         ));
+        train_progress.set_position(state.global_step as u64);
 
-        while global_step < n_steps - 1 {
-            let mut teacher_train_dataset = Self::open_dataset(teacher_train_file)?;
-            let mut student_train_dataset = Self::open_dataset(student_train_file)?;
+        while state.global_step < n_steps - 1 {
+            let mut train_dataset = Self::open_dataset(train_corpus)?;
 
-            let teacher_train_batches = teacher_train_dataset
+            let sentences = train_dataset
                 .sentences(&*teacher.tokenizer)?
-                .filter_by_len(self.max_len)
-                .batched_tensors(None, None, self.batch_size);
-
-            let student_train_batches = student_train_dataset
-                .sentences(&*student.tokenizer)?
-                .filter_by_len(self.max_len)
-                .batched_tensors(None, None, self.batch_size);
-
-            for (teacher_steps, student_steps) in teacher_train_batches
-                .chunks(self.eval_steps)
-                .into_iter()
-                .zip(student_train_batches.chunks(self.eval_steps).into_iter())
-            {
-                self.train_steps(
+                .filter_by_len(self.max_len);
+            let teacher_batches: Box<dyn Iterator<Item = _>> = match self.shuffle_buffer_size {
+                Some(buffer_size) => Box::new(sentences.bucketed_shuffle(
+                    self.batch_size,
+                    buffer_size,
+                    state.seed.wrapping_add(state.epoch as i64) as u64,
+                )),
+                None => Box::new(sentences.batched(self.batch_size)),
+            };
+
+            // The teacher and the student can use different tokenizers.
+            // Batches are formed using the teacher pieces, the sentences
+            // of each batch are then tokenized again for the student. This
+            // ensures that the teacher and student batches are aligned.
+            //
+            // When resuming, skip the batches of the current pass over
+            // the training data that were already processed.
+            let train_batches = teacher_batches.skip(state.epoch_step).map(|teacher_batch| {
+                let teacher_batch = teacher_batch?;
+                let student_batch = Self::retokenize(&*student.tokenizer, &teacher_batch);
+                Ok::<_, SyntaxDotError>((
+                    batch_tensors(teacher_batch, None, None)?,
+                    batch_tensors(student_batch, None, None)?,
+                ))
+            });
+
+            // Evaluation is done after a number of optimizer steps, each
+            // step uses the gradients of grad_accumulation_steps batches.
+            let eval_batches = self.eval_steps * self.grad_accumulation_steps;
+
+            for steps in train_batches.chunks(eval_batches).into_iter() {
+                seed_torch(state.seed, state.global_step);
+
+                state.epoch_step += self.train_steps(
                     auxiliary_params,
                     &train_progress,
-                    teacher_steps,
-                    student_steps,
-                    &mut global_step,
+                    steps,
+                    &mut state.global_step,
+                    &mut state.lr_schedules,
                     grad_scaler,
                     &teacher.model,
                     &student.inner,
                 )?;
 
+                // Validate and save the averaged parameters when a moving
+                // average is used.
+                grad_scaler.optimizer_mut().swap_average()?;
+
                 let acc = self.validation_epoch(
                     teacher.biaffine_encoder.as_ref(),
                     &teacher.encoders,
                     &*student.tokenizer,
                     &student.inner,
-                    validation_file,
-                    global_step,
+                    validation_corpus,
+                    state.global_step,
                 )?;
 
-                self.summary_writer
-                    .write_scalar("acc:validation,avg", global_step as i64, acc)?;
+                self.summary_writer.write_scalar(
+                    "acc:validation,avg",
+                    state.global_step as i64,
+                    acc,
+                )?;
 
-                if acc > best_acc {
-                    best_step = global_step;
-                    best_acc = acc;
+                if acc > state.best_acc {
+                    state.best_step = state.global_step;
+                    state.best_acc = acc;
 
-                    let step_path = format!("distill-step-{}", global_step);
+                    let step_path = format!("distill-step-{}", state.global_step);
 
                     student.vs.save(&step_path).context(format!(
                         "Cannot save variable store for step {}",
-                        global_step
+                        state.global_step
                     ))?;
 
-                    self.cleanup_old_best_steps(&mut best_step_paths, step_path);
+                    self.cleanup_old_best_steps(&mut state.best_step_paths, step_path);
                 }
 
-                let step_status = if best_step == global_step { "🎉" } else { "" };
+                grad_scaler.optimizer_mut().swap_average()?;
+
+                checkpoint
+                    .save(&student.vs, &*grad_scaler, &state)
+                    .context("Cannot save checkpoint")?;
+
+                let step_status = if state.best_step == state.global_step {
+                    "🎉"
+                } else {
+                    ""
+                };
 
                 log::info!(
                     "Step {} (validation): acc: {:.4}, best step: {}, best acc: {:.4} {}\n",
-                    global_step,
+                    state.global_step,
                     acc,
-                    best_step,
-                    best_acc,
+                    state.best_step,
+                    state.best_acc,
                     step_status
                 );
 
-                if global_step >= n_steps - 1 {
+                if state.global_step >= n_steps - 1 {
                     break;
                 }
             }
+
+            if let Some(swa_saver) = &mut state.swa_saver {
+                swa_saver
+                    .save_epoch(&student.vs, state.epoch)
+                    .context("Cannot save epoch parameters for averaging")?;
+            }
+
+            state.epoch += 1;
+            state.epoch_step = 0;
+        }
+
+        if let Some(swa_saver) = &mut state.swa_saver {
+            swa_saver
+                .average(&mut student.vs)
+                .context("Cannot average parameters")?;
         }
 
         Ok(())
@@ -515,6 +613,34 @@ impl DistillApp {
         Ok(loss)
     }
 
+    /// Compute loss for sentence encoders.
+    fn sentence_encoders_loss(
+        teacher_sentence_logits: HashMap<String, Tensor>,
+        student_sentence_logits: HashMap<String, Tensor>,
+        device: Device,
+    ) -> Result<Tensor, SyntaxDotError> {
+        let mut loss = Tensor::zeros(&[], (Kind::Float, device));
+
+        for (encoder_name, teacher_logits) in teacher_sentence_logits {
+            let n_labels = teacher_logits.size()[2];
+
+            let student_logits = student_sentence_logits[&encoder_name].reshape(&[-1, n_labels]);
+            let teacher_logits = teacher_logits.reshape(&[-1, n_labels]);
+
+            // Compute the soft loss.
+            let teacher_probs = teacher_logits.f_softmax(-1, Kind::Float)?;
+            let student_logprobs = student_logits.f_log_softmax(-1, Kind::Float)?;
+            let soft_losses = teacher_probs.f_mul(&student_logprobs)?.f_neg()?;
+            let _ = loss.f_add_(
+                &soft_losses
+                    .f_sum_dim_intlist(&[-1], false, Kind::Float)?
+                    .f_mean(Kind::Float)?,
+            )?;
+        }
+
+        Ok(loss)
+    }
+
     fn student_loss(
         &self,
         auxiliary_params: &AuxiliaryParameters,
@@ -536,6 +662,7 @@ impl DistillApp {
             &teacher_batch.inputs.to_device(self.device),
             &teacher_attention_mask,
             &teacher_token_spans,
+            &teacher_batch.treebank_ids.to_device(self.device),
             false,
             FreezeLayers {
                 embeddings: true,
@@ -545,6 +672,8 @@ impl DistillApp {
         )?;
         let teacher_encoder_logits =
             teacher.encoder_logits_from_encoding(&teacher_layer_outputs, false)?;
+        let teacher_sentence_logits =
+            teacher.sentence_logits_from_encoding(&teacher_layer_outputs, false)?;
         let teacher_biaffine_logits =
             teacher.biaffine_logits_from_encoding(&teacher_layer_outputs, &token_mask, false)?;
 
@@ -559,6 +688,7 @@ impl DistillApp {
                 &student_batch.inputs.to_device(self.device),
                 &student_attention_mask,
                 &student_token_spans,
+                &student_batch.treebank_ids.to_device(self.device),
                 true,
                 FreezeLayers {
                     embeddings: false,
@@ -595,8 +725,20 @@ impl DistillApp {
                 &token_mask,
             )?)?;
 
+            // Compute sentence encoder/decoder loss.
+            let student_sentence_logits =
+                student.sentence_logits_from_encoding(&student_layer_outputs, true)?;
+            let _ = soft_loss.f_add_(&Self::sentence_encoders_loss(
+                teacher_sentence_logits,
+                student_sentence_logits,
+                self.device,
+            )?)?;
+
             let attention_loss = if self.attention_loss {
-                self.attention_loss(&teacher_layer_outputs, &student_layer_outputs)?
+                self.attention_loss(
+                    teacher_layer_outputs.layers(),
+                    student_layer_outputs.layers(),
+                )?
             } else {
                 Tensor::zeros(&[], (Kind::Float, self.device))
             };
@@ -605,8 +747,8 @@ impl DistillApp {
                 Some(ref mappings) => self.hidden_loss(
                     &token_mask,
                     mappings,
-                    &teacher_layer_outputs,
-                    &student_layer_outputs,
+                    &teacher_layer_outputs.pooled_layers()?,
+                    &student_layer_outputs.pooled_layers()?,
                 )?,
                 None => Tensor::zeros(&[], (Kind::Float, self.device)),
             };
@@ -620,21 +762,26 @@ impl DistillApp {
         })
     }
 
+    /// Train on the given batches.
+    ///
+    /// Returns the number of batches that were used.
     #[allow(clippy::too_many_arguments)]
     fn train_steps(
         &self,
         auxiliary_params: &AuxiliaryParameters,
         progress: &ProgressBar,
-        teacher_batches: impl Iterator<Item = Result<Tensors, SyntaxDotError>>,
-        student_batches: impl Iterator<Item = Result<Tensors, SyntaxDotError>>,
+        batches: impl Iterator<Item = Result<(Tensors, Tensors), SyntaxDotError>>,
         global_step: &mut usize,
+        lr_schedules: &mut LearningRateSchedules,
         grad_scaler: &mut GradScaler<impl Optimizer>,
         teacher: &BertModel,
         student: &BertModel,
-    ) -> Result<()> {
-        for (teacher_batch, student_batch) in teacher_batches.zip(student_batches) {
-            let teacher_batch = teacher_batch.context("Cannot read teacher batch")?;
-            let student_batch = student_batch.context("Cannot read student batch")?;
+    ) -> Result<usize> {
+        let mut n_batches = 0;
+        let mut accumulated_batches = 0;
+
+        for batch in batches {
+            let (teacher_batch, student_batch) = batch.context("Cannot read batch")?;
 
             let distill_loss = self.student_loss(
                 auxiliary_params,
@@ -644,38 +791,38 @@ impl DistillApp {
                 student_batch,
             )?;
 
-            let lr_classifier = self
-                .lr_schedules
-                .borrow_mut()
+            let lr_classifier = lr_schedules
                 .classifier
                 .compute_step_learning_rate(*global_step);
-            let lr_encoder = self
-                .lr_schedules
-                .borrow_mut()
+            let lr_encoder = lr_schedules
                 .encoder
                 .compute_step_learning_rate(*global_step);
 
             let optimizer = grad_scaler.optimizer_mut();
 
-            optimizer.set_lr_group(ParameterGroup::Encoder as usize, lr_encoder.into());
-            optimizer.set_lr_group(
-                ParameterGroup::EncoderNoWeightDecay as usize,
-                lr_encoder.into(),
-            );
-            optimizer.set_lr_group(ParameterGroup::Classifier as usize, lr_classifier.into());
-            optimizer.set_lr_group(
-                ParameterGroup::ClassifierNoWeightDecay as usize,
-                lr_classifier.into(),
-            );
+            self.set_lr_groups(optimizer, student.n_layers(), lr_encoder, lr_classifier);
 
-            grad_scaler.backward_step(&distill_loss.loss)?;
+            // Log the learning rates once per optimizer step.
+            if accumulated_batches == 0 {
+                for (group, lr) in self.group_lrs(student.n_layers(), lr_encoder, lr_classifier) {
+                    self.summary_writer.write_scalar(
+                        &format!("lr:{}", group),
+                        *global_step as i64,
+                        lr,
+                    )?;
+                }
+            }
 
-            self.summary_writer.write_scalar(
-                "gradient_scale",
-                *global_step as i64,
-                grad_scaler.current_scale(),
+            // Average the loss over the accumulated batches.
+            grad_scaler.backward(
+                &distill_loss
+                    .loss
+                    .f_div_scalar(self.grad_accumulation_steps as f64)?,
             )?;
 
+            n_batches += 1;
+            accumulated_batches += 1;
+
             progress.set_message(format!(
                 "step: {} | lr enc: {:+.1e}, class: {:+.1e} | loss soft: {:+.1e}, attention: {:+.1e}, hidden: {:+.1e}",
                 global_step,
@@ -685,19 +832,110 @@ impl DistillApp {
                 f32::from(distill_loss.attention_loss),
                 f32::from(distill_loss.hidden_loss)
             ));
-            progress.inc(1);
 
-            *global_step += 1;
+            if accumulated_batches == self.grad_accumulation_steps {
+                self.optimizer_step(progress, global_step, grad_scaler)?;
+                accumulated_batches = 0;
+            }
+        }
+
+        // Update with the gradients of the last batches.
+        if accumulated_batches != 0 {
+            self.optimizer_step(progress, global_step, grad_scaler)?;
         }
 
+        Ok(n_batches)
+    }
+
+    /// Perform an optimizer step with the accumulated gradients.
+    fn optimizer_step(
+        &self,
+        progress: &ProgressBar,
+        global_step: &mut usize,
+        grad_scaler: &mut GradScaler<impl Optimizer>,
+    ) -> Result<()> {
+        grad_scaler.step_accumulated();
+
+        self.summary_writer.write_scalar(
+            "gradient_scale",
+            *global_step as i64,
+            grad_scaler.current_scale(),
+        )?;
+        self.summary_writer.write_scalar(
+            "gradient_norm",
+            *global_step as i64,
+            grad_scaler.last_grad_norm(),
+        )?;
+        self.summary_writer.write_scalar(
+            "skipped_step",
+            *global_step as i64,
+            grad_scaler.last_step_skipped() as i32 as f32,
+        )?;
+
+        progress.inc(1);
+
+        *global_step += 1;
+
         Ok(())
     }
 
-    fn open_dataset(file: &File) -> Result<PlainTextDataSet<impl BufRead + Seek>> {
-        let read = BufReader::new(
-            file.try_clone()
-                .context("Cannot open data set for reading")?,
+    /// Tokenize the sentences of a batch with another tokenizer.
+    fn retokenize(
+        tokenizer: &dyn Tokenize,
+        batch: &[SentenceWithPieces],
+    ) -> Vec<SentenceWithPieces> {
+        batch
+            .iter()
+            .map(|sentence| tokenizer.tokenize(sentence.sentence.clone()))
+            .collect()
+    }
+
+    /// Get the number of optimizer steps in an epoch.
+    fn steps_per_epoch(&self, train_corpus: &Corpus, tokenizer: &dyn Tokenize) -> Result<usize> {
+        log::info!("Counting number of steps in an epoch...");
+        let read_progress =
+            ReadProgress::new(train_corpus.open()?).context("Cannot open train file")?;
+
+        let progress_bar = read_progress.progress_bar().clone();
+        progress_bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}%"),
         );
+
+        let (n_sentences, n_batches) = match self.batch_size {
+            BatchSize::Sentences(batch_size) => {
+                let n_sentences = count_sentences(BufReader::new(read_progress))?;
+                (n_sentences, (n_sentences + batch_size - 1) / batch_size)
+            }
+            BatchSize::Pieces(_) => {
+                // The number of batches depends on the sentence lengths,
+                // so the sentences have to be tokenized.
+                let mut dataset = PlainTextDataSet::new(BufReader::new(read_progress));
+                dataset
+                    .sentences(tokenizer)?
+                    .filter_by_len(self.max_len)
+                    .batched(self.batch_size)
+                    .try_fold((0, 0), |(n_sentences, n_batches), batch| {
+                        batch.map(|batch| (n_sentences + batch.len(), n_batches + 1))
+                    })?
+            }
+        };
+
+        progress_bar.finish_and_clear();
+
+        let steps_per_epoch =
+            (n_batches + self.grad_accumulation_steps - 1) / self.grad_accumulation_steps;
+        log::info!(
+            "sentences: {}, steps_per epoch: {}",
+            n_sentences,
+            steps_per_epoch
+        );
+
+        Ok(steps_per_epoch)
+    }
+
+    fn open_dataset(corpus: &Corpus) -> Result<PlainTextDataSet<impl BufRead + Seek>> {
+        let read = corpus.open().context("Cannot open data set for reading")?;
         Ok(PlainTextDataSet::new(read))
     }
 
@@ -711,7 +949,19 @@ impl DistillApp {
 
         let vs = VarStore::new(self.device);
 
-        let inner = BertModel::new(
+        // Students use layer output dropout by default.
+        let regularization = Regularization {
+            layers_dropout: Some(
+                student_config
+                    .model
+                    .regularization
+                    .layers_dropout
+                    .unwrap_or(0.1),
+            ),
+            ..student_config.model.regularization.clone()
+        };
+
+        let mut inner = BertModel::new(
             vs.root_ext(parameter_group_fun),
             &pretrain_config,
             student_config.biaffine.as_ref(),
@@ -721,11 +971,21 @@ impl DistillApp {
                 .map(ImmutableDependencyEncoder::n_relations)
                 .unwrap_or(0),
             &teacher.encoders,
+            &student_config.labeler.encoders,
             student_config.model.pooler,
-            0.1,
+            student_config.model.pooling_position,
+            &regularization,
             student_config.model.position_embeddings.clone(),
+            student_config.model.task_weighting,
+            student_config.model.treebanks.len(),
         )
         .context("Cannot construct fresh student model")?;
+        inner
+            .load_class_weights(
+                student_config.biaffine.as_ref(),
+                &student_config.labeler.encoders,
+            )
+            .context("Cannot read class weights")?;
 
         let tokenizer = load_tokenizer(student_config)?;
 
@@ -737,28 +997,26 @@ impl DistillApp {
         })
     }
 
-    pub fn create_lr_schedules(
-        initial_lr_classifier: NotNan<f32>,
-        initial_lr_encoder: NotNan<f32>,
-        lr_decay_rate: NotNan<f32>,
-        lr_decay_steps: usize,
-        warmup_steps: usize,
-    ) -> LearningRateSchedules {
-        let classifier = ExponentialDecay::new(
-            initial_lr_classifier.into_inner(),
-            lr_decay_rate.into_inner(),
-            lr_decay_steps,
-            false,
-            warmup_steps,
-        );
+    /// Create the learning rate schedules.
+    ///
+    /// Linear and cosine decay use the number of training steps as
+    /// the number of schedule steps, unless it is set explicitly.
+    pub fn create_lr_schedules(&self, n_steps: usize) -> Result<LearningRateSchedules> {
+        let classifier = self.lr_schedule.schedule.decay_schedule(
+            self.lr_schedule.initial_lr_classifier.into_inner(),
+            self.lr_schedule.lr_decay_rate.into_inner(),
+            self.lr_schedule.lr_decay_steps,
+            self.lr_schedule.warmup_steps,
+            Some(n_steps),
+        )?;
 
         let mut encoder = classifier.clone();
-        encoder.set_initial_lr(initial_lr_encoder.into_inner());
+        encoder.set_initial_lr(self.lr_schedule.initial_lr_encoder.into_inner());
 
-        LearningRateSchedules {
+        Ok(LearningRateSchedules {
             classifier,
             encoder,
-        }
+        })
     }
 
     fn validation_epoch(
@@ -767,7 +1025,7 @@ impl DistillApp {
         encoders: &Encoders,
         tokenizer: &dyn Tokenize,
         model: &BertModel,
-        file: &mut File,
+        corpus: &Corpus,
         global_step: usize,
     ) -> Result<f32> {
         let epoch_stats = self.validation_epoch_steps(
@@ -775,7 +1033,7 @@ impl DistillApp {
             encoders,
             tokenizer,
             model,
-            file,
+            corpus,
             global_step,
         )?;
         self.log_epoch_stats(global_step, epoch_stats)
@@ -848,6 +1106,27 @@ impl DistillApp {
             accs.push(acc);
         }
 
+        for (encoder_name, loss) in epoch_stats.sentence_loss {
+            let acc = epoch_stats.sentence_accuracy[&encoder_name] / epoch_stats.n_sentences as f32;
+            let loss = loss / epoch_stats.n_sentences as f32;
+
+            log::info!("{} loss: {} accuracy: {:.4}", encoder_name, loss, acc);
+
+            self.summary_writer.write_scalar(
+                &format!("loss:validation,sentence:{}", &encoder_name),
+                global_step as i64,
+                loss,
+            )?;
+
+            self.summary_writer.write_scalar(
+                &format!("acc:validation,sentence:{}", &encoder_name),
+                global_step as i64,
+                acc,
+            )?;
+
+            accs.push(acc);
+        }
+
         Ok(accs.iter().sum::<f32>() / accs.len() as f32)
     }
 
@@ -857,16 +1136,18 @@ impl DistillApp {
         encoders: &Encoders,
         tokenizer: &dyn Tokenize,
         model: &BertModel,
-        file: &mut File,
+        corpus: &Corpus,
         global_step: usize,
     ) -> Result<EpochStats> {
-        let read_progress = ReadProgress::new(file).context("Cannot create progress bar")?;
+        let reader = corpus.open()?;
+        let source_files = reader.source_files();
+        let read_progress = ReadProgress::new(reader).context("Cannot create progress bar")?;
         let progress_bar = read_progress.progress_bar().clone();
         progress_bar.set_style(ProgressStyle::default_bar().template(
             "[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}% validation {msg}",
         ));
 
-        let mut dataset = ConlluDataSet::new(BufReader::new(read_progress));
+        let mut dataset = self.format.data_set(BufReader::new(read_progress));
 
         let mut biaffine_las = 0f32;
         let mut biaffine_ls = 0f32;
@@ -875,17 +1156,28 @@ impl DistillApp {
         let mut biaffine_relation_loss = 0f32;
         let mut encoder_accuracy = BTreeMap::new();
         let mut encoder_loss = BTreeMap::new();
+        let mut sentence_accuracy = BTreeMap::new();
+        let mut sentence_loss = BTreeMap::new();
 
         let mut n_tokens = 0;
+        let mut n_sentences = 0;
 
         for batch in dataset
             .sentences(tokenizer)?
+            .map(|sentence| {
+                sentence.map(|mut sentence| {
+                    source_files.set_source_file(&mut sentence.sentence);
+                    sentence
+                })
+            })
             .filter_by_len(self.max_len)
             .batched_tensors(biaffine_encoder, Some(encoders), self.batch_size)
+            .malformed_sentences(self.malformed)
         {
             let batch = batch?;
 
             let n_batch_tokens = i64::from(batch.token_spans.token_mask()?.f_sum(Kind::Int64)?);
+            let n_batch_sentences = batch.seq_lens.size()[0];
 
             let attention_mask = batch.seq_lens.attention_mask()?;
 
@@ -894,6 +1186,7 @@ impl DistillApp {
                     &batch.inputs.to_device(self.device),
                     &attention_mask.to_device(self.device),
                     &batch.token_spans.to_device(self.device),
+                    &batch.treebank_ids.to_device(self.device),
                     batch
                         .biaffine_encodings
                         .map(|tensors| tensors.to_device(self.device)),
@@ -914,6 +1207,7 @@ impl DistillApp {
             })?;
 
             n_tokens += n_batch_tokens;
+            n_sentences += n_batch_sentences;
 
             let scalar_loss: f32 = model_loss
                 .seq_classifiers
@@ -947,6 +1241,16 @@ impl DistillApp {
                 };
             }
 
+            for (encoder_name, loss) in model_loss.sentence_classifiers.encoder_losses {
+                *sentence_accuracy
+                    .entry(encoder_name.clone())
+                    .or_insert(0f32) +=
+                    f32::from(&model_loss.sentence_classifiers.encoder_accuracies[&encoder_name])
+                        * n_batch_sentences as f32;
+                *sentence_loss.entry(encoder_name).or_insert(0f32) +=
+                    f32::from(loss) * n_batch_sentences as f32;
+            }
+
             if let Some(biaffine_loss) = model_loss.biaffine.as_ref() {
                 let head_loss = f32::from(&biaffine_loss.head_loss);
                 let relation_loss = f32::from(&biaffine_loss.relation_loss);
@@ -984,6 +1288,9 @@ impl DistillApp {
             encoder_accuracy,
             encoder_loss,
             n_tokens,
+            sentence_accuracy,
+            sentence_loss,
+            n_sentences,
         })
     }
 }
@@ -1029,6 +1336,13 @@ impl SyntaxDotApp for DistillApp {
                     .help("Batch size")
                     .default_value("32"),
             )
+            .arg(
+                Arg::with_name(CHECKPOINT)
+                    .long("checkpoint")
+                    .value_name("DIR")
+                    .help("Directory to store the training checkpoint in")
+                    .default_value("checkpoint"),
+            )
             .arg(
                 Arg::with_name(EPOCHS)
                     .long("epochs")
@@ -1045,6 +1359,13 @@ impl SyntaxDotApp for DistillApp {
                     .help("Evaluate after N steps, save the model on improvement")
                     .default_value("1000"),
             )
+            .arg(
+                Arg::with_name(GRAD_ACCUMULATION_STEPS)
+                    .long("grad-accumulation-steps")
+                    .value_name("N")
+                    .help("Accumulate gradients over N batches before an update step")
+                    .default_value("1"),
+            )
             .arg(
                 Arg::with_name(GPU)
                     .long("gpu")
@@ -1083,6 +1404,20 @@ impl SyntaxDotApp for DistillApp {
                     .long("mixed-precision")
                     .help("Enable automatic mixed-precision"),
             )
+            .arg(
+                Arg::with_name(SHUFFLE_BUFFER_SIZE)
+                    .long("shuffle-buffer-size")
+                    .value_name("N")
+                    .takes_value(true)
+                    .help(
+                        "Shuffle batches of similar-length sentences from a buffer of N sentences",
+                    ),
+            )
+            .arg(
+                Arg::with_name(RESUME)
+                    .long("resume")
+                    .help("Resume distillation from the last checkpoint"),
+            )
             .arg(
                 Arg::with_name(LR_DECAY_RATE)
                     .long("lr-decay-rate")
@@ -1097,6 +1432,19 @@ impl SyntaxDotApp for DistillApp {
                     .help("Exponential decay rate")
                     .default_value("10"),
             )
+            .arg(
+                Arg::with_name(MAX_BATCH_PIECES)
+                    .long("max-batch-pieces")
+                    .value_name("N")
+                    .takes_value(true)
+                    .help("Use batches of at most N pieces, overrides the batch size"),
+            )
+            .arg(
+                Arg::with_name(MAX_GRAD_NORM)
+                    .long("max-grad-norm")
+                    .value_name("NORM")
+                    .help("Clip gradients to a global norm of NORM"),
+            )
             .arg(
                 Arg::with_name(MAX_LEN)
                     .long("maxlen")
@@ -1129,6 +1477,12 @@ impl SyntaxDotApp for DistillApp {
                     .default_value("0.0"),
             );
 
+        let app = LayerLrDecayOption::add_to_app(app);
+        let app = LrScheduleOption::add_to_app(app);
+        let app = OptimizerOption::add_to_app(app);
+        let app = AveragingOption::add_to_app(app);
+        let app = SentenceFormatOption::add_to_app(app);
+        let app = MalformedSentencesOption::add_to_app(app);
         SummaryOption::add_to_app(app)
     }
 
@@ -1141,11 +1495,21 @@ impl SyntaxDotApp for DistillApp {
             .map(ToOwned::to_owned)
             .unwrap();
         let attention_loss = matches.is_present(ATTENTION_LOSS);
-        let batch_size = matches
-            .value_of(BATCH_SIZE)
-            .unwrap()
-            .parse()
-            .context("Cannot parse batch size")?;
+        let batch_size = match matches.value_of(MAX_BATCH_PIECES) {
+            Some(max_batch_pieces) => BatchSize::Pieces(
+                max_batch_pieces
+                    .parse()
+                    .context("Cannot parse maximum number of batch pieces")?,
+            ),
+            None => BatchSize::Sentences(
+                matches
+                    .value_of(BATCH_SIZE)
+                    .unwrap()
+                    .parse()
+                    .context("Cannot parse batch size")?,
+            ),
+        };
+        let checkpoint = matches.value_of(CHECKPOINT).unwrap().into();
         let device = match matches.value_of("GPU") {
             Some(gpu) => Device::Cuda(
                 gpu.parse()
@@ -1158,6 +1522,14 @@ impl SyntaxDotApp for DistillApp {
             .unwrap()
             .parse()
             .context("Cannot parse number of batches after which to save")?;
+        let grad_accumulation_steps = matches
+            .value_of(GRAD_ACCUMULATION_STEPS)
+            .unwrap()
+            .parse()
+            .context("Cannot parse number of gradient accumulation steps")?;
+        if grad_accumulation_steps == 0 {
+            bail!("The number of gradient accumulation steps must be at least 1")
+        }
         let hidden_loss = matches
             .value_of(HIDDEN_LOSS)
             .map(Self::parse_layer_mappings)
@@ -1172,6 +1544,12 @@ impl SyntaxDotApp for DistillApp {
             .unwrap()
             .parse()
             .context("Cannot parse initial encoder learning rate")?;
+        let layer_lr_decay = LayerLrDecayOption::parse(matches)?;
+        let lr_schedule = LrScheduleOption::parse(matches)?;
+        let optimizer = OptimizerOption::parse(matches)?;
+        let averaging = AveragingOption::parse(matches)?;
+        let format = SentenceFormatOption::parse(matches)?;
+        let malformed = MalformedSentencesOption::parse(matches)?;
         let summary_writer = SummaryOption::parse(matches)?;
 
         let keep_best_steps = matches
@@ -1195,6 +1573,16 @@ impl SyntaxDotApp for DistillApp {
             .unwrap()
             .parse()
             .context("Cannot parse exponential decay steps")?;
+        let max_grad_norm = matches
+            .value_of(MAX_GRAD_NORM)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse maximum gradient norm: {}", v))
+            })
+            .transpose()?;
+        if max_grad_norm.map(|norm: f64| norm <= 0.).unwrap_or(false) {
+            bail!("The maximum gradient norm must be positive")
+        }
         let max_len = matches
             .value_of(MAX_LEN)
             .map(|v| v.parse().context("Cannot parse maximum sentence length"))
@@ -1202,6 +1590,14 @@ impl SyntaxDotApp for DistillApp {
             .map(SequenceLength::Tokens)
             .unwrap_or(SequenceLength::Unbounded);
         let mixed_precision = matches.is_present(MIXED_PRECISION);
+        let resume = matches.is_present(RESUME);
+        let shuffle_buffer_size = matches
+            .value_of(SHUFFLE_BUFFER_SIZE)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse shuffle buffer size: {}", v))
+            })
+            .transpose()?;
         let warmup_steps = matches
             .value_of(WARMUP)
             .unwrap()
@@ -1230,20 +1626,31 @@ impl SyntaxDotApp for DistillApp {
 
         Ok(DistillApp {
             attention_loss,
+            averaging,
             batch_size,
+            checkpoint,
             device,
             eval_steps,
+            format,
+            grad_accumulation_steps,
             hidden_loss,
             keep_best_steps,
+            malformed,
+            max_grad_norm,
             max_len,
             mixed_precision,
-            lr_schedules: RefCell::new(Self::create_lr_schedules(
+            optimizer,
+            lr_schedule: LrSchedule {
                 initial_lr_classifier,
                 initial_lr_encoder,
+                layer_lr_decay,
                 lr_decay_rate,
                 lr_decay_steps,
+                schedule: lr_schedule,
                 warmup_steps,
-            )),
+            },
+            resume,
+            shuffle_buffer_size,
             student_config,
             teacher_config,
             summary_writer,
@@ -1258,86 +1665,98 @@ impl SyntaxDotApp for DistillApp {
         let student_config = load_config(&self.student_config)?;
         let teacher = Model::load(&self.teacher_config, self.device, true, false, |_| 0)?;
 
-        let teacher_train_file = File::open(&self.train_data)
-            .context(format!("Cannot open train data file: {}", self.train_data))?;
-        let student_train_file = File::open(&self.train_data)
-            .context(format!("Cannot open train data file: {}", self.train_data))?;
-        let mut validation_file = File::open(&self.validation_data).context(format!(
-            "Cannot open validation data file: {}",
-            self.validation_data
-        ))?;
+        let train_corpus = Corpus::new(&self.train_data)?;
+        let validation_corpus = Corpus::new(&self.validation_data)?;
+        for corpus in &[&train_corpus, &validation_corpus] {
+            let mut reader = corpus.open().context("Cannot open data set for reading")?;
+            if is_cached_data_set(&mut reader).context("Cannot read data set")? {
+                bail!("Pre-processed data cannot be used for distillation");
+            }
+        }
 
-        let student =
+        let mut student =
             self.fresh_student(&student_config, &teacher, Self::build_parameter_group_fun())?;
 
-        let mut grad_scaler = self.build_optimizer(&student.vs)?;
-
         let auxiliary_params = self.create_auxiliary_params(
             &student.vs,
             &teacher.pretrain_config,
             &student.pretrain_config,
         )?;
 
+        // The optimizer only updates the variables that exist at
+        // construction, so it must be built after the auxiliary parameters.
+        let mut grad_scaler = self.build_optimizer(&student.vs, student.inner.n_layers())?;
+
+        let n_steps = self
+            .train_duration
+            .as_steps(|| self.steps_per_epoch(&train_corpus, &*teacher.tokenizer))
+            .context("Cannot determine number of training steps")?;
+
+        let checkpoint = Checkpoint::new(&self.checkpoint);
+        let state = if self.resume {
+            if !checkpoint.exists() {
+                bail!("Cannot resume, no checkpoint in: {}", self.checkpoint);
+            }
+
+            log::info!("Resuming from checkpoint: {}", self.checkpoint);
+            let state: DistillState = checkpoint
+                .load(&mut student.vs, &mut grad_scaler, self.device)
+                .context("Cannot load checkpoint")?;
+            state
+        } else {
+            DistillState {
+                best_acc: 0.0,
+                best_step: 0,
+                best_step_paths: self.keep_best_steps.map(VecDeque::with_capacity),
+                epoch: 0,
+                epoch_step: 0,
+                global_step: 0,
+                lr_schedules: self.create_lr_schedules(n_steps)?,
+                seed: random_seed(),
+                swa_saver: self
+                    .averaging
+                    .swa_epochs
+                    .map(|n_epochs| SwaSaver::new("distill-", n_epochs)),
+            }
+        };
+
         self.distill_model(
             &mut grad_scaler,
             &auxiliary_params,
             &teacher,
-            &student,
-            &teacher_train_file,
-            &student_train_file,
-            &mut validation_file,
+            &mut student,
+            &train_corpus,
+            &validation_corpus,
+            &checkpoint,
+            n_steps,
+            state,
         )
         .context("Model distillation failed")
     }
 }
 
 impl SyntaxDotTrainApp for DistillApp {
-    fn mixed_precision(&self) -> bool {
-        self.mixed_precision
+    fn averaging(&self) -> Averaging {
+        self.averaging
     }
 
-    fn weight_decay(&self) -> f64 {
-        self.weight_decay
+    fn max_grad_norm(&self) -> Option<f64> {
+        self.max_grad_norm
     }
-}
-
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum TrainDuration {
-    Epochs(usize),
-    Steps(usize),
-}
 
-impl TrainDuration {
-    fn as_steps(&self, train_file: &File, batch_size: usize) -> Result<usize> {
-        use TrainDuration::*;
-
-        match *self {
-            Epochs(epochs) => {
-                log::info!("Counting number of steps in an epoch...");
-                let read_progress =
-                    ReadProgress::new(train_file.try_clone()?).context("Cannot open train file")?;
-
-                let progress_bar = read_progress.progress_bar().clone();
-                progress_bar
-                    .set_style(ProgressStyle::default_bar().template(
-                        "[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}%",
-                    ));
+    fn mixed_precision(&self) -> bool {
+        self.mixed_precision
+    }
 
-                let n_sentences = count_sentences(BufReader::new(read_progress))?;
+    fn layer_lr_decay(&self) -> f32 {
+        self.lr_schedule.layer_lr_decay.into_inner()
+    }
 
-                progress_bar.finish_and_clear();
+    fn optimizer(&self) -> OptimizerSelection {
+        self.optimizer
+    }
 
-                // Compute number of steps of the given batch size.
-                let steps_per_epoch = (n_sentences + batch_size - 1) / batch_size;
-                log::info!(
-                    "sentences: {}, steps_per epoch: {}, total_steps: {}",
-                    n_sentences,
-                    steps_per_epoch,
-                    epochs * steps_per_epoch
-                );
-                Ok(epochs * steps_per_epoch)
-            }
-            Steps(steps) => Ok(steps),
-        }
+    fn weight_decay(&self) -> f64 {
+        self.weight_decay
     }
 }
@@ -1,37 +1,53 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{App, Arg, ArgMatches};
-use indicatif::ProgressStyle;
+use indicatif::{ProgressBar, ProgressStyle};
 use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
 use syntaxdot::dataset::{
-    BatchedTensors, ConlluDataSet, DataSet, SentenceIterTools, SequenceLength,
+    encoded_batch_tensors, is_cached_data_set, set_treebank_id, Augmentation, BatchSize,
+    BatchedTensors, CachedDataSet, CompositeDataSet, DataSet, DataSetSampling, IntoTensors,
+    MalformedSentences, SentenceFormat, SentenceIterTools, SequenceLength,
 };
 use syntaxdot::encoders::Encoders;
-use syntaxdot::lr::{ExponentialDecay, LearningRateSchedule, PlateauLearningRate};
+use syntaxdot::error::SyntaxDotError;
+use syntaxdot::lr::{DecaySchedule, LearningRateSchedule, PlateauLearningRate};
 use syntaxdot::model::bert::{BertModel, FreezeLayers};
-use syntaxdot::optimizers::{GradScaler, Optimizer};
+use syntaxdot::optimizers::{AnyOptimizer, GradScaler, Optimizer};
+use syntaxdot::tensor::Tensors;
 use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
 use syntaxdot_tokenizers::Tokenize;
-use tch::nn::{self};
 use tch::{self, Device, Kind};
 
-use crate::io::Model;
+use crate::augment::AugmentationOption;
+use crate::checkpoint::{random_seed, seed_torch, Checkpoint};
+use crate::corpus::{Corpus, CorpusReader};
+use crate::format::SentenceFormatOption;
+use crate::io::{load_config, Model};
+use crate::lr::{LayerLrDecayOption, LrScheduleOption, LrScheduleSelection};
+use crate::malformed::MalformedSentencesOption;
+use crate::metric::{SelectionMetric, SelectionMetricOption, ValidationScores, AVERAGE_METRIC};
+use crate::optimizer::{Averaging, AveragingOption, OptimizerOption, OptimizerSelection};
 use crate::progress::ReadProgress;
-use crate::save::{BestEpochSaver, CompletedUnit, Save};
+use crate::save::{BestEpochSaver, CompletedUnit, Save, SwaSaver};
 use crate::summary::{ScalarWriter, SummaryOption};
-use crate::traits::{
-    ParameterGroup, SyntaxDotApp, SyntaxDotOption, SyntaxDotTrainApp, DEFAULT_CLAP_SETTINGS,
-};
-use crate::util::autocast_or_preserve;
+use crate::traits::{SyntaxDotApp, SyntaxDotOption, SyntaxDotTrainApp, DEFAULT_CLAP_SETTINGS};
+use crate::util::{autocast_or_preserve, parse_duration, TrainDuration};
 
 const BATCH_SIZE: &str = "BATCH_SIZE";
+const CHECKPOINT: &str = "CHECKPOINT";
 const CONFIG: &str = "CONFIG";
 const CONTINUE: &str = "CONTINUE";
+const EPOCHS: &str = "EPOCHS";
+const EVAL_STEPS: &str = "EVAL_STEPS";
 const GPU: &str = "GPU";
 const FINETUNE_EMBEDS: &str = "FINETUNE_EMBEDS";
+const GRAD_ACCUMULATION_STEPS: &str = "GRAD_ACCUMULATION_STEPS";
 const INITIAL_LR_CLASSIFIER: &str = "INITIAL_LR_CLASSIFIER";
 const INITIAL_LR_ENCODER: &str = "INITIAL_LR_ENCODER";
 const LABEL_SMOOTHING: &str = "LABEL_SMOOTHING";
@@ -40,10 +56,18 @@ const KEEP_BEST_EPOCHS: &str = "KEEP_BEST_EPOCHS";
 const LR_DECAY_RATE: &str = "LR_DECAY_RATE";
 const LR_PATIENCE: &str = "LR_PATIENCE";
 const LR_SCALE: &str = "LR_SCALE";
+const MAX_BATCH_PIECES: &str = "MAX_BATCH_PIECES";
+const MAX_GRAD_NORM: &str = "MAX_GRAD_NORM";
 const MAX_LEN: &str = "MAX_LEN";
 const PATIENCE: &str = "PATIENCE";
 const PRETRAINED_MODEL: &str = "PRETRAINED_MODEL";
+const RESUME: &str = "RESUME";
+const SAMPLING_TEMPERATURE: &str = "SAMPLING_TEMPERATURE";
+const SHUFFLE_BUFFER_SIZE: &str = "SHUFFLE_BUFFER_SIZE";
+const STEPS: &str = "N_STEPS";
+const TIME_LIMIT: &str = "TIME_LIMIT";
 const TRAIN_DATA: &str = "TRAIN_DATA";
+const TREEBANK_WEIGHTS: &str = "TREEBANK_WEIGHTS";
 const VALIDATION_DATA: &str = "VALIDATION_DATA";
 const WARMUP: &str = "WARMUP";
 const WEIGHT_DECAY: &str = "WEIGHT_DECAY";
@@ -51,34 +75,185 @@ const WEIGHT_DECAY: &str = "WEIGHT_DECAY";
 pub struct LrSchedule {
     pub initial_lr_encoder: NotNan<f32>,
     pub initial_lr_classifier: NotNan<f32>,
+    pub layer_lr_decay: NotNan<f32>,
     pub lr_decay_rate: NotNan<f32>,
     pub lr_scale: NotNan<f32>,
     pub lr_patience: usize,
+    pub schedule: LrScheduleSelection,
     pub warmup_steps: usize,
 }
 
 pub struct FinetuneApp {
-    batch_size: usize,
+    augmentation: Augmentation,
+    averaging: Averaging,
+    batch_size: BatchSize,
+    checkpoint: String,
     config: String,
     continue_finetune: bool,
     device: Device,
+    eval_steps: Option<usize>,
     finetune_embeds: bool,
+    format: SentenceFormat,
+    grad_accumulation_steps: usize,
+    max_grad_norm: Option<f64>,
     max_len: SequenceLength,
     label_smoothing: Option<f64>,
+    malformed: MalformedSentences,
     mixed_precision: bool,
+    optimizer: OptimizerSelection,
     summary_writer: Box<dyn ScalarWriter>,
     lr_schedule: LrSchedule,
     patience: usize,
     pretrained_model: String,
+    resume: bool,
     saver: BestEpochSaver<f32>,
-    train_data: String,
-    validation_data: String,
+    selection_metric: SelectionMetric,
+    shuffle_buffer_size: Option<usize>,
+    time_limit: Option<Duration>,
+    train_data: Vec<TrainSet>,
+    train_duration: Option<TrainDuration>,
+    train_sampling: DataSetSampling,
+    validation_data: Vec<ValidationSet>,
     weight_decay: f64,
 }
 
+/// Split a `NAME=PATH` data set argument into its name and path.
+///
+/// The part before the first `=` is only used as the name when it
+/// consists of alphanumeric characters, `_` and `-`. Otherwise, the
+/// full argument is a path, so that paths such as `data/a=b.conllu`
+/// can still be used.
+fn split_data_set_name(arg: &str) -> (Option<&str>, &str) {
+    let mut parts = arg.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(path))
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-') =>
+        {
+            (Some(name), path)
+        }
+        _ => (None, arg),
+    }
+}
+
+/// Training data set.
+struct TrainSet {
+    /// Name of the treebank.
+    name: String,
+
+    path: String,
+}
+
+impl TrainSet {
+    /// Parse training data sets from a comma-separated list of
+    /// `NAME=PATH` or `PATH` arguments.
+    ///
+    /// Data sets without a name are named after the file stem of
+    /// their path.
+    fn from_arg(arg: &str) -> Vec<Self> {
+        arg.split(',')
+            .map(|data_set| match split_data_set_name(data_set) {
+                (Some(name), path) => TrainSet {
+                    name: name.to_string(),
+                    path: path.to_string(),
+                },
+                (None, path) => TrainSet {
+                    name: Path::new(path)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| path.to_string()),
+                    path: path.to_string(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Validation data set.
+struct ValidationSet {
+    /// Name of the validation set.
+    name: String,
+
+    /// Name of the validation set in logs, e.g. `validation-ood`.
+    epoch_type: String,
+
+    path: String,
+}
+
+impl ValidationSet {
+    /// Parse a validation set from a `NAME=PATH` or `PATH` argument.
+    fn from_arg(arg: &str) -> Self {
+        match split_data_set_name(arg) {
+            (Some(name), path) => ValidationSet {
+                name: name.to_string(),
+                epoch_type: format!("validation-{}", name),
+                path: path.to_string(),
+            },
+            (None, path) => ValidationSet {
+                name: "validation".to_string(),
+                epoch_type: "validation".to_string(),
+                path: path.to_string(),
+            },
+        }
+    }
+}
+
+/// Training data.
+enum TrainData {
+    /// Data sets that are tokenized and encoded in every epoch.
+    Sentences(CompositeDataSet<CorpusReader>),
+
+    /// A data set that was pre-processed with `syntaxdot preprocess`.
+    Cached(CachedDataSet),
+}
+
+impl TrainData {
+    /// The number of sentences in a pass over the training data.
+    ///
+    /// For pre-processed data sets, sentences that are longer than
+    /// `max_len` are not counted.
+    fn n_sentences(&self, max_len: SequenceLength) -> usize {
+        match self {
+            TrainData::Sentences(train_dataset) => train_dataset.n_sentences(),
+            TrainData::Cached(train_dataset) => train_dataset.n_sentences(max_len),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct LearningRateSchedules {
-    pub classifier: PlateauLearningRate<ExponentialDecay>,
-    pub encoder: PlateauLearningRate<ExponentialDecay>,
+    pub classifier: PlateauLearningRate<DecaySchedule>,
+    pub encoder: PlateauLearningRate<DecaySchedule>,
+}
+
+/// Finetuning state that is stored in checkpoints.
+#[derive(Deserialize, Serialize)]
+struct FinetuneState {
+    best_acc: f32,
+
+    /// The validation with the best accuracy.
+    best_evaluation: usize,
+
+    /// The epoch or step with the best accuracy, e.g. `epoch 3`.
+    best_unit: String,
+
+    /// The epoch to start with when resuming.
+    epoch: usize,
+
+    /// The number of batches of the epoch that were processed.
+    epoch_batch: usize,
+
+    /// The number of validations that were done.
+    evaluation: usize,
+
+    global_step: usize,
+    last_acc: f32,
+    lr_schedules: LearningRateSchedules,
+    saver: BestEpochSaver<f32>,
+    seed: i64,
+    swa_saver: Option<SwaSaver>,
 }
 
 struct BiaffineEpochStats {
@@ -93,21 +268,25 @@ struct EpochStats {
     biaffine: Option<BiaffineEpochStats>,
     encoder_accuracy: BTreeMap<String, f32>,
     encoder_loss: BTreeMap<String, f32>,
+    n_batches: usize,
     n_tokens: i64,
+    sentence_accuracy: BTreeMap<String, f32>,
+    sentence_loss: BTreeMap<String, f32>,
+    n_sentences: i64,
 }
 
 impl FinetuneApp {
-    pub fn lr_schedules(&self) -> LearningRateSchedules {
-        let exp_decay = ExponentialDecay::new(
+    pub fn lr_schedules(&self) -> Result<LearningRateSchedules> {
+        let decay = self.lr_schedule.schedule.decay_schedule(
             self.lr_schedule.initial_lr_classifier.into_inner(),
             self.lr_schedule.lr_decay_rate.into_inner(),
             1,
-            false,
             self.lr_schedule.warmup_steps,
-        );
+            None,
+        )?;
 
         let classifier = PlateauLearningRate::new(
-            exp_decay,
+            decay,
             self.lr_schedule.lr_scale.into_inner(),
             self.lr_schedule.lr_patience,
         );
@@ -115,52 +294,155 @@ impl FinetuneApp {
         let mut encoder = classifier.clone();
         encoder.set_initial_lr(self.lr_schedule.initial_lr_encoder.into_inner());
 
-        LearningRateSchedules {
+        Ok(LearningRateSchedules {
             classifier,
             encoder,
+        })
+    }
+
+    /// Set the style of the progress bar of a pass over a data set.
+    fn style_progress_bar(progress_bar: &ProgressBar, epoch_type: &str) {
+        progress_bar.set_style(ProgressStyle::default_bar().template(&format!(
+            "[Time: {{elapsed_precise}}, ETA: {{eta_precise}}] {{bar}} {{percent}}% {} {{msg}}",
+            epoch_type
+        )));
+    }
+
+    /// Check whether the training budget is exhausted.
+    fn train_budget_exhausted(&self, state: &FinetuneState) -> bool {
+        match self.train_duration {
+            Some(TrainDuration::Epochs(epochs)) => state.epoch >= epochs,
+            Some(TrainDuration::Steps(_)) => self.step_budget_exhausted(state.global_step),
+            None => false,
+        }
+    }
+
+    /// Check whether the budget of training steps is exhausted.
+    fn step_budget_exhausted(&self, global_step: usize) -> bool {
+        match self.train_duration {
+            // The global step starts at 1.
+            Some(TrainDuration::Steps(steps)) => global_step > steps,
+            _ => false,
         }
     }
 
+    /// Check whether the time limit is exceeded.
+    fn time_limit_exceeded(&self, start: Instant) -> bool {
+        self.time_limit
+            .map(|time_limit| start.elapsed() >= time_limit)
+            .unwrap_or(false)
+    }
+
     #[allow(clippy::too_many_arguments)]
-    fn run_epoch(
+    fn validate(
         &self,
         biaffine_encoder: Option<&ImmutableDependencyEncoder>,
         encoders: &Encoders,
         tokenizer: &dyn Tokenize,
         model: &BertModel,
-        file: &mut File,
-        mut grad_scaler: Option<&mut GradScaler<impl Optimizer>>,
+        corpus: &Corpus,
+        epoch_type: &str,
+        treebank_id: Option<usize>,
         lr_schedulers: &mut LearningRateSchedules,
-        global_step: &mut usize,
+        mut global_step: usize,
         epoch: usize,
-    ) -> Result<f32> {
-        let epoch_stats = self.run_epoch_steps(
+    ) -> Result<ValidationScores> {
+        if self.validation_data.len() > 1 {
+            log::info!("Validation set: {}", epoch_type);
+        }
+
+        let reader = corpus.open()?;
+        let source_files = reader.source_files();
+        let read_progress = ReadProgress::new(reader).context("Cannot create progress bar")?;
+        let progress_bar = read_progress.progress_bar().clone();
+        Self::style_progress_bar(&progress_bar, epoch_type);
+
+        let mut dataset = self.format.data_set(BufReader::new(read_progress));
+        let mut batches = dataset
+            .sentences(tokenizer)?
+            .map(|sentence| {
+                sentence.map(|mut sentence| {
+                    source_files.set_source_file(&mut sentence.sentence);
+                    if let Some(treebank_id) = treebank_id {
+                        set_treebank_id(&mut sentence.sentence, treebank_id);
+                    }
+                    sentence
+                })
+            })
+            .filter_by_len(self.max_len)
+            .batched_tensors(biaffine_encoder, Some(encoders), self.batch_size)
+            .malformed_sentences(self.malformed);
+
+        let epoch_stats = self.run_steps(
             biaffine_encoder,
-            encoders,
-            tokenizer,
             model,
-            file,
-            &mut grad_scaler,
+            &mut batches,
+            &progress_bar,
+            None as Option<&mut GradScaler<AnyOptimizer>>,
             lr_schedulers,
-            global_step,
+            &mut global_step,
             epoch,
+            |_| false,
         )?;
 
-        self.log_epoch_stats(global_step, epoch_stats, grad_scaler.is_some())
+        self.log_epoch_stats(&global_step, epoch_stats, epoch_type)
+    }
+
+    /// Perform an optimizer step with the accumulated gradients.
+    fn optimizer_step(
+        &self,
+        grad_scaler: &mut GradScaler<impl Optimizer>,
+        global_step: &mut usize,
+        epoch: usize,
+    ) -> Result<()> {
+        grad_scaler.step_accumulated();
+
+        if epoch != 0 {
+            self.summary_writer.write_scalar(
+                "gradient_scale",
+                *global_step as i64,
+                grad_scaler.current_scale(),
+            )?;
+            self.summary_writer.write_scalar(
+                "gradient_norm",
+                *global_step as i64,
+                grad_scaler.last_grad_norm(),
+            )?;
+            self.summary_writer.write_scalar(
+                "skipped_step",
+                *global_step as i64,
+                grad_scaler.last_step_skipped() as i32 as f32,
+            )?;
+
+            *global_step += 1;
+        }
+
+        Ok(())
     }
 
     fn log_epoch_stats(
         &self,
         global_step: &usize,
         epoch_stats: EpochStats,
-        train: bool,
-    ) -> Result<f32> {
-        let epoch_type = if train { "train" } else { "validation" };
-
+        epoch_type: &str,
+    ) -> Result<ValidationScores> {
+        let mut scores = ValidationScores::new();
         let mut accs = Vec::new();
 
         if let Some(biaffine_stats) = epoch_stats.biaffine {
             accs.push(biaffine_stats.las / epoch_stats.n_tokens as f32);
+            scores.insert(
+                "las".to_string(),
+                biaffine_stats.las / epoch_stats.n_tokens as f32,
+            );
+            scores.insert(
+                "ls".to_string(),
+                biaffine_stats.ls / epoch_stats.n_tokens as f32,
+            );
+            scores.insert(
+                "uas".to_string(),
+                biaffine_stats.uas / epoch_stats.n_tokens as f32,
+            );
 
             log::info!(
                 "biaffine head loss: {:.4}, rel loss: {:.4}, las: {:.4}, uas: {:.4}, ls: {:.4}",
@@ -221,40 +503,64 @@ impl FinetuneApp {
             )?;
 
             accs.push(acc);
+            scores.insert(encoder_name, acc);
         }
 
-        Ok(accs.iter().sum::<f32>() / accs.len() as f32)
+        for (encoder_name, loss) in epoch_stats.sentence_loss {
+            let acc = epoch_stats.sentence_accuracy[&encoder_name] / epoch_stats.n_sentences as f32;
+            let loss = loss / epoch_stats.n_sentences as f32;
+
+            log::info!("{} loss: {} accuracy: {:.4}", encoder_name, loss, acc);
+
+            self.summary_writer.write_scalar(
+                &format!("loss:{},sentence:{}", epoch_type, &encoder_name),
+                *global_step as i64,
+                loss,
+            )?;
+
+            self.summary_writer.write_scalar(
+                &format!("acc:{},sentence:{}", epoch_type, &encoder_name),
+                *global_step as i64,
+                acc,
+            )?;
+
+            accs.push(acc);
+            scores.insert(encoder_name, acc);
+        }
+
+        let avg = accs.iter().sum::<f32>() / accs.len() as f32;
+        self.summary_writer.write_scalar(
+            &format!("acc:{},avg", epoch_type),
+            *global_step as i64,
+            avg,
+        )?;
+        scores.insert(AVERAGE_METRIC.to_string(), avg);
+
+        Ok(scores)
     }
 
+    /// Run the model on batches.
+    ///
+    /// The model is trained when `grad_scaler` is not `None`. After
+    /// each optimizer step, `stop` is called with the global step.
+    /// Processing of batches stops when `stop` returns `true` or when
+    /// all batches are processed.
     #[allow(clippy::too_many_arguments)]
-    fn run_epoch_steps(
+    fn run_steps(
         &self,
         biaffine_encoder: Option<&ImmutableDependencyEncoder>,
-        encoders: &Encoders,
-        tokenizer: &dyn Tokenize,
         model: &BertModel,
-        file: &mut File,
-        mut grad_scaler: &mut Option<&mut GradScaler<impl Optimizer>>,
+        batches: &mut dyn Iterator<Item = Result<Tensors, SyntaxDotError>>,
+        progress_bar: &ProgressBar,
+        mut grad_scaler: Option<&mut GradScaler<impl Optimizer>>,
         lr_schedulers: &mut LearningRateSchedules,
         global_step: &mut usize,
         epoch: usize,
+        mut stop: impl FnMut(usize) -> bool,
     ) -> Result<EpochStats> {
-        let epoch_type = if grad_scaler.is_some() {
-            "train"
-        } else {
-            "validation"
-        };
-
-        let read_progress = ReadProgress::new(file).context("Cannot create progress bar")?;
-        let progress_bar = read_progress.progress_bar().clone();
-        progress_bar.set_style(ProgressStyle::default_bar().template(&format!(
-            "[Time: {{elapsed_precise}}, ETA: {{eta_precise}}] {{bar}} {{percent}}% {} {{msg}}",
-            epoch_type
-        )));
-
-        let mut dataset = ConlluDataSet::new(BufReader::new(read_progress));
-
+        let mut n_batches = 0;
         let mut n_tokens = 0;
+        let mut n_sentences = 0;
 
         // Freeze the encoder during the first epoch.
         let freeze_encoder = epoch == 0;
@@ -266,13 +572,14 @@ impl FinetuneApp {
         let mut biaffine_relation_loss = 0f32;
         let mut encoder_accuracy = BTreeMap::new();
         let mut encoder_loss = BTreeMap::new();
+        let mut sentence_accuracy = BTreeMap::new();
+        let mut sentence_loss = BTreeMap::new();
 
-        for batch in dataset
-            .sentences(tokenizer)?
-            .filter_by_len(self.max_len)
-            .batched_tensors(biaffine_encoder, Some(encoders), self.batch_size)
-        {
+        let mut accumulated_batches = 0;
+
+        for batch in batches {
             let batch = batch?;
+            let mut stop_after_batch = false;
 
             let (lr_classifier, lr_encoder) = if epoch == 0 {
                 (self.lr_schedule.initial_lr_classifier.into_inner(), 0.)
@@ -290,12 +597,14 @@ impl FinetuneApp {
             let attention_mask = batch.seq_lens.attention_mask()?;
 
             let n_batch_tokens = i64::from(batch.token_spans.token_mask()?.f_sum(Kind::Int64)?);
+            let n_batch_sentences = batch.seq_lens.size()[0];
 
             let model_loss = autocast_or_preserve(self.mixed_precision, || {
                 model.loss(
                     &batch.inputs.to_device(self.device),
                     &attention_mask.to_device(self.device),
                     &batch.token_spans.to_device(self.device),
+                    &batch.treebank_ids.to_device(self.device),
                     batch
                         .biaffine_encodings
                         .map(|tensors| tensors.to_device(self.device)),
@@ -316,6 +625,7 @@ impl FinetuneApp {
             })?;
 
             n_tokens += n_batch_tokens;
+            n_sentences += n_batch_sentences;
 
             let scalar_loss: f32 = model_loss
                 .seq_classifiers
@@ -326,41 +636,43 @@ impl FinetuneApp {
             if let Some(scaler) = &mut grad_scaler {
                 let optimizer = scaler.optimizer_mut();
 
-                optimizer.set_lr_group(ParameterGroup::Encoder as usize, lr_encoder.into());
-                optimizer.set_lr_group(
-                    ParameterGroup::EncoderNoWeightDecay as usize,
-                    lr_encoder.into(),
-                );
-                optimizer.set_lr_group(ParameterGroup::Classifier as usize, lr_classifier.into());
-                optimizer.set_lr_group(
-                    ParameterGroup::ClassifierNoWeightDecay as usize,
-                    lr_classifier.into(),
-                );
-
-                let mut loss = model_loss.seq_classifiers.summed_loss.f_sum(Kind::Float)?;
-                if let Some(biaffine_loss) = model_loss.biaffine.as_ref() {
-                    let _ = loss.f_add_(
-                        &biaffine_loss
-                            .head_loss
-                            .f_add(&biaffine_loss.relation_loss)?,
-                    )?;
-                }
-
-                scaler.backward_step(&loss)?;
-
-                if epoch != 0 {
-                    self.summary_writer.write_scalar(
-                        "gradient_scale",
-                        *global_step as i64,
-                        scaler.current_scale(),
-                    )?;
+                self.set_lr_groups(optimizer, model.n_layers(), lr_encoder, lr_classifier);
+
+                // Log the learning rates once per optimizer step.
+                if accumulated_batches == 0 && epoch != 0 {
+                    for (group, lr) in self.group_lrs(model.n_layers(), lr_encoder, lr_classifier) {
+                        self.summary_writer.write_scalar(
+                            &format!("lr:{}", group),
+                            *global_step as i64,
+                            lr,
+                        )?;
+                    }
+
+                    for (task, &weight) in &model_loss.task_weights {
+                        self.summary_writer.write_scalar(
+                            &format!("task_weight:{}", task),
+                            *global_step as i64,
+                            weight,
+                        )?;
+                    }
                 }
 
-                if epoch != 0 {
-                    *global_step += 1;
+                // Average the loss over the accumulated batches.
+                let loss = model_loss
+                    .loss
+                    .f_div_scalar(self.grad_accumulation_steps as f64)?;
+                scaler.backward(&loss)?;
+
+                accumulated_batches += 1;
+                if accumulated_batches == self.grad_accumulation_steps {
+                    self.optimizer_step(scaler, global_step, epoch)?;
+                    accumulated_batches = 0;
+                    stop_after_batch = stop(*global_step);
                 }
             };
 
+            n_batches += 1;
+
             for (encoder_name, loss) in model_loss.seq_classifiers.encoder_losses {
                 *encoder_accuracy.entry(encoder_name.clone()).or_insert(0f32) +=
                     f32::from(&model_loss.seq_classifiers.encoder_accuracies[&encoder_name])
@@ -369,6 +681,16 @@ impl FinetuneApp {
                     f32::from(loss) * n_batch_tokens as f32;
             }
 
+            for (encoder_name, loss) in model_loss.sentence_classifiers.encoder_losses {
+                *sentence_accuracy
+                    .entry(encoder_name.clone())
+                    .or_insert(0f32) +=
+                    f32::from(&model_loss.sentence_classifiers.encoder_accuracies[&encoder_name])
+                        * n_batch_sentences as f32;
+                *sentence_loss.entry(encoder_name).or_insert(0f32) +=
+                    f32::from(loss) * n_batch_sentences as f32;
+            }
+
             if let Some(biaffine_loss) = model_loss.biaffine.as_ref() {
                 let head_loss = f32::from(&biaffine_loss.head_loss);
                 let relation_loss = f32::from(&biaffine_loss.relation_loss);
@@ -389,9 +711,18 @@ impl FinetuneApp {
                     lr_classifier, lr_encoder, scalar_loss, global_step
                 ));
             }
+
+            if stop_after_batch {
+                break;
+            }
         }
 
-        progress_bar.finish();
+        // Update with the gradients of the last batches of the epoch.
+        if let Some(scaler) = grad_scaler {
+            if accumulated_batches != 0 {
+                self.optimizer_step(scaler, global_step, epoch)?;
+            }
+        }
 
         let biaffine_stats = biaffine_encoder.map(|_| BiaffineEpochStats {
             las: biaffine_las,
@@ -405,7 +736,11 @@ impl FinetuneApp {
             biaffine: biaffine_stats,
             encoder_accuracy,
             encoder_loss,
+            n_batches,
             n_tokens,
+            sentence_accuracy,
+            sentence_loss,
+            n_sentences,
         })
     }
 }
@@ -426,6 +761,20 @@ impl SyntaxDotApp for FinetuneApp {
                     .long("continue")
                     .help("Continue training a SyntaxDot model"),
             )
+            .arg(
+                Arg::with_name(SHUFFLE_BUFFER_SIZE)
+                    .long("shuffle-buffer-size")
+                    .value_name("N")
+                    .takes_value(true)
+                    .help(
+                        "Shuffle batches of similar-length sentences from a buffer of N sentences",
+                    ),
+            )
+            .arg(
+                Arg::with_name(RESUME)
+                    .long("resume")
+                    .help("Resume training from the last checkpoint"),
+            )
             .arg(
                 Arg::with_name(PRETRAINED_MODEL)
                     .help("Pretrained model in Torch format")
@@ -434,14 +783,15 @@ impl SyntaxDotApp for FinetuneApp {
             )
             .arg(
                 Arg::with_name(TRAIN_DATA)
-                    .help("Training data")
+                    .help("Training data, use comma-separated NAME=PATH for multiple treebanks")
                     .index(3)
                     .required(true),
             )
             .arg(
                 Arg::with_name(VALIDATION_DATA)
-                    .help("Validation data")
+                    .help("Validation data, use NAME=PATH to name validation sets")
                     .index(4)
+                    .multiple(true)
                     .required(true),
             )
             .arg(
@@ -451,11 +801,37 @@ impl SyntaxDotApp for FinetuneApp {
                     .help("Batch size")
                     .default_value("32"),
             )
+            .arg(
+                Arg::with_name(CHECKPOINT)
+                    .long("checkpoint")
+                    .value_name("DIR")
+                    .help("Directory to store the training checkpoint in")
+                    .default_value("checkpoint"),
+            )
+            .arg(
+                Arg::with_name(EPOCHS)
+                    .long("epochs")
+                    .value_name("N")
+                    .help("Train for at most N epochs"),
+            )
+            .arg(
+                Arg::with_name(EVAL_STEPS)
+                    .long("eval-steps")
+                    .value_name("N")
+                    .help("Validate after every N steps instead of after every epoch"),
+            )
             .arg(
                 Arg::with_name(FINETUNE_EMBEDS)
                     .long("finetune-embeds")
                     .help("Finetune embeddings"),
             )
+            .arg(
+                Arg::with_name(GRAD_ACCUMULATION_STEPS)
+                    .long("grad-accumulation-steps")
+                    .value_name("N")
+                    .help("Accumulate gradients over N batches before an update step")
+                    .default_value("1"),
+            )
             .arg(
                 Arg::with_name(GPU)
                     .long("gpu")
@@ -494,6 +870,19 @@ impl SyntaxDotApp for FinetuneApp {
                     .long("mixed-precision")
                     .help("Enable automatic mixed-precision"),
             )
+            .arg(
+                Arg::with_name(MAX_BATCH_PIECES)
+                    .long("max-batch-pieces")
+                    .value_name("N")
+                    .takes_value(true)
+                    .help("Use batches of at most N pieces, overrides the batch size"),
+            )
+            .arg(
+                Arg::with_name(MAX_GRAD_NORM)
+                    .long("max-grad-norm")
+                    .value_name("NORM")
+                    .help("Clip gradients to a global norm of NORM"),
+            )
             .arg(
                 Arg::with_name(MAX_LEN)
                     .long("maxlen")
@@ -512,7 +901,7 @@ impl SyntaxDotApp for FinetuneApp {
                 Arg::with_name(LR_PATIENCE)
                     .long("lr-patience")
                     .value_name("N")
-                    .help("Scale learning rate after N epochs without improvement")
+                    .help("Scale learning rate after N validations without improvement")
                     .default_value("2"),
             )
             .arg(
@@ -526,9 +915,36 @@ impl SyntaxDotApp for FinetuneApp {
                 Arg::with_name(PATIENCE)
                     .long("patience")
                     .value_name("N")
-                    .help("Maximum number of epochs without improvement")
+                    .help("Maximum number of validations without improvement")
                     .default_value("15"),
             )
+            .arg(
+                Arg::with_name(SAMPLING_TEMPERATURE)
+                    .long("sampling-temperature")
+                    .value_name("T")
+                    .help("Sample treebanks proportional to their size to the power 1/T")
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::with_name(STEPS)
+                    .long("steps")
+                    .value_name("N")
+                    .help("Train for at most N steps")
+                    .overrides_with(EPOCHS),
+            )
+            .arg(
+                Arg::with_name(TIME_LIMIT)
+                    .long("time-limit")
+                    .value_name("DURATION")
+                    .help("Stop training after DURATION (e.g. 90m, 12h, 2d)"),
+            )
+            .arg(
+                Arg::with_name(TREEBANK_WEIGHTS)
+                    .long("treebank-weights")
+                    .value_name("WEIGHTS")
+                    .help("Sample treebanks proportional to comma-separated weights")
+                    .conflicts_with(SAMPLING_TEMPERATURE),
+            )
             .arg(
                 Arg::with_name(WARMUP)
                     .long("warmup")
@@ -546,6 +962,14 @@ impl SyntaxDotApp for FinetuneApp {
                     .default_value("0.0"),
             );
 
+        let app = AugmentationOption::add_to_app(app);
+        let app = LayerLrDecayOption::add_to_app(app);
+        let app = LrScheduleOption::add_to_app(app);
+        let app = OptimizerOption::add_to_app(app);
+        let app = AveragingOption::add_to_app(app);
+        let app = SelectionMetricOption::add_to_app(app);
+        let app = SentenceFormatOption::add_to_app(app);
+        let app = MalformedSentencesOption::add_to_app(app);
         SummaryOption::add_to_app(app)
     }
 
@@ -555,16 +979,60 @@ impl SyntaxDotApp for FinetuneApp {
             .value_of(PRETRAINED_MODEL)
             .map(ToOwned::to_owned)
             .unwrap();
-        let train_data = matches.value_of(TRAIN_DATA).map(ToOwned::to_owned).unwrap();
-        let validation_data = matches
-            .value_of(VALIDATION_DATA)
-            .map(ToOwned::to_owned)
-            .unwrap();
-        let batch_size = matches
-            .value_of(BATCH_SIZE)
+        let train_data = TrainSet::from_arg(matches.value_of(TRAIN_DATA).unwrap());
+        let train_sampling = match matches.value_of(TREEBANK_WEIGHTS) {
+            Some(weights) => {
+                let weights = weights
+                    .split(',')
+                    .map(|weight| {
+                        weight
+                            .trim()
+                            .parse()
+                            .context(format!("Cannot parse treebank weight: {}", weight))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if weights.len() != train_data.len() {
+                    bail!(
+                        "Got {} treebank weights for {} training data sets",
+                        weights.len(),
+                        train_data.len()
+                    );
+                }
+                DataSetSampling::Weights(weights)
+            }
+            None => DataSetSampling::Temperature(
+                matches
+                    .value_of(SAMPLING_TEMPERATURE)
+                    .unwrap()
+                    .parse()
+                    .context("Cannot parse sampling temperature")?,
+            ),
+        };
+        let validation_data: Vec<_> = matches
+            .values_of(VALIDATION_DATA)
             .unwrap()
-            .parse()
-            .context("Cannot parse batch size")?;
+            .map(ValidationSet::from_arg)
+            .collect();
+        let validation_set_names: HashSet<_> =
+            validation_data.iter().map(|set| &set.name).collect();
+        if validation_set_names.len() != validation_data.len() {
+            bail!("Multiple validation sets must have unique names, use NAME=PATH")
+        }
+        let batch_size = match matches.value_of(MAX_BATCH_PIECES) {
+            Some(max_batch_pieces) => BatchSize::Pieces(
+                max_batch_pieces
+                    .parse()
+                    .context("Cannot parse maximum number of batch pieces")?,
+            ),
+            None => BatchSize::Sentences(
+                matches
+                    .value_of(BATCH_SIZE)
+                    .unwrap()
+                    .parse()
+                    .context("Cannot parse batch size")?,
+            ),
+        };
+        let checkpoint = matches.value_of(CHECKPOINT).unwrap().into();
         let continue_finetune = matches.is_present(CONTINUE);
         let device = match matches.value_of("GPU") {
             Some(gpu) => Device::Cuda(
@@ -573,7 +1041,25 @@ impl SyntaxDotApp for FinetuneApp {
             ),
             None => Device::Cpu,
         };
+        let eval_steps = matches
+            .value_of(EVAL_STEPS)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse number of evaluation steps: {}", v))
+            })
+            .transpose()?;
+        if eval_steps == Some(0) {
+            bail!("The number of evaluation steps must be at least 1")
+        }
         let finetune_embeds = matches.is_present(FINETUNE_EMBEDS);
+        let grad_accumulation_steps = matches
+            .value_of(GRAD_ACCUMULATION_STEPS)
+            .unwrap()
+            .parse()
+            .context("Cannot parse number of gradient accumulation steps")?;
+        if grad_accumulation_steps == 0 {
+            bail!("The number of gradient accumulation steps must be at least 1")
+        }
         let initial_lr_classifier = matches
             .value_of(INITIAL_LR_CLASSIFIER)
             .unwrap()
@@ -592,7 +1078,25 @@ impl SyntaxDotApp for FinetuneApp {
             })
             .transpose()?;
         let mixed_precision = matches.is_present(MIXED_PRECISION);
+        let layer_lr_decay = LayerLrDecayOption::parse(matches)?;
+        let schedule = LrScheduleOption::parse(matches)?;
+        let optimizer = OptimizerOption::parse(matches)?;
+        let augmentation = AugmentationOption::parse(matches)?;
+        let averaging = AveragingOption::parse(matches)?;
+        let selection_metric = SelectionMetricOption::parse(matches)?;
+        let format = SentenceFormatOption::parse(matches)?;
+        let malformed = MalformedSentencesOption::parse(matches)?;
         let summary_writer = SummaryOption::parse(matches)?;
+        let max_grad_norm = matches
+            .value_of(MAX_GRAD_NORM)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse maximum gradient norm: {}", v))
+            })
+            .transpose()?;
+        if max_grad_norm.map(|norm: f64| norm <= 0.).unwrap_or(false) {
+            bail!("The maximum gradient norm must be positive")
+        }
         let max_len = matches
             .value_of(MAX_LEN)
             .map(|v| {
@@ -634,7 +1138,37 @@ impl SyntaxDotApp for FinetuneApp {
             .unwrap()
             .parse()
             .context("Cannot parse patience")?;
+        let resume = matches.is_present(RESUME);
+        let shuffle_buffer_size = matches
+            .value_of(SHUFFLE_BUFFER_SIZE)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse shuffle buffer size: {}", v))
+            })
+            .transpose()?;
         let saver = BestEpochSaver::new("", keep_best_epochs);
+        let time_limit = matches
+            .value_of(TIME_LIMIT)
+            .map(parse_duration)
+            .transpose()?;
+
+        // If steps is present, it overrides epochs.
+        let train_duration = if let Some(steps) = matches.value_of(STEPS) {
+            let steps = steps
+                .parse()
+                .context("Cannot parse the number of training steps")?;
+            Some(TrainDuration::Steps(steps))
+        } else {
+            matches
+                .value_of(EPOCHS)
+                .map(|epochs| {
+                    epochs
+                        .parse()
+                        .context("Cannot parse number of training epochs")
+                })
+                .transpose()?
+                .map(TrainDuration::Epochs)
+        };
         let warmup_steps = matches
             .value_of(WARMUP)
             .unwrap()
@@ -647,34 +1181,54 @@ impl SyntaxDotApp for FinetuneApp {
             .context("Cannot parse weight decay")?;
 
         Ok(FinetuneApp {
+            augmentation,
+            averaging,
             batch_size,
+            checkpoint,
             config,
             continue_finetune,
             device,
+            eval_steps,
             finetune_embeds,
+            format,
+            grad_accumulation_steps,
+            max_grad_norm,
             max_len,
             label_smoothing,
+            malformed,
             mixed_precision,
+            optimizer,
             summary_writer,
             lr_schedule: LrSchedule {
                 initial_lr_encoder,
                 initial_lr_classifier,
+                layer_lr_decay,
                 lr_decay_rate,
                 lr_scale,
                 lr_patience,
+                schedule,
                 warmup_steps,
             },
             patience,
             pretrained_model,
+            resume,
+            shuffle_buffer_size,
             saver,
+            selection_metric,
+            time_limit,
             train_data,
+            train_duration,
+            train_sampling,
             validation_data,
             weight_decay,
         })
     }
 
     fn run(&self) -> Result<()> {
-        let model = if self.continue_finetune {
+        // Construct the schedules first to report errors before loading the model.
+        let lr_schedules = self.lr_schedules()?;
+
+        let mut model = if self.continue_finetune {
             Model::load_from(
                 &self.config,
                 &self.pretrained_model,
@@ -694,91 +1248,407 @@ impl SyntaxDotApp for FinetuneApp {
             )?
         };
 
-        let mut train_file = File::open(&self.train_data)
-            .context(format!("Cannot open train data file: {}", self.train_data))?;
-        let mut validation_file = File::open(&self.validation_data).context(format!(
-            "Cannot open validation data file: {}",
-            self.validation_data
-        ))?;
+        let config = load_config(&self.config)?;
+        model
+            .model
+            .load_class_weights(config.biaffine.as_ref(), &config.labeler.encoders)
+            .context("Cannot read class weights")?;
+
+        let train_corpora = self
+            .train_data
+            .iter()
+            .map(|train_set| Corpus::new(&train_set.path))
+            .collect::<Result<Vec<_>>>()?;
+        let mut train_readers = train_corpora
+            .iter()
+            .zip(&self.train_data)
+            .map(|(corpus, train_set)| {
+                corpus
+                    .open()
+                    .context(format!("Cannot open train data: {}", train_set.path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let validation_corpora = self
+            .validation_data
+            .iter()
+            .map(|validation_set| Corpus::new(&validation_set.path))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut metrics = vec![AVERAGE_METRIC.to_string()];
+        if model.biaffine_encoder.is_some() {
+            metrics.extend(["las", "ls", "uas"].iter().map(ToString::to_string));
+        }
+        metrics.extend(
+            model
+                .encoders
+                .iter()
+                .map(|encoder| encoder.name().to_string()),
+        );
+        let validation_set_names = self
+            .validation_data
+            .iter()
+            .map(|validation_set| validation_set.name.clone())
+            .collect::<Vec<_>>();
+        self.selection_metric
+            .check(&validation_set_names, &metrics)?;
+
+        // Sentences are only tagged with treebank identifiers when the
+        // model has treebank embeddings. Validation sets that are named
+        // after a treebank of the model are tagged as well.
+        let cached_train_data = train_readers
+            .iter_mut()
+            .map(is_cached_data_set)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Cannot read train data")?;
+        let cached_train_data = cached_train_data.iter().any(|&cached| cached);
+        if cached_train_data && (train_corpora.len() > 1 || train_corpora[0].shards().len() > 1) {
+            bail!("Pre-processed train data cannot be combined with other train data");
+        }
+        if cached_train_data && !self.augmentation.is_identity() {
+            bail!("Pre-processed train data cannot be augmented");
+        }
 
-        let mut saver = self.saver.clone();
-        let mut grad_scaler = self.build_optimizer(&model.vs)?;
+        // Pre-processed sentences already have treebank identifiers.
+        let train_treebank_ids = if config.model.treebanks.is_empty() || cached_train_data {
+            None
+        } else {
+            Some(
+                self.train_data
+                    .iter()
+                    .map(|train_set| {
+                        config.model.treebank_id(&train_set.name).ok_or_else(|| {
+                            anyhow!(
+                                "Training data set {} is not a treebank of the model, treebanks: {}",
+                                train_set.name,
+                                config.model.treebanks.join(", ")
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            )
+        };
+        let validation_treebank_ids = self
+            .validation_data
+            .iter()
+            .map(|validation_set| config.model.treebank_id(&validation_set.name))
+            .collect::<Vec<_>>();
+
+        let mut grad_scaler = self.build_optimizer(&model.vs, model.model.n_layers())?;
+
+        let checkpoint = Checkpoint::new(&self.checkpoint);
+        let mut state = if self.resume {
+            if !checkpoint.exists() {
+                bail!("Cannot resume, no checkpoint in: {}", self.checkpoint);
+            }
 
-        let mut lr_schedules = self.lr_schedules();
+            log::info!("Resuming from checkpoint: {}", self.checkpoint);
+            checkpoint
+                .load(&mut model.vs, &mut grad_scaler, self.device)
+                .context("Cannot load checkpoint")?
+        } else {
+            FinetuneState {
+                best_acc: 0.0,
+                best_evaluation: 0,
+                best_unit: String::new(),
+                epoch: 0,
+                epoch_batch: 0,
+                evaluation: 0,
+                global_step: 1,
+                last_acc: 0.0,
+                lr_schedules,
+                saver: self.saver.clone(),
+                seed: random_seed(),
+                swa_saver: self
+                    .averaging
+                    .swa_epochs
+                    .map(|n_epochs| SwaSaver::new("", n_epochs)),
+            }
+        };
 
-        let mut last_acc = 0.0;
-        let mut best_acc = 0.0;
-        let mut best_epoch = 0;
+        let mut train_dataset = if cached_train_data {
+            let train_file = File::open(&train_corpora[0].shards()[0])
+                .context("Cannot open pre-processed train data")?;
+            let train_dataset =
+                CachedDataSet::mmap(&train_file).context("Cannot read pre-processed train data")?;
+            let fingerprint = config
+                .fingerprint()
+                .context("Cannot read tokenizer vocabulary or label files")?;
+            train_dataset
+                .check_encoders(
+                    model.biaffine_encoder.is_some(),
+                    &model.encoders,
+                    fingerprint,
+                )
+                .context("Cannot use pre-processed train data with this model")?;
+            TrainData::Cached(train_dataset)
+        } else {
+            let train_source_files = train_readers
+                .iter()
+                .map(CorpusReader::source_files)
+                .collect();
+            let mut train_dataset = CompositeDataSet::new_with_format(
+                train_readers,
+                self.format.clone(),
+                self.train_sampling.clone(),
+                state.seed as u64,
+            )
+            .context("Cannot construct training data set")?
+            .with_source_files(train_source_files)?;
+            if let Some(treebank_ids) = train_treebank_ids {
+                train_dataset = train_dataset.with_treebank_ids(treebank_ids)?;
+            }
+            if self.train_data.len() > 1 {
+                for (train_set, prob) in self.train_data.iter().zip(train_dataset.probs()) {
+                    log::info!("Sampling {} with probability {:.4}", train_set.name, prob);
+                }
+            }
+            TrainData::Sentences(train_dataset)
+        };
 
-        let mut global_step = 1;
+        let start = Instant::now();
 
-        for epoch in 0.. {
+        'train: while !self.train_budget_exhausted(&state) {
+            let epoch = state.epoch;
             log::info!("Epoch {}", epoch);
 
-            let _ = lr_schedules
-                .classifier
-                .compute_epoch_learning_rate(epoch, last_acc);
-            let _ = lr_schedules
-                .encoder
-                .compute_epoch_learning_rate(epoch, last_acc);
-
-            self.run_epoch(
-                model.biaffine_encoder.as_ref(),
-                &model.encoders,
-                &*model.tokenizer,
-                &model.model,
-                &mut train_file,
-                Some(&mut grad_scaler),
-                &mut lr_schedules,
-                &mut global_step,
-                epoch,
-            )
-            .context("Cannot run train epoch")?;
-
-            last_acc = self
-                .run_epoch(
-                    model.biaffine_encoder.as_ref(),
-                    &model.encoders,
-                    &*model.tokenizer,
-                    &model.model,
-                    &mut validation_file,
-                    None as Option<&mut GradScaler<nn::Optimizer>>,
-                    &mut lr_schedules,
-                    &mut global_step,
-                    epoch,
-                )
-                .context("Cannot run valdidation epoch")?;
+            let progress_bar = ProgressBar::new(train_dataset.n_sentences(self.max_len) as u64);
+            Self::style_progress_bar(&progress_bar, "train");
+
+            let epoch_seed = state.seed.wrapping_add(epoch as i64) as u64;
+            let batches: Box<dyn Iterator<Item = Result<Tensors, SyntaxDotError>> + '_> =
+                match &mut train_dataset {
+                    TrainData::Sentences(train_dataset) => {
+                        // The epoch determines the interleaving of the treebanks.
+                        train_dataset.set_epoch(epoch);
+                        let sentences = train_dataset
+                            .sentences(&*model.tokenizer)?
+                            .inspect(|_| progress_bar.inc(1))
+                            .augment(self.augmentation, &*model.tokenizer, epoch_seed)
+                            .filter_by_len(self.max_len);
+                        match self.shuffle_buffer_size {
+                            Some(buffer_size) => Box::new(
+                                sentences
+                                    .bucketed_shuffle(self.batch_size, buffer_size, epoch_seed)
+                                    .into_tensors(
+                                        model.biaffine_encoder.as_ref(),
+                                        Some(&model.encoders),
+                                    )
+                                    .malformed_sentences(self.malformed),
+                            ),
+                            None => Box::new(
+                                sentences
+                                    .batched_tensors(
+                                        model.biaffine_encoder.as_ref(),
+                                        Some(&model.encoders),
+                                        self.batch_size,
+                                    )
+                                    .malformed_sentences(self.malformed),
+                            ),
+                        }
+                    }
+                    TrainData::Cached(train_dataset) => {
+                        // Pre-processed data sets are shuffled globally, the
+                        // shuffle buffer size is used as the bucket size.
+                        let batches = match self.shuffle_buffer_size {
+                            Some(buffer_size) => train_dataset.shuffled_batches(
+                                self.batch_size,
+                                self.max_len,
+                                buffer_size,
+                                epoch_seed,
+                            ),
+                            None => train_dataset.batches(self.batch_size, self.max_len),
+                        };
+
+                        let biaffine = model.biaffine_encoder.is_some();
+                        let encoders = &model.encoders;
+                        Box::new(
+                            batches
+                                .inspect(|batch| progress_bar.inc(batch.len() as u64))
+                                .map(move |batch| encoded_batch_tensors(batch, biaffine, encoders)),
+                        )
+                    }
+                };
+
+            // When resuming, skip the batches of the epoch that were
+            // already processed.
+            let mut batches = batches.skip(state.epoch_batch).peekable();
+
+            while batches.peek().is_some() {
+                seed_torch(state.seed, state.global_step);
+
+                let _ = state
+                    .lr_schedules
+                    .classifier
+                    .compute_epoch_learning_rate(epoch, state.last_acc);
+                let _ = state
+                    .lr_schedules
+                    .encoder
+                    .compute_epoch_learning_rate(epoch, state.last_acc);
+
+                // The first epoch, which only trains the classifiers, is
+                // always validated after the full epoch.
+                let eval_steps = if epoch == 0 { None } else { self.eval_steps };
+                let mut n_steps = 0;
+
+                let train_stats = self
+                    .run_steps(
+                        model.biaffine_encoder.as_ref(),
+                        &model.model,
+                        &mut batches,
+                        &progress_bar,
+                        Some(&mut grad_scaler),
+                        &mut state.lr_schedules,
+                        &mut state.global_step,
+                        epoch,
+                        |global_step| {
+                            n_steps += 1;
+                            eval_steps
+                                .map(|eval_steps| n_steps >= eval_steps)
+                                .unwrap_or(false)
+                                || self.step_budget_exhausted(global_step)
+                                || self.time_limit_exceeded(start)
+                        },
+                    )
+                    .context("Cannot run train steps")?;
+                state.epoch_batch += train_stats.n_batches;
+                self.log_epoch_stats(&state.global_step, train_stats, "train")?;
+
+                let epoch_done = batches.peek().is_none();
+
+                // Validate and save the averaged parameters when a moving
+                // average is used.
+                grad_scaler.optimizer_mut().swap_average()?;
+
+                let mut validation_scores = Vec::with_capacity(self.validation_data.len());
+                for ((validation_set, validation_corpus), &treebank_id) in self
+                    .validation_data
+                    .iter()
+                    .zip(&validation_corpora)
+                    .zip(&validation_treebank_ids)
+                {
+                    let scores = self
+                        .validate(
+                            model.biaffine_encoder.as_ref(),
+                            &model.encoders,
+                            &*model.tokenizer,
+                            &model.model,
+                            validation_corpus,
+                            &validation_set.epoch_type,
+                            treebank_id,
+                            &mut state.lr_schedules,
+                            state.global_step,
+                            epoch,
+                        )
+                        .context(format!("Cannot run validation on {}", validation_set.path))?;
+                    validation_scores.push((validation_set.name.clone(), scores));
+                }
 
-            if last_acc > best_acc {
-                best_epoch = epoch;
-                best_acc = last_acc;
-            }
+                state.last_acc = self.selection_metric.compute(&validation_scores)?;
 
-            saver
-                .save(&model.vs, CompletedUnit::Epoch(last_acc))
-                .context("Error saving model")?;
+                let (completed, unit) = if self.eval_steps.is_none() && epoch_done {
+                    (
+                        CompletedUnit::Epoch(state.last_acc),
+                        format!("epoch {}", epoch),
+                    )
+                } else {
+                    (
+                        CompletedUnit::Step(state.global_step, state.last_acc),
+                        format!("step {}", state.global_step),
+                    )
+                };
+
+                let evaluation = state.evaluation;
+                state.evaluation += 1;
+                if state.last_acc > state.best_acc {
+                    state.best_acc = state.last_acc;
+                    state.best_evaluation = evaluation;
+                    state.best_unit = unit.clone();
+                }
 
-            let epoch_status = if best_epoch == epoch { "🎉" } else { "" };
-            log::info!(
-                "Epoch {} (validation): acc: {:.4}, best epoch: {}, best acc: {:.4} {}",
-                epoch,
-                last_acc,
-                best_epoch,
-                best_acc,
-                epoch_status
-            );
+                state
+                    .saver
+                    .save(&model.vs, completed)
+                    .context("Error saving model")?;
+
+                grad_scaler.optimizer_mut().swap_average()?;
+
+                if epoch_done {
+                    if let Some(swa_saver) = &mut state.swa_saver {
+                        swa_saver
+                            .save_epoch(&model.vs, epoch)
+                            .context("Cannot save epoch parameters for averaging")?;
+                    }
+
+                    state.epoch = epoch + 1;
+                    state.epoch_batch = 0;
+                }
 
-            self.summary_writer
-                .write_scalar("acc:validation,avg", global_step as i64, last_acc)?;
+                checkpoint
+                    .save(&model.vs, &grad_scaler, &state)
+                    .context("Cannot save checkpoint")?;
 
-            if epoch - best_epoch == self.patience {
+                let status = if state.best_evaluation == evaluation {
+                    "🎉"
+                } else {
+                    ""
+                };
                 log::info!(
-                    "Lost my patience! Best epoch: {} with accuracy: {:.4}",
-                    best_epoch,
-                    best_acc
+                    "Validation after {}: acc: {:.4}, best: {}, best acc: {:.4} {}",
+                    unit,
+                    state.last_acc,
+                    state.best_unit,
+                    state.best_acc,
+                    status
                 );
-                break;
+
+                self.summary_writer.write_scalar(
+                    "selection_metric",
+                    state.global_step as i64,
+                    state.last_acc,
+                )?;
+
+                if evaluation - state.best_evaluation >= self.patience {
+                    log::info!(
+                        "Lost my patience! Best: {} with accuracy: {:.4}",
+                        state.best_unit,
+                        state.best_acc
+                    );
+                    break 'train;
+                }
+
+                if self.time_limit_exceeded(start) {
+                    log::info!(
+                        "Reached the time limit! Best: {} with accuracy: {:.4}",
+                        state.best_unit,
+                        state.best_acc
+                    );
+                    break 'train;
+                }
+
+                if self.step_budget_exhausted(state.global_step) {
+                    break;
+                }
             }
+
+            progress_bar.finish();
+
+            if state.epoch == epoch && !self.train_budget_exhausted(&state) {
+                bail!("No training batches in epoch {}", epoch);
+            }
+        }
+
+        if self.train_budget_exhausted(&state) {
+            log::info!(
+                "Training budget exhausted! Best: {} with accuracy: {:.4}",
+                state.best_unit,
+                state.best_acc
+            );
+        }
+
+        if let Some(swa_saver) = &mut state.swa_saver {
+            swa_saver
+                .average(&mut model.vs)
+                .context("Cannot average parameters")?;
         }
 
         Ok(())
@@ -786,11 +1656,58 @@ impl SyntaxDotApp for FinetuneApp {
 }
 
 impl SyntaxDotTrainApp for FinetuneApp {
+    fn averaging(&self) -> Averaging {
+        self.averaging
+    }
+
+    fn max_grad_norm(&self) -> Option<f64> {
+        self.max_grad_norm
+    }
+
     fn mixed_precision(&self) -> bool {
         self.mixed_precision
     }
 
+    fn layer_lr_decay(&self) -> f32 {
+        self.lr_schedule.layer_lr_decay.into_inner()
+    }
+
+    fn optimizer(&self) -> OptimizerSelection {
+        self.optimizer
+    }
+
     fn weight_decay(&self) -> f64 {
         self.weight_decay
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::split_data_set_name;
+
+    #[test]
+    fn data_set_names_are_split_from_paths() {
+        assert_eq!(
+            split_data_set_name("ood=dev-web.conllu"),
+            (Some("ood"), "dev-web.conllu")
+        );
+        assert_eq!(
+            split_data_set_name("de_hdt=data/a=b.conllu"),
+            (Some("de_hdt"), "data/a=b.conllu")
+        );
+        assert_eq!(split_data_set_name("dev.conllu"), (None, "dev.conllu"));
+    }
+
+    #[test]
+    fn paths_with_equals_signs_are_not_split() {
+        assert_eq!(
+            split_data_set_name("data/a=b.conllu"),
+            (None, "data/a=b.conllu")
+        );
+        assert_eq!(
+            split_data_set_name("dev.a=b.conllu"),
+            (None, "dev.a=b.conllu")
+        );
+        assert_eq!(split_data_set_name("=dev.conllu"), (None, "=dev.conllu"));
+    }
+}
@@ -1,6 +1,9 @@
 mod annotate;
 pub use annotate::AnnotateApp;
 
+mod average;
+pub use average::AverageApp;
+
 mod distill;
 pub use distill::DistillApp;
 
@@ -12,3 +15,6 @@ pub use finetune::FinetuneApp;
 
 mod prepare;
 pub use prepare::PrepareApp;
+
+mod preprocess;
+pub use preprocess::PreprocessApp;
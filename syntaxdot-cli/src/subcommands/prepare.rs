@@ -1,17 +1,21 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{BufReader, BufWriter, Write};
 
 use anyhow::{Context, Result};
 use clap::{App, Arg, ArgMatches};
-use conllu::io::{ReadSentence, Reader};
 use indicatif::ProgressStyle;
-use syntaxdot::config::{BiaffineParserConfig, Config};
+use syntaxdot::config::{BiaffineParserConfig, ClassWeights, Config, LossConfig};
+use syntaxdot::dataset::{MalformedSentences, SentenceFormat};
 use syntaxdot::encoders::Encoders;
 use syntaxdot_encoders::SentenceEncoder;
+use udgraph::graph::Sentence;
 
+use crate::format::SentenceFormatOption;
 use crate::io::load_config;
+use crate::malformed::MalformedSentencesOption;
 use crate::progress::ReadProgress;
-use crate::traits::{SyntaxDotApp, DEFAULT_CLAP_SETTINGS};
+use crate::traits::{SyntaxDotApp, SyntaxDotOption, DEFAULT_CLAP_SETTINGS};
 use syntaxdot_encoders::dependency::MutableDependencyEncoder;
 
 const CONFIG: &str = "CONFIG";
@@ -19,10 +23,87 @@ static TRAIN_DATA: &str = "TRAIN_DATA";
 
 pub struct PrepareApp {
     config: String,
+    format: SentenceFormat,
+    malformed: MalformedSentences,
     train_data: String,
 }
 
+/// Labels of a sentence, per encoder, and dependency relations.
+type SentenceLabels<'a> = (Vec<(&'a str, Vec<usize>)>, Vec<usize>);
+
 impl PrepareApp {
+    /// Encode the labels of a sentence.
+    ///
+    /// Encoding adds the labels of the sentence to the label inventories.
+    /// Returns `None` when the sentence is skipped as malformed. Labels
+    /// that are masked are not returned.
+    fn encode_sentence<'a>(
+        &self,
+        sentence: &Sentence,
+        encoders: &'a Encoders,
+        biaffine_decoder: Option<&MutableDependencyEncoder>,
+    ) -> Result<Option<SentenceLabels<'a>>> {
+        let mut labels = Vec::with_capacity(encoders.len());
+        for encoder in &**encoders {
+            let encoder_labels = match self.malformed {
+                MalformedSentences::MaskLabels => encoder
+                    .encoder()
+                    .encode_partial(sentence)
+                    .map(|encoding| encoding.into_iter().flatten().collect()),
+                _ => encoder.encoder().encode(sentence),
+            };
+
+            match encoder_labels {
+                Ok(encoder_labels) => labels.push((encoder.name(), encoder_labels)),
+                Err(err) => {
+                    if !self.malformed.handle(sentence, err.into())? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        let relations = match (biaffine_decoder, self.malformed) {
+            (Some(biaffine_decoder), MalformedSentences::MaskLabels) => biaffine_decoder
+                .encode_partial(sentence)
+                .relations
+                .into_iter()
+                .flatten()
+                .collect(),
+            (Some(biaffine_decoder), _) => match biaffine_decoder.encode(sentence) {
+                Ok(encoding) => encoding.relations,
+                Err(err) => {
+                    // Fails or skips, since labels are not masked.
+                    self.malformed.handle(sentence, err.into())?;
+                    return Ok(None);
+                }
+            },
+            (None, _) => Vec::new(),
+        };
+
+        Ok(Some((labels, relations)))
+    }
+
+    /// Write class weights that are computed from label frequencies.
+    ///
+    /// Nothing is written when the loss does not use frequency-based
+    /// class weights.
+    fn write_class_weights(loss: &LossConfig, counts: &[usize], n_classes: usize) -> Result<()> {
+        let path = match &loss.class_weights {
+            Some(ClassWeights::Frequency(path)) => path,
+            _ => return Ok(()),
+        };
+
+        let mut f = BufWriter::new(
+            File::create(path).context(format!("Cannot create class weights file: {}", path))?,
+        );
+        for weight in frequency_class_weights(counts, n_classes) {
+            writeln!(f, "{}", weight).context("Cannot write class weights")?;
+        }
+
+        Ok(())
+    }
+
     fn write_dependency_labels(
         config: &BiaffineParserConfig,
         encoder: &MutableDependencyEncoder,
@@ -51,7 +132,7 @@ impl PrepareApp {
 
 impl SyntaxDotApp for PrepareApp {
     fn app() -> App<'static, 'static> {
-        App::new("prepare")
+        let app = App::new("prepare")
             .settings(DEFAULT_CLAP_SETTINGS)
             .about("Prepare shape and label files for training")
             .arg(
@@ -65,20 +146,30 @@ impl SyntaxDotApp for PrepareApp {
                     .help("Training data")
                     .index(2)
                     .required(true),
-            )
+            );
+
+        let app = SentenceFormatOption::add_to_app(app);
+        MalformedSentencesOption::add_to_app(app)
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
         let config = matches.value_of(CONFIG).unwrap().into();
         let train_data = matches.value_of(TRAIN_DATA).unwrap().into();
+        let format = SentenceFormatOption::parse(matches)?;
+        let malformed = MalformedSentencesOption::parse(matches)?;
 
-        Ok(PrepareApp { config, train_data })
+        Ok(PrepareApp {
+            config,
+            format,
+            malformed,
+            train_data,
+        })
     }
 
     fn run(&self) -> Result<()> {
         let config = load_config(&self.config)?;
 
-        let mut biaffine_decoder = config.biaffine.as_ref().map(MutableDependencyEncoder::from);
+        let biaffine_decoder = config.biaffine.as_ref().map(MutableDependencyEncoder::from);
 
         let encoders: Encoders = (&config.labeler.encoders).into();
 
@@ -91,21 +182,48 @@ impl SyntaxDotApp for PrepareApp {
                 .template("[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}% {msg}"),
         );
 
-        let treebank_reader = Reader::new(BufReader::new(read_progress));
+        // Label counts for computing class weights.
+        let mut label_counts: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut relation_counts = Vec::new();
 
-        for sentence in treebank_reader.sentences() {
+        for sentence in self.format.sentences(BufReader::new(read_progress)) {
             let sentence = sentence.context("Cannot read sentence from treebank")?;
 
-            for encoder in &*encoders {
-                encoder.encoder().encode(&sentence).context(format!(
-                    "Cannot encode sentence with encoder {}",
-                    encoder.name()
-                ))?;
-            }
+            let (labels, relations) =
+                match self.encode_sentence(&sentence, &encoders, biaffine_decoder.as_ref())? {
+                    Some(sentence_labels) => sentence_labels,
+                    None => continue,
+                };
 
-            if let Some(biaffine_decoder) = biaffine_decoder.as_mut() {
-                biaffine_decoder.encode(&sentence)?;
+            for (encoder_name, encoder_labels) in labels {
+                count_labels(
+                    label_counts.entry(encoder_name).or_default(),
+                    encoder_labels,
+                );
             }
+
+            count_labels(&mut relation_counts, relations);
+        }
+
+        for encoder in &*encoders {
+            Self::write_class_weights(
+                &config.labeler.encoders.loss(encoder.name()),
+                label_counts
+                    .get(encoder.name())
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                encoder.encoder().len(),
+            )?;
+        }
+
+        if let (Some(biaffine_config), Some(biaffine_decoder)) =
+            (config.biaffine.as_ref(), biaffine_decoder.as_ref())
+        {
+            Self::write_class_weights(
+                &biaffine_config.loss,
+                &relation_counts,
+                biaffine_decoder.n_relations(),
+            )?;
         }
 
         Self::write_labels(&config, &encoders)?;
@@ -123,3 +241,64 @@ impl SyntaxDotApp for PrepareApp {
         Ok(())
     }
 }
+
+/// Add label occurrences to the label counts.
+fn count_labels(counts: &mut Vec<usize>, labels: impl IntoIterator<Item = usize>) {
+    for label in labels {
+        if label >= counts.len() {
+            counts.resize(label + 1, 0);
+        }
+        counts[label] += 1;
+    }
+}
+
+/// Compute class weights from label frequencies.
+///
+/// The weight of a class is proportional to the inverse square root of
+/// its frequency. The weights are normalized, such that the average
+/// weight of the labels in the training data is one. Classes that do not
+/// occur in the training data get weight one.
+fn frequency_class_weights(counts: &[usize], n_classes: usize) -> Vec<f32> {
+    let n_labels: usize = counts.iter().sum();
+    let sqrt_sum: f64 = counts.iter().map(|&count| (count as f64).sqrt()).sum();
+
+    (0..n_classes)
+        .map(|class| match counts.get(class) {
+            Some(&count) if count > 0 => {
+                (n_labels as f64 / (sqrt_sum * (count as f64).sqrt())) as f32
+            }
+            _ => 1.0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::frequency_class_weights;
+
+    #[test]
+    fn frequency_class_weights_are_normalized() {
+        let counts = [1, 4, 0];
+        let weights = frequency_class_weights(&counts, 4);
+
+        assert_eq!(weights.len(), 4);
+        assert_abs_diff_eq!(weights[0], 5. / 3., epsilon = 1e-6);
+        assert_abs_diff_eq!(weights[1], 5. / 6., epsilon = 1e-6);
+
+        // Classes that do not occur get weight one.
+        assert_eq!(weights[2], 1.);
+        assert_eq!(weights[3], 1.);
+
+        // The average weight of the labels is one.
+        let n_labels: usize = counts.iter().sum();
+        let average = counts
+            .iter()
+            .zip(&weights)
+            .map(|(&count, &weight)| count as f32 * weight)
+            .sum::<f32>()
+            / n_labels as f32;
+        assert_abs_diff_eq!(average, 1., epsilon = 1e-6);
+    }
+}
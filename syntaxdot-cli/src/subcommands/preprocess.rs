@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use indicatif::ProgressStyle;
+use syntaxdot::dataset::{
+    encode_sentence, set_treebank_id, CachedDataSetWriter, DataSet, MalformedSentences,
+    SentenceFormat,
+};
+
+use crate::corpus::Corpus;
+use crate::format::SentenceFormatOption;
+use crate::io::{load_biaffine_decoder, load_config, load_encoders, load_tokenizer};
+use crate::malformed::MalformedSentencesOption;
+use crate::progress::ReadProgress;
+use crate::traits::{SyntaxDotApp, SyntaxDotOption, DEFAULT_CLAP_SETTINGS};
+
+const CONFIG: &str = "CONFIG";
+const INPUT: &str = "INPUT";
+const OUTPUT: &str = "OUTPUT";
+const TREEBANK: &str = "TREEBANK";
+
+pub struct PreprocessApp {
+    config: String,
+    format: SentenceFormat,
+    input: String,
+    malformed: MalformedSentences,
+    output: String,
+    treebank: Option<String>,
+}
+
+impl SyntaxDotApp for PreprocessApp {
+    fn app() -> App<'static, 'static> {
+        let app = App::new("preprocess")
+            .settings(DEFAULT_CLAP_SETTINGS)
+            .about("Tokenize and encode a corpus for training")
+            .arg(
+                Arg::with_name(CONFIG)
+                    .help("SyntaxDot configuration file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(INPUT)
+                    .help("Input corpus")
+                    .index(2)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(OUTPUT)
+                    .help("Pre-processed output data set")
+                    .index(3)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(TREEBANK)
+                    .long("treebank")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Treebank of the sentences, for models with treebank embeddings"),
+            );
+
+        let app = SentenceFormatOption::add_to_app(app);
+        MalformedSentencesOption::add_to_app(app)
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self> {
+        let config = matches.value_of(CONFIG).unwrap().into();
+        let input = matches.value_of(INPUT).unwrap().into();
+        let output = matches.value_of(OUTPUT).unwrap().into();
+        let treebank = matches.value_of(TREEBANK).map(ToOwned::to_owned);
+        let format = SentenceFormatOption::parse(matches)?;
+        let malformed = MalformedSentencesOption::parse(matches)?;
+
+        Ok(PreprocessApp {
+            config,
+            format,
+            input,
+            malformed,
+            output,
+            treebank,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let config = load_config(&self.config)?;
+
+        let biaffine_encoder = config
+            .biaffine
+            .as_ref()
+            .map(load_biaffine_decoder)
+            .transpose()?;
+        let encoders = load_encoders(&config)?;
+        let tokenizer = load_tokenizer(&config)?;
+        let fingerprint = config
+            .fingerprint()
+            .context("Cannot read tokenizer vocabulary or label files")?;
+
+        let treebank_id = self
+            .treebank
+            .as_ref()
+            .map(|treebank| {
+                config.model.treebank_id(treebank).ok_or_else(|| {
+                    anyhow!(
+                        "{} is not a treebank of the model, treebanks: {}",
+                        treebank,
+                        config.model.treebanks.join(", ")
+                    )
+                })
+            })
+            .transpose()?;
+
+        let input = Corpus::new(&self.input)?
+            .open()
+            .context(format!("Cannot open input corpus: {}", self.input))?;
+        let source_files = input.source_files();
+        let read_progress = ReadProgress::new(input).context("Cannot create progress bar")?;
+        let progress_bar = read_progress.progress_bar().clone();
+        progress_bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[Time: {elapsed_precise}, ETA: {eta_precise}] {bar} {percent}% {msg}"),
+        );
+
+        let output_file = File::create(&self.output)
+            .context(format!("Cannot create output data set: {}", self.output))?;
+        let mut writer = CachedDataSetWriter::new(
+            BufWriter::new(output_file),
+            biaffine_encoder.is_some(),
+            &encoders,
+            fingerprint,
+        )
+        .context("Cannot write data set header")?;
+
+        let mut dataset = self.format.data_set(BufReader::new(read_progress));
+        for sentence in dataset.sentences(&*tokenizer)? {
+            let mut sentence = sentence.context("Cannot read sentence from corpus")?;
+            source_files.set_source_file(&mut sentence.sentence);
+            if let Some(treebank_id) = treebank_id {
+                set_treebank_id(&mut sentence.sentence, treebank_id);
+            }
+
+            let encoded = encode_sentence(
+                &sentence,
+                biaffine_encoder.as_ref(),
+                &encoders,
+                self.malformed,
+            )?;
+            if let Some(encoded) = encoded {
+                writer.write(&encoded).context("Cannot write sentence")?;
+            }
+        }
+
+        let n_sentences = writer.finish().context("Cannot write data set index")?;
+        progress_bar.finish();
+
+        log::info!("Wrote {} sentences to {}", n_sentences, self.output);
+
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use anyhow::Result;
 use clap::{App, AppSettings, ArgMatches};
-use syntaxdot::optimizers::{GradScaler, Optimizer};
-use tch::nn::{adamw, Optimizer as TchOptimizer, OptimizerConfig, VarStore};
+use syntaxdot::optimizers::{AnyOptimizer, ExponentialMovingAverage, GradScaler, Optimizer};
+use tch::nn::VarStore;
+
+use crate::optimizer::{Averaging, OptimizerSelection};
 
 pub static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
     AppSettings::DontCollapseArgsInUsage,
@@ -15,6 +17,39 @@ pub enum ParameterGroup {
     ClassifierNoWeightDecay = 3,
 }
 
+/// The number of parameter groups that are not tied to an encoder layer.
+const N_SHARED_PARAMETER_GROUPS: usize = 4;
+
+impl ParameterGroup {
+    /// Get the parameter group of a variable in an encoder layer.
+    ///
+    /// Layer 0 is the embedding layer, layer `n + 1` is the transformer
+    /// layer `encoder.layer_n`. Each layer has separate groups for
+    /// variables with and without weight decay.
+    pub fn encoder_layer(layer: usize, weight_decay: bool) -> usize {
+        N_SHARED_PARAMETER_GROUPS + 2 * layer + if weight_decay { 0 } else { 1 }
+    }
+}
+
+/// Get the learning rate of an encoder layer.
+///
+/// The top layer `n_layers - 1` uses `lr_encoder`, every layer below it
+/// is scaled by another factor `layer_lr_decay`.
+pub(crate) fn layer_lr(lr_encoder: f32, layer_lr_decay: f32, n_layers: i64, layer: i64) -> f32 {
+    lr_encoder * layer_lr_decay.powi((n_layers - 1 - layer) as i32)
+}
+
+/// Get the transformer layer of an encoder variable.
+///
+/// Returns `None` if the variable is not in a layer `encoder.layer_n`.
+fn encoder_layer_index(name: &str) -> Option<usize> {
+    name.strip_prefix("encoder.layer_")?
+        .split('.')
+        .next()?
+        .parse()
+        .ok()
+}
+
 pub trait SyntaxDotApp
 where
     Self: Sized,
@@ -29,13 +64,32 @@ where
 pub trait SyntaxDotTrainApp: SyntaxDotApp {
     fn build_parameter_group_fun() -> fn(&str) -> usize {
         |name: &str| {
-            if name.starts_with("classifiers") || name.starts_with("biaffine") {
-                if name.contains("layer_norm") || name.contains("bias") {
+            if name.starts_with("classifiers")
+                || name.starts_with("sentence_classifiers")
+                || name.starts_with("biaffine")
+                || name.starts_with("pooler")
+                || name.starts_with("task_weights")
+                || name.starts_with("treebank_embeddings")
+            {
+                // Treebank embeddings are not pretrained, so they are
+                // trained like the classifiers.
+                if name.contains("layer_norm")
+                    || name.contains("bias")
+                    || name.starts_with("task_weights")
+                {
                     ParameterGroup::ClassifierNoWeightDecay as usize
                 } else {
                     ParameterGroup::Classifier as usize
                 }
-            } else if name.starts_with("encoder") || name.starts_with("embeddings") {
+            } else if name.starts_with("embeddings") {
+                let weight_decay = !(name.contains("layer_norm") || name.contains("bias"));
+                ParameterGroup::encoder_layer(0, weight_decay)
+            } else if let Some(layer) = encoder_layer_index(name) {
+                let weight_decay = !(name.contains("layer_norm") || name.contains("bias"));
+                ParameterGroup::encoder_layer(layer + 1, weight_decay)
+            } else if name.starts_with("encoder") {
+                // Variables that are shared between layers, such as the
+                // ALBERT layer groups.
                 if name.contains("layer_norm") || name.contains("bias") {
                     ParameterGroup::EncoderNoWeightDecay as usize
                 } else {
@@ -47,16 +101,97 @@ pub trait SyntaxDotTrainApp: SyntaxDotApp {
         }
     }
 
-    fn build_optimizer(&self, var_store: &VarStore) -> Result<GradScaler<TchOptimizer>> {
-        let opt = adamw(0.9, 0.999, self.weight_decay()).build(var_store, 1e-3)?;
+    /// Build the optimizer.
+    ///
+    /// `n_layers` is the number of encoder layers, including the
+    /// embedding layer.
+    fn build_optimizer(
+        &self,
+        var_store: &VarStore,
+        n_layers: i64,
+    ) -> Result<GradScaler<ExponentialMovingAverage<AnyOptimizer>>> {
+        let opt = self.optimizer().build(var_store, self.weight_decay())?;
+        let ema_decay = self.averaging().ema_decay;
+        let opt = ExponentialMovingAverage::new(ema_decay.is_some(), opt, ema_decay.unwrap_or(0.))?;
         let mut grad_scaler = GradScaler::new_with_defaults(self.mixed_precision(), opt)?;
+        grad_scaler.set_max_grad_norm(self.max_grad_norm());
         grad_scaler.set_weight_decay_group(ParameterGroup::EncoderNoWeightDecay as usize, 0.);
         grad_scaler.set_weight_decay_group(ParameterGroup::ClassifierNoWeightDecay as usize, 0.);
+        for layer in 0..n_layers as usize {
+            grad_scaler.set_weight_decay_group(ParameterGroup::encoder_layer(layer, false), 0.);
+        }
         Ok(grad_scaler)
     }
 
+    /// Decay of the learning rate per encoder layer.
+    fn layer_lr_decay(&self) -> f32;
+
+    /// Set the learning rates of the parameter groups.
+    ///
+    /// The learning rate of encoder layer `n` is `lr_encoder` scaled by
+    /// `layer_lr_decay^(n_layers - 1 - n)`, where layer 0 is the embedding
+    /// layer. So, the top layer uses `lr_encoder`. Variables that are shared between layers use `lr_encoder`.
+    fn set_lr_groups(
+        &self,
+        optimizer: &mut impl Optimizer,
+        n_layers: i64,
+        lr_encoder: f32,
+        lr_classifier: f32,
+    ) {
+        optimizer.set_lr_group(ParameterGroup::Encoder as usize, lr_encoder.into());
+        optimizer.set_lr_group(
+            ParameterGroup::EncoderNoWeightDecay as usize,
+            lr_encoder.into(),
+        );
+        optimizer.set_lr_group(ParameterGroup::Classifier as usize, lr_classifier.into());
+        optimizer.set_lr_group(
+            ParameterGroup::ClassifierNoWeightDecay as usize,
+            lr_classifier.into(),
+        );
+
+        for layer in 0..n_layers {
+            let lr_layer = layer_lr(lr_encoder, self.layer_lr_decay(), n_layers, layer);
+            optimizer.set_lr_group(
+                ParameterGroup::encoder_layer(layer as usize, true),
+                lr_layer.into(),
+            );
+            optimizer.set_lr_group(
+                ParameterGroup::encoder_layer(layer as usize, false),
+                lr_layer.into(),
+            );
+        }
+    }
+
+    /// Get the learning rates of the parameter groups for logging.
+    ///
+    /// Returns the learning rates of the shared encoder variables
+    /// (`encoder`), the classifiers (`classifier`) and each encoder layer
+    /// (`encoder_layer_n`).
+    fn group_lrs(&self, n_layers: i64, lr_encoder: f32, lr_classifier: f32) -> Vec<(String, f32)> {
+        let mut lrs = vec![
+            ("encoder".to_string(), lr_encoder),
+            ("classifier".to_string(), lr_classifier),
+        ];
+        lrs.extend((0..n_layers).map(|layer| {
+            (
+                format!("encoder_layer_{}", layer),
+                layer_lr(lr_encoder, self.layer_lr_decay(), n_layers, layer),
+            )
+        }));
+        lrs
+    }
+
+    /// Parameter averaging during training.
+    fn averaging(&self) -> Averaging;
+
+    /// The global norm to which gradients are clipped.
+    fn max_grad_norm(&self) -> Option<f64>;
+
     fn mixed_precision(&self) -> bool;
 
+    /// The optimizer that is used for training.
+    fn optimizer(&self) -> OptimizerSelection;
+
     fn weight_decay(&self) -> f64;
 }
 
@@ -67,3 +202,85 @@ pub trait SyntaxDotOption {
 
     fn parse(matches: &ArgMatches) -> Result<Self::Value>;
 }
+
+#[cfg(test)]
+mod tests {
+    use syntaxdot::config::{Config, PretrainConfig, TomlRead};
+    use syntaxdot::encoders::Encoders;
+    use syntaxdot::model::bert::BertModel;
+    use syntaxdot_tch_ext::RootExt;
+    use syntaxdot_transformers::models::bert::BertConfig;
+    use tch::nn::VarStore;
+    use tch::Device;
+
+    use super::{layer_lr, ParameterGroup, SyntaxDotTrainApp};
+    use crate::subcommands::FinetuneApp;
+
+    const CONFIG: &str = r#"
+[input]
+tokenizer = { bert = { vocab = "vocab.txt" } }
+
+[labeler]
+labels = "syntaxdot.labels"
+encoders = []
+
+[model]
+parameters = "epoch-1"
+pooler = "discard"
+position_embeddings = "model"
+pretrain_config = "bert_config.json"
+pretrain_type = "bert"
+treebanks = ["alpino", "lassy-small"]
+"#;
+
+    #[test]
+    fn treebank_embeddings_are_in_classifier_group() {
+        let config = Config::from_toml_read(CONFIG.as_bytes()).unwrap();
+        let pretrain_config = PretrainConfig::Bert(BertConfig {
+            hidden_size: 8,
+            intermediate_size: 16,
+            max_position_embeddings: 16,
+            num_attention_heads: 2,
+            num_hidden_layers: 1,
+            vocab_size: 16,
+            ..BertConfig::default()
+        });
+        let parameter_group_fun = FinetuneApp::build_parameter_group_fun();
+
+        // Construction fails if a variable is not in a parameter group.
+        let vs = VarStore::new(Device::Cpu);
+        BertModel::new(
+            vs.root_ext(parameter_group_fun),
+            &pretrain_config,
+            None,
+            0,
+            &Encoders::from(&config.labeler.encoders),
+            &config.labeler.encoders,
+            config.model.pooler,
+            config.model.pooling_position,
+            &config.model.regularization,
+            config.model.position_embeddings.clone(),
+            config.model.task_weighting,
+            config.model.treebanks.len(),
+        )
+        .unwrap();
+
+        assert!(vs
+            .variables()
+            .keys()
+            .any(|name| name.starts_with("treebank_embeddings")));
+        assert_eq!(
+            parameter_group_fun("treebank_embeddings.embeddings"),
+            ParameterGroup::Classifier as usize
+        );
+    }
+
+    #[test]
+    fn layer_lr_decays_from_top_layer() {
+        // Embeddings and three transformer layers.
+        assert_eq!(layer_lr(1e-4, 0.5, 4, 3), 1e-4);
+        assert_eq!(layer_lr(1e-4, 0.5, 4, 2), 0.5e-4);
+        assert_eq!(layer_lr(1e-4, 0.5, 4, 0), 0.125e-4);
+        assert_eq!(layer_lr(1e-4, 1.0, 4, 0), 1e-4);
+    }
+}
@@ -1,7 +1,8 @@
 use std::io::BufRead;
 use std::os::raw::c_int;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 pub fn count_sentences(mut buf_read: impl BufRead) -> Result<usize> {
     let mut n_sents = 0;
@@ -23,6 +24,65 @@ pub fn count_sentences(mut buf_read: impl BufRead) -> Result<usize> {
     Ok(n_sents)
 }
 
+/// Parse a duration such as `90m` or `12h`.
+///
+/// The suffix gives the unit of the duration: `s` (seconds), `m`
+/// (minutes), `h` (hours) or `d` (days).
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    let unit_idx = duration
+        .char_indices()
+        .last()
+        .map(|(idx, _)| idx)
+        .unwrap_or(0);
+    let (amount, unit) = duration.split_at(unit_idx);
+
+    let unit_secs = match unit {
+        "s" => 1.,
+        "m" => 60.,
+        "h" => 3600.,
+        "d" => 86400.,
+        _ => bail!("Duration should end with s, m, h, or d, was: {}", duration),
+    };
+
+    let amount: f64 = amount
+        .parse()
+        .context(format!("Cannot parse duration: {}", duration))?;
+    if !amount.is_finite() || amount < 0. {
+        bail!("Duration must be a non-negative number, was: {}", duration);
+    }
+
+    // Duration::from_secs_f64 panics when the duration does not fit.
+    let secs = amount * unit_secs;
+    if !secs.is_finite() || secs >= u64::MAX as f64 {
+        bail!("Duration is too long: {}", duration);
+    }
+
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Training duration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrainDuration {
+    Epochs(usize),
+    Steps(usize),
+}
+
+impl TrainDuration {
+    pub fn as_steps(&self, steps_per_epoch: impl FnOnce() -> Result<usize>) -> Result<usize> {
+        use TrainDuration::*;
+
+        match *self {
+            Epochs(epochs) => {
+                let total_steps = epochs * steps_per_epoch()?;
+                log::info!("total steps: {}", total_steps);
+                Ok(total_steps)
+            }
+            Steps(steps) => Ok(steps),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[no_mangle]
 extern "C" fn mkl_serv_intel_cpu_true() -> c_int {
@@ -46,3 +106,30 @@ where
         f()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_duration;
+
+    #[test]
+    fn parse_duration_uses_unit_suffix() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("90m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172800));
+    }
+
+    #[test]
+    fn parse_duration_rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("12").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1h").is_err());
+        assert!(parse_duration("infh").is_err());
+        assert!(parse_duration("NaNs").is_err());
+        assert!(parse_duration("1e20d").is_err());
+        assert!(parse_duration("1e308d").is_err());
+    }
+}
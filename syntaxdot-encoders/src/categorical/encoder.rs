@@ -111,6 +111,18 @@ where
             .collect();
         Ok(categorical_encoding)
     }
+
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        let encoding = self.inner.encode_partial(sentence)?;
+        let categorical_encoding = encoding
+            .into_iter()
+            .map(|e| e.map(|e| self.numberer.number(e).unwrap_or(0)))
+            .collect();
+        Ok(categorical_encoding)
+    }
 }
 
 impl<D, M> SentenceDecoder for CategoricalEncoder<D, D::Encoding, M>
@@ -0,0 +1,176 @@
+//! Sentence comment encoder.
+
+use std::convert::Infallible;
+
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use udgraph::graph::{Comment, Sentence};
+
+use super::{EncodingProb, SentenceDecoder, SentenceEncoder};
+
+/// Comment encoding error.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum EncodeError {
+    /// The sentence does not have a comment with the attribute.
+    #[error("sentence without a '{attr:?}' comment")]
+    MissingLabel { attr: String },
+}
+
+/// Encode sentence labels stored in comments.
+///
+/// This encoder encodes the value of a sentence comment of the form
+/// `# attr = value` as a sentence label. In contrast to other encoders,
+/// the encoding of a sentence consists of a single label.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CommentEncoder {
+    attr: String,
+}
+
+impl CommentEncoder {
+    /// Construct a new encoder for the comment with the given attribute.
+    pub fn new(attr: impl Into<String>) -> Self {
+        CommentEncoder { attr: attr.into() }
+    }
+}
+
+impl SentenceDecoder for CommentEncoder {
+    type Encoding = String;
+
+    type Error = Infallible;
+
+    fn decode<S>(&self, labels: &[S], sentence: &mut Sentence) -> Result<(), Self::Error>
+    where
+        S: AsRef<[EncodingProb<Self::Encoding>]>,
+    {
+        assert_eq!(labels.len(), 1, "Expected a single sentence label");
+
+        let label = match labels[0].as_ref().get(0) {
+            Some(label) => label.encoding().clone(),
+            None => return Ok(()),
+        };
+
+        // Replace the comment if it is present, otherwise add it.
+        let mut comments = sentence.comments().to_owned();
+        match comments
+            .iter_mut()
+            .find(|comment| matches!(comment, Comment::AttrVal { attr, .. } if attr == &self.attr))
+        {
+            Some(Comment::AttrVal { val, .. }) => *val = label,
+            _ => comments.push(Comment::AttrVal {
+                attr: self.attr.clone(),
+                val: label,
+            }),
+        }
+        sentence.set_comments(comments);
+
+        Ok(())
+    }
+}
+
+impl SentenceEncoder for CommentEncoder {
+    type Encoding = String;
+
+    type Error = EncodeError;
+
+    fn encode(&self, sentence: &Sentence) -> Result<Vec<Self::Encoding>, Self::Error> {
+        sentence
+            .comments()
+            .iter()
+            .find_map(|comment| match comment {
+                Comment::AttrVal { attr, val } if attr == &self.attr => Some(vec![val.clone()]),
+                _ => None,
+            })
+            .ok_or_else(|| EncodeError::MissingLabel {
+                attr: self.attr.clone(),
+            })
+    }
+
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        let label = sentence
+            .comments()
+            .iter()
+            .find_map(|comment| match comment {
+                Comment::AttrVal { attr, val } if attr == &self.attr => Some(val.clone()),
+                _ => None,
+            });
+
+        Ok(vec![label])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use conllu::io::Reader;
+    use udgraph::graph::{Comment, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::{CommentEncoder, EncodeError};
+    use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
+
+    static NON_PROJECTIVE_DATA: &str = "testdata/lassy-small-dev.conllu";
+
+    #[test]
+    fn comment_encoder_roundtrip() {
+        let f = File::open(NON_PROJECTIVE_DATA).unwrap();
+        let reader = Reader::new(BufReader::new(f));
+
+        let encoder = CommentEncoder::new("source");
+
+        for sentence in reader {
+            let sentence = sentence.unwrap();
+
+            let encoding = encoder.encode(&sentence).unwrap();
+            assert_eq!(encoding.len(), 1);
+
+            let mut test_sentence = sentence.clone();
+            encoder
+                .decode(
+                    &[[EncodingProb::new(encoding[0].clone(), 1.)]],
+                    &mut test_sentence,
+                )
+                .unwrap();
+
+            assert_eq!(sentence, test_sentence);
+        }
+    }
+
+    #[test]
+    fn decoder_adds_missing_comment() {
+        let mut sent = Sentence::new();
+        sent.push(TokenBuilder::new("Hallo").into());
+
+        CommentEncoder::new("label")
+            .decode(
+                &[[EncodingProb::new("greeting".to_string(), 1.)]],
+                &mut sent,
+            )
+            .unwrap();
+
+        assert_eq!(
+            sent.comments(),
+            &[Comment::AttrVal {
+                attr: "label".to_string(),
+                val: "greeting".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn encoding_fails_with_missing_comment() {
+        let mut sent = Sentence::new();
+        sent.push(TokenBuilder::new("Hallo").into());
+
+        assert_eq!(
+            CommentEncoder::new("label").encode(&sent),
+            Err(EncodeError::MissingLabel {
+                attr: "label".to_string()
+            })
+        );
+    }
+}
@@ -22,6 +22,22 @@ pub struct DependencyEncoding {
     pub relations: Vec<usize>,
 }
 
+/// Partial dependency encoding.
+///
+/// This encoding is used for sentences in which some tokens do not
+/// have a head or dependency relation.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PartialDependencyEncoding {
+    /// The head of each (non-ROOT) token, if any.
+    pub heads: Vec<Option<usize>>,
+
+    /// The dependency relation of each (non-ROOT) token, if any.
+    ///
+    /// The relation is `None` when the token does not have a head or
+    /// when the relation is unknown to the encoder.
+    pub relations: Vec<Option<usize>>,
+}
+
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum EncodeError {
     /// The token does not have a head.
@@ -29,6 +45,13 @@ pub enum EncodeError {
 
     /// The token does not have a dependency relation.
     MissingRelation { token: usize, sent: Vec<String> },
+
+    /// The dependency relation of the token is not known.
+    UnknownRelation {
+        token: usize,
+        relation: String,
+        sent: Vec<String>,
+    },
 }
 
 impl EncodeError {
@@ -54,6 +77,18 @@ impl EncodeError {
         }
     }
 
+    /// Construct `EncodeError::UnknownRelation` from a CoNLL-X graph.
+    ///
+    /// Construct an error. `token` is the node index for which the
+    /// error applies in `sentence`.
+    pub fn unknown_relation(token: usize, relation: &str, sentence: &Sentence) -> Self {
+        Self::UnknownRelation {
+            sent: Self::sentence_to_forms(sentence),
+            token: token - 1,
+            relation: relation.to_string(),
+        }
+    }
+
     fn format_bracketed(bracket_idx: usize, tokens: &[String]) -> String {
         let mut tokens = tokens.to_owned();
         tokens.insert(bracket_idx + 1, "]".to_string());
@@ -87,6 +122,16 @@ impl fmt::Display for EncodeError {
                 "Token does not have a dependency relation:\n\n{}\n",
                 Self::format_bracketed(*token, sent),
             ),
+            UnknownRelation {
+                token,
+                relation,
+                sent,
+            } => write!(
+                f,
+                "Token has an unknown dependency relation ({}):\n\n{}\n",
+                relation,
+                Self::format_bracketed(*token, sent),
+            ),
         }
     }
 }
@@ -126,14 +171,38 @@ where
                 .ok_or_else(|| EncodeError::missing_relation(token_idx, sentence))?;
             relations.push(
                 self.relations
-                    .number(relation.to_string())
-                    .expect("Unknown dependency relation"),
+                    .number(relation.clone())
+                    .ok_or_else(|| EncodeError::unknown_relation(token_idx, &relation, sentence))?,
             );
         }
 
         Ok(DependencyEncoding { heads, relations })
     }
 
+    /// Encode a sentence that may lack some annotations.
+    ///
+    /// In contrast to `encode`, this method does not fail on tokens
+    /// without a head or a (known) dependency relation. Instead, the
+    /// head and/or relation of such tokens is `None` in the encoding.
+    pub fn encode_partial(&self, sentence: &Sentence) -> PartialDependencyEncoding {
+        let dep_graph = sentence.dep_graph();
+
+        let mut heads = Vec::with_capacity(sentence.len());
+        let mut relations = Vec::with_capacity(sentence.len());
+
+        for token_idx in 1..sentence.len() {
+            let triple = dep_graph.head(token_idx);
+            heads.push(triple.as_ref().map(DepTriple::head));
+            relations.push(
+                triple
+                    .and_then(|triple| triple.relation().map(ToString::to_string))
+                    .and_then(|relation| self.relations.number(relation)),
+            );
+        }
+
+        PartialDependencyEncoding { heads, relations }
+    }
+
     /// Decode a dependency graph from a score matrix.
     ///
     /// The following arguments must be provided:
@@ -261,8 +330,14 @@ mod tests {
     use udgraph::graph::{DepTriple, Sentence};
     use udgraph::token::Token;
 
-    use crate::dependency::{DependencyEncoding, EncodeError, MutableDependencyEncoder};
     use ndarray::Array2;
+    use numberer::Numberer;
+
+    use crate::categorical::{ImmutableNumberer, Number};
+    use crate::dependency::{
+        DependencyEncoder, DependencyEncoding, EncodeError, MutableDependencyEncoder,
+        PartialDependencyEncoding,
+    };
 
     static NON_PROJECTIVE_DATA: &str = "testdata/lassy-small-dev.conllu";
 
@@ -313,6 +388,58 @@ mod tests {
         ));
     }
 
+    #[test]
+    pub fn encoding_fails_with_unknown_relation() {
+        let mut sent: Sentence = vec![Token::new("Ze"), Token::new("slaapt")]
+            .into_iter()
+            .collect();
+
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+
+        let mut relations = Numberer::new(0);
+        relations.add("root".to_string());
+        let encoder = DependencyEncoder {
+            relations: ImmutableNumberer::new(relations),
+        };
+
+        assert!(matches!(
+            encoder.encode(&sent),
+            Err(EncodeError::UnknownRelation { token: 0, .. })
+        ));
+    }
+
+    #[test]
+    pub fn partial_encoding_skips_missing_annotations() {
+        let mut sent: Sentence = vec![
+            Token::new("Ze"),
+            Token::new("koopt"),
+            Token::new("een"),
+            Token::new("auto"),
+        ]
+        .into_iter()
+        .collect();
+
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::<&str>::new(2, None, 1));
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(4, Some("det"), 3));
+
+        let encoder = MutableDependencyEncoder::new();
+
+        assert_eq!(
+            encoder.encode_partial(&sent),
+            PartialDependencyEncoding {
+                heads: vec![Some(2), Some(0), Some(4), None],
+                relations: vec![None, Some(0), Some(1), None],
+            }
+        );
+    }
+
     #[test]
     pub fn encoder_encodes_correctly() {
         let mut sent: Sentence = vec![
@@ -3,6 +3,7 @@
 mod encoder;
 pub use encoder::{
     DependencyEncoding, EncodeError, ImmutableDependencyEncoder, MutableDependencyEncoder,
+    PartialDependencyEncoding,
 };
 
 #[doc(hidden)]
@@ -5,6 +5,9 @@ use serde_derive::{Deserialize, Serialize};
 mod error;
 pub use self::error::*;
 
+mod mst;
+pub(crate) use self::mst::*;
+
 mod post_processing;
 pub(crate) use self::post_processing::*;
 
@@ -14,6 +17,29 @@ pub use self::relative_position::*;
 mod relative_pos;
 pub use self::relative_pos::*;
 
+/// Strategy for decoding dependency trees from token labels.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodingStrategy {
+    /// Use the best decodable label of each token.
+    ///
+    /// The resulting graph is made into a tree by adding a root if
+    /// necessary, attaching orphans, and breaking cycles.
+    Heuristic,
+
+    /// Find the maximum spanning tree.
+    ///
+    /// The probabilities of all top-k labels of each token are used
+    /// as arc scores. This always results in a well-formed tree.
+    Mst,
+}
+
+impl Default for DecodingStrategy {
+    fn default() -> Self {
+        DecodingStrategy::Heuristic
+    }
+}
+
 /// Encoding of a dependency relation as a token label.
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct DependencyEncoding<H> {
@@ -49,7 +75,7 @@ mod tests {
     use conllu::io::Reader;
     use udgraph::graph::{Node, Sentence};
 
-    use super::{PosLayer, RelativePosEncoder, RelativePositionEncoder};
+    use super::{DecodingStrategy, PosLayer, RelativePosEncoder, RelativePositionEncoder};
     use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
 
     const NON_PROJECTIVE_DATA: &str = "testdata/lassy-small-dev.conllu";
@@ -109,4 +135,18 @@ mod tests {
         let encoder = RelativePositionEncoder::new(ROOT_RELATION);
         test_encoding(NON_PROJECTIVE_DATA, encoder);
     }
+
+    #[test]
+    fn relative_pos_position_mst() {
+        let encoder = RelativePosEncoder::new(PosLayer::XPos, ROOT_RELATION)
+            .with_decoding(DecodingStrategy::Mst);
+        test_encoding(NON_PROJECTIVE_DATA, encoder);
+    }
+
+    #[test]
+    fn relative_position_mst() {
+        let encoder =
+            RelativePositionEncoder::new(ROOT_RELATION).with_decoding(DecodingStrategy::Mst);
+        test_encoding(NON_PROJECTIVE_DATA, encoder);
+    }
 }
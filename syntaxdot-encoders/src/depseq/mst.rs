@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use ndarray::{Array2, ArrayView2};
+use ordered_float::OrderedFloat;
+use udgraph::graph::{DepTriple, Sentence};
+
+use super::DependencyEncoding;
+use crate::dependency::mst::chu_liu_edmonds;
+use crate::EncodingProb;
+
+/// Probability mass of arcs that are not predicted by any encoding.
+///
+/// The score matrix is in log space, so arcs without probability
+/// mass get a small probability to keep the scores finite.
+const MIN_ARC_PROB: f32 = 1e-8;
+
+/// Decode a dependency tree using the maximum spanning tree.
+///
+/// This function constructs a weighted score matrix from the
+/// encodings of each token. The score of an arc is the (log of the)
+/// summed probability of all encodings of the dependent that decode
+/// to that arc. The highest-scoring tree is then found using the
+/// Chu-Liu-Edmonds algorithm. Each token gets the relation of the
+/// most probable encoding that decodes to its attachment.
+///
+/// In contrast to the heuristic post-processing, this always results
+/// in a well-formed tree with a single token attached to the root.
+pub fn decode_mst<S, H, F>(
+    labels: &[S],
+    sentence: &mut Sentence,
+    decode_fun: F,
+    root_relation: &str,
+) where
+    S: AsRef<[EncodingProb<DependencyEncoding<H>>]>,
+    F: Fn(usize, &DependencyEncoding<H>) -> Option<DepTriple<String>>,
+{
+    let token_indices: Vec<_> = (0..sentence.len())
+        .filter(|&idx| sentence[idx].is_token())
+        .collect();
+
+    // Arc probability masses, indexed by (head, dependent).
+    let mut arc_probs = Array2::<f32>::zeros((sentence.len(), sentence.len()));
+    let mut best_relations: HashMap<(usize, usize), (f32, String)> = HashMap::new();
+
+    for (&idx, encodings) in token_indices.iter().zip(labels) {
+        for encoding in encodings.as_ref() {
+            let triple = match decode_fun(idx, encoding.encoding()) {
+                Some(triple) => triple,
+                None => continue,
+            };
+
+            arc_probs[(triple.head(), triple.dependent())] += encoding.prob();
+
+            let relation = best_relations
+                .entry((triple.head(), triple.dependent()))
+                .or_insert_with(|| (encoding.prob(), encoding.encoding().label().to_owned()));
+            if encoding.prob() > relation.0 {
+                *relation = (encoding.prob(), encoding.encoding().label().to_owned());
+            }
+        }
+    }
+
+    let scores = arc_probs.mapv(|prob| prob.max(MIN_ARC_PROB).ln());
+    let heads = single_root_mst(scores.view());
+
+    for (idx, encodings) in token_indices.into_iter().zip(labels) {
+        let head = heads[idx].expect("Non-root token without a head");
+
+        let relation = match best_relations.remove(&(head, idx)) {
+            Some((_, relation)) => Some(relation),
+            // The arc was not predicted by any encoding. Use the root
+            // relation for root attachments and the relation of the
+            // most probable encoding otherwise.
+            None if head == 0 => Some(root_relation.to_owned()),
+            None => encodings
+                .as_ref()
+                .first()
+                .map(|encoding| encoding.encoding().label().to_owned()),
+        };
+
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(head, relation, idx));
+    }
+}
+
+/// Find the maximum spanning tree with a single root dependent.
+///
+/// If the maximum spanning tree attaches more than one token to the
+/// root, the maximum spanning tree is computed for every token as the
+/// only root dependent. The highest-scoring of these trees is returned.
+fn single_root_mst(scores: ArrayView2<f32>) -> Vec<Option<usize>> {
+    let heads = chu_liu_edmonds(scores, 0);
+    if heads.iter().filter(|&&head| head == Some(0)).count() <= 1 {
+        return heads;
+    }
+
+    (1..scores.nrows())
+        .map(|root_dependent| {
+            let mut constrained = scores.to_owned();
+            for dependent in 1..scores.ncols() {
+                if dependent != root_dependent {
+                    constrained[(0, dependent)] = -f32::INFINITY;
+                }
+            }
+            chu_liu_edmonds(constrained.view(), 0)
+        })
+        .max_by_key(|heads| OrderedFloat(tree_score(scores, heads)))
+        .expect("Sentence with multiple root dependents has no tokens")
+}
+
+/// Compute the score of a tree.
+fn tree_score(scores: ArrayView2<f32>, heads: &[Option<usize>]) -> f32 {
+    heads
+        .iter()
+        .enumerate()
+        .filter_map(|(dependent, head)| head.map(|head| scores[(head, dependent)]))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{DepTriple, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::decode_mst;
+    use crate::depseq::{DependencyEncoding, RelativePosition, RelativePositionEncoder};
+    use crate::EncodingProb;
+
+    const ROOT_RELATION: &str = "root";
+
+    fn test_sentence() -> Sentence {
+        let mut sent = Sentence::new();
+        sent.push(TokenBuilder::new("Ze").into());
+        sent.push(TokenBuilder::new("koopt").into());
+        sent.push(TokenBuilder::new("auto's").into());
+        sent
+    }
+
+    fn encoding(
+        label: &str,
+        head: isize,
+        prob: f32,
+    ) -> EncodingProb<DependencyEncoding<RelativePosition>> {
+        EncodingProb::new(
+            DependencyEncoding::new(RelativePosition::new(head), label),
+            prob,
+        )
+    }
+
+    #[test]
+    fn mst_breaks_cycle_using_probability_mass() {
+        let mut sent = test_sentence();
+
+        // The best encodings of 'koopt' and 'auto's' form a cycle.
+        // The second-best encoding of 'koopt' attaches it to the root.
+        let labels = vec![
+            vec![encoding("nsubj", 1, 0.9), encoding("obj", 2, 0.1)],
+            vec![
+                encoding("ccomp", 1, 0.4),
+                encoding(ROOT_RELATION, -2, 0.35),
+                encoding("xcomp", 1, 0.25),
+            ],
+            vec![encoding("acl", -1, 0.7), encoding("obj", -1, 0.3)],
+        ];
+
+        let sent_len = sent.len();
+        decode_mst(
+            &labels,
+            &mut sent,
+            |idx, encoding| RelativePositionEncoder::decode_idx(idx, sent_len, encoding).ok(),
+            ROOT_RELATION,
+        );
+
+        assert_eq!(
+            sent.dep_graph().head(1),
+            Some(DepTriple::new(2, Some("nsubj"), 1))
+        );
+        assert_eq!(
+            sent.dep_graph().head(2),
+            Some(DepTriple::new(0, Some(ROOT_RELATION), 2))
+        );
+        assert_eq!(
+            sent.dep_graph().head(3),
+            Some(DepTriple::new(2, Some("acl"), 3))
+        );
+    }
+
+    #[test]
+    fn mst_attaches_unpredicted_root() {
+        let mut sent = Sentence::new();
+        sent.push(TokenBuilder::new("a").into());
+
+        let labels = vec![vec![encoding("dep", -2, 1.0)]];
+
+        let sent_len = sent.len();
+        decode_mst(
+            &labels,
+            &mut sent,
+            |idx, encoding| RelativePositionEncoder::decode_idx(idx, sent_len, encoding).ok(),
+            ROOT_RELATION,
+        );
+
+        assert_eq!(
+            sent.dep_graph().head(1),
+            Some(DepTriple::new(0, Some(ROOT_RELATION), 1))
+        );
+    }
+
+    #[test]
+    fn mst_attaches_single_token_to_root() {
+        let mut sent = Sentence::new();
+        sent.push(TokenBuilder::new("a").into());
+        sent.push(TokenBuilder::new("b").into());
+
+        // The most probable encodings attach both tokens to the root.
+        let labels = vec![
+            vec![encoding(ROOT_RELATION, -1, 0.7), encoding("dep", 1, 0.3)],
+            vec![encoding(ROOT_RELATION, -2, 0.6), encoding("obj", -1, 0.4)],
+        ];
+
+        let sent_len = sent.len();
+        decode_mst(
+            &labels,
+            &mut sent,
+            |idx, encoding| RelativePositionEncoder::decode_idx(idx, sent_len, encoding).ok(),
+            ROOT_RELATION,
+        );
+
+        assert_eq!(
+            sent.dep_graph().head(1),
+            Some(DepTriple::new(0, Some(ROOT_RELATION), 1))
+        );
+        assert_eq!(
+            sent.dep_graph().head(2),
+            Some(DepTriple::new(1, Some("obj"), 2))
+        );
+    }
+}
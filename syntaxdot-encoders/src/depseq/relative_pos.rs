@@ -15,7 +15,8 @@ use udgraph::graph::{DepTriple, Node, Sentence};
 use udgraph::token::Token;
 
 use super::{
-    attach_orphans, break_cycles, find_or_create_root, DecodeError, DependencyEncoding, EncodeError,
+    attach_orphans, break_cycles, decode_mst, find_or_create_root, DecodeError, DecodingStrategy,
+    DependencyEncoding, EncodeError,
 };
 use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
 
@@ -78,6 +79,9 @@ impl ToString for DependencyEncoding<RelativePos> {
 pub struct RelativePosEncoder {
     pos_layer: PosLayer,
     root_relation: String,
+
+    #[serde(default)]
+    decoding: DecodingStrategy,
 }
 
 impl RelativePosEncoder {
@@ -85,8 +89,15 @@ impl RelativePosEncoder {
         RelativePosEncoder {
             pos_layer,
             root_relation: root_relation.into(),
+            decoding: DecodingStrategy::default(),
         }
     }
+
+    /// Use the given strategy to decode dependency trees.
+    pub fn with_decoding(mut self, decoding: DecodingStrategy) -> Self {
+        self.decoding = decoding;
+        self
+    }
 }
 
 impl RelativePosEncoder {
@@ -257,6 +268,16 @@ impl SentenceDecoder for RelativePosEncoder {
     {
         let pos_table = self.pos_position_table(sentence);
 
+        if let DecodingStrategy::Mst = self.decoding {
+            decode_mst(
+                labels,
+                sentence,
+                |idx, encoding| Self::decode_idx(&pos_table, idx, encoding).ok(),
+                &self.root_relation,
+            );
+            return Ok(());
+        }
+
         // Collect to avoid immutable + mutable reference.
         #[allow(clippy::needless_collect)]
         let token_indices: Vec<_> = (0..sentence.len())
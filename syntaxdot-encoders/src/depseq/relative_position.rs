@@ -4,7 +4,8 @@ use serde_derive::{Deserialize, Serialize};
 use udgraph::graph::{DepTriple, Sentence};
 
 use super::{
-    attach_orphans, break_cycles, find_or_create_root, DecodeError, DependencyEncoding, EncodeError,
+    attach_orphans, break_cycles, decode_mst, find_or_create_root, DecodeError, DecodingStrategy,
+    DependencyEncoding, EncodeError,
 };
 use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
 
@@ -14,6 +15,12 @@ use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct RelativePosition(isize);
 
+impl RelativePosition {
+    pub fn new(position: isize) -> Self {
+        RelativePosition(position)
+    }
+}
+
 impl ToString for DependencyEncoding<RelativePosition> {
     fn to_string(&self) -> String {
         format!("{}/{}", self.label, self.head.0)
@@ -28,18 +35,28 @@ impl ToString for DependencyEncoding<RelativePosition> {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct RelativePositionEncoder {
     root_relation: String,
+
+    #[serde(default)]
+    decoding: DecodingStrategy,
 }
 
 impl RelativePositionEncoder {
     pub fn new(root_relation: impl Into<String>) -> Self {
         RelativePositionEncoder {
             root_relation: root_relation.into(),
+            decoding: DecodingStrategy::default(),
         }
     }
+
+    /// Use the given strategy to decode dependency trees.
+    pub fn with_decoding(mut self, decoding: DecodingStrategy) -> Self {
+        self.decoding = decoding;
+        self
+    }
 }
 
 impl RelativePositionEncoder {
-    fn decode_idx(
+    pub(crate) fn decode_idx(
         idx: usize,
         sentence_len: usize,
         encoding: &DependencyEncoding<RelativePosition>,
@@ -97,6 +114,17 @@ impl SentenceDecoder for RelativePositionEncoder {
     where
         S: AsRef<[EncodingProb<Self::Encoding>]>,
     {
+        if let DecodingStrategy::Mst = self.decoding {
+            let sentence_len = sentence.len();
+            decode_mst(
+                labels,
+                sentence,
+                |idx, encoding| Self::decode_idx(idx, sentence_len, encoding).ok(),
+                &self.root_relation,
+            );
+            return Ok(());
+        }
+
         // Collect to avoid immutable + mutable reference.
         #[allow(clippy::needless_collect)]
         let token_indices: Vec<_> = (0..sentence.len())
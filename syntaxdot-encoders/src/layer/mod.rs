@@ -173,6 +173,17 @@ impl SentenceEncoder for LayerEncoder {
 
         Ok(encoding)
     }
+
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        Ok(sentence
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.value(&self.layer))
+            .collect())
+    }
 }
 
 #[cfg(test)]
@@ -180,9 +191,11 @@ mod tests {
     use std::convert::TryFrom;
 
     use conllu::display::{ConlluFeatures, ConlluMisc};
+    use udgraph::graph::Sentence;
     use udgraph::token::{Token, TokenBuilder};
 
-    use crate::layer::{Layer, LayerValue};
+    use crate::layer::{Layer, LayerEncoder, LayerValue};
+    use crate::SentenceEncoder;
 
     #[test]
     fn layer() {
@@ -260,4 +273,21 @@ mod tests {
         );
         assert_eq!(token.value(&Layer::misc("x".to_owned(), None)), None);
     }
+
+    #[test]
+    fn partial_encoding_skips_missing_values() {
+        let tokens: Vec<Token> = vec![
+            TokenBuilder::new("Die").upos("DET").into(),
+            TokenBuilder::new("Katze").into(),
+            TokenBuilder::new("schläft").upos("VERB").into(),
+        ];
+        let sentence: Sentence = tokens.into_iter().collect();
+
+        let encoder = LayerEncoder::new(Layer::UPos);
+        assert!(encoder.encode(&sentence).is_err());
+        assert_eq!(
+            encoder.encode_partial(&sentence).unwrap(),
+            vec![Some("DET".to_string()), None, Some("VERB".to_string())]
+        );
+    }
 }
@@ -6,6 +6,8 @@ use udgraph::graph::Sentence;
 
 pub mod categorical;
 
+pub mod comment;
+
 pub mod dependency;
 
 pub mod depseq;
@@ -79,4 +81,17 @@ pub trait SentenceEncoder {
 
     /// Encode the given sentence.
     fn encode(&self, sentence: &Sentence) -> Result<Vec<Self::Encoding>, Self::Error>;
+
+    /// Encode the given sentence, allowing for missing annotations.
+    ///
+    /// Returns `None` for tokens that do not have the annotation that
+    /// is encoded. The default implementation does not support missing
+    /// annotations and fails in the same manner as `encode`.
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        self.encode(sentence)
+            .map(|encoding| encoding.into_iter().map(Some).collect())
+    }
 }
@@ -3,7 +3,7 @@ use std::rc::Rc;
 
 use itertools::Itertools;
 use tch::nn::{Init, Path, VarStore};
-use tch::{TchError, Tensor};
+use tch::{Device, TchError, Tensor};
 
 /// Trait that provides the root of a variable store.
 pub trait RootExt {
@@ -37,6 +37,11 @@ pub struct PathExt<'a> {
 }
 
 impl<'a> PathExt<'a> {
+    /// Get the device of the variables.
+    pub fn device(&self) -> Device {
+        self.inner.device()
+    }
+
     /// Create a tensor variable initialized with ones.
     pub fn ones(&self, name: &str, dims: &[i64]) -> Tensor {
         let group = self.name_group(name);
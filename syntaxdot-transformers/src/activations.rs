@@ -3,14 +3,14 @@
 use std::convert::TryFrom;
 use std::f64;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tch::Tensor;
 
 use crate::module::FallibleModule;
 use crate::TransformerError;
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
-#[serde(try_from = "String")]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum Activation {
     /// GELU activation function.
     ///
@@ -55,6 +55,17 @@ impl TryFrom<String> for Activation {
     }
 }
 
+impl From<Activation> for String {
+    fn from(activation: Activation) -> Self {
+        match activation {
+            Activation::Gelu => "gelu",
+            Activation::GeluNew => "gelu_new",
+            Activation::Relu => "relu",
+        }
+        .to_string()
+    }
+}
+
 impl FallibleModule for Activation {
     type Error = TransformerError;
 
@@ -15,6 +15,20 @@ pub enum TransformerError {
         num_attention_heads: i64,
     },
 
+    /// The number of class weights differs from the number of classes.
+    #[error("got {n_class_weights:?} class weights, but there are {n_classes:?} classes")]
+    IncorrectNumberOfClassWeights {
+        /// The number of class weights.
+        n_class_weights: usize,
+
+        /// The number of classes.
+        n_classes: i64,
+    },
+
+    /// No layers are selected for scalar weighting.
+    #[error("at least one layer should be used in scalar weighting")]
+    NoWeightedLayers,
+
     /// Torch error.
     #[error(transparent)]
     Tch(#[from] TchError),
@@ -22,4 +36,14 @@ pub enum TransformerError {
     /// The activation function is unknown.
     #[error("unknown activation function: {activation:?}")]
     UnknownActivationFunction { activation: String },
+
+    /// A layer that is selected for scalar weighting does not exist.
+    #[error("layer {layer:?} does not exist, the number of layers is {n_layers:?}")]
+    UnknownLayer {
+        /// The selected layer.
+        layer: usize,
+
+        /// The number of layers.
+        n_layers: i64,
+    },
 }
@@ -14,7 +14,8 @@ impl Reduce for Reduction {
     fn reduce(&self, t: &Tensor) -> Result<Tensor, Self::Error> {
         match self {
             Reduction::None => Ok(t.shallow_clone()),
-            Reduction::Mean => Ok(t.f_mean(t.kind())?),
+            // Avoid NaN when all targets are ignored.
+            Reduction::Mean => Ok(t.f_sum(t.kind())?.f_div_scalar(t.numel().max(1) as f64)?),
             Reduction::Sum => Ok(t.f_sum(t.kind())?),
             Reduction::Other(_) => unimplemented!(),
         }
@@ -23,6 +24,8 @@ impl Reduce for Reduction {
 
 /// Cross-entropy loss function.
 pub struct CrossEntropyLoss {
+    class_weights: Option<Tensor>,
+    focal_gamma: Option<f64>,
     ignore_index: i64,
     label_smoothing: Option<f64>,
     reduction: Reduction,
@@ -38,12 +41,34 @@ impl CrossEntropyLoss {
     /// be reduced/summarized.
     pub fn new(ignore_index: i64, label_smoothing: Option<f64>, reduction: Reduction) -> Self {
         CrossEntropyLoss {
+            class_weights: None,
+            focal_gamma: None,
             ignore_index,
             label_smoothing,
             reduction,
         }
     }
 
+    /// Weigh the loss of each target by the weight of its class.
+    ///
+    /// `class_weights` should have the shape `[n_classes]`. Mean reduction
+    /// computes the mean of the weighted losses.
+    pub fn with_class_weights(mut self, class_weights: Option<&Tensor>) -> Self {
+        self.class_weights = class_weights.map(Tensor::shallow_clone);
+        self
+    }
+
+    /// Use the focal loss (Lin et al., 2017).
+    ///
+    /// The loss of each target is scaled by *(1 - p)^γ*, where *p* is the
+    /// probability of the correct label. This focuses training on targets
+    /// that are not classified confidently. A `gamma` of zero gives the
+    /// cross-entropy loss.
+    pub fn with_focal_gamma(mut self, gamma: Option<f64>) -> Self {
+        self.focal_gamma = gamma;
+        self
+    }
+
     /// Compute the cross-entropy loss.
     ///
     /// `logits` should be the unnormalized probablilities of shape
@@ -59,60 +84,111 @@ impl CrossEntropyLoss {
         targets: &Tensor,
         target_mask: Option<&Tensor>,
     ) -> Result<Tensor, TransformerError> {
-        let (_, n_classes) = logits.size2()?;
         let log_probs = logits.f_log_softmax(-1, logits.kind())?;
 
-        match self.label_smoothing {
-            Some(label_smoothing) => {
-                let token_mask = targets.f_ne(self.ignore_index)?;
-
-                // Do not attempt to use negative indices for the correct target.
-                let targets_non_negative =
-                    targets.f_where_scalarother(&targets.f_ne(self.ignore_index)?, 0)?;
-
-                // Set all labels to label_smoothing and the target to 1-label_smoothing.
-                let smoothed_targets = tch::no_grad(|| match target_mask {
-                    None => {
-                        Tensor::f_full_like(&log_probs, label_smoothing / (n_classes - 1) as f64)?
-                            .f_scatter_value(
-                                1,
-                                &targets_non_negative.f_unsqueeze(1)?,
-                                1. - label_smoothing,
-                            )
-                    }
-                    Some(target_mask) => {
-                        let batch_probs = label_smoothing
-                            / target_mask
-                                .f_sum_dim_intlist(&[-1], false, Kind::Float)?
-                                .f_sub_scalar(1)?;
-                        Tensor::f_zeros_like(&log_probs)?
-                            // Set label probabilities to batch smoothing probability.
-                            .f_add_(&batch_probs.f_unsqueeze(-1)?)?
-                            // Mask out padding.
-                            .f_mul(&target_mask.to_kind(Kind::Float))?
-                            // Assign probabilities to gold standard labels.
-                            .f_scatter_value(
-                                1,
-                                &targets_non_negative.f_unsqueeze(1)?,
-                                1. - label_smoothing,
-                            )
-                    }
-                })?;
-                let losses = (smoothed_targets.f_neg()?.f_mul(&log_probs)?).f_sum_dim_intlist(
-                    &[-1],
-                    false,
-                    log_probs.kind(),
-                )?;
-
-                Ok(self.reduction.reduce(&losses.masked_select(&token_mask))?)
-            }
-            None => Ok(log_probs.f_nll_loss::<&Tensor>(
+        // The mean reduction of nll_loss is NaN when all targets are ignored.
+        if self.label_smoothing.is_none()
+            && self.class_weights.is_none()
+            && self.focal_gamma.is_none()
+            && !matches!(self.reduction, Reduction::Mean)
+        {
+            return Ok(log_probs.f_nll_loss::<&Tensor>(
                 targets,
                 None,
                 self.reduction,
                 self.ignore_index,
-            )?),
+            )?);
+        }
+
+        let token_mask = targets.f_ne(self.ignore_index)?;
+
+        // Do not attempt to use negative indices for the correct target.
+        let targets_non_negative = targets.f_where_scalarother(&token_mask, 0)?;
+
+        let mut losses = match self.label_smoothing {
+            Some(label_smoothing) => self.smoothed_losses(
+                &log_probs,
+                &targets_non_negative,
+                target_mask,
+                label_smoothing,
+            )?,
+            None => log_probs
+                .f_gather(1, &targets_non_negative.f_unsqueeze(1)?, false)?
+                .f_squeeze_dim(1)?
+                .f_neg()?,
+        };
+
+        if let Some(gamma) = self.focal_gamma {
+            let target_probs = log_probs
+                .f_gather(1, &targets_non_negative.f_unsqueeze(1)?, false)?
+                .f_squeeze_dim(1)?
+                .f_exp()?;
+            losses = losses.f_mul(
+                &target_probs
+                    .f_neg()?
+                    .f_add_scalar(1.)?
+                    .f_pow_tensor_scalar(gamma)?,
+            )?;
+        }
+
+        if let Some(class_weights) = &self.class_weights {
+            losses = losses.f_mul(
+                &class_weights
+                    .f_index_select(0, &targets_non_negative)?
+                    .f_to_kind(losses.kind())?,
+            )?;
         }
+
+        match self.reduction {
+            // Keep the shape of the targets, ignored targets have no loss.
+            Reduction::None => Ok(losses.f_mul(&token_mask.f_to_kind(losses.kind())?)?),
+            _ => Ok(self
+                .reduction
+                .reduce(&losses.f_masked_select(&token_mask)?)?),
+        }
+    }
+
+    /// Compute the losses against label-smoothed targets.
+    fn smoothed_losses(
+        &self,
+        log_probs: &Tensor,
+        targets_non_negative: &Tensor,
+        target_mask: Option<&Tensor>,
+        label_smoothing: f64,
+    ) -> Result<Tensor, TransformerError> {
+        let (_, n_classes) = log_probs.size2()?;
+
+        // Set all labels to label_smoothing and the target to 1-label_smoothing.
+        let smoothed_targets = tch::no_grad(|| match target_mask {
+            None => Tensor::f_full_like(log_probs, label_smoothing / (n_classes - 1) as f64)?
+                .f_scatter_value(
+                    1,
+                    &targets_non_negative.f_unsqueeze(1)?,
+                    1. - label_smoothing,
+                ),
+            Some(target_mask) => {
+                let batch_probs = label_smoothing
+                    / target_mask
+                        .f_sum_dim_intlist(&[-1], false, Kind::Float)?
+                        .f_sub_scalar(1)?;
+                Tensor::f_zeros_like(log_probs)?
+                    // Set label probabilities to batch smoothing probability.
+                    .f_add_(&batch_probs.f_unsqueeze(-1)?)?
+                    // Mask out padding.
+                    .f_mul(&target_mask.to_kind(Kind::Float))?
+                    // Assign probabilities to gold standard labels.
+                    .f_scatter_value(
+                        1,
+                        &targets_non_negative.f_unsqueeze(1)?,
+                        1. - label_smoothing,
+                    )
+            }
+        })?;
+
+        Ok(smoothed_targets
+            .f_neg()?
+            .f_mul(log_probs)?
+            .f_sum_dim_intlist(&[-1], false, log_probs.kind())?)
     }
 }
 
@@ -162,4 +238,71 @@ mod tests {
             .unwrap();
         assert_abs_diff_eq!(loss, array![0.632653].into_dyn(), epsilon = 1e-6);
     }
+
+    #[test]
+    fn cross_entropy_with_label_smoothing_keeps_ignored_targets() {
+        let logits =
+            Tensor::of_slice(&[-1., -1., 1., -1., -1., 1., -1., -1., -1., -1.]).view([2, 5]);
+        let targets = Tensor::of_slice(&[2i64, -1]);
+        let cross_entropy_loss = CrossEntropyLoss::new(-1, Some(0.1), Reduction::None);
+        let loss: ArrayD<f32> = (&cross_entropy_loss.forward(&logits, &targets, None).unwrap())
+            .try_into()
+            .unwrap();
+        assert_abs_diff_eq!(loss, array![0.632653, 0.].into_dyn(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn mean_loss_is_zero_when_all_targets_are_ignored() {
+        let logits = Tensor::of_slice(&[-1., -1., 1., -1., -1.]).view([1, 5]);
+        let targets = Tensor::of_slice(&[-1i64]);
+        let cross_entropy_loss = CrossEntropyLoss::new(-1, None, Reduction::Mean);
+        let loss = f32::from(cross_entropy_loss.forward(&logits, &targets, None).unwrap());
+
+        assert_abs_diff_eq!(loss, 0.);
+    }
+
+    #[test]
+    fn cross_entropy_with_class_weights() {
+        let logits =
+            Tensor::of_slice(&[-1., -1., 1., -1., -1., 1., -1., -1., -1., -1.]).view([2, 5]);
+        let targets = Tensor::of_slice(&[2i64, 0]);
+        let class_weights = Tensor::of_slice(&[0.5f32, 1., 2., 1., 1.]);
+        let cross_entropy_loss = CrossEntropyLoss::new(-1, None, Reduction::None)
+            .with_class_weights(Some(&class_weights));
+        let loss: ArrayD<f32> = (&cross_entropy_loss.forward(&logits, &targets, None).unwrap())
+            .try_into()
+            .unwrap();
+
+        assert_abs_diff_eq!(
+            loss,
+            array![2. * 0.432653, 0.5 * 0.432653].into_dyn(),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn focal_loss_downweighs_confident_predictions() {
+        let logits = Tensor::of_slice(&[-1., -1., 1., -1., -1.]).view([1, 5]);
+        let targets = Tensor::of_slice(&[2i64]).view([1]);
+        let cross_entropy_loss =
+            CrossEntropyLoss::new(-1, None, Reduction::None).with_focal_gamma(Some(2.));
+        let loss: ArrayD<f32> = (&cross_entropy_loss.forward(&logits, &targets, None).unwrap())
+            .try_into()
+            .unwrap();
+
+        // p = exp(-0.432653) = 0.648786, (1 - p)^2 = 0.123351
+        assert_abs_diff_eq!(loss, array![0.053368].into_dyn(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn focal_loss_ignores_ignore_index() {
+        let logits =
+            Tensor::of_slice(&[-1., -1., 1., -1., -1., 1., -1., -1., -1., -1.]).view([2, 5]);
+        let targets = Tensor::of_slice(&[2i64, -1]);
+        let cross_entropy_loss =
+            CrossEntropyLoss::new(-1, None, Reduction::Mean).with_focal_gamma(Some(0.));
+        let loss = f32::from(cross_entropy_loss.forward(&logits, &targets, None).unwrap());
+
+        assert_abs_diff_eq!(loss, 0.432653, epsilon = 1e-6);
+    }
 }
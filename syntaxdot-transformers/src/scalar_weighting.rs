@@ -4,8 +4,9 @@ use std::borrow::Borrow;
 
 use syntaxdot_tch_ext::PathExt;
 use tch::nn::{Init, Linear, Module};
-use tch::{Kind, Reduction, Tensor};
+use tch::{Device, Kind, Reduction, Tensor};
 
+use crate::activations::Activation;
 use crate::cow::CowTensor;
 use crate::layers::{Dropout, LayerNorm};
 use crate::loss::CrossEntropyLoss;
@@ -13,9 +14,10 @@ use crate::models::LayerOutput;
 use crate::module::{FallibleModule, FallibleModuleT};
 use crate::TransformerError;
 
-/// Non-linear ReLU layer with layer normalization and dropout.
+/// Non-linear layer with layer normalization and dropout.
 #[derive(Debug)]
 struct NonLinearWithLayerNorm {
+    activation: Activation,
     layer_norm: LayerNorm,
     linear: Linear,
     dropout: Dropout,
@@ -26,12 +28,14 @@ impl NonLinearWithLayerNorm {
         vs: impl Borrow<PathExt<'a>>,
         in_size: i64,
         out_size: i64,
+        activation: Activation,
         dropout: f64,
         layer_norm_eps: f64,
     ) -> Result<NonLinearWithLayerNorm, TransformerError> {
         let vs = vs.borrow();
 
         Ok(NonLinearWithLayerNorm {
+            activation,
             dropout: Dropout::new(dropout),
             layer_norm: LayerNorm::new(vs / "layer_norm", vec![out_size], layer_norm_eps, true),
             linear: Linear {
@@ -46,7 +50,7 @@ impl FallibleModuleT for NonLinearWithLayerNorm {
     type Error = TransformerError;
 
     fn forward_t(&self, input: &Tensor, train: bool) -> Result<Tensor, Self::Error> {
-        let mut hidden = self.linear.forward(input).relu();
+        let mut hidden = self.activation.forward(&self.linear.forward(input))?;
         hidden = self.layer_norm.forward(&hidden)?;
         self.dropout.forward_t(&hidden, train)
     }
@@ -148,10 +152,13 @@ impl ScalarWeight {
 /// See Peters et al., 2018 and Kondratyuk & Straka, 2019.
 #[derive(Debug)]
 pub struct ScalarWeightClassifier {
+    class_weights: Option<Tensor>,
     dropout: Dropout,
+    focal_gamma: Option<f64>,
+    layers: Option<Vec<usize>>,
     scalar_weight: ScalarWeight,
     linear: Linear,
-    non_linear: NonLinearWithLayerNorm,
+    non_linear: Vec<NonLinearWithLayerNorm>,
 }
 
 impl ScalarWeightClassifier {
@@ -174,50 +181,178 @@ impl ScalarWeightClassifier {
             "The hidden size should be larger than 0",
         );
 
+        if let Some(layers) = &config.layers {
+            if layers.is_empty() {
+                return Err(TransformerError::NoWeightedLayers);
+            }
+
+            if let Some(&layer) = layers
+                .iter()
+                .find(|&&layer| layer as i64 >= config.n_layers)
+            {
+                return Err(TransformerError::UnknownLayer {
+                    layer,
+                    n_layers: config.n_layers,
+                });
+            }
+        }
+
         let vs = vs.borrow();
 
+        let class_weights = config
+            .class_weights
+            .as_ref()
+            .map(|class_weights| {
+                Self::class_weights_tensor(class_weights, config.n_labels, vs.device())
+            })
+            .transpose()?;
+
+        // The first hidden layer uses the `nonlinear` prefix for compatibility
+        // with models that have a single hidden layer.
+        let non_linear = (0..config.n_hidden_layers)
+            .map(|idx| {
+                let (prefix, in_size) = if idx == 0 {
+                    ("nonlinear".to_string(), config.input_size)
+                } else {
+                    (format!("nonlinear_{}", idx), config.hidden_size)
+                };
+
+                NonLinearWithLayerNorm::new(
+                    vs / prefix,
+                    in_size,
+                    config.hidden_size,
+                    config.activation,
+                    config.dropout_prob,
+                    config.layer_norm_eps,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let linear_in_size = if config.n_hidden_layers == 0 {
+            config.input_size
+        } else {
+            config.hidden_size
+        };
+
         let ws = vs.var(
             "weight",
-            &[config.n_labels, config.hidden_size],
+            &[config.n_labels, linear_in_size],
             Init::KaimingUniform,
         )?;
         let bs = vs.var("bias", &[config.n_labels], Init::Const(0.))?;
 
-        let non_linear = NonLinearWithLayerNorm::new(
-            vs / "nonlinear",
-            config.input_size,
-            config.hidden_size,
-            config.dropout_prob,
-            config.layer_norm_eps,
-        )?;
+        let n_weighted_layers = config
+            .layers
+            .as_ref()
+            .map(|layers| layers.len() as i64)
+            .unwrap_or(config.n_layers);
 
         Ok(ScalarWeightClassifier {
+            class_weights,
             dropout: Dropout::new(config.dropout_prob),
+            focal_gamma: config.focal_gamma,
+            layers: config.layers.clone(),
             linear: Linear { ws, bs: Some(bs) },
             non_linear,
             scalar_weight: ScalarWeight::new(
                 vs / "scalar_weight",
-                config.n_layers,
+                n_weighted_layers,
                 config.layer_dropout_prob,
             )?,
         })
     }
 
+    fn class_weights_tensor(
+        class_weights: &[f32],
+        n_labels: i64,
+        device: Device,
+    ) -> Result<Tensor, TransformerError> {
+        if class_weights.len() as i64 != n_labels {
+            return Err(TransformerError::IncorrectNumberOfClassWeights {
+                n_class_weights: class_weights.len(),
+                n_classes: n_labels,
+            });
+        }
+
+        Ok(Tensor::of_slice(class_weights).f_to_device(device)?)
+    }
+
+    /// Set the per-class weights of the loss.
+    ///
+    /// This replaces the class weights from the classifier configuration.
+    /// The weights are not stored as parameters, so they only need to
+    /// be set for training.
+    pub fn set_class_weights(&mut self, class_weights: &[f32]) -> Result<(), TransformerError> {
+        let n_labels = self.linear.ws.size()[0];
+        self.class_weights = Some(Self::class_weights_tensor(
+            class_weights,
+            n_labels,
+            self.linear.ws.device(),
+        )?);
+        Ok(())
+    }
+
     pub fn forward(&self, layers: &[LayerOutput], train: bool) -> Result<Tensor, TransformerError> {
         let logits = self.logits(layers, train)?;
         Ok(logits.f_softmax(-1, Kind::Float)?)
     }
 
+    /// Apply the classifier to scalar weighted layers.
+    ///
+    /// `weighted` should be the output of `weighted_layers`.
+    pub fn forward_from_weighted(
+        &self,
+        weighted: &Tensor,
+        train: bool,
+    ) -> Result<Tensor, TransformerError> {
+        let logits = self.logits_from_weighted(weighted, train)?;
+        Ok(logits.f_softmax(-1, Kind::Float)?)
+    }
+
     pub fn logits(&self, layers: &[LayerOutput], train: bool) -> Result<Tensor, TransformerError> {
-        let mut features = self.scalar_weight.forward(layers, train)?;
+        let weighted = self.weighted_layers(layers, train)?;
+        self.logits_from_weighted(&weighted, train)
+    }
 
-        features = self.dropout.forward_t(&features, train)?;
+    /// Compute the logits of scalar weighted layers.
+    ///
+    /// `weighted` should be the output of `weighted_layers`.
+    pub fn logits_from_weighted(
+        &self,
+        weighted: &Tensor,
+        train: bool,
+    ) -> Result<Tensor, TransformerError> {
+        let mut features = self.dropout.forward_t(weighted, train)?;
 
-        features = self.non_linear.forward_t(&features, train)?;
+        for non_linear in &self.non_linear {
+            features = non_linear.forward_t(&features, train)?;
+        }
 
         Ok(self.linear.forward(&features))
     }
 
+    /// Apply scalar weighting to the (selected) layers.
+    ///
+    /// This is the first step of the classifier. Splitting off this step
+    /// makes it possible to transform the weighted representation before
+    /// classification, e.g. by pooling it.
+    pub fn weighted_layers(
+        &self,
+        layers: &[LayerOutput],
+        train: bool,
+    ) -> Result<Tensor, TransformerError> {
+        match &self.layers {
+            Some(selected) => {
+                let selected = selected
+                    .iter()
+                    .map(|&idx| layers[idx].map_output(|output| Ok(output.shallow_clone())))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.scalar_weight.forward(&selected, train)
+            }
+            None => self.scalar_weight.forward(layers, train),
+        }
+    }
+
     /// Compute the losses and correctly predicted labels of the given targets.
     ///
     /// `targets` should be of the shape `[batch_size, seq_len]`.
@@ -227,6 +362,21 @@ impl ScalarWeightClassifier {
         targets: &Tensor,
         label_smoothing: Option<f64>,
         train: bool,
+    ) -> Result<(Tensor, Tensor), TransformerError> {
+        let weighted = self.weighted_layers(layers, train)?;
+        self.losses_from_weighted(&weighted, targets, label_smoothing, train)
+    }
+
+    /// Compute the losses and correctly predicted labels from scalar weighted layers.
+    ///
+    /// `weighted` should be the output of `weighted_layers`, `targets` should
+    /// be of the shape `[batch_size, seq_len]`.
+    pub fn losses_from_weighted(
+        &self,
+        weighted: &Tensor,
+        targets: &Tensor,
+        label_smoothing: Option<f64>,
+        train: bool,
     ) -> Result<(Tensor, Tensor), TransformerError> {
         assert_eq!(
             targets.dim(),
@@ -240,13 +390,15 @@ impl ScalarWeightClassifier {
         let n_labels = self.linear.ws.size()[0];
 
         let logits = self
-            .logits(layers, train)?
+            .logits_from_weighted(weighted, train)?
             .f_view([batch_size * seq_len, n_labels])?;
         let targets = targets.f_view([batch_size * seq_len])?;
 
         let predicted = logits.f_argmax(-1, false)?;
 
         let losses = CrossEntropyLoss::new(-1, label_smoothing, Reduction::None)
+            .with_class_weights(self.class_weights.as_ref())
+            .with_focal_gamma(self.focal_gamma)
             .forward(&logits, &targets, None)?
             .f_view([batch_size, seq_len])?;
 
@@ -261,15 +413,35 @@ impl ScalarWeightClassifier {
 
 /// Configuration for the scalar weight classifier.
 pub struct ScalarWeightClassifierConfig {
-    /// Size of the hidden layer.
+    /// Activation function of the hidden layers.
+    pub activation: Activation,
+
+    /// Per-class weights of the loss.
+    ///
+    /// If present, the number of weights must be equal to the number
+    /// of labels.
+    pub class_weights: Option<Vec<f32>>,
+
+    /// Use the focal loss with the given gamma.
+    pub focal_gamma: Option<f64>,
+
+    /// Size of the hidden layers.
     pub hidden_size: i64,
 
+    /// Number of hidden layers.
+    pub n_hidden_layers: usize,
+
     /// Size of the input to the classification layer.
     pub input_size: i64,
 
-    /// Number of layers to weigh.
+    /// Number of layers of the transformer.
     pub n_layers: i64,
 
+    /// Layers to weigh.
+    ///
+    /// If this is `None`, all `n_layers` layers are weighed.
+    pub layers: Option<Vec<usize>>,
+
     /// Number of labels.
     pub n_labels: i64,
 
@@ -293,6 +465,7 @@ mod tests {
     use tch::{Device, Kind, Tensor};
 
     use super::{ScalarWeightClassifier, ScalarWeightClassifierConfig};
+    use crate::activations::Activation;
     use crate::models::{HiddenLayer, LayerOutput};
 
     fn varstore_variables(vs: &VarStore) -> BTreeSet<String> {
@@ -309,10 +482,15 @@ mod tests {
         let classifier = ScalarWeightClassifier::new(
             vs.root_ext(|_| 0),
             &ScalarWeightClassifierConfig {
+                activation: Activation::Relu,
+                class_weights: None,
+                focal_gamma: None,
                 hidden_size: 10,
+                n_hidden_layers: 1,
                 input_size: 8,
                 n_labels: 5,
                 n_layers: 2,
+                layers: None,
                 dropout_prob: 0.1,
                 layer_dropout_prob: 0.1,
                 layer_norm_eps: 0.01,
@@ -342,10 +520,15 @@ mod tests {
         let _classifier = ScalarWeightClassifier::new(
             vs.root_ext(|_| 0),
             &ScalarWeightClassifierConfig {
+                activation: Activation::Relu,
+                class_weights: None,
+                focal_gamma: None,
                 hidden_size: 10,
+                n_hidden_layers: 1,
                 input_size: 8,
                 n_labels: 5,
                 n_layers: 2,
+                layers: None,
                 dropout_prob: 0.1,
                 layer_dropout_prob: 0.1,
                 layer_norm_eps: 0.01,
@@ -366,4 +549,86 @@ mod tests {
             ])
         )
     }
+
+    #[test]
+    fn scalar_weight_classifier_with_hidden_layers_and_selected_layers() {
+        let vs = VarStore::new(Device::Cpu);
+
+        let classifier = ScalarWeightClassifier::new(
+            vs.root_ext(|_| 0),
+            &ScalarWeightClassifierConfig {
+                activation: Activation::Gelu,
+                class_weights: None,
+                focal_gamma: None,
+                hidden_size: 10,
+                n_hidden_layers: 2,
+                input_size: 8,
+                n_labels: 5,
+                n_layers: 3,
+                layers: Some(vec![0, 2]),
+                dropout_prob: 0.1,
+                layer_dropout_prob: 0.1,
+                layer_norm_eps: 0.01,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            varstore_variables(&vs),
+            BTreeSet::from_iter(vec![
+                "bias".to_string(),
+                "weight".to_string(),
+                "nonlinear.bias".to_string(),
+                "nonlinear.weight".to_string(),
+                "nonlinear.layer_norm.bias".to_string(),
+                "nonlinear.layer_norm.weight".to_string(),
+                "nonlinear_1.bias".to_string(),
+                "nonlinear_1.weight".to_string(),
+                "nonlinear_1.layer_norm.bias".to_string(),
+                "nonlinear_1.layer_norm.weight".to_string(),
+                "scalar_weight.layer_weights".to_string(),
+                "scalar_weight.scale".to_string()
+            ])
+        );
+
+        let layers = (0..3)
+            .map(|_| {
+                LayerOutput::EncoderWithAttention(HiddenLayer {
+                    attention: Tensor::zeros(&[1, 3, 2], (Kind::Float, Device::Cpu)),
+                    output: Tensor::zeros(&[1, 3, 8], (Kind::Float, Device::Cpu)),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let results = classifier.forward(&layers, false).unwrap();
+
+        assert_eq!(results.size(), &[1, 3, 5]);
+    }
+
+    #[test]
+    fn scalar_weight_classifier_rejects_invalid_layers() {
+        for layers in &[vec![], vec![0, 3]] {
+            let vs = VarStore::new(Device::Cpu);
+
+            let classifier = ScalarWeightClassifier::new(
+                vs.root_ext(|_| 0),
+                &ScalarWeightClassifierConfig {
+                    activation: Activation::Relu,
+                    class_weights: None,
+                    focal_gamma: None,
+                    hidden_size: 10,
+                    n_hidden_layers: 1,
+                    input_size: 8,
+                    n_labels: 5,
+                    n_layers: 3,
+                    layers: Some(layers.clone()),
+                    dropout_prob: 0.1,
+                    layer_dropout_prob: 0.1,
+                    layer_norm_eps: 0.01,
+                },
+            );
+
+            assert!(classifier.is_err());
+        }
+    }
 }
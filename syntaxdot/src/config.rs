@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read};
 use std::path::Path;
 
@@ -13,7 +13,7 @@ use syntaxdot_transformers::models::squeeze_bert::SqueezeBertConfig;
 
 use crate::encoders::EncodersConfig;
 use crate::error::SyntaxDotError;
-use crate::model::pooling::PiecePooler;
+use crate::model::pooling::{PiecePooler, PoolingPosition};
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields, rename = "biaffine")]
@@ -24,7 +24,7 @@ pub struct BiaffineConfig {
 }
 
 /// Configuration for bi-affine dependency parsing.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields, rename = "biaffine_parser")]
 pub struct BiaffineParserConfig {
     /// Activation for feed-forward layers.
@@ -39,6 +39,14 @@ pub struct BiaffineParserConfig {
 
     /// Label file for biaffine dependency parsing relation labels.
     pub labels: String,
+
+    /// Configuration of the dependency relation loss.
+    #[serde(default)]
+    pub loss: LossConfig,
+
+    /// Weight of the dependency parsing loss.
+    #[serde(default = "default_loss_weight")]
+    pub loss_weight: f64,
 }
 
 impl From<&BiaffineParserConfig> for MutableDependencyEncoder {
@@ -51,6 +59,74 @@ fn default_biaffine_activation() -> Activation {
     Activation::Gelu
 }
 
+pub(crate) fn default_loss_weight() -> f64 {
+    1.0
+}
+
+/// Configuration of a classification loss.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LossConfig {
+    /// Per-class weights of the loss.
+    pub class_weights: Option<ClassWeights>,
+
+    /// Use the focal loss with the given gamma.
+    pub focal_gamma: Option<f64>,
+}
+
+/// Per-class weights of a loss.
+///
+/// The weights are stored in a file with one weight per line, ordered
+/// by class index.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassWeights {
+    /// Weights that are supplied in the given file.
+    File(String),
+
+    /// Weights that are computed from the label frequencies.
+    ///
+    /// The weights are computed by `syntaxdot prepare` and stored in
+    /// the given file.
+    Frequency(String),
+}
+
+impl ClassWeights {
+    /// Get the path of the class weights file.
+    pub fn path(&self) -> &str {
+        match self {
+            ClassWeights::File(path) => path,
+            ClassWeights::Frequency(path) => path,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut String {
+        match self {
+            ClassWeights::File(path) => path,
+            ClassWeights::Frequency(path) => path,
+        }
+    }
+
+    /// Read the class weights.
+    pub fn read(&self) -> Result<Vec<f32>, SyntaxDotError> {
+        let mut data = String::new();
+        File::open(self.path())?.read_to_string(&mut data)?;
+
+        data.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                line.trim().parse().map_err(|_| {
+                    SyntaxDotError::IllegalConfigurationError(format!(
+                        "Cannot parse class weight `{}` in `{}`",
+                        line,
+                        self.path()
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
 /// Input configuration.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -60,7 +136,7 @@ pub struct Input {
 }
 
 /// Labeler configuration.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Labeler {
     /// The encoder labels file.
@@ -71,7 +147,7 @@ pub struct Labeler {
 }
 
 /// Model configuration.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Model {
     /// Model parameters.
@@ -80,6 +156,10 @@ pub struct Model {
     /// Pooler for token representations.
     pub pooler: PiecePooler,
 
+    /// Pool pieces before or after scalar weighting.
+    #[serde(default)]
+    pub pooling_position: PoolingPosition,
+
     /// Configuration of position embeddings.
     pub position_embeddings: PositionEmbeddings,
 
@@ -88,14 +168,34 @@ pub struct Model {
 
     /// Type of pretraining model.
     pub pretrain_type: PretrainModelType,
+
+    /// Regularization settings.
+    #[serde(default)]
+    pub regularization: Regularization,
+
+    /// Weighting of the task losses.
+    #[serde(default)]
+    pub task_weighting: TaskWeighting,
+
+    /// Treebanks that have a treebank embedding.
+    ///
+    /// The treebank embedding of a sentence is added to its piece
+    /// embeddings. The `i`-th treebank has the identifier `i + 1`,
+    /// the identifier `0` is used for sentences from other treebanks.
+    #[serde(default)]
+    pub treebanks: Vec<String>,
 }
 
 impl Model {
     /// Read the pretraining model configuration.
+    ///
+    /// Dropout probabilities that are set in the regularization
+    /// configuration override those of the pretraining model
+    /// configuration.
     pub fn pretrain_config(&self) -> Result<PretrainConfig, SyntaxDotError> {
         let reader = BufReader::new(File::open(&self.pretrain_config)?);
 
-        Ok(match self.pretrain_type {
+        let mut pretrain_config = match self.pretrain_type {
             PretrainModelType::Albert => {
                 PretrainConfig::Albert(serde_json::from_reader(reader).map_err(|err| {
                     SyntaxDotError::JSonSerialization(
@@ -151,7 +251,57 @@ impl Model {
                     )
                 })?)
             }
-        })
+        };
+
+        pretrain_config.override_dropout(&self.regularization);
+
+        Ok(pretrain_config)
+    }
+
+    /// Get the identifier of a treebank.
+    ///
+    /// Returns `None` if the treebank does not have a treebank embedding.
+    pub fn treebank_id(&self, treebank: &str) -> Option<usize> {
+        self.treebanks
+            .iter()
+            .position(|name| name == treebank)
+            .map(|idx| idx + 1)
+    }
+}
+
+/// Regularization configuration.
+///
+/// Options that are not set use the defaults of the pretrained model
+/// or SyntaxDot.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Regularization {
+    /// Dropout probability of transformer attention probabilities.
+    pub attention_dropout: Option<f64>,
+
+    /// Dropout probability of the biaffine parser's hidden representations.
+    pub biaffine_dropout: Option<f64>,
+
+    /// Dropout probability of transformer hidden layers.
+    pub hidden_dropout: Option<f64>,
+
+    /// Dropout probability of the layer outputs that are used by the
+    /// classifiers and the biaffine parser.
+    pub layers_dropout: Option<f64>,
+
+    /// The probability of excluding a layer from scalar weighting.
+    pub scalar_weight_dropout: Option<f64>,
+}
+
+impl Regularization {
+    /// The probability of excluding a layer from scalar weighting in
+    /// the classifiers.
+    ///
+    /// Defaults to `0.1` when `scalar_weight_dropout` is not set. The
+    /// biaffine parser uses the hidden dropout probability of the
+    /// pretrained model as its default instead.
+    pub fn scalar_weight_dropout_prob(&self) -> f64 {
+        self.scalar_weight_dropout.unwrap_or(0.1)
     }
 }
 
@@ -175,6 +325,27 @@ fn sinusoidal_normalize_default() -> bool {
     true
 }
 
+/// Weighting of the losses of the tasks.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskWeighting {
+    /// Use the configured loss weights of the tasks.
+    Static,
+
+    /// Learn the task weights using homoscedastic uncertainty.
+    ///
+    /// Following Kendall et al. (2018), a log variance `s` is learned
+    /// for each task. The loss of a task is then `exp(-s) * loss + s`,
+    /// multiplied by the configured loss weight of the task.
+    Uncertainty,
+}
+
+impl Default for TaskWeighting {
+    fn default() -> Self {
+        TaskWeighting::Static
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub enum PretrainConfig {
     Albert(AlbertConfig),
@@ -184,6 +355,42 @@ pub enum PretrainConfig {
     XlmRoberta(BertConfig),
 }
 
+impl PretrainConfig {
+    /// Override dropout probabilities using the regularization configuration.
+    fn override_dropout(&mut self, regularization: &Regularization) {
+        let (attention_dropout, hidden_dropout) = match self {
+            PretrainConfig::Albert(config) => (
+                &mut config.attention_probs_dropout_prob,
+                &mut config.hidden_dropout_prob,
+            ),
+            PretrainConfig::Bert(config) => (
+                &mut config.attention_probs_dropout_prob,
+                &mut config.hidden_dropout_prob,
+            ),
+            PretrainConfig::SqueezeAlbert(config) => (
+                &mut config.attention_probs_dropout_prob,
+                &mut config.hidden_dropout_prob,
+            ),
+            PretrainConfig::SqueezeBert(config) => (
+                &mut config.attention_probs_dropout_prob,
+                &mut config.hidden_dropout_prob,
+            ),
+            PretrainConfig::XlmRoberta(config) => (
+                &mut config.attention_probs_dropout_prob,
+                &mut config.hidden_dropout_prob,
+            ),
+        };
+
+        if let Some(dropout) = regularization.attention_dropout {
+            *attention_dropout = dropout;
+        }
+
+        if let Some(dropout) = regularization.hidden_dropout {
+            *hidden_dropout = dropout;
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PretrainModelType {
@@ -195,7 +402,7 @@ pub enum PretrainModelType {
 }
 
 /// Sequence labeler configuration.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     /// Configuration of the input representations.
@@ -221,6 +428,14 @@ impl Config {
 
         if let Some(ref mut biaffine) = self.biaffine {
             biaffine.labels = relativize_path(config_path, &biaffine.labels)?;
+            if let Some(class_weights) = &mut biaffine.loss.class_weights {
+                *class_weights.path_mut() = relativize_path(config_path, class_weights.path())?;
+            }
+        }
+        for encoder in &mut self.labeler.encoders.0 {
+            if let Some(class_weights) = &mut encoder.loss.class_weights {
+                *class_weights.path_mut() = relativize_path(config_path, class_weights.path())?;
+            }
         }
         *self.input.tokenizer.vocab_mut() =
             relativize_path(config_path, self.input.tokenizer.vocab())?;
@@ -248,6 +463,43 @@ impl Config {
             }
         }
     }
+
+    /// Compute a fingerprint of the tokenizer and label files.
+    ///
+    /// The fingerprint covers the tokenizer type, the contents of the
+    /// tokenizer vocabulary, the contents of the label files and the
+    /// treebanks, which determine the treebank identifiers. It is
+    /// stored in pre-processed data sets, since their pieces and labels
+    /// can only be used with the configuration that encoded them.
+    pub fn fingerprint(&self) -> Result<u64, SyntaxDotError> {
+        let tokenizer_type: &[u8] = match self.input.tokenizer {
+            Tokenizer::Albert { .. } => b"albert",
+            Tokenizer::Bert { .. } => b"bert",
+            Tokenizer::XlmRoberta { .. } => b"xlm_roberta",
+        };
+
+        let mut paths = vec![self.input.tokenizer.vocab(), self.labeler.labels.as_str()];
+        paths.extend(
+            self.biaffine
+                .as_ref()
+                .map(|biaffine| biaffine.labels.as_str()),
+        );
+
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, tokenizer_type);
+        for path in paths {
+            let data = fs::read(path)?;
+            hash = fnv1a(hash, &(data.len() as u64).to_le_bytes());
+            hash = fnv1a(hash, &data);
+        }
+
+        hash = fnv1a(hash, &(self.model.treebanks.len() as u64).to_le_bytes());
+        for treebank in &self.model.treebanks {
+            hash = fnv1a(hash, &(treebank.len() as u64).to_le_bytes());
+            hash = fnv1a(hash, treebank.as_bytes());
+        }
+
+        Ok(hash)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -295,6 +547,20 @@ impl TomlRead for Config {
     }
 }
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Update an FNV-1a hash with `data`.
+///
+/// FNV-1a is used rather than `DefaultHasher`, because the hash is
+/// stored in files and must not change between Rust versions.
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
 fn relativize_path(config_path: &Path, filename: &str) -> Result<String, SyntaxDotError> {
     if filename.is_empty() {
         return Ok(filename.to_owned());
@@ -329,17 +595,22 @@ fn relativize_path(config_path: &Path, filename: &str) -> Result<String, SyntaxD
 
 #[cfg(test)]
 mod tests {
-    use syntaxdot_encoders::depseq::PosLayer;
+    use std::fs;
+
+    use syntaxdot_encoders::depseq::{DecodingStrategy, PosLayer};
     use syntaxdot_encoders::layer::Layer;
     use syntaxdot_encoders::lemma::BackoffStrategy;
     use syntaxdot_transformers::activations::Activation;
 
     use crate::config::{
-        BiaffineConfig, BiaffineParserConfig, Config, Input, Labeler, Model, PositionEmbeddings,
-        PretrainModelType, Tokenizer, TomlRead,
+        BiaffineConfig, BiaffineParserConfig, ClassWeights, Config, Input, Labeler, LossConfig,
+        Model, PositionEmbeddings, PretrainModelType, Regularization, TaskWeighting, Tokenizer,
+        TomlRead,
+    };
+    use crate::encoders::{
+        ClassifierConfig, DependencyEncoder, EncoderType, EncodersConfig, NamedEncoderConfig,
     };
-    use crate::encoders::{DependencyEncoder, EncoderType, EncodersConfig, NamedEncoderConfig};
-    use crate::model::pooling::PiecePooler;
+    use crate::model::pooling::{PiecePooler, PoolingPosition};
 
     #[test]
     fn config() {
@@ -366,36 +637,126 @@ mod tests {
                         head_bias: true,
                         dependent_bias: true
                     },
-                    labels: "sticker.biaffine_labels".to_string()
+                    labels: "sticker.biaffine_labels".to_string(),
+                    loss: LossConfig {
+                        class_weights: Some(ClassWeights::Frequency(
+                            "sticker.biaffine_weights".to_string()
+                        )),
+                        focal_gamma: None,
+                    },
+                    loss_weight: 0.5,
                 }),
                 labeler: Labeler {
                     labels: "sticker.labels".to_string(),
                     encoders: EncodersConfig(vec![
                         NamedEncoderConfig {
+                            classifier: ClassifierConfig::default(),
                             name: "dep".to_string(),
                             encoder: EncoderType::Dependency {
                                 encoder: DependencyEncoder::RelativePos(PosLayer::XPos),
-                                root_relation: "root".to_string()
-                            }
+                                root_relation: "root".to_string(),
+                                decoder: DecodingStrategy::Heuristic,
+                            },
+                            loss: LossConfig::default(),
+                            loss_weight: 1.0,
                         },
                         NamedEncoderConfig {
+                            classifier: ClassifierConfig::default(),
                             name: "lemma".to_string(),
-                            encoder: EncoderType::Lemma(BackoffStrategy::Form)
+                            encoder: EncoderType::Lemma(BackoffStrategy::Form),
+                            loss: LossConfig::default(),
+                            loss_weight: 1.0,
                         },
                         NamedEncoderConfig {
+                            classifier: ClassifierConfig {
+                                activation: Activation::Gelu,
+                                dropout: Some(0.2),
+                                hidden_layers: 2,
+                                hidden_size: Some(256),
+                                layers: Some(vec![8, 9, 10, 11, 12]),
+                            },
                             name: "pos".to_string(),
-                            encoder: EncoderType::Sequence(Layer::XPos)
+                            encoder: EncoderType::Sequence(Layer::XPos),
+                            loss: LossConfig {
+                                class_weights: Some(ClassWeights::File("pos.weights".to_string())),
+                                focal_gamma: Some(2.0),
+                            },
+                            loss_weight: 2.0,
                         },
                     ]),
                 },
                 model: Model {
                     parameters: "epoch-99".to_string(),
                     pooler: PiecePooler::Discard,
+                    pooling_position: PoolingPosition::BeforeScalarWeighting,
                     position_embeddings: PositionEmbeddings::Model,
                     pretrain_config: "bert_config.json".to_string(),
                     pretrain_type: PretrainModelType::Bert,
+                    regularization: Regularization {
+                        attention_dropout: None,
+                        biaffine_dropout: Some(0.2),
+                        hidden_dropout: Some(0.15),
+                        layers_dropout: Some(0.1),
+                        scalar_weight_dropout: None,
+                    },
+                    task_weighting: TaskWeighting::Uncertainty,
+                    treebanks: vec!["alpino".to_string(), "lassy-small".to_string()],
                 }
             }
         );
     }
+
+    fn fingerprint_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "syntaxdot-fingerprint-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn fingerprint_covers_tokenizer_labels_and_treebanks() {
+        let vocab = fingerprint_file("vocab", "[UNK]\nhaus\n");
+        let labels = fingerprint_file("labels", "pos: a\n");
+        let biaffine_labels = fingerprint_file("biaffine-labels", "root\n");
+
+        let mut config =
+            Config::from_toml_read(include_bytes!("../testdata/sticker.conf").as_ref()).unwrap();
+        config.input.tokenizer = Tokenizer::Bert {
+            vocab: vocab.clone(),
+        };
+        config.labeler.labels = labels.clone();
+        config.biaffine.as_mut().unwrap().labels = biaffine_labels.clone();
+
+        let fingerprint = config.fingerprint().unwrap();
+        assert_eq!(config.fingerprint().unwrap(), fingerprint);
+
+        config.input.tokenizer = Tokenizer::Albert {
+            vocab: vocab.clone(),
+        };
+        let albert_fingerprint = config.fingerprint().unwrap();
+        config.input.tokenizer = Tokenizer::Bert {
+            vocab: vocab.clone(),
+        };
+
+        fs::write(&labels, "pos: b\n").unwrap();
+        let labels_fingerprint = config.fingerprint().unwrap();
+        fs::write(&labels, "pos: a\n").unwrap();
+
+        config.model.treebanks = vec!["alpino".to_string(), "lassy".to_string()];
+        let treebanks_fingerprint = config.fingerprint().unwrap();
+        config.model.treebanks = vec!["lassy".to_string(), "alpino".to_string()];
+        let reordered_fingerprint = config.fingerprint().unwrap();
+
+        for path in &[vocab, labels, biaffine_labels] {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert_ne!(albert_fingerprint, fingerprint);
+        assert_ne!(labels_fingerprint, fingerprint);
+        assert_ne!(treebanks_fingerprint, fingerprint);
+        assert_ne!(reordered_fingerprint, treebanks_fingerprint);
+    }
 }
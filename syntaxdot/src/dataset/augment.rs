@@ -0,0 +1,360 @@
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Token;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::SyntaxDotError;
+
+/// Probabilities of on-the-fly data augmentations.
+///
+/// Augmentation only changes the word pieces of a sentence, the
+/// sentence itself is left untouched. Consequently, labels are always
+/// encoded from the original forms. This is necessary for labels that
+/// are derived from the form, such as edit trees for lemmatization.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Augmentation {
+    case_flip: f64,
+    strip_diacritics: f64,
+    char_noise: f64,
+    piece_dropout: f64,
+}
+
+impl Augmentation {
+    /// Construct augmentation probabilities.
+    ///
+    /// * `case_flip`: probability of changing the case of a token to
+    ///   lowercase, uppercase, or title case.
+    /// * `strip_diacritics`: probability of removing the diacritics
+    ///   of a token.
+    /// * `char_noise`: probability of deleting, duplicating, swapping,
+    ///   or substituting a character of a token.
+    /// * `piece_dropout`: probability of dropping a word piece that
+    ///   is not the initial piece of a token.
+    pub fn new(
+        case_flip: f64,
+        strip_diacritics: f64,
+        char_noise: f64,
+        piece_dropout: f64,
+    ) -> Result<Self, SyntaxDotError> {
+        for &prob in &[case_flip, strip_diacritics, char_noise, piece_dropout] {
+            if !(0. ..=1.).contains(&prob) {
+                return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                    "Augmentation probability should be in [0, 1], was: {}",
+                    prob
+                )));
+            }
+        }
+
+        Ok(Augmentation {
+            case_flip,
+            strip_diacritics,
+            char_noise,
+            piece_dropout,
+        })
+    }
+
+    /// Check whether the augmentation leaves sentences unchanged.
+    pub fn is_identity(&self) -> bool {
+        self.case_flip == 0.
+            && self.strip_diacritics == 0.
+            && self.char_noise == 0.
+            && self.piece_dropout == 0.
+    }
+
+    /// Augment the form of a token.
+    fn augment_form(&self, form: &str, rng: &mut impl Rng) -> String {
+        let mut form = form.to_string();
+
+        if rng.gen_bool(self.case_flip) {
+            form = flip_case(&form, rng);
+        }
+
+        if rng.gen_bool(self.strip_diacritics) {
+            form = strip_diacritics(&form);
+        }
+
+        if rng.gen_bool(self.char_noise) {
+            form = char_noise(&form, rng);
+        }
+
+        form
+    }
+}
+
+/// An iterator adapter that augments sentences.
+pub struct Augmented<'a, I> {
+    inner: I,
+    augmentation: Augmentation,
+    tokenizer: &'a dyn Tokenize,
+    rng: XorShiftRng,
+}
+
+impl<'a, I> Augmented<'a, I> {
+    pub(crate) fn new(
+        inner: I,
+        augmentation: Augmentation,
+        tokenizer: &'a dyn Tokenize,
+        seed: u64,
+    ) -> Self {
+        Augmented {
+            inner,
+            augmentation,
+            tokenizer,
+            rng: XorShiftRng::seed_from_u64(seed),
+        }
+    }
+
+    fn augment(&mut self, sentence: SentenceWithPieces) -> SentenceWithPieces {
+        if self.augmentation.is_identity() {
+            return sentence;
+        }
+
+        // Only the forms are needed for tokenization.
+        let noisy_sentence: Sentence = sentence
+            .sentence
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| Token::new(self.augmentation.augment_form(token.form(), &mut self.rng)))
+            .collect();
+        let noisy = self.tokenizer.tokenize(noisy_sentence);
+
+        let (pieces, token_offsets) = drop_pieces(
+            &noisy.pieces.to_vec(),
+            &noisy.token_offsets,
+            self.augmentation.piece_dropout,
+            &mut self.rng,
+        );
+
+        SentenceWithPieces {
+            pieces: pieces.into(),
+            sentence: sentence.sentence,
+            token_offsets,
+        }
+    }
+}
+
+impl<'a, I> Iterator for Augmented<'a, I>
+where
+    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+{
+    type Item = Result<SentenceWithPieces, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sentence = self.inner.next()?;
+        Some(sentence.map(|sentence| self.augment(sentence)))
+    }
+}
+
+/// Drop word pieces with probability `prob`.
+///
+/// The initial piece of each token is never dropped, so that every
+/// token retains a representation. Pieces before the first token and
+/// the last piece are never dropped either, since tokenizers use them
+/// for special pieces marking the beginning and end of a sentence.
+fn drop_pieces(
+    pieces: &[i64],
+    token_offsets: &[usize],
+    prob: f64,
+    rng: &mut impl Rng,
+) -> (Vec<i64>, Vec<usize>) {
+    if prob == 0. {
+        return (pieces.to_vec(), token_offsets.to_vec());
+    }
+
+    let first_offset = token_offsets.first().cloned().unwrap_or(pieces.len());
+    let mut offsets = token_offsets.iter().peekable();
+
+    let mut new_pieces = Vec::with_capacity(pieces.len());
+    let mut new_offsets = Vec::with_capacity(token_offsets.len());
+    for (idx, &piece) in pieces.iter().enumerate() {
+        if offsets.peek() == Some(&&idx) {
+            offsets.next();
+            new_offsets.push(new_pieces.len());
+        } else if idx >= first_offset && idx + 1 != pieces.len() && rng.gen_bool(prob) {
+            continue;
+        }
+
+        new_pieces.push(piece);
+    }
+
+    (new_pieces, new_offsets)
+}
+
+/// Change a form to lowercase, uppercase, or title case.
+///
+/// One of the variants that differs from the form is chosen randomly.
+/// The form is returned unchanged if there is no such variant.
+fn flip_case(form: &str, rng: &mut impl Rng) -> String {
+    let mut chars = form.chars();
+    let title_case = match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.as_str().to_lowercase().chars())
+            .collect(),
+        None => String::new(),
+    };
+
+    let variants = [form.to_lowercase(), form.to_uppercase(), title_case]
+        .iter()
+        .filter(|variant| variant.as_str() != form)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    variants
+        .choose(rng)
+        .cloned()
+        .unwrap_or_else(|| form.to_string())
+}
+
+/// Remove diacritics from a form.
+///
+/// The form is returned unchanged if it only consists of diacritics.
+fn strip_diacritics(form: &str) -> String {
+    let stripped: String = form
+        .nfd()
+        .filter(|&c| !is_combining_mark(c))
+        .nfc()
+        .collect();
+
+    if stripped.is_empty() {
+        form.to_string()
+    } else {
+        stripped
+    }
+}
+
+/// Apply a random character edit to a form.
+///
+/// The edit is one of deletion, duplication, swapping with the next
+/// character, or substitution by another character of the form. Edits
+/// never result in an empty form.
+fn char_noise(form: &str, rng: &mut impl Rng) -> String {
+    let mut chars = form.chars().collect::<Vec<_>>();
+    if chars.is_empty() {
+        return form.to_string();
+    }
+
+    let idx = rng.gen_range(0..chars.len());
+    match rng.gen_range(0..4) {
+        0 if chars.len() > 1 => {
+            chars.remove(idx);
+        }
+        1 => chars.insert(idx, chars[idx]),
+        2 if idx + 1 < chars.len() => chars.swap(idx, idx + 1),
+        3 => chars[idx] = chars[rng.gen_range(0..chars.len())],
+        _ => (),
+    }
+
+    chars.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{char_noise, drop_pieces, flip_case, strip_diacritics, Augmentation};
+    use crate::dataset::tests::wordpiece_tokenizer;
+    use crate::dataset::{DataSet, PlainTextDataSet, SentenceIterTools};
+
+    const SENTENCES: &str = "Dit is de eerste zin .
+Dit de tweede zin .
+En nu de laatste zin .";
+
+    #[test]
+    fn augmentation_keeps_sentences_and_tokens() {
+        let tokenizer = wordpiece_tokenizer();
+
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let original = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let augmentation = Augmentation::new(1., 1., 1., 1.).unwrap();
+        let augmented = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .augment(augmentation, &tokenizer, 42)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(augmented.len(), original.len());
+        for (augmented, original) in augmented.iter().zip(&original) {
+            assert_eq!(augmented.sentence, original.sentence);
+            assert_eq!(augmented.token_offsets.len(), original.token_offsets.len());
+        }
+    }
+
+    #[test]
+    fn identity_augmentation_does_not_change_pieces() {
+        let tokenizer = wordpiece_tokenizer();
+
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let original = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let augmented = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .augment(Augmentation::default(), &tokenizer, 42)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(augmented, original);
+    }
+
+    #[test]
+    fn invalid_probabilities_are_rejected() {
+        assert!(Augmentation::new(1.5, 0., 0., 0.).is_err());
+        assert!(Augmentation::new(0., 0., 0., -0.1).is_err());
+    }
+
+    #[test]
+    fn piece_dropout_keeps_initial_pieces() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let (pieces, offsets) = drop_pieces(&[0, 5, 6, 7, 8, 9], &[1, 3], 1., &mut rng);
+        assert_eq!(pieces, vec![0, 5, 7, 9]);
+        assert_eq!(offsets, vec![1, 2]);
+    }
+
+    #[test]
+    fn case_is_flipped() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        for _ in 0..10 {
+            assert_ne!(flip_case("Haus", &mut rng), "Haus");
+        }
+        assert_eq!(flip_case("123", &mut rng), "123");
+    }
+
+    #[test]
+    fn diacritics_are_stripped() {
+        assert_eq!(strip_diacritics("Übergrößenträger"), "Ubergroßentrager");
+        assert_eq!(strip_diacritics("crème brûlée"), "creme brulee");
+        assert_eq!(strip_diacritics("\u{301}"), "\u{301}");
+    }
+
+    #[test]
+    fn char_noise_never_empties_forms() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        for _ in 0..100 {
+            assert!(!char_noise("a", &mut rng).is_empty());
+        }
+    }
+}
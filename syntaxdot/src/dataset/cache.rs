@@ -0,0 +1,747 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use memmap2::Mmap;
+use ndarray::Array1;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use serde::{Deserialize, Serialize};
+
+use crate::dataset::tensor_iter::EncodedSentence;
+use crate::dataset::{BatchSize, SequenceLength};
+use crate::encoders::NamedEncoder;
+use crate::error::SyntaxDotError;
+
+/// Magic number of pre-processed data sets.
+const MAGIC: [u8; 4] = *b"SDDS";
+
+/// Version of the pre-processed data set format.
+const VERSION: u32 = 2;
+
+/// Size of the preamble: magic, version and header length.
+const PREAMBLE_SIZE: usize = 16;
+
+/// Size of an index entry: offset, number of pieces and tokens.
+const INDEX_ENTRY_SIZE: usize = 16;
+
+/// Size of the trailer: index offset and number of sentences.
+const TRAILER_SIZE: usize = 16;
+
+/// Encoders of the sentences in a pre-processed data set.
+///
+/// `fingerprint` is the fingerprint of the tokenizer and label files
+/// that the sentences were encoded with (see `Config::fingerprint`).
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct CachedDataSetHeader {
+    biaffine: bool,
+    encoders: Vec<String>,
+    fingerprint: u64,
+    sentence_encoders: Vec<String>,
+}
+
+impl CachedDataSetHeader {
+    fn from_encoders(biaffine: bool, encoders: &[NamedEncoder], fingerprint: u64) -> Self {
+        CachedDataSetHeader {
+            biaffine,
+            encoders: encoders
+                .iter()
+                .filter(|encoder| !encoder.encoder().is_sentence_level())
+                .map(|encoder| encoder.name().to_string())
+                .collect(),
+            fingerprint,
+            sentence_encoders: encoders
+                .iter()
+                .filter(|encoder| encoder.encoder().is_sentence_level())
+                .map(|encoder| encoder.name().to_string())
+                .collect(),
+        }
+    }
+
+    /// The length of a sentence record in `i32`s.
+    fn record_len(&self, n_pieces: usize, n_tokens: usize) -> usize {
+        let biaffine_len = if self.biaffine { 2 * n_tokens } else { 0 };
+        1 + n_pieces
+            + n_tokens
+            + biaffine_len
+            + self.encoders.len() * n_tokens
+            + self.sentence_encoders.len()
+    }
+}
+
+/// Index entry of a sentence in a pre-processed data set.
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    offset: usize,
+    n_pieces: usize,
+    n_tokens: usize,
+}
+
+impl IndexEntry {
+    fn fits(self, max_len: SequenceLength) -> bool {
+        match max_len {
+            SequenceLength::Pieces(max_len) => self.n_pieces <= max_len,
+            SequenceLength::Tokens(max_len) => self.n_tokens <= max_len,
+            SequenceLength::Unbounded => true,
+        }
+    }
+}
+
+/// Writer for pre-processed data sets.
+///
+/// A pre-processed data set stores the pieces, token offsets and
+/// encoded labels of sentences, so that they do not have to be
+/// tokenized and encoded again in every epoch. The data set can be
+/// read with `CachedDataSet`.
+pub struct CachedDataSetWriter<W> {
+    header: CachedDataSetHeader,
+    index: Vec<IndexEntry>,
+    offset: usize,
+    write: W,
+}
+
+impl<W> CachedDataSetWriter<W>
+where
+    W: Write,
+{
+    /// Construct a writer for sentences with the given encodings.
+    ///
+    /// If `biaffine` is `true`, the sentences must have dependency
+    /// labels. The sentences must have labels for every encoder in
+    /// `encoders`. `fingerprint` is the fingerprint of the configuration
+    /// that the sentences are encoded with.
+    pub fn new(
+        write: W,
+        biaffine: bool,
+        encoders: &[NamedEncoder],
+        fingerprint: u64,
+    ) -> Result<Self, SyntaxDotError> {
+        Self::with_header(
+            write,
+            CachedDataSetHeader::from_encoders(biaffine, encoders, fingerprint),
+        )
+    }
+
+    fn with_header(mut write: W, header: CachedDataSetHeader) -> Result<Self, SyntaxDotError> {
+        let header_data = serde_json::to_vec(&header).map_err(|err| {
+            SyntaxDotError::JSonSerialization("Cannot serialize data set header".to_string(), err)
+        })?;
+
+        write.write_all(&MAGIC)?;
+        write.write_u32::<LittleEndian>(VERSION)?;
+        write.write_u64::<LittleEndian>(header_data.len() as u64)?;
+        write.write_all(&header_data)?;
+
+        Ok(CachedDataSetWriter {
+            header,
+            index: Vec::new(),
+            offset: PREAMBLE_SIZE + header_data.len(),
+            write,
+        })
+    }
+
+    /// Write an encoded sentence.
+    pub fn write(&mut self, sentence: &EncodedSentence) -> Result<(), SyntaxDotError> {
+        let n_tokens = sentence.token_offsets.len();
+
+        let mut record = Vec::with_capacity(
+            self.header
+                .record_len(sentence.pieces.len(), sentence.token_offsets.len()),
+        );
+        record.push(sentence.treebank_id as i64);
+        record.extend(sentence.pieces.iter().copied());
+        record.extend(sentence.token_offsets.iter().map(|&offset| offset as i64));
+
+        if self.header.biaffine {
+            let (heads, relations) = sentence
+                .biaffine_labels
+                .as_ref()
+                .ok_or_else(|| invalid_sentence("dependency labels are missing"))?;
+            check_labels_len("dependency heads", heads, n_tokens)?;
+            check_labels_len("dependency relations", relations, n_tokens)?;
+            record.extend(heads.iter().copied());
+            record.extend(relations.iter().copied());
+        }
+
+        for encoder in &self.header.encoders {
+            let labels = sentence
+                .labels
+                .get(encoder)
+                .ok_or_else(|| invalid_sentence(format!("labels of {} are missing", encoder)))?;
+            check_labels_len(encoder, labels, n_tokens)?;
+            record.extend(labels.iter().copied());
+        }
+
+        for encoder in &self.header.sentence_encoders {
+            let label = sentence
+                .sentence_labels
+                .get(encoder)
+                .ok_or_else(|| invalid_sentence(format!("label of {} is missing", encoder)))?;
+            record.push(*label);
+        }
+
+        for value in record {
+            let value = i32::try_from(value).map_err(|_| {
+                invalid_sentence(format!("value {} does not fit in 32 bits", value))
+            })?;
+            self.write.write_i32::<LittleEndian>(value)?;
+        }
+
+        self.index.push(IndexEntry {
+            offset: self.offset,
+            n_pieces: sentence.pieces.len(),
+            n_tokens,
+        });
+        self.offset += self.header.record_len(sentence.pieces.len(), n_tokens) * 4;
+
+        Ok(())
+    }
+
+    /// Finish the data set by writing the index.
+    ///
+    /// Returns the number of sentences in the data set.
+    pub fn finish(mut self) -> Result<usize, SyntaxDotError> {
+        for entry in &self.index {
+            self.write.write_u64::<LittleEndian>(entry.offset as u64)?;
+            self.write
+                .write_u32::<LittleEndian>(entry.n_pieces as u32)?;
+            self.write
+                .write_u32::<LittleEndian>(entry.n_tokens as u32)?;
+        }
+
+        self.write.write_u64::<LittleEndian>(self.offset as u64)?;
+        self.write
+            .write_u64::<LittleEndian>(self.index.len() as u64)?;
+        self.write.flush()?;
+
+        Ok(self.index.len())
+    }
+}
+
+fn check_labels_len(
+    name: &str,
+    labels: &Array1<i64>,
+    n_tokens: usize,
+) -> Result<(), SyntaxDotError> {
+    if labels.len() != n_tokens {
+        return Err(invalid_sentence(format!(
+            "got {} labels for {}, sentence has {} tokens",
+            labels.len(),
+            name,
+            n_tokens
+        )));
+    }
+
+    Ok(())
+}
+
+fn invalid_sentence(message: impl Into<String>) -> SyntaxDotError {
+    SyntaxDotError::CachedDataSetError(format!("cannot write sentence, {}", message.into()))
+}
+
+fn invalid_data_set(message: impl Into<String>) -> SyntaxDotError {
+    SyntaxDotError::CachedDataSetError(message.into())
+}
+
+/// Check whether `read` contains a pre-processed data set.
+///
+/// The reader is rewound to the beginning of the data.
+pub fn is_cached_data_set<R>(mut read: R) -> io::Result<bool>
+where
+    R: Read + Seek,
+{
+    read.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 4];
+    let is_cached = match read.read_exact(&mut magic) {
+        Ok(()) => magic == MAGIC,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err),
+    };
+    read.seek(SeekFrom::Start(0))?;
+
+    Ok(is_cached)
+}
+
+/// A pre-processed data set.
+///
+/// The data set stores sentences that were already tokenized and
+/// encoded, which avoids parsing, tokenization and label encoding in
+/// every epoch. Since the sentences are already encoded, this data set
+/// does not implement `DataSet`, but returns batches of encoded
+/// sentences instead. Sentences are stored in a memory-mappable format
+/// with an index, so that they can be accessed in any order. This
+/// makes it possible to shuffle the complete data set, rather than
+/// shuffling within a buffer.
+pub struct CachedDataSet<D = Mmap> {
+    data: D,
+    header: CachedDataSetHeader,
+    index: Vec<IndexEntry>,
+}
+
+impl CachedDataSet<Mmap> {
+    /// Memory-map a pre-processed data set.
+    pub fn mmap(file: &File) -> Result<Self, SyntaxDotError> {
+        // Safety: the data set must not be modified while training.
+        let data = unsafe { Mmap::map(file)? };
+        Self::from_data(data)
+    }
+}
+
+impl<D> CachedDataSet<D>
+where
+    D: Deref<Target = [u8]>,
+{
+    /// Read a pre-processed data set from the given data.
+    pub fn from_data(data: D) -> Result<Self, SyntaxDotError> {
+        if data.len() < PREAMBLE_SIZE + TRAILER_SIZE || data[..4] != MAGIC {
+            return Err(invalid_data_set("not a pre-processed data set"));
+        }
+
+        let version = LittleEndian::read_u32(&data[4..8]);
+        if version != VERSION {
+            return Err(invalid_data_set(format!(
+                "unsupported data set version {}, expected {}",
+                version, VERSION
+            )));
+        }
+
+        let header_len = LittleEndian::read_u64(&data[8..PREAMBLE_SIZE]) as usize;
+        let header_data = PREAMBLE_SIZE
+            .checked_add(header_len)
+            .and_then(|header_end| data.get(PREAMBLE_SIZE..header_end))
+            .ok_or_else(|| invalid_data_set("data set header is truncated"))?;
+        let header: CachedDataSetHeader = serde_json::from_slice(header_data).map_err(|err| {
+            SyntaxDotError::JSonSerialization("Cannot deserialize data set header".to_string(), err)
+        })?;
+
+        let trailer = &data[data.len() - TRAILER_SIZE..];
+        let index_offset = LittleEndian::read_u64(&trailer[..8]) as usize;
+        let n_sentences = LittleEndian::read_u64(&trailer[8..]) as usize;
+        let index_data = n_sentences
+            .checked_mul(INDEX_ENTRY_SIZE)
+            .and_then(|index_len| index_offset.checked_add(index_len))
+            .and_then(|index_end| data.get(index_offset..index_end))
+            .ok_or_else(|| invalid_data_set("data set index is truncated"))?;
+
+        let index = index_data
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|entry| {
+                let entry = IndexEntry {
+                    offset: LittleEndian::read_u64(&entry[..8]) as usize,
+                    n_pieces: LittleEndian::read_u32(&entry[8..12]) as usize,
+                    n_tokens: LittleEndian::read_u32(&entry[12..16]) as usize,
+                };
+
+                let record_end = entry
+                    .offset
+                    .saturating_add(header.record_len(entry.n_pieces, entry.n_tokens) * 4);
+                if record_end > index_offset {
+                    return Err(invalid_data_set("sentence record is truncated"));
+                }
+
+                Ok(entry)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CachedDataSet {
+            data,
+            header,
+            index,
+        })
+    }
+
+    /// Check that the data set was encoded with the given encoders.
+    ///
+    /// `fingerprint` is the fingerprint of the configuration that the
+    /// data set is used with. It must be the same as the fingerprint of
+    /// the configuration that encoded the data set.
+    pub fn check_encoders(
+        &self,
+        biaffine: bool,
+        encoders: &[NamedEncoder],
+        fingerprint: u64,
+    ) -> Result<(), SyntaxDotError> {
+        let expected = CachedDataSetHeader::from_encoders(biaffine, encoders, fingerprint);
+        if self.header.fingerprint != expected.fingerprint {
+            return Err(invalid_data_set(
+                "data set was encoded with a different tokenizer or different label files",
+            ));
+        }
+
+        if self.header != expected {
+            return Err(invalid_data_set(format!(
+                "data set was encoded with different encoders (biaffine: {}, encoders: {}), \
+                 expected (biaffine: {}, encoders: {})",
+                self.header.biaffine,
+                encoder_names(&self.header),
+                expected.biaffine,
+                encoder_names(&expected)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Get the sentence at `idx`.
+    pub fn get(&self, idx: usize) -> Option<EncodedSentence> {
+        let entry = *self.index.get(idx)?;
+
+        let record_len = self.header.record_len(entry.n_pieces, entry.n_tokens);
+        let mut record = vec![0i32; record_len];
+        LittleEndian::read_i32_into(
+            &self.data[entry.offset..entry.offset + record_len * 4],
+            &mut record,
+        );
+
+        let mut values = record.into_iter().map(|value| value as i64);
+        let mut take = |n| values.by_ref().take(n).collect::<Array1<i64>>();
+
+        let treebank_id = take(1)[0] as usize;
+        let pieces = take(entry.n_pieces);
+        let token_offsets = take(entry.n_tokens)
+            .iter()
+            .map(|&offset| offset as usize)
+            .collect();
+        let biaffine_labels = if self.header.biaffine {
+            Some((take(entry.n_tokens), take(entry.n_tokens)))
+        } else {
+            None
+        };
+        let labels = self
+            .header
+            .encoders
+            .iter()
+            .map(|encoder| (encoder.clone(), take(entry.n_tokens)))
+            .collect();
+        let sentence_labels = self
+            .header
+            .sentence_encoders
+            .iter()
+            .map(|encoder| (encoder.clone(), take(1)[0]))
+            .collect();
+
+        Some(EncodedSentence {
+            pieces,
+            token_offsets,
+            treebank_id,
+            biaffine_labels,
+            labels,
+            sentence_labels,
+        })
+    }
+
+    /// Get the number of sentences in the data set.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Check whether the data set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Get the number of sentences that are not longer than `max_len`.
+    pub fn n_sentences(&self, max_len: SequenceLength) -> usize {
+        self.index
+            .iter()
+            .filter(|entry| entry.fits(max_len))
+            .count()
+    }
+
+    /// Get batches of the sentences in data set order.
+    ///
+    /// Sentences that are longer than `max_len` are skipped.
+    pub fn batches(&self, batch_size: BatchSize, max_len: SequenceLength) -> CachedBatches<D> {
+        let indices = self.filter_by_len(max_len);
+        CachedBatches {
+            batches: self.batch_indices(&indices, batch_size).into_iter(),
+            data_set: self,
+        }
+    }
+
+    /// Get batches of the sentences in random order.
+    ///
+    /// All sentences of the data set are shuffled. The shuffled
+    /// sentences are then split in buckets of `bucket_size` sentences,
+    /// which are sorted by length and grouped into batches. This
+    /// results in batches of sentences with similar lengths, which
+    /// minimizes padding. Finally, the batches of all buckets are
+    /// shuffled.
+    ///
+    /// Sentences that are longer than `max_len` are skipped. The
+    /// random number generator is seeded with `seed`, so that the
+    /// order of the batches can be reproduced.
+    pub fn shuffled_batches(
+        &self,
+        batch_size: BatchSize,
+        max_len: SequenceLength,
+        bucket_size: usize,
+        seed: u64,
+    ) -> CachedBatches<D> {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+
+        let mut indices = self.filter_by_len(max_len);
+        indices.shuffle(&mut rng);
+
+        let mut batches = Vec::new();
+        for bucket in indices.chunks_mut(bucket_size.max(1)) {
+            // Sorting is stable, so that the batches are deterministic
+            // given the shuffled order.
+            bucket.sort_by_key(|&idx| self.index[idx].n_pieces);
+            batches.extend(self.batch_indices(bucket, batch_size));
+        }
+        batches.shuffle(&mut rng);
+
+        CachedBatches {
+            batches: batches.into_iter(),
+            data_set: self,
+        }
+    }
+
+    fn filter_by_len(&self, max_len: SequenceLength) -> Vec<usize> {
+        self.index
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.fits(max_len))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Group sentences into batches.
+    ///
+    /// A batch is completed when adding the next sentence would exceed
+    /// `batch_size`, like `SentenceIterTools::batched`.
+    fn batch_indices(&self, indices: &[usize], batch_size: BatchSize) -> Vec<Vec<usize>> {
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        let mut max_seq_len = 0;
+
+        for &idx in indices {
+            let n_pieces = self.index[idx].n_pieces;
+            let next_max_seq_len = max_seq_len.max(n_pieces);
+            if !batch.is_empty() && !batch_size.fits(batch.len() + 1, next_max_seq_len) {
+                batches.push(std::mem::take(&mut batch));
+                max_seq_len = 0;
+            }
+
+            max_seq_len = max_seq_len.max(n_pieces);
+            batch.push(idx);
+        }
+
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+
+        batches
+    }
+}
+
+fn encoder_names(header: &CachedDataSetHeader) -> String {
+    header
+        .encoders
+        .iter()
+        .chain(&header.sentence_encoders)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// An iterator over batches of a pre-processed data set.
+pub struct CachedBatches<'a, D = Mmap> {
+    batches: std::vec::IntoIter<Vec<usize>>,
+    data_set: &'a CachedDataSet<D>,
+}
+
+impl<'a, D> Iterator for CachedBatches<'a, D>
+where
+    D: Deref<Target = [u8]>,
+{
+    type Item = Vec<EncodedSentence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.batches.next().map(|batch| {
+            batch
+                .into_iter()
+                .map(|idx| {
+                    self.data_set
+                        .get(idx)
+                        .expect("Batch refers to non-existing sentence")
+                })
+                .collect()
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.batches.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use byteorder::{ByteOrder, LittleEndian};
+    use ndarray::{arr1, Array1};
+
+    use super::{is_cached_data_set, CachedDataSet, CachedDataSetHeader, CachedDataSetWriter};
+    use crate::dataset::tensor_iter::EncodedSentence;
+    use crate::dataset::{BatchSize, SequenceLength};
+
+    fn encoded_sentence(n_tokens: usize, treebank_id: usize) -> EncodedSentence {
+        let pieces = (0..n_tokens as i64 + 1).collect::<Array1<_>>();
+        EncodedSentence {
+            pieces,
+            token_offsets: (1..=n_tokens).collect(),
+            treebank_id,
+            biaffine_labels: Some((
+                (0..n_tokens as i64).collect(),
+                (0..n_tokens as i64).map(|idx| idx + 3).collect(),
+            )),
+            labels: vec![(
+                "pos".to_string(),
+                (0..n_tokens as i64).map(|idx| idx * 2).collect(),
+            )]
+            .into_iter()
+            .collect(),
+            sentence_labels: vec![("genre".to_string(), n_tokens as i64)]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn header() -> CachedDataSetHeader {
+        CachedDataSetHeader {
+            biaffine: true,
+            encoders: vec!["pos".to_string()],
+            fingerprint: 42,
+            sentence_encoders: vec!["genre".to_string()],
+        }
+    }
+
+    fn write_data_set(sentences: &[EncodedSentence]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = CachedDataSetWriter::with_header(&mut data, header()).unwrap();
+        for sentence in sentences {
+            writer.write(sentence).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), sentences.len());
+
+        data
+    }
+
+    #[test]
+    fn cached_data_set_round_trip() {
+        let sentences = vec![
+            encoded_sentence(3, 0),
+            encoded_sentence(1, 2),
+            encoded_sentence(5, 1),
+        ];
+        let data_set = CachedDataSet::from_data(write_data_set(&sentences)).unwrap();
+
+        assert_eq!(data_set.len(), 3);
+        for (idx, sentence) in sentences.iter().enumerate() {
+            assert_eq!(data_set.get(idx).as_ref(), Some(sentence));
+        }
+        assert_eq!(data_set.get(3), None);
+    }
+
+    #[test]
+    fn cached_data_set_batches() {
+        let sentences = vec![
+            encoded_sentence(3, 0),
+            encoded_sentence(1, 0),
+            encoded_sentence(5, 0),
+            encoded_sentence(2, 0),
+        ];
+        let data_set = CachedDataSet::from_data(write_data_set(&sentences)).unwrap();
+
+        let batch_lens = |batches: Vec<Vec<EncodedSentence>>| {
+            batches
+                .into_iter()
+                .map(|batch| batch.iter().map(|s| s.token_offsets.len()).collect())
+                .collect::<Vec<Vec<_>>>()
+        };
+
+        assert_eq!(
+            batch_lens(
+                data_set
+                    .batches(BatchSize::Sentences(2), SequenceLength::Tokens(4))
+                    .collect()
+            ),
+            vec![vec![3, 1], vec![2]]
+        );
+
+        let mut shuffled = batch_lens(
+            data_set
+                .shuffled_batches(BatchSize::Sentences(2), SequenceLength::Unbounded, 4, 42)
+                .collect(),
+        );
+        shuffled.sort();
+        assert_eq!(shuffled, vec![vec![1, 2], vec![3, 5]]);
+    }
+
+    #[test]
+    fn detects_cached_data_set() {
+        let data = write_data_set(&[encoded_sentence(3, 0)]);
+        assert!(is_cached_data_set(Cursor::new(data)).unwrap());
+        assert!(!is_cached_data_set(Cursor::new("1\tDit\n")).unwrap());
+        assert!(!is_cached_data_set(Cursor::new("")).unwrap());
+    }
+
+    #[test]
+    fn cached_data_set_rejects_invalid_data() {
+        assert!(CachedDataSet::from_data(vec![0u8; 64]).is_err());
+
+        let mut data = write_data_set(&[encoded_sentence(3, 0)]);
+        data[4] = 2;
+        assert!(CachedDataSet::from_data(data).is_err());
+    }
+
+    #[test]
+    fn cached_data_set_rejects_overflowing_lengths() {
+        let data = write_data_set(&[encoded_sentence(3, 0)]);
+
+        let mut header_overflow = data.clone();
+        LittleEndian::write_u64(&mut header_overflow[8..16], u64::MAX);
+        assert!(CachedDataSet::from_data(header_overflow).is_err());
+
+        let mut index_overflow = data;
+        let trailer_offset = index_overflow.len() - 16;
+        LittleEndian::write_u64(
+            &mut index_overflow[trailer_offset..trailer_offset + 8],
+            u64::MAX,
+        );
+        assert!(CachedDataSet::from_data(index_overflow).is_err());
+    }
+
+    #[test]
+    fn cached_data_set_rejects_different_fingerprint() {
+        let mut header = header();
+        header.encoders.clear();
+        header.sentence_encoders.clear();
+        let mut data = Vec::new();
+        CachedDataSetWriter::with_header(&mut data, header)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let data_set = CachedDataSet::from_data(data).unwrap();
+        assert!(data_set.check_encoders(true, &[], 42).is_ok());
+        assert!(data_set.check_encoders(true, &[], 43).is_err());
+    }
+
+    #[test]
+    fn cached_data_set_writer_rejects_missing_labels() {
+        let mut sentence = encoded_sentence(2, 0);
+        sentence.labels = HashMap::new();
+
+        let mut writer = CachedDataSetWriter::with_header(Vec::new(), header()).unwrap();
+        assert!(writer.write(&sentence).is_err());
+
+        sentence.labels.insert("pos".to_string(), arr1(&[1]));
+        assert!(writer.write(&sentence).is_err());
+    }
+}
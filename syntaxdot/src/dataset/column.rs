@@ -0,0 +1,567 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use conllu::display::ConlluFeatures;
+use conllu::io::WriteSentence;
+use syntaxdot_encoders::layer::{Layer, LayerValue};
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Token;
+
+use crate::dataset::conll::{set_source_line, LinePosition};
+use crate::dataset::DataSet;
+use crate::error::SyntaxDotError;
+
+/// Form of the tokens that start a new document in CoNLL-2003 data.
+const DOCSTART: &str = "-DOCSTART-";
+
+/// Value of a column that is not annotated.
+const EMPTY_VALUE: &str = "_";
+
+/// A column of a column-based data format.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Column {
+    /// The form of the token.
+    Form,
+
+    /// The lemma of the token.
+    Lemma,
+
+    /// A layer, such as the part-of-speech or a MISC feature.
+    Layer(Layer),
+
+    /// A column that is not used.
+    Ignore,
+}
+
+impl Column {
+    /// Get the value of this column for a token.
+    fn value(&self, token: &Token) -> Option<String> {
+        match self {
+            Column::Form => Some(token.form().to_owned()),
+            Column::Lemma => token.lemma().map(ToOwned::to_owned),
+            Column::Layer(layer) => token.value(layer),
+            Column::Ignore => None,
+        }
+    }
+
+    /// Set the value of this column in a token.
+    fn set_value(&self, token: &mut Token, value: &str) -> Result<(), String> {
+        match self {
+            Column::Form | Column::Ignore => (),
+            Column::Lemma => {
+                token.set_lemma(Some(value));
+            }
+            Column::Layer(Layer::FeatureString) => {
+                let features = ConlluFeatures::try_from(value)
+                    .map_err(|_| format!("invalid features: {}", value))?;
+                token.set_features(features.into_owned());
+            }
+            Column::Layer(layer) => token.set_value(layer, value),
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Column::Form => write!(f, "form"),
+            Column::Lemma => write!(f, "lemma"),
+            Column::Layer(Layer::UPos) => write!(f, "upos"),
+            Column::Layer(Layer::XPos) => write!(f, "xpos"),
+            Column::Layer(Layer::FeatureString) => write!(f, "features"),
+            Column::Layer(Layer::Feature { feature, .. }) => write!(f, "feature:{}", feature),
+            Column::Layer(Layer::Misc { feature, .. }) => write!(f, "misc:{}", feature),
+            Column::Ignore => write!(f, "{}", EMPTY_VALUE),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = SyntaxDotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let column = match (name, parts.next()) {
+            ("feature", Some(feature)) if !feature.is_empty() => {
+                Column::Layer(Layer::feature(feature.to_string(), None))
+            }
+            ("misc", Some(feature)) if !feature.is_empty() => {
+                Column::Layer(Layer::misc(feature.to_string(), None))
+            }
+            (_, Some(_)) => return Err(unknown_column(s)),
+            (_, None) => match s {
+                "form" => Column::Form,
+                "lemma" => Column::Lemma,
+                "upos" => Column::Layer(Layer::UPos),
+                "xpos" => Column::Layer(Layer::XPos),
+                "features" => Column::Layer(Layer::FeatureString),
+                EMPTY_VALUE => Column::Ignore,
+                _ => return Err(unknown_column(s)),
+            },
+        };
+
+        Ok(column)
+    }
+}
+
+fn unknown_column(column: &str) -> SyntaxDotError {
+    SyntaxDotError::IllegalConfigurationError(format!("Unknown column: {}", column))
+}
+
+/// A column-based data format.
+///
+/// In column-based formats, such as the CoNLL-2003 format, every line
+/// contains the columns of a token and sentences are separated by an
+/// empty line. Columns are separated by tabs or, if a line does not
+/// contain tabs, by spaces. The format describes the token field or
+/// layer that each column is mapped to. Columns with the value `_`
+/// are not annotated.
+///
+/// A format is written as a comma-separated list of columns, e.g.
+/// `form,xpos,_,misc:ner`. The supported columns are `form`, `lemma`,
+/// `upos`, `xpos`, `features` (all morphological features),
+/// `feature:NAME`, `misc:NAME` and `_` (not used). `conll2003` is the
+/// format of the CoNLL-2003 shared task, `form,xpos,misc:chunk,misc:ner`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ColumnFormat {
+    columns: Vec<Column>,
+}
+
+impl ColumnFormat {
+    /// Construct a column format.
+    ///
+    /// The format must have exactly one form column.
+    pub fn new(columns: Vec<Column>) -> Result<Self, SyntaxDotError> {
+        let n_form = columns
+            .iter()
+            .filter(|&column| *column == Column::Form)
+            .count();
+        if n_form != 1 {
+            return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                "A column format must have one form column, has: {}",
+                n_form
+            )));
+        }
+
+        Ok(ColumnFormat { columns })
+    }
+
+    /// The format of the CoNLL-2003 named entity recognition data.
+    ///
+    /// The columns contain the form, part-of-speech tag, chunk tag and
+    /// named entity tag of each token. The chunk and named entity tags
+    /// are stored as the `chunk` and `ner` MISC features.
+    pub fn conll2003() -> Self {
+        ColumnFormat {
+            columns: vec![
+                Column::Form,
+                Column::Layer(Layer::XPos),
+                Column::Layer(Layer::misc("chunk".to_string(), None)),
+                Column::Layer(Layer::misc("ner".to_string(), None)),
+            ],
+        }
+    }
+
+    /// Get the columns of the format.
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    fn parse_token(&self, line: &str) -> Result<Token, String> {
+        let fields: Vec<_> = if line.contains('\t') {
+            line.split('\t').collect()
+        } else {
+            line.split_whitespace().collect()
+        };
+
+        if fields.len() < self.columns.len() {
+            return Err(format!(
+                "expected {} columns, found {}",
+                self.columns.len(),
+                fields.len()
+            ));
+        }
+
+        let form = self
+            .columns
+            .iter()
+            .zip(&fields)
+            .find_map(|(column, &field)| match column {
+                Column::Form => Some(field),
+                _ => None,
+            })
+            .expect("Column format without form column");
+
+        let mut token = Token::new(form);
+        for (column, &field) in self.columns.iter().zip(&fields) {
+            if field != EMPTY_VALUE {
+                column.set_value(&mut token, field)?;
+            }
+        }
+
+        Ok(token)
+    }
+}
+
+impl fmt::Display for ColumnFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let columns = self
+            .columns
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{}", columns.join(","))
+    }
+}
+
+impl FromStr for ColumnFormat {
+    type Err = SyntaxDotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "conll2003" {
+            return Ok(ColumnFormat::conll2003());
+        }
+
+        ColumnFormat::new(
+            s.split(',')
+                .map(|column| column.trim().parse())
+                .collect::<Result<_, _>>()?,
+        )
+    }
+}
+
+/// A data set in a column-based format.
+pub struct ColumnDataSet<R> {
+    format: ColumnFormat,
+    read: R,
+}
+
+impl<R> ColumnDataSet<R> {
+    /// Construct a column-based data set with the given format.
+    pub fn new(read: R, format: ColumnFormat) -> Self {
+        ColumnDataSet { format, read }
+    }
+}
+
+impl<'a, R> DataSet<'a> for &'a mut ColumnDataSet<R>
+where
+    R: BufRead + Seek,
+{
+    type Iter = ColumnIter<'a, &'a mut R>;
+
+    fn sentences(self, tokenizer: &'a dyn Tokenize) -> Result<Self::Iter, SyntaxDotError> {
+        // Rewind to the beginning of the dataset (if necessary).
+        self.read.seek(SeekFrom::Start(0))?;
+
+        Ok(ColumnIter {
+            sentences: ColumnSentences::new(&mut self.read, self.format.clone()),
+            tokenizer,
+        })
+    }
+}
+
+pub struct ColumnIter<'a, R> {
+    sentences: ColumnSentences<R>,
+    tokenizer: &'a dyn Tokenize,
+}
+
+impl<'a, R> Iterator for ColumnIter<'a, R>
+where
+    R: BufRead,
+{
+    type Item = Result<SentenceWithPieces, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.sentences
+            .next()
+            .map(|s| s.map(|s| self.tokenizer.tokenize(s)))
+    }
+}
+
+/// Iterator over the sentences of a column-based reader.
+///
+/// Like [`ConlluSentences`](crate::dataset::ConlluSentences), the line
+/// on which each sentence starts is stored in the sentence. CoNLL-2003
+/// document separators (`-DOCSTART-`) are skipped.
+pub struct ColumnSentences<R> {
+    format: ColumnFormat,
+    position: LinePosition,
+    read: R,
+}
+
+impl<R> ColumnSentences<R>
+where
+    R: BufRead,
+{
+    /// Read sentences in the given format from `read`.
+    ///
+    /// Line numbers are counted from the current position of `read`.
+    pub fn new(read: R, format: ColumnFormat) -> Self {
+        ColumnSentences {
+            format,
+            position: LinePosition::default(),
+            read,
+        }
+    }
+}
+
+impl<R> Iterator for ColumnSentences<R>
+where
+    R: BufRead,
+{
+    type Item = Result<Sentence, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_column_sentence_with_line(&mut self.read, &self.format, &mut self.position).transpose()
+    }
+}
+
+/// Read a sentence in a column-based format.
+///
+/// The line on which the sentence starts is recorded in the sentence.
+pub(crate) fn read_column_sentence_with_line<R>(
+    read: &mut R,
+    format: &ColumnFormat,
+    position: &mut LinePosition,
+) -> Result<Option<Sentence>, SyntaxDotError>
+where
+    R: BufRead,
+{
+    let mut line = String::new();
+
+    loop {
+        position.sentence_start = None;
+
+        let mut tokens = Vec::new();
+        loop {
+            line.clear();
+            if read.read_line(&mut line)? == 0 {
+                break;
+            }
+            position.lines += 1;
+
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if line.trim().is_empty() {
+                if tokens.is_empty() {
+                    continue;
+                }
+                break;
+            }
+
+            position.sentence_start.get_or_insert(position.lines);
+
+            tokens.push(format.parse_token(line).map_err(|message| {
+                SyntaxDotError::ColumnFormatError {
+                    line: position.lines,
+                    message,
+                }
+            })?);
+        }
+
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+
+        if tokens[0].form() == DOCSTART {
+            continue;
+        }
+
+        let mut sentence = tokens.into_iter().collect::<Sentence>();
+        if let Some(line) = position.sentence_start {
+            set_source_line(&mut sentence, line);
+        }
+
+        return Ok(Some(sentence));
+    }
+}
+
+/// Writer for sentences in a column-based format.
+///
+/// Columns are separated by tabs. Columns that are not annotated or
+/// not used are written as `_`. Sentence comments are not written.
+pub struct ColumnWriter<W> {
+    format: ColumnFormat,
+    write: W,
+}
+
+impl<W> ColumnWriter<W>
+where
+    W: Write,
+{
+    /// Construct a writer for the given format.
+    pub fn new(write: W, format: ColumnFormat) -> Self {
+        ColumnWriter { format, write }
+    }
+}
+
+impl<W> WriteSentence for ColumnWriter<W>
+where
+    W: Write,
+{
+    fn write_sentence(&mut self, sentence: &Sentence) -> Result<(), conllu::IOError> {
+        for token in sentence.iter().filter_map(Node::token) {
+            let fields = self
+                .format
+                .columns
+                .iter()
+                .map(|column| {
+                    column
+                        .value(token)
+                        .unwrap_or_else(|| EMPTY_VALUE.to_string())
+                })
+                .collect::<Vec<_>>();
+            writeln!(self.write, "{}", fields.join("\t"))?;
+        }
+
+        writeln!(self.write)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use conllu::io::WriteSentence;
+    use syntaxdot_encoders::layer::{Layer, LayerValue};
+    use udgraph::graph::{Node, Sentence};
+
+    use crate::dataset::tests::{dataset_to_pieces, wordpiece_tokenizer, CORRECT_PIECE_IDS};
+    use crate::dataset::{
+        source_line, Column, ColumnDataSet, ColumnFormat, ColumnSentences, ColumnWriter,
+    };
+
+    const SENTENCES: &str = "-DOCSTART- -X- -X- O
+
+Dit\tB-PER
+is\tO
+de\tO
+eerste\tO
+zin\tO
+.\tO
+
+Dit O
+de O
+tweede O
+zin O
+. O
+
+En\tO
+nu\tO
+de\tO
+laatste\tO
+zin\tO
+.\t_
+";
+
+    fn ner_format() -> ColumnFormat {
+        "form,misc:ner".parse().unwrap()
+    }
+
+    fn read_sentences(data: &str, format: ColumnFormat) -> Vec<Sentence> {
+        ColumnSentences::new(BufReader::new(Cursor::new(data)), format)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn column_dataset_works() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut cursor = Cursor::new(SENTENCES);
+        let mut dataset = ColumnDataSet::new(BufReader::new(&mut cursor), ner_format());
+
+        let pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        assert_eq!(pieces, *CORRECT_PIECE_IDS);
+
+        // Verify that the data set is correctly read again.
+        let more_pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        assert_eq!(more_pieces, *CORRECT_PIECE_IDS);
+    }
+
+    #[test]
+    fn columns_are_mapped_to_layers() {
+        let sentences = read_sentences(SENTENCES, ner_format());
+        let ner = Layer::misc("ner".to_string(), None);
+
+        let tags = sentences[0]
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.value(&ner))
+            .collect::<Vec<_>>();
+        assert_eq!(tags[0], Some("B-PER".to_string()));
+        assert_eq!(tags[1], Some("O".to_string()));
+
+        // Empty values are not annotated.
+        let last_token = sentences[2].iter().filter_map(Node::token).last().unwrap();
+        assert_eq!(last_token.value(&ner), None);
+    }
+
+    #[test]
+    fn sentences_record_source_lines() {
+        let lines = read_sentences(SENTENCES, ner_format())
+            .iter()
+            .map(source_line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![Some(3), Some(10), Some(16)]);
+    }
+
+    #[test]
+    fn too_few_columns_are_rejected() {
+        let err = ColumnSentences::new(BufReader::new(Cursor::new("a\tb\nc\n")), ner_format())
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot read column data on line 2: expected 2 columns, found 1"
+        );
+    }
+
+    #[test]
+    fn format_round_trips_through_strings() {
+        let format: ColumnFormat = "form,lemma,upos,xpos,features,feature:case,misc:ner,_"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            format.to_string(),
+            "form,lemma,upos,xpos,features,feature:case,misc:ner,_"
+        );
+        assert_eq!(format.columns()[7], Column::Ignore);
+
+        assert_eq!(
+            "conll2003".parse::<ColumnFormat>().unwrap(),
+            ColumnFormat::conll2003()
+        );
+
+        assert!("upos,misc:ner".parse::<ColumnFormat>().is_err());
+        assert!("form,form".parse::<ColumnFormat>().is_err());
+        assert!("form,ner".parse::<ColumnFormat>().is_err());
+    }
+
+    #[test]
+    fn sentences_are_written_in_column_format() {
+        let format = ColumnFormat::conll2003();
+        let data = "EU NNP B-NP B-ORG\nrejects VBZ B-VP O\n\n";
+        let sentences = read_sentences(data, format.clone());
+
+        let mut output = Vec::new();
+        {
+            let mut writer = ColumnWriter::new(&mut output, format);
+            for sentence in &sentences {
+                writer.write_sentence(sentence).unwrap();
+            }
+        }
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "EU\tNNP\tB-NP\tB-ORG\nrejects\tVBZ\tB-VP\tO\n\n"
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use std::io::{BufRead, Seek, SeekFrom};
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use udgraph::graph::{Comment, Sentence};
+
+use crate::dataset::conll::LinePosition;
+use crate::dataset::{DataSet, SentenceFormat, SourceFiles};
+use crate::error::SyntaxDotError;
+
+/// Comment attribute that stores the treebank identifier of a sentence.
+pub const TREEBANK_ID_ATTR: &str = "treebank_id";
+
+/// Get the treebank identifier of a sentence.
+///
+/// The identifier is read from the `# treebank_id = ID` comment. `0` is
+/// returned for sentences without such a comment.
+pub fn treebank_id(sentence: &Sentence) -> Result<usize, SyntaxDotError> {
+    match sentence
+        .comments()
+        .iter()
+        .find_map(|comment| match comment {
+            Comment::AttrVal { attr, val } if attr == TREEBANK_ID_ATTR => Some(val),
+            _ => None,
+        }) {
+        Some(val) => val.trim().parse().map_err(|_| {
+            SyntaxDotError::IllegalConfigurationError(format!(
+                "Invalid treebank identifier: {}",
+                val
+            ))
+        }),
+        None => Ok(0),
+    }
+}
+
+/// Set the treebank identifier of a sentence.
+///
+/// The identifier is stored in a `# treebank_id = ID` comment, replacing
+/// an existing identifier.
+pub fn set_treebank_id(sentence: &mut Sentence, treebank_id: usize) {
+    let mut comments = sentence.comments().to_owned();
+    match comments.iter_mut().find(
+        |comment| matches!(comment, Comment::AttrVal { attr, .. } if attr == TREEBANK_ID_ATTR),
+    ) {
+        Some(Comment::AttrVal { val, .. }) => *val = treebank_id.to_string(),
+        _ => comments.push(Comment::AttrVal {
+            attr: TREEBANK_ID_ATTR.to_string(),
+            val: treebank_id.to_string(),
+        }),
+    }
+    sentence.set_comments(comments);
+}
+
+/// Sampling of the data sets in a `CompositeDataSet`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataSetSampling {
+    /// Sample data sets proportional to the given weights.
+    Weights(Vec<f64>),
+
+    /// Sample data sets proportional to `n^(1/temperature)`.
+    ///
+    /// `n` is the number of sentences in a data set. A temperature of
+    /// `1` samples data sets proportional to their sizes, higher
+    /// temperatures approach uniform sampling.
+    Temperature(f64),
+}
+
+/// A data set that interleaves several data sets.
+///
+/// A pass over the composite data set returns as many sentences as
+/// the data sets contain together. The data set of each sentence is
+/// sampled according to the sampling probabilities of the data sets.
+/// Data sets that are sampled more often than their size permits
+/// are cycled through again. Each pass continues where the earlier
+/// passes are expected to have stopped in each data set, given the
+/// sampling probabilities, so that sentences of downsampled data sets
+/// are not skipped.
+///
+/// Optionally, each sentence is tagged with the treebank identifier of
+/// its data set.
+pub struct CompositeDataSet<R> {
+    epoch: usize,
+    format: SentenceFormat,
+    n_sentences: Vec<usize>,
+    probs: Vec<f64>,
+    readers: Vec<R>,
+    seed: u64,
+    source_files: Option<Vec<SourceFiles>>,
+    treebank_ids: Option<Vec<usize>>,
+}
+
+impl<R> CompositeDataSet<R>
+where
+    R: BufRead + Seek,
+{
+    /// Construct a composite data set.
+    ///
+    /// The sentences of the data sets are counted during construction.
+    /// The interleaving of the data sets is determined by the `sampling`
+    /// strategy and the random number generator `seed`. The data sets
+    /// must be in CoNLL-U format.
+    pub fn new(
+        readers: Vec<R>,
+        sampling: DataSetSampling,
+        seed: u64,
+    ) -> Result<Self, SyntaxDotError> {
+        Self::new_with_format(readers, SentenceFormat::Conllu, sampling, seed)
+    }
+
+    /// Construct a composite data set of data sets in the given format.
+    ///
+    /// This constructor is the same as [`CompositeDataSet::new`], except
+    /// that the data sets are read in `format`.
+    pub fn new_with_format(
+        mut readers: Vec<R>,
+        format: SentenceFormat,
+        sampling: DataSetSampling,
+        seed: u64,
+    ) -> Result<Self, SyntaxDotError> {
+        if readers.is_empty() {
+            return Err(SyntaxDotError::IllegalConfigurationError(
+                "A composite data set requires at least one data set".to_string(),
+            ));
+        }
+
+        let n_sentences = readers
+            .iter_mut()
+            .map(|reader| count_sentences(reader, &format))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let weights = match sampling {
+            DataSetSampling::Weights(weights) => {
+                if weights.len() != readers.len() {
+                    return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                        "Got {} data set weights for {} data sets",
+                        weights.len(),
+                        readers.len()
+                    )));
+                }
+
+                if weights
+                    .iter()
+                    .any(|&weight| !weight.is_finite() || weight < 0.)
+                {
+                    return Err(SyntaxDotError::IllegalConfigurationError(
+                        "Data set weights must be non-negative".to_string(),
+                    ));
+                }
+
+                weights
+            }
+            DataSetSampling::Temperature(temperature) => {
+                if !temperature.is_finite() || temperature <= 0. {
+                    return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                        "Sampling temperature must be positive, was: {}",
+                        temperature
+                    )));
+                }
+
+                n_sentences
+                    .iter()
+                    .map(|&n| (n as f64).powf(temperature.recip()))
+                    .collect()
+            }
+        };
+
+        // Empty data sets cannot be sampled.
+        let weights = weights
+            .into_iter()
+            .zip(&n_sentences)
+            .map(|(weight, &n)| if n == 0 { 0. } else { weight })
+            .collect::<Vec<_>>();
+
+        let weight_sum: f64 = weights.iter().sum();
+        if weight_sum <= 0. {
+            return Err(SyntaxDotError::IllegalConfigurationError(
+                "None of the data sets can be sampled".to_string(),
+            ));
+        }
+
+        Ok(CompositeDataSet {
+            epoch: 0,
+            format,
+            n_sentences,
+            probs: weights.iter().map(|weight| weight / weight_sum).collect(),
+            readers,
+            seed,
+            source_files: None,
+            treebank_ids: None,
+        })
+    }
+
+    /// Tag sentences with treebank identifiers.
+    ///
+    /// Every sentence of the `i`-th data set is tagged with the
+    /// `i`-th treebank identifier.
+    pub fn with_treebank_ids(mut self, treebank_ids: Vec<usize>) -> Result<Self, SyntaxDotError> {
+        if treebank_ids.len() != self.readers.len() {
+            return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                "Got {} treebank identifiers for {} data sets",
+                treebank_ids.len(),
+                self.readers.len()
+            )));
+        }
+
+        self.treebank_ids = Some(treebank_ids);
+
+        Ok(self)
+    }
+
+    /// Map sentence lines to the files of the data sets.
+    ///
+    /// The line of each sentence of the `i`-th data set is mapped using
+    /// the `i`-th source files (see [`SourceFiles::set_source_file`]).
+    pub fn with_source_files(
+        mut self,
+        source_files: Vec<SourceFiles>,
+    ) -> Result<Self, SyntaxDotError> {
+        if source_files.len() != self.readers.len() {
+            return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                "Got {} source files for {} data sets",
+                source_files.len(),
+                self.readers.len()
+            )));
+        }
+
+        self.source_files = Some(source_files);
+
+        Ok(self)
+    }
+
+    /// The total number of sentences in the data sets.
+    ///
+    /// This is also the number of sentences in a pass over the data set.
+    pub fn n_sentences(&self) -> usize {
+        self.n_sentences.iter().sum()
+    }
+
+    /// The sampling probabilities of the data sets.
+    pub fn probs(&self) -> &[f64] {
+        &self.probs
+    }
+
+    /// Set the epoch of the next pass over the data set.
+    ///
+    /// The epoch is incremented after every pass. Setting the epoch
+    /// explicitly is useful when training is resumed.
+    pub fn set_epoch(&mut self, epoch: usize) {
+        self.epoch = epoch;
+    }
+
+    /// Get the random number generator of the current epoch.
+    ///
+    /// The generator is seeded from the seed and the epoch, so that the
+    /// sampling of an epoch does not depend on earlier epochs.
+    fn epoch_rng(&self) -> XorShiftRng {
+        XorShiftRng::seed_from_u64(self.seed.wrapping_add(self.epoch as u64))
+    }
+}
+
+impl<'a, R> DataSet<'a> for &'a mut CompositeDataSet<R>
+where
+    R: BufRead + Seek,
+{
+    type Iter = CompositeIter<'a, R>;
+
+    fn sentences(self, tokenizer: &'a dyn Tokenize) -> Result<Self::Iter, SyntaxDotError> {
+        let dist = WeightedIndex::new(&self.probs).map_err(|err| {
+            SyntaxDotError::IllegalConfigurationError(format!(
+                "Invalid data set sampling probabilities: {}",
+                err
+            ))
+        })?;
+
+        // Continue each data set after the number of sentences that
+        // earlier epochs are expected to have sampled from it. This only
+        // depends on the epoch, so resumed training reads the same
+        // sentences as uninterrupted training.
+        let n_sentences = self.n_sentences();
+        let n_sampled = self
+            .probs
+            .iter()
+            .map(|&prob| (self.epoch as f64 * n_sentences as f64 * prob).round() as usize)
+            .collect::<Vec<_>>();
+
+        let mut positions = vec![LinePosition::default(); self.readers.len()];
+        for (((reader, position), n_sampled), &n) in self
+            .readers
+            .iter_mut()
+            .zip(&mut positions)
+            .zip(n_sampled)
+            .zip(&self.n_sentences)
+        {
+            reader.seek(SeekFrom::Start(0))?;
+            if n != 0 {
+                for _ in 0..n_sampled % n {
+                    self.format.read_sentence_with_line(reader, position)?;
+                }
+            }
+        }
+
+        let rng = self.epoch_rng();
+        self.epoch += 1;
+
+        Ok(CompositeIter {
+            dist,
+            format: &self.format,
+            n_remaining: n_sentences,
+            positions,
+            readers: &mut self.readers,
+            rng,
+            source_files: self.source_files.as_deref(),
+            tokenizer,
+            treebank_ids: self.treebank_ids.as_deref(),
+        })
+    }
+}
+
+/// Iterator over the sentences of a `CompositeDataSet`.
+pub struct CompositeIter<'a, R> {
+    dist: WeightedIndex<f64>,
+    format: &'a SentenceFormat,
+    n_remaining: usize,
+    positions: Vec<LinePosition>,
+    readers: &'a mut [R],
+    rng: XorShiftRng,
+    source_files: Option<&'a [SourceFiles]>,
+    tokenizer: &'a dyn Tokenize,
+    treebank_ids: Option<&'a [usize]>,
+}
+
+impl<'a, R> CompositeIter<'a, R>
+where
+    R: BufRead + Seek,
+{
+    fn read_sentence(&mut self, data_set: usize) -> Result<Sentence, SyntaxDotError> {
+        let reader = &mut self.readers[data_set];
+        let position = &mut self.positions[data_set];
+
+        if let Some(sentence) = self.format.read_sentence_with_line(reader, position)? {
+            return Ok(sentence);
+        }
+
+        // Cycle through the data set again.
+        reader.seek(SeekFrom::Start(0))?;
+        *position = LinePosition::default();
+        self.format
+            .read_sentence_with_line(reader, position)?
+            .ok_or_else(|| {
+                SyntaxDotError::IllegalConfigurationError(format!(
+                    "Data set {} does not contain any sentences",
+                    data_set
+                ))
+            })
+    }
+}
+
+impl<'a, R> Iterator for CompositeIter<'a, R>
+where
+    R: BufRead + Seek,
+{
+    type Item = Result<SentenceWithPieces, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.n_remaining == 0 {
+            return None;
+        }
+        self.n_remaining -= 1;
+
+        let data_set = self.dist.sample(&mut self.rng);
+        let mut sentence = match self.read_sentence(data_set) {
+            Ok(sentence) => sentence,
+            Err(err) => return Some(Err(err)),
+        };
+
+        if let Some(source_files) = self.source_files {
+            source_files[data_set].set_source_file(&mut sentence);
+        }
+
+        if let Some(treebank_ids) = self.treebank_ids {
+            set_treebank_id(&mut sentence, treebank_ids[data_set]);
+        }
+
+        Some(Ok(self.tokenizer.tokenize(sentence)))
+    }
+}
+
+fn count_sentences<R>(reader: &mut R, format: &SentenceFormat) -> Result<usize, SyntaxDotError>
+where
+    R: BufRead + Seek,
+{
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut n_sentences = 0;
+    for sentence in format.sentences(&mut *reader) {
+        sentence?;
+        n_sentences += 1;
+    }
+
+    Ok(n_sentences)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use approx::assert_abs_diff_eq;
+
+    use super::{treebank_id, CompositeDataSet, DataSetSampling};
+    use crate::dataset::tests::{dataset_to_pieces, wordpiece_tokenizer, CORRECT_PIECE_IDS};
+    use crate::dataset::DataSet;
+
+    const SENTENCES: &str = r#"
+1	Dit
+2	is
+3	de
+4	eerste
+5	zin
+6	.
+
+1	Dit
+2	de
+3	tweede
+4	zin
+5	.
+
+1	En
+2	nu
+3	de
+4	laatste
+5	zin
+6	."#;
+
+    const MORE_SENTENCES: &str = r#"
+1	Nu
+2	de
+3	zin
+4	.
+"#;
+
+    const COLUMN_SENTENCES: &str = r#"
+Dit
+is
+de
+eerste
+zin
+.
+
+Dit
+de
+tweede
+zin
+.
+
+En
+nu
+de
+laatste
+zin
+."#;
+
+    fn readers() -> Vec<BufReader<Cursor<&'static str>>> {
+        vec![
+            BufReader::new(Cursor::new(SENTENCES)),
+            BufReader::new(Cursor::new(MORE_SENTENCES)),
+        ]
+    }
+
+    #[test]
+    fn single_data_set_is_read_in_order() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut dataset = CompositeDataSet::new(
+            vec![BufReader::new(Cursor::new(SENTENCES))],
+            DataSetSampling::Temperature(1.),
+            42,
+        )
+        .unwrap();
+
+        let pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        assert_eq!(pieces, *CORRECT_PIECE_IDS);
+
+        let more_pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        assert_eq!(more_pieces, *CORRECT_PIECE_IDS);
+    }
+
+    #[test]
+    fn data_sets_are_read_in_format() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut dataset = CompositeDataSet::new_with_format(
+            vec![BufReader::new(Cursor::new(COLUMN_SENTENCES))],
+            "form".parse().unwrap(),
+            DataSetSampling::Temperature(1.),
+            42,
+        )
+        .unwrap();
+        assert_eq!(dataset.n_sentences(), 3);
+
+        let pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        assert_eq!(pieces, *CORRECT_PIECE_IDS);
+    }
+
+    #[test]
+    fn sentences_are_tagged_with_treebank_ids() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut dataset = CompositeDataSet::new(readers(), DataSetSampling::Temperature(1.), 42)
+            .unwrap()
+            .with_treebank_ids(vec![1, 2])
+            .unwrap();
+        assert_eq!(dataset.n_sentences(), 4);
+
+        for _ in 0..3 {
+            let sentences = dataset
+                .sentences(&tokenizer)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(sentences.len(), 4);
+
+            for sentence in sentences {
+                let form = sentence.sentence[1].token().unwrap().form();
+                let expected_id = if form == "Nu" { 2 } else { 1 };
+                assert_eq!(treebank_id(&sentence.sentence).unwrap(), expected_id);
+            }
+        }
+    }
+
+    #[test]
+    fn resumed_epoch_reads_same_sentences() {
+        let tokenizer = wordpiece_tokenizer();
+
+        let mut dataset =
+            CompositeDataSet::new(readers(), DataSetSampling::Temperature(1.), 42).unwrap();
+        let mut pieces = Vec::new();
+        for _ in 0..3 {
+            pieces = dataset_to_pieces(&mut dataset, &tokenizer).unwrap();
+        }
+
+        let mut resumed =
+            CompositeDataSet::new(readers(), DataSetSampling::Temperature(1.), 42).unwrap();
+        resumed.set_epoch(2);
+        assert_eq!(dataset_to_pieces(&mut resumed, &tokenizer).unwrap(), pieces);
+    }
+
+    #[test]
+    fn temperature_flattens_sampling_probabilities() {
+        let dataset =
+            CompositeDataSet::new(readers(), DataSetSampling::Temperature(2.), 42).unwrap();
+        let sqrt3 = 3f64.sqrt();
+        assert_abs_diff_eq!(dataset.probs()[0], sqrt3 / (sqrt3 + 1.), epsilon = 1e-6);
+        assert_abs_diff_eq!(dataset.probs()[1], 1. / (sqrt3 + 1.), epsilon = 1e-6);
+
+        assert!(CompositeDataSet::new(readers(), DataSetSampling::Weights(vec![1.]), 42).is_err());
+        assert!(
+            CompositeDataSet::new(readers(), DataSetSampling::Weights(vec![0., 0.]), 42).is_err()
+        );
+    }
+}
@@ -1,11 +1,118 @@
-use std::io::{BufRead, Seek, SeekFrom};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
 
-use conllu::io::{ReadSentence, Reader, Sentences};
+use conllu::io::{ReadSentence, Reader};
 use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use udgraph::graph::{Comment, Sentence};
 
 use crate::dataset::DataSet;
 use crate::error::SyntaxDotError;
 
+/// Comment attribute that stores the line on which a sentence starts.
+pub const SOURCE_LINE_ATTR: &str = "source_line";
+
+/// Comment attribute that stores the file from which a sentence was read.
+pub const SOURCE_FILE_ATTR: &str = "source_file";
+
+/// Get the line on which a sentence starts in its data set.
+///
+/// The line is read from the `# source_line = N` comment that is added
+/// by CoNLL-U data sets. `None` is returned for sentences without such
+/// a comment.
+pub fn source_line(sentence: &Sentence) -> Option<usize> {
+    sentence
+        .comments()
+        .iter()
+        .find_map(|comment| match comment {
+            Comment::AttrVal { attr, val } if attr == SOURCE_LINE_ATTR => val.trim().parse().ok(),
+            _ => None,
+        })
+}
+
+pub(crate) fn set_source_line(sentence: &mut Sentence, line: usize) {
+    let mut comments = sentence.comments().to_owned();
+    comments.retain(
+        |comment| !matches!(comment, Comment::AttrVal { attr, .. } if attr == SOURCE_LINE_ATTR),
+    );
+    comments.push(Comment::AttrVal {
+        attr: SOURCE_LINE_ATTR.to_string(),
+        val: line.to_string(),
+    });
+    sentence.set_comments(comments);
+}
+
+/// Get the file from which a sentence was read.
+///
+/// The file is read from the `# source_file = PATH` comment that is
+/// added by [`SourceFiles::set_source_file`]. `None` is returned for
+/// sentences without such a comment.
+pub fn source_file(sentence: &Sentence) -> Option<&str> {
+    sentence
+        .comments()
+        .iter()
+        .find_map(|comment| match comment {
+            Comment::AttrVal { attr, val } if attr == SOURCE_FILE_ATTR => Some(val.as_str()),
+            _ => None,
+        })
+}
+
+/// Files of a reader that concatenates several files.
+///
+/// The reader records the number of lines that precede each file. The
+/// line of a sentence in the concatenation can then be mapped to the
+/// file and the line within that file. Clones share the recorded files.
+#[derive(Clone, Debug, Default)]
+pub struct SourceFiles {
+    files: Arc<Mutex<Vec<(String, usize)>>>,
+}
+
+impl SourceFiles {
+    /// Record that the `index`-th file is preceded by `line_offset` lines.
+    ///
+    /// Files must be recorded in order. Recording a file again, e.g.
+    /// after the reader was rewound, does not change the recorded files.
+    pub fn add_file(&self, index: usize, file: impl Into<String>, line_offset: usize) {
+        let mut files = self.files.lock().expect("Source files lock is poisoned");
+        if index == files.len() {
+            files.push((file.into(), line_offset));
+        }
+    }
+
+    /// Get the file and the line within that file of `line`.
+    pub fn locate(&self, line: usize) -> Option<(String, usize)> {
+        let files = self.files.lock().expect("Source files lock is poisoned");
+        files
+            .iter()
+            .rev()
+            .find(|(_, line_offset)| *line_offset < line)
+            .map(|(file, line_offset)| (file.clone(), line - line_offset))
+    }
+
+    /// Store the file and the line within that file in a sentence.
+    ///
+    /// The source line of the sentence (see [`source_line`]) is replaced
+    /// by the line within its file and the file is stored in a
+    /// `# source_file = PATH` comment.
+    pub fn set_source_file(&self, sentence: &mut Sentence) {
+        let (file, line) = match source_line(sentence).and_then(|line| self.locate(line)) {
+            Some(location) => location,
+            None => return,
+        };
+
+        set_source_line(sentence, line);
+
+        let mut comments = sentence.comments().to_owned();
+        comments.retain(
+            |comment| !matches!(comment, Comment::AttrVal { attr, .. } if attr == SOURCE_FILE_ATTR),
+        );
+        comments.push(Comment::AttrVal {
+            attr: SOURCE_FILE_ATTR.to_string(),
+            val: file,
+        });
+        sentence.set_comments(comments);
+    }
+}
+
 /// A CoNLL-X data set.
 pub struct ConlluDataSet<R>(R);
 
@@ -20,40 +127,154 @@ impl<'a, R> DataSet<'a> for &'a mut ConlluDataSet<R>
 where
     R: BufRead + Seek,
 {
-    type Iter = ConllIter<'a, Reader<&'a mut R>>;
+    type Iter = ConllIter<'a, &'a mut R>;
 
     fn sentences(self, tokenizer: &'a dyn Tokenize) -> Result<Self::Iter, SyntaxDotError> {
         // Rewind to the beginning of the dataset (if necessary).
         self.0.seek(SeekFrom::Start(0))?;
 
-        let reader = Reader::new(&mut self.0);
-
         Ok(ConllIter {
-            sentences: reader.sentences(),
+            sentences: ConlluSentences::new(&mut self.0),
             tokenizer,
         })
     }
 }
 
-pub struct ConllIter<'a, R>
-where
-    R: ReadSentence,
-{
-    sentences: Sentences<R>,
+pub struct ConllIter<'a, R> {
+    sentences: ConlluSentences<R>,
     tokenizer: &'a dyn Tokenize,
 }
 
 impl<'a, R> Iterator for ConllIter<'a, R>
 where
-    R: ReadSentence,
+    R: BufRead,
 {
     type Item = Result<SentenceWithPieces, SyntaxDotError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.sentences.next().map(|s| {
-            s.map(|s| self.tokenizer.tokenize(s))
-                .map_err(SyntaxDotError::ConlluIoError)
-        })
+        self.sentences
+            .next()
+            .map(|s| s.map(|s| self.tokenizer.tokenize(s)))
+    }
+}
+
+/// Iterator over the sentences of a CoNLL-U reader.
+///
+/// The line on which each sentence starts is stored in the sentence
+/// (see [`source_line`]), so that problems with a sentence can be
+/// reported with its position.
+pub struct ConlluSentences<R> {
+    read: R,
+    position: LinePosition,
+}
+
+impl<R> ConlluSentences<R>
+where
+    R: BufRead,
+{
+    /// Read sentences from `read`.
+    ///
+    /// Line numbers are counted from the current position of `read`.
+    pub fn new(read: R) -> Self {
+        ConlluSentences {
+            read,
+            position: LinePosition::default(),
+        }
+    }
+}
+
+impl<R> Iterator for ConlluSentences<R>
+where
+    R: BufRead,
+{
+    type Item = Result<Sentence, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_sentence_with_line(&mut self.read, &mut self.position).transpose()
+    }
+}
+
+/// Read a sentence, recording the line on which it starts.
+pub(crate) fn read_sentence_with_line<R>(
+    read: &mut R,
+    position: &mut LinePosition,
+) -> Result<Option<Sentence>, SyntaxDotError>
+where
+    R: BufRead,
+{
+    position.sentence_start = None;
+
+    let sentence = Reader::new(LineCounter {
+        inner: read,
+        position: &mut *position,
+    })
+    .read_sentence()
+    .map_err(SyntaxDotError::ConlluIoError)?;
+
+    Ok(sentence.map(|mut sentence| {
+        if let Some(line) = position.sentence_start {
+            set_source_line(&mut sentence, line);
+        }
+        sentence
+    }))
+}
+
+/// Line position in a data set.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct LinePosition {
+    /// The number of lines that were read.
+    pub(crate) lines: usize,
+
+    /// The first line with content since the start of a sentence.
+    pub(crate) sentence_start: Option<usize>,
+}
+
+impl LinePosition {
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte == b'\n' {
+                self.lines += 1;
+            } else if self.sentence_start.is_none() && !byte.is_ascii_whitespace() {
+                self.sentence_start = Some(self.lines + 1);
+            }
+        }
+    }
+}
+
+/// Reader that keeps track of the line position.
+struct LineCounter<'a, R> {
+    inner: &'a mut R,
+    position: &'a mut LinePosition,
+}
+
+impl<'a, R> Read for LineCounter<'a, R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<'a, R> BufRead for LineCounter<'a, R>
+where
+    R: BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt != 0 {
+            // The data to consume is still buffered, so this does not read.
+            if let Ok(buf) = self.inner.fill_buf() {
+                self.position.update(&buf[..amt.min(buf.len())]);
+            }
+        }
+
+        self.inner.consume(amt)
     }
 }
 
@@ -62,7 +283,7 @@ mod tests {
     use std::io::{BufReader, Cursor};
 
     use crate::dataset::tests::{dataset_to_pieces, wordpiece_tokenizer, CORRECT_PIECE_IDS};
-    use crate::dataset::ConlluDataSet;
+    use crate::dataset::{source_file, source_line, ConlluDataSet, ConlluSentences, SourceFiles};
 
     const SENTENCES: &str = r#"
 1	Dit
@@ -85,6 +306,43 @@ mod tests {
 5	zin
 6	."#;
 
+    #[test]
+    fn sentences_record_source_lines() {
+        let sentences = ConlluSentences::new(BufReader::new(Cursor::new(SENTENCES)))
+            .map(|sentence| source_line(&sentence.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(sentences, vec![Some(2), Some(9), Some(15)]);
+    }
+
+    #[test]
+    fn source_files_map_lines_to_files() {
+        let source_files = SourceFiles::default();
+        source_files.add_file(0, "first.conllu", 0);
+        source_files.add_file(1, "second.conllu", 8);
+        // Rewinding the reader records the files again.
+        source_files.add_file(0, "first.conllu", 0);
+
+        assert_eq!(
+            source_files.locate(2),
+            Some(("first.conllu".to_string(), 2))
+        );
+        assert_eq!(
+            source_files.locate(8),
+            Some(("first.conllu".to_string(), 8))
+        );
+        assert_eq!(
+            source_files.locate(9),
+            Some(("second.conllu".to_string(), 1))
+        );
+
+        let mut sentences = ConlluSentences::new(BufReader::new(Cursor::new(SENTENCES)))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        source_files.set_source_file(&mut sentences[1]);
+        assert_eq!(source_line(&sentences[1]), Some(1));
+        assert_eq!(source_file(&sentences[1]), Some("second.conllu"));
+    }
+
     #[test]
     fn plain_text_dataset_works() {
         let tokenizer = wordpiece_tokenizer();
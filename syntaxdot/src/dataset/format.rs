@@ -0,0 +1,190 @@
+use std::fmt;
+use std::io::{BufRead, Seek};
+use std::str::FromStr;
+
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use udgraph::graph::Sentence;
+
+use crate::dataset::column::{read_column_sentence_with_line, ColumnIter};
+use crate::dataset::conll::{read_sentence_with_line, ConllIter, LinePosition};
+use crate::dataset::{
+    ColumnDataSet, ColumnFormat, ColumnSentences, ConlluDataSet, ConlluSentences, DataSet,
+};
+use crate::error::SyntaxDotError;
+
+/// Format of annotated sentences.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SentenceFormat {
+    /// CoNLL-U.
+    Conllu,
+
+    /// A column-based format, such as the CoNLL-2003 format.
+    Column(ColumnFormat),
+}
+
+impl SentenceFormat {
+    /// Get a data set that reads sentences in this format from `read`.
+    pub fn data_set<R>(&self, read: R) -> FormatDataSet<R> {
+        match self {
+            SentenceFormat::Conllu => FormatDataSet::Conllu(ConlluDataSet::new(read)),
+            SentenceFormat::Column(format) => {
+                FormatDataSet::Column(ColumnDataSet::new(read, format.clone()))
+            }
+        }
+    }
+
+    /// Get an iterator over the sentences in this format in `read`.
+    pub fn sentences<R>(&self, read: R) -> FormatSentences<R>
+    where
+        R: BufRead,
+    {
+        match self {
+            SentenceFormat::Conllu => FormatSentences::Conllu(ConlluSentences::new(read)),
+            SentenceFormat::Column(format) => {
+                FormatSentences::Column(ColumnSentences::new(read, format.clone()))
+            }
+        }
+    }
+
+    /// Read a sentence, recording the line on which it starts.
+    pub(crate) fn read_sentence_with_line<R>(
+        &self,
+        read: &mut R,
+        position: &mut LinePosition,
+    ) -> Result<Option<Sentence>, SyntaxDotError>
+    where
+        R: BufRead,
+    {
+        match self {
+            SentenceFormat::Conllu => read_sentence_with_line(read, position),
+            SentenceFormat::Column(format) => {
+                read_column_sentence_with_line(read, format, position)
+            }
+        }
+    }
+}
+
+impl Default for SentenceFormat {
+    fn default() -> Self {
+        SentenceFormat::Conllu
+    }
+}
+
+impl fmt::Display for SentenceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SentenceFormat::Conllu => write!(f, "conllu"),
+            SentenceFormat::Column(format) => write!(f, "{}", format),
+        }
+    }
+}
+
+impl FromStr for SentenceFormat {
+    type Err = SyntaxDotError;
+
+    /// Parse a sentence format.
+    ///
+    /// The format is either `conllu` or a column format (see
+    /// [`ColumnFormat`]).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "conllu" => Ok(SentenceFormat::Conllu),
+            _ => s.parse().map(SentenceFormat::Column),
+        }
+    }
+}
+
+/// A data set in one of the supported sentence formats.
+pub enum FormatDataSet<R> {
+    Conllu(ConlluDataSet<R>),
+    Column(ColumnDataSet<R>),
+}
+
+impl<'a, R> DataSet<'a> for &'a mut FormatDataSet<R>
+where
+    R: BufRead + Seek,
+{
+    type Iter = FormatIter<'a, &'a mut R>;
+
+    fn sentences(self, tokenizer: &'a dyn Tokenize) -> Result<Self::Iter, SyntaxDotError> {
+        match self {
+            FormatDataSet::Conllu(data_set) => {
+                data_set.sentences(tokenizer).map(FormatIter::Conllu)
+            }
+            FormatDataSet::Column(data_set) => {
+                data_set.sentences(tokenizer).map(FormatIter::Column)
+            }
+        }
+    }
+}
+
+pub enum FormatIter<'a, R> {
+    Conllu(ConllIter<'a, R>),
+    Column(ColumnIter<'a, R>),
+}
+
+impl<'a, R> Iterator for FormatIter<'a, R>
+where
+    R: BufRead,
+{
+    type Item = Result<SentenceWithPieces, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            FormatIter::Conllu(iter) => iter.next(),
+            FormatIter::Column(iter) => iter.next(),
+        }
+    }
+}
+
+/// Iterator over the sentences in one of the supported formats.
+pub enum FormatSentences<R> {
+    Conllu(ConlluSentences<R>),
+    Column(ColumnSentences<R>),
+}
+
+impl<R> Iterator for FormatSentences<R>
+where
+    R: BufRead,
+{
+    type Item = Result<Sentence, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            FormatSentences::Conllu(sentences) => sentences.next(),
+            FormatSentences::Column(sentences) => sentences.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use crate::dataset::{ColumnFormat, SentenceFormat};
+
+    #[test]
+    fn format_round_trips_through_strings() {
+        for format in &[
+            SentenceFormat::Conllu,
+            SentenceFormat::Column(ColumnFormat::conll2003()),
+        ] {
+            assert_eq!(
+                format.to_string().parse::<SentenceFormat>().unwrap(),
+                *format
+            );
+        }
+
+        assert!("conll".parse::<SentenceFormat>().is_err());
+    }
+
+    #[test]
+    fn sentences_are_read_in_format() {
+        let format: SentenceFormat = "form,upos".parse().unwrap();
+        let sentences = format
+            .sentences(BufReader::new(Cursor::new("Hallo\tINTJ\n\nWelt\tNOUN\n")))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(sentences.len(), 2);
+    }
+}
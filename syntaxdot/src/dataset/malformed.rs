@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+
+use udgraph::graph::Sentence;
+
+use crate::dataset::{source_file, source_line, treebank_id};
+use crate::error::SyntaxDotError;
+
+/// Handling of sentences whose annotations cannot be encoded.
+///
+/// A sentence is malformed when one of its annotation layers cannot be
+/// encoded, for instance because a token does not have a head or has
+/// an unknown dependency relation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MalformedSentences {
+    /// Fail on the first malformed sentence.
+    Fail,
+
+    /// Skip malformed sentences, logging a warning for each.
+    SkipWithWarning,
+
+    /// Mask annotations that cannot be encoded in the loss.
+    ///
+    /// Missing dependency heads and relations, layer values, and
+    /// sentence comments are masked per token, so that partially
+    /// annotated corpora can be used for training. Other annotations
+    /// are masked for the whole sentence when they cannot be encoded,
+    /// logging a warning.
+    MaskLabels,
+}
+
+impl Default for MalformedSentences {
+    fn default() -> Self {
+        MalformedSentences::Fail
+    }
+}
+
+impl fmt::Display for MalformedSentences {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MalformedSentences::*;
+
+        match self {
+            Fail => write!(f, "fail"),
+            SkipWithWarning => write!(f, "skip-with-warning"),
+            MaskLabels => write!(f, "mask-labels"),
+        }
+    }
+}
+
+impl FromStr for MalformedSentences {
+    type Err = SyntaxDotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(MalformedSentences::Fail),
+            "skip-with-warning" => Ok(MalformedSentences::SkipWithWarning),
+            "mask-labels" => Ok(MalformedSentences::MaskLabels),
+            _ => Err(SyntaxDotError::IllegalConfigurationError(format!(
+                "Unknown malformed sentence policy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl MalformedSentences {
+    /// Handle a sentence with annotations that cannot be encoded.
+    ///
+    /// Returns an error with the position of the sentence when
+    /// sentences should not be skipped or masked. Otherwise, a warning
+    /// is logged and `true` is returned when the labels of the sentence
+    /// should be masked, `false` when the sentence should be skipped.
+    pub fn handle(self, sentence: &Sentence, err: SyntaxDotError) -> Result<bool, SyntaxDotError> {
+        let position = sentence_position(sentence);
+
+        match self {
+            MalformedSentences::Fail => Err(SyntaxDotError::MalformedSentence {
+                position,
+                source: Box::new(err),
+            }),
+            MalformedSentences::SkipWithWarning => {
+                log::warn!("Skipping sentence on {}: {}", position, err);
+                Ok(false)
+            }
+            MalformedSentences::MaskLabels => {
+                log::warn!("Masking labels of sentence on {}: {}", position, err);
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Describe the position of a sentence for error messages.
+///
+/// The position consists of the line on which the sentence starts, the
+/// file that contains the sentence and the treebank of the sentence, as
+/// far as they are known.
+pub fn sentence_position(sentence: &Sentence) -> String {
+    let mut position = match source_line(sentence) {
+        Some(line) => format!("line {}", line),
+        None => "unknown line".to_string(),
+    };
+
+    if let Some(file) = source_file(sentence) {
+        position.push_str(&format!(" of {}", file));
+    }
+
+    match treebank_id(sentence) {
+        Ok(treebank_id) if treebank_id != 0 => {
+            position.push_str(&format!(" (treebank {})", treebank_id))
+        }
+        _ => (),
+    }
+
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{Comment, Sentence};
+    use udgraph::token::Token;
+
+    use super::{sentence_position, MalformedSentences};
+    use crate::dataset::{set_treebank_id, SourceFiles, SOURCE_LINE_ATTR};
+
+    #[test]
+    fn policy_round_trips_through_strings() {
+        for policy in &[
+            MalformedSentences::Fail,
+            MalformedSentences::SkipWithWarning,
+            MalformedSentences::MaskLabels,
+        ] {
+            assert_eq!(
+                policy.to_string().parse::<MalformedSentences>().unwrap(),
+                *policy
+            );
+        }
+
+        assert!("skip".parse::<MalformedSentences>().is_err());
+    }
+
+    #[test]
+    fn sentence_position_includes_line_file_and_treebank() {
+        let mut sentence: Sentence = vec![Token::new("Hallo")].into_iter().collect();
+        assert_eq!(sentence_position(&sentence), "unknown line");
+
+        sentence.set_comments(vec![Comment::AttrVal {
+            attr: SOURCE_LINE_ATTR.to_string(),
+            val: "42".to_string(),
+        }]);
+        assert_eq!(sentence_position(&sentence), "line 42");
+
+        set_treebank_id(&mut sentence, 2);
+        assert_eq!(sentence_position(&sentence), "line 42 (treebank 2)");
+
+        let source_files = SourceFiles::default();
+        source_files.add_file(0, "train/a.conllu", 0);
+        source_files.add_file(1, "train/b.conllu", 40);
+        source_files.set_source_file(&mut sentence);
+        assert_eq!(
+            sentence_position(&sentence),
+            "line 2 of train/b.conllu (treebank 2)"
+        );
+    }
+}
@@ -4,17 +4,44 @@ use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
 
 use crate::error::SyntaxDotError;
 
+mod augment;
+pub use augment::{Augmentation, Augmented};
+
+mod cache;
+pub use cache::{is_cached_data_set, CachedBatches, CachedDataSet, CachedDataSetWriter};
+
+mod column;
+pub use column::{Column, ColumnDataSet, ColumnFormat, ColumnSentences, ColumnWriter};
+
+mod composite;
+pub use composite::{
+    set_treebank_id, treebank_id, CompositeDataSet, CompositeIter, DataSetSampling,
+    TREEBANK_ID_ATTR,
+};
+
 mod conll;
-pub use conll::ConlluDataSet;
+pub use conll::{
+    source_file, source_line, ConlluDataSet, ConlluSentences, SourceFiles, SOURCE_FILE_ATTR,
+    SOURCE_LINE_ATTR,
+};
+
+mod format;
+pub use format::{FormatDataSet, FormatIter, FormatSentences, SentenceFormat};
+
+mod malformed;
+pub use malformed::{sentence_position, MalformedSentences};
 
 mod plaintext;
 pub use plaintext::PlainTextDataSet;
 
 pub(crate) mod tensor_iter;
-pub use tensor_iter::BatchedTensors;
+pub use tensor_iter::{
+    batch_tensors, encode_sentence, encoded_batch_tensors, BatchedTensors, EncodedSentence,
+    IntoTensors,
+};
 
 mod sentence_itertools;
-pub use sentence_itertools::{SentenceIterTools, SequenceLength};
+pub use sentence_itertools::{BatchSize, SentenceIterTools, SequenceLength};
 
 /// A data set consisting of annotated or unannotated sentences.
 ///
@@ -1,7 +1,12 @@
+use std::cmp;
+use std::iter::Peekable;
+
+use rand::seq::SliceRandom;
 use rand::SeedableRng;
 use rand_xorshift::XorShiftRng;
-use syntaxdot_tokenizers::SentenceWithPieces;
+use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
 
+use crate::dataset::{Augmentation, Augmented};
 use crate::error::SyntaxDotError;
 use crate::util::RandomRemoveVec;
 
@@ -16,8 +21,69 @@ pub enum SequenceLength {
     Unbounded,
 }
 
+/// The size of a batch.
+///
+/// This enum can be used to express the size of a batch in sentences
+/// or as a budget of pieces. The piece budget includes the padding
+/// of sentences to the length of the longest sentence in the batch.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BatchSize {
+    Sentences(usize),
+    Pieces(usize),
+}
+
+impl BatchSize {
+    /// Check whether a batch with the given number of sentences and
+    /// maximum sentence length in pieces fits in the batch size.
+    pub(crate) fn fits(self, n_sentences: usize, max_seq_len: usize) -> bool {
+        match self {
+            BatchSize::Sentences(batch_size) => n_sentences <= batch_size,
+            BatchSize::Pieces(max_pieces) => n_sentences * max_seq_len <= max_pieces,
+        }
+    }
+}
+
 /// Trait providing adapters for `SentenceWithPieces` iterators.
 pub trait SentenceIterTools<'a>: Sized {
+    /// Augment sentences on the fly.
+    ///
+    /// The forms of the sentences are perturbed according to
+    /// `augmentation` and split into pieces with `tokenizer`. Only the
+    /// pieces are replaced, the annotations of the sentences are not
+    /// changed. The random number generator is seeded with `seed`.
+    fn augment(
+        self,
+        augmentation: Augmentation,
+        tokenizer: &'a dyn Tokenize,
+        seed: u64,
+    ) -> Augmented<'a, Self>;
+
+    /// Group sentences into batches.
+    ///
+    /// A batch is completed when adding the next sentence would exceed
+    /// `batch_size`. A sentence that exceeds a piece budget by itself
+    /// is put in a singleton batch.
+    fn batched(self, batch_size: BatchSize) -> Batched<Self>
+    where
+        Self: Iterator;
+
+    /// Shuffle sentences with length bucketing.
+    ///
+    /// Sentences are read into a shuffle buffer of `buffer_size`
+    /// sentences. The sentences in the buffer are sorted by length and
+    /// grouped into batches of `batch_size`. The batches of the buffer
+    /// are then returned in random order. This results in batches of
+    /// sentences with similar lengths, which minimizes padding.
+    ///
+    /// The random number generator is seeded with `seed`, so that the
+    /// order of the batches can be reproduced.
+    fn bucketed_shuffle(
+        self,
+        batch_size: BatchSize,
+        buffer_size: usize,
+        seed: u64,
+    ) -> BucketedShuffle<Self>;
+
     /// Filter sentences by their length.
     ///
     /// If `max_len` is `None`, then the sentences will not be
@@ -36,6 +102,37 @@ impl<'a, I> SentenceIterTools<'a> for I
 where
     I: 'a + Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
 {
+    fn augment(
+        self,
+        augmentation: Augmentation,
+        tokenizer: &'a dyn Tokenize,
+        seed: u64,
+    ) -> Augmented<'a, Self> {
+        Augmented::new(self, augmentation, tokenizer, seed)
+    }
+
+    fn batched(self, batch_size: BatchSize) -> Batched<Self> {
+        Batched {
+            inner: self.peekable(),
+            batch_size,
+        }
+    }
+
+    fn bucketed_shuffle(
+        self,
+        batch_size: BatchSize,
+        buffer_size: usize,
+        seed: u64,
+    ) -> BucketedShuffle<Self> {
+        BucketedShuffle {
+            inner: self,
+            batch_size,
+            batches: Vec::new(),
+            buffer_size,
+            rng: XorShiftRng::seed_from_u64(seed),
+        }
+    }
+
     fn filter_by_len(self, max_len: SequenceLength) -> LengthFilter<Self> {
         LengthFilter {
             inner: self,
@@ -128,3 +225,178 @@ where
         }
     }
 }
+
+/// An Iterator adapter grouping sentences into batches.
+pub struct Batched<I>
+where
+    I: Iterator,
+{
+    inner: Peekable<I>,
+    batch_size: BatchSize,
+}
+
+impl<I> Iterator for Batched<I>
+where
+    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+{
+    type Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = Vec::new();
+        let mut max_seq_len = 0;
+
+        loop {
+            // Complete the batch if adding the next sentence would exceed
+            // the batch size, unless the batch is empty.
+            if let Some(Ok(next)) = self.inner.peek() {
+                let next_max_seq_len = cmp::max(max_seq_len, next.pieces.len());
+                if !batch.is_empty() && !self.batch_size.fits(batch.len() + 1, next_max_seq_len) {
+                    return Some(Ok(batch));
+                }
+            }
+
+            match self.inner.next() {
+                Some(Ok(sentence)) => {
+                    max_seq_len = cmp::max(max_seq_len, sentence.pieces.len());
+                    batch.push(sentence);
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None if batch.is_empty() => return None,
+                None => return Some(Ok(batch)),
+            }
+        }
+    }
+}
+
+/// An Iterator adapter performing local shuffling with length bucketing.
+///
+/// Fills a buffer with `buffer_size` sentences, sorts the sentences by
+/// length and groups them into batches. The batches are returned in
+/// random order. The buffer is refilled when all of its batches have
+/// been returned.
+pub struct BucketedShuffle<I> {
+    inner: I,
+    batch_size: BatchSize,
+    batches: Vec<Vec<SentenceWithPieces>>,
+    buffer_size: usize,
+    rng: XorShiftRng,
+}
+
+impl<I> BucketedShuffle<I>
+where
+    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+{
+    fn fill_batches(&mut self) -> Result<(), SyntaxDotError> {
+        let mut buffer = Vec::with_capacity(self.buffer_size);
+        for sent in (&mut self.inner).take(self.buffer_size) {
+            buffer.push(sent?);
+        }
+
+        // Sorting is stable, so that the batches are deterministic
+        // given the input order.
+        buffer.sort_by_key(|sent| sent.pieces.len());
+
+        self.batches = buffer
+            .into_iter()
+            .map(Ok)
+            .batched(self.batch_size)
+            .collect::<Result<_, _>>()?;
+        self.batches.shuffle(&mut self.rng);
+
+        Ok(())
+    }
+}
+
+impl<I> Iterator for BucketedShuffle<I>
+where
+    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+{
+    type Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batches.is_empty() {
+            if let Err(err) = self.fill_batches() {
+                return Some(Err(err));
+            }
+        }
+
+        self.batches.pop().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use syntaxdot_tokenizers::SentenceWithPieces;
+
+    use super::BatchSize;
+    use crate::dataset::tests::wordpiece_tokenizer;
+    use crate::dataset::{DataSet, PlainTextDataSet, SentenceIterTools};
+    use crate::error::SyntaxDotError;
+
+    const SENTENCES: &str = "Dit is de eerste zin .
+Dit de tweede zin .
+nu
+En nu de laatste zin .
+Dit is
+Dit is de zin .";
+
+    fn batch_lens(
+        batches: impl Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>>,
+    ) -> Vec<Vec<usize>> {
+        batches
+            .map(|batch| {
+                batch
+                    .unwrap()
+                    .iter()
+                    .map(|sent| sent.pieces.len())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batched_by_pieces() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let batches = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .batched(BatchSize::Pieces(14));
+
+        assert_eq!(
+            batch_lens(batches),
+            vec![vec![7, 6], vec![2, 7], vec![3, 6]]
+        );
+    }
+
+    #[test]
+    fn batched_by_sentences() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let batches = dataset
+            .sentences(&tokenizer)
+            .unwrap()
+            .batched(BatchSize::Sentences(4));
+
+        assert_eq!(batch_lens(batches), vec![vec![7, 6, 2, 7], vec![3, 6]]);
+    }
+
+    #[test]
+    fn bucketed_shuffle_groups_similar_lengths() {
+        let tokenizer = wordpiece_tokenizer();
+        let mut cursor = BufReader::new(Cursor::new(SENTENCES));
+        let mut dataset = PlainTextDataSet::new(&mut cursor);
+        let mut batches = batch_lens(dataset.sentences(&tokenizer).unwrap().bucketed_shuffle(
+            BatchSize::Pieces(14),
+            6,
+            42,
+        ));
+        batches.sort();
+
+        assert_eq!(batches, vec![vec![2, 3], vec![6, 6], vec![7, 7]]);
+    }
+}
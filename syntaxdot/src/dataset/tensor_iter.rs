@@ -5,47 +5,77 @@ use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
 use syntaxdot_encoders::SentenceEncoder;
 use syntaxdot_tokenizers::SentenceWithPieces;
 
+use crate::dataset::{treebank_id, BatchSize, IntoTensors, MalformedSentences, SentenceIterTools};
 use crate::encoders::NamedEncoder;
 use crate::error::SyntaxDotError;
-use crate::tensor::{TensorBuilder, Tensors};
+use crate::tensor::{TensorBuilder, Tensors, UNKNOWN_LABEL};
+
+/// Batches of sentences.
+type SentenceBatches<'a> =
+    Box<dyn Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>> + 'a>;
 
 pub trait BatchedTensors<'a> {
     /// Get an iterator over batch tensors.
     ///
-    /// The sequence labels using the `encoders`, syntactic
-    /// dependencies using `biaffine_encoder`.
+    /// The sentences are grouped in batches of `batch_size`. The
+    /// sequence labels using the `encoders`, syntactic dependencies
+    /// using `biaffine_encoder`.
     ///
     /// If `encoders` is not `None`, output tensors will be created
     /// for the sequence labels in the data set.
     ///
     /// If `biaffine_encoder` is not `None`, output tensors will be
     /// created dependency heads and relations.
-    #[allow(clippy::type_complexity)]
     fn batched_tensors(
         self,
         biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
         encoders: Option<&'a [NamedEncoder]>,
-        batch_size: usize,
-    ) -> TensorIter<'a, Box<dyn Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>> + 'a>>;
+        batch_size: BatchSize,
+    ) -> TensorIter<'a, SentenceBatches<'a>>;
 }
 
 impl<'a, I> BatchedTensors<'a> for I
 where
     I: 'a + Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
 {
-    #[allow(clippy::type_complexity)]
     fn batched_tensors(
         self,
         biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
         encoders: Option<&'a [NamedEncoder]>,
-        batch_size: usize,
-    ) -> TensorIter<'a, Box<dyn Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>> + 'a>>
-    {
+        batch_size: BatchSize,
+    ) -> TensorIter<'a, SentenceBatches<'a>> {
+        self.batched(batch_size)
+            .into_tensors(biaffine_encoder, encoders)
+    }
+}
+
+pub trait IntoTensors<'a> {
+    /// Get an iterator over the tensors of batches of sentences.
+    ///
+    /// This method is similar to `BatchedTensors::batched_tensors`,
+    /// but is used for iterators over sentences that are already
+    /// grouped in batches.
+    fn into_tensors(
+        self,
+        biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
+        encoders: Option<&'a [NamedEncoder]>,
+    ) -> TensorIter<'a, SentenceBatches<'a>>;
+}
+
+impl<'a, I> IntoTensors<'a> for I
+where
+    I: 'a + Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>>,
+{
+    fn into_tensors(
+        self,
+        biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
+        encoders: Option<&'a [NamedEncoder]>,
+    ) -> TensorIter<'a, SentenceBatches<'a>> {
         TensorIter {
-            batch_size,
+            batches: Box::new(self),
             biaffine_encoder,
             encoders,
-            sentences: Box::new(self),
+            malformed: MalformedSentences::Fail,
         }
     }
 }
@@ -53,210 +83,449 @@ where
 /// An iterator returning input and (optionally) output tensors.
 pub struct TensorIter<'a, I>
 where
-    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+    I: Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>>,
 {
-    pub batch_size: usize,
+    pub batches: I,
     pub biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
     pub encoders: Option<&'a [NamedEncoder]>,
-    pub sentences: I,
+    pub malformed: MalformedSentences,
 }
 
 impl<'a, I> TensorIter<'a, I>
 where
-    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+    I: Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>>,
 {
-    fn next_with_labels(
-        &mut self,
-        tokenized_sentences: Vec<SentenceWithPieces>,
-        max_seq_len: usize,
-        max_tokens_len: usize,
-        biaffine_encoder: Option<&'a ImmutableDependencyEncoder>,
-        encoders: &'a [NamedEncoder],
-    ) -> Result<Tensors, SyntaxDotError> {
-        let mut builder = TensorBuilder::new_with_labels(
-            tokenized_sentences.len(),
-            max_seq_len,
-            max_tokens_len,
-            biaffine_encoder.is_some(),
-            encoders.iter().map(NamedEncoder::name),
-        );
+    /// Set the handling of sentences that cannot be encoded.
+    ///
+    /// By default, iteration fails on such sentences. Batches of
+    /// which all sentences are skipped are not returned.
+    pub fn malformed_sentences(mut self, malformed: MalformedSentences) -> Self {
+        self.malformed = malformed;
+        self
+    }
+}
 
-        for sentence in tokenized_sentences {
-            let mut token_mask = Array1::zeros((sentence.pieces.len(),));
-            for token_idx in &sentence.token_offsets {
-                token_mask[*token_idx] = 1;
-            }
+/// Convert a batch of sentences to tensors.
+///
+/// If `encoders` is not `None`, output tensors will be created for
+/// the sequence labels. If `biaffine_encoder` is not `None`, output
+/// tensors will be created for dependency heads and relations.
+pub fn batch_tensors(
+    batch_sentences: Vec<SentenceWithPieces>,
+    biaffine_encoder: Option<&ImmutableDependencyEncoder>,
+    encoders: Option<&[NamedEncoder]>,
+) -> Result<Tensors, SyntaxDotError> {
+    batch_tensors_with_malformed(
+        batch_sentences,
+        biaffine_encoder,
+        encoders,
+        MalformedSentences::Fail,
+    )
+    .map(|tensors| tensors.expect("Sentences are not skipped when failing on malformed sentences"))
+}
 
-            let token_offsets = sentence
-                .token_offsets
-                .iter()
-                .map(|&offset| offset as i32)
-                .collect::<Array1<i32>>();
-
-            let token_lens: Array1<i32> =
-                Array1::from_shape_fn((sentence.token_offsets.len(),), |idx| {
-                    if idx + 1 < sentence.token_offsets.len() {
-                        sentence.token_offsets[idx + 1] as i32 - sentence.token_offsets[idx] as i32
-                    } else {
-                        sentence.pieces.len() as i32 - sentence.token_offsets[idx] as i32
-                    }
-                });
-
-            let biaffine_encoding = match Self::encode_biaffine(biaffine_encoder, &sentence) {
-                Ok(biaffine_encoding) => biaffine_encoding,
-                Err(err) => return Err(err),
-            };
+/// Convert a batch of sentences to tensors.
+///
+/// Returns `None` when all sentences of a non-empty batch are skipped
+/// as malformed.
+fn batch_tensors_with_malformed(
+    batch_sentences: Vec<SentenceWithPieces>,
+    biaffine_encoder: Option<&ImmutableDependencyEncoder>,
+    encoders: Option<&[NamedEncoder]>,
+    malformed: MalformedSentences,
+) -> Result<Option<Tensors>, SyntaxDotError> {
+    let max_seq_len = batch_sentences
+        .iter()
+        .map(|s| s.pieces.len())
+        .max()
+        .unwrap_or(0);
+
+    let max_tokens_len = batch_sentences
+        .iter()
+        .map(|s| s.token_offsets.len())
+        .max()
+        .unwrap_or(0);
+
+    match encoders {
+        Some(encoders) => next_with_labels(batch_sentences, biaffine_encoder, encoders, malformed),
+        None => next_without_labels(batch_sentences, max_seq_len, max_tokens_len).map(Some),
+    }
+}
 
-            let sequence_encoding = match Self::encode_sequence(encoders, &sentence) {
-                Ok(sequence_encoding) => sequence_encoding,
-                Err(err) => return Err(err),
-            };
+fn next_with_labels(
+    tokenized_sentences: Vec<SentenceWithPieces>,
+    biaffine_encoder: Option<&ImmutableDependencyEncoder>,
+    encoders: &[NamedEncoder],
+    malformed: MalformedSentences,
+) -> Result<Option<Tensors>, SyntaxDotError> {
+    let encoded_sentences = tokenized_sentences
+        .iter()
+        .filter_map(|sentence| {
+            encode_sentence(sentence, biaffine_encoder, encoders, malformed).transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if encoded_sentences.is_empty() && !tokenized_sentences.is_empty() {
+        return Ok(None);
+    }
+
+    encoded_batch_tensors(encoded_sentences, biaffine_encoder.is_some(), encoders).map(Some)
+}
+
+/// A sentence with encoded pieces and labels.
+///
+/// This is the representation of a sentence after tokenization and
+/// label encoding, which is all that is needed to construct training
+/// tensors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedSentence {
+    /// Word pieces in the sentence.
+    pub pieces: Array1<i64>,
+
+    /// The offsets of tokens in `pieces`.
+    pub token_offsets: Vec<usize>,
+
+    /// The treebank identifier of the sentence.
+    pub treebank_id: usize,
+
+    /// Dependency heads and relations.
+    pub biaffine_labels: Option<(Array1<i64>, Array1<i64>)>,
 
-            builder.add_with_labels(
-                sentence.pieces.view(),
-                biaffine_encoding,
-                sequence_encoding,
-                token_offsets.view(),
-                token_lens.view(),
-                token_mask.view(),
-            );
+    /// Labels of token-level encoders.
+    pub labels: HashMap<String, Array1<i64>>,
+
+    /// Labels of sentence-level encoders.
+    pub sentence_labels: HashMap<String, i64>,
+}
+
+/// Encode the labels of a tokenized sentence.
+///
+/// Dependency heads and relations are encoded when `biaffine_encoder`
+/// is not `None`. Sentences with annotations that cannot be encoded
+/// are handled according to `malformed`, `None` is returned when the
+/// sentence is skipped.
+pub fn encode_sentence(
+    sentence: &SentenceWithPieces,
+    biaffine_encoder: Option<&ImmutableDependencyEncoder>,
+    encoders: &[NamedEncoder],
+    malformed: MalformedSentences,
+) -> Result<Option<EncodedSentence>, SyntaxDotError> {
+    let biaffine_labels = match malformed {
+        // Missing or unknown heads and relations are masked per token.
+        MalformedSentences::MaskLabels => {
+            biaffine_encoder.map(|encoder| encode_biaffine_partial(encoder, sentence))
         }
+        _ => match encode_biaffine(biaffine_encoder, sentence) {
+            Ok(biaffine_labels) => biaffine_labels,
+            Err(err) => {
+                // Fails or skips, since labels are not masked.
+                malformed.handle(&sentence.sentence, err)?;
+                return Ok(None);
+            }
+        },
+    };
+
+    let (labels, sentence_labels) = match encode_sequence(encoders, sentence, malformed)? {
+        Some(labels) => labels,
+        None => return Ok(None),
+    };
+
+    Ok(Some(EncodedSentence {
+        pieces: sentence.pieces.clone(),
+        token_offsets: sentence.token_offsets.clone(),
+        treebank_id: treebank_id(&sentence.sentence)?,
+        biaffine_labels,
+        labels: labels
+            .into_iter()
+            .map(|(name, labels)| (name.to_string(), labels))
+            .collect(),
+        sentence_labels: sentence_labels
+            .into_iter()
+            .map(|(name, label)| (name.to_string(), label))
+            .collect(),
+    }))
+}
 
-        Ok(builder.into())
-    }
+/// Convert a batch of encoded sentences to tensors.
+///
+/// `biaffine` indicates whether the sentences have dependency labels.
+/// The sentences must have labels for each of the `encoders`.
+pub fn encoded_batch_tensors(
+    encoded_sentences: Vec<EncodedSentence>,
+    biaffine: bool,
+    encoders: &[NamedEncoder],
+) -> Result<Tensors, SyntaxDotError> {
+    let max_seq_len = encoded_sentences
+        .iter()
+        .map(|s| s.pieces.len())
+        .max()
+        .unwrap_or(0);
+
+    let max_tokens_len = encoded_sentences
+        .iter()
+        .map(|s| s.token_offsets.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut builder = TensorBuilder::new_with_labels(
+        encoded_sentences.len(),
+        max_seq_len,
+        max_tokens_len,
+        biaffine,
+        encoders
+            .iter()
+            .filter(|encoder| !encoder.encoder().is_sentence_level())
+            .map(NamedEncoder::name),
+        encoders
+            .iter()
+            .filter(|encoder| encoder.encoder().is_sentence_level())
+            .map(NamedEncoder::name),
+    );
+
+    for sentence in encoded_sentences {
+        let mut token_mask = Array1::zeros((sentence.pieces.len(),));
+        for token_idx in &sentence.token_offsets {
+            token_mask[*token_idx] = 1;
+        }
 
-    fn encode_sequence<'e>(
-        encoders: &'e [NamedEncoder],
-        sentence: &SentenceWithPieces,
-    ) -> Result<HashMap<&'e str, Array1<i64>>, SyntaxDotError> {
-        let mut encoder_labels = HashMap::with_capacity(encoders.len());
-        for encoder in encoders {
-            let encoding = match encoder.encoder().encode(&sentence.sentence) {
-                Ok(encoding) => encoding,
-                Err(err) => return Err(err.into()),
-            };
+        let token_offsets = sentence
+            .token_offsets
+            .iter()
+            .map(|&offset| offset as i32)
+            .collect::<Array1<i32>>();
 
-            let labels = encoding.into_iter().map(|label| label as i64).collect();
+        let token_lens: Array1<i32> =
+            Array1::from_shape_fn((sentence.token_offsets.len(),), |idx| {
+                if idx + 1 < sentence.token_offsets.len() {
+                    sentence.token_offsets[idx + 1] as i32 - sentence.token_offsets[idx] as i32
+                } else {
+                    sentence.pieces.len() as i32 - sentence.token_offsets[idx] as i32
+                }
+            });
 
-            encoder_labels.insert(encoder.name(), labels);
+        let labels = encoders
+            .iter()
+            .filter(|encoder| !encoder.encoder().is_sentence_level())
+            .map(|encoder| {
+                sentence
+                    .labels
+                    .get(encoder.name())
+                    .map(|labels| (encoder.name(), labels.clone()))
+                    .ok_or_else(|| missing_labels(encoder.name()))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        let sentence_labels = encoders
+            .iter()
+            .filter(|encoder| encoder.encoder().is_sentence_level())
+            .map(|encoder| {
+                sentence
+                    .sentence_labels
+                    .get(encoder.name())
+                    .map(|&label| (encoder.name(), label))
+                    .ok_or_else(|| missing_labels(encoder.name()))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        if biaffine && sentence.biaffine_labels.is_none() {
+            return Err(missing_labels("biaffine"));
         }
-        Ok(encoder_labels)
+
+        builder.set_treebank_id(sentence.treebank_id as i64);
+        builder.add_with_labels(
+            sentence.pieces.view(),
+            sentence.biaffine_labels.filter(|_| biaffine),
+            labels,
+            sentence_labels,
+            token_offsets.view(),
+            token_lens.view(),
+            token_mask.view(),
+        );
     }
 
-    #[allow(clippy::type_complexity)]
-    fn encode_biaffine(
-        biaffine_encoder: Option<&ImmutableDependencyEncoder>,
-        sentence: &SentenceWithPieces,
-    ) -> Result<Option<(Array1<i64>, Array1<i64>)>, SyntaxDotError> {
-        let encoding = match biaffine_encoder {
-            Some(biaffine_encoder) => {
-                let encoding = biaffine_encoder.encode(&sentence.sentence)?;
-
-                let dependency_heads = encoding.heads.into_iter().map(|head| head as i64).collect();
-                let dependency_labels = encoding
-                    .relations
-                    .into_iter()
-                    .map(|relation| relation as i64)
-                    .collect();
+    Ok(builder.into())
+}
+
+fn missing_labels(encoder_name: &str) -> SyntaxDotError {
+    SyntaxDotError::IllegalConfigurationError(format!(
+        "Encoded sentence does not have labels for encoder: {}",
+        encoder_name
+    ))
+}
 
-                Some((dependency_heads, dependency_labels))
+/// Encode a sentence using the given encoders.
+///
+/// Returns the labels of the token-level encoders and the labels of
+/// the sentence-level encoders. When labels are masked, they are set
+/// to `UNKNOWN_LABEL`. `None` is returned when the sentence is skipped.
+///
+/// When labels are masked, tokens without an annotation are masked
+/// individually. All labels of an encoder are masked if the sentence
+/// cannot be encoded otherwise.
+#[allow(clippy::type_complexity)]
+fn encode_sequence<'e>(
+    encoders: &'e [NamedEncoder],
+    sentence: &SentenceWithPieces,
+    malformed: MalformedSentences,
+) -> Result<Option<(HashMap<&'e str, Array1<i64>>, HashMap<&'e str, i64>)>, SyntaxDotError> {
+    let mut encoder_labels = HashMap::with_capacity(encoders.len());
+    let mut sentence_labels = HashMap::new();
+    for encoder in encoders {
+        let encoding = match malformed {
+            MalformedSentences::MaskLabels => encoder.encoder().encode_partial(&sentence.sentence),
+            _ => encoder
+                .encoder()
+                .encode(&sentence.sentence)
+                .map(|encoding| encoding.into_iter().map(Some).collect()),
+        };
+
+        let encoding = match encoding {
+            Ok(encoding) => Some(encoding),
+            Err(err) => {
+                if !malformed.handle(&sentence.sentence, err.into())? {
+                    return Ok(None);
+                }
+                None
             }
-            None => None,
         };
 
-        Ok(encoding)
+        if encoder.encoder().is_sentence_level() {
+            let label = encoding
+                .and_then(|encoding| encoding[0])
+                .map(|label| label as i64)
+                .unwrap_or(UNKNOWN_LABEL);
+            sentence_labels.insert(encoder.name(), label);
+        } else {
+            let labels = match encoding {
+                Some(encoding) => encoding
+                    .into_iter()
+                    .map(|label| label.map(|label| label as i64).unwrap_or(UNKNOWN_LABEL))
+                    .collect(),
+                None => Array1::from_elem((sentence.token_offsets.len(),), UNKNOWN_LABEL),
+            };
+            encoder_labels.insert(encoder.name(), labels);
+        }
     }
+    Ok(Some((encoder_labels, sentence_labels)))
+}
 
-    fn next_without_labels(
-        &mut self,
-        tokenized_sentences: Vec<SentenceWithPieces>,
-        max_seq_len: usize,
-        max_tokens_len: usize,
-    ) -> Tensors {
-        let mut builder: TensorBuilder = TensorBuilder::new_without_labels(
-            tokenized_sentences.len(),
-            max_seq_len,
-            max_tokens_len,
-        );
+#[allow(clippy::type_complexity)]
+fn encode_biaffine(
+    biaffine_encoder: Option<&ImmutableDependencyEncoder>,
+    sentence: &SentenceWithPieces,
+) -> Result<Option<(Array1<i64>, Array1<i64>)>, SyntaxDotError> {
+    let encoding = match biaffine_encoder {
+        Some(biaffine_encoder) => {
+            let encoding = biaffine_encoder.encode(&sentence.sentence)?;
+
+            let dependency_heads = encoding.heads.into_iter().map(|head| head as i64).collect();
+            let dependency_labels = encoding
+                .relations
+                .into_iter()
+                .map(|relation| relation as i64)
+                .collect();
+
+            Some((dependency_heads, dependency_labels))
+        }
+        None => None,
+    };
 
-        for sentence in tokenized_sentences {
-            let input = sentence.pieces;
-            let mut token_mask = Array1::zeros((input.len(),));
-            for token_idx in &sentence.token_offsets {
-                token_mask[*token_idx] = 1;
-            }
+    Ok(encoding)
+}
 
-            let token_offsets = sentence
-                .token_offsets
-                .iter()
-                .map(|&offset| offset as i32)
-                .collect::<Array1<i32>>();
+/// Encode the dependency heads and relations that are present.
+///
+/// Heads and relations that are missing or unknown are set to
+/// `UNKNOWN_LABEL`.
+fn encode_biaffine_partial(
+    biaffine_encoder: &ImmutableDependencyEncoder,
+    sentence: &SentenceWithPieces,
+) -> (Array1<i64>, Array1<i64>) {
+    let encoding = biaffine_encoder.encode_partial(&sentence.sentence);
+
+    let dependency_heads = encoding
+        .heads
+        .into_iter()
+        .map(|head| head.map(|head| head as i64).unwrap_or(UNKNOWN_LABEL))
+        .collect();
+    let dependency_labels = encoding
+        .relations
+        .into_iter()
+        .map(|relation| {
+            relation
+                .map(|relation| relation as i64)
+                .unwrap_or(UNKNOWN_LABEL)
+        })
+        .collect();
 
-            let token_lens: Array1<i32> = Array1::from_shape_fn((token_offsets.len(),), |idx| {
-                if idx + 1 < token_offsets.len() {
-                    token_offsets[idx + 1] as i32 - token_offsets[idx] as i32
-                } else {
-                    input.len() as i32 - token_offsets[idx] as i32
-                }
-            });
+    (dependency_heads, dependency_labels)
+}
 
-            builder.add_without_labels(
-                input.view(),
-                token_offsets.view(),
-                token_lens.view(),
-                token_mask.view(),
-            );
+fn next_without_labels(
+    tokenized_sentences: Vec<SentenceWithPieces>,
+    max_seq_len: usize,
+    max_tokens_len: usize,
+) -> Result<Tensors, SyntaxDotError> {
+    let mut builder: TensorBuilder =
+        TensorBuilder::new_without_labels(tokenized_sentences.len(), max_seq_len, max_tokens_len);
+
+    for sentence in tokenized_sentences {
+        let input = sentence.pieces;
+        let mut token_mask = Array1::zeros((input.len(),));
+        for token_idx in &sentence.token_offsets {
+            token_mask[*token_idx] = 1;
         }
 
-        builder.into()
+        let token_offsets = sentence
+            .token_offsets
+            .iter()
+            .map(|&offset| offset as i32)
+            .collect::<Array1<i32>>();
+
+        let token_lens: Array1<i32> = Array1::from_shape_fn((token_offsets.len(),), |idx| {
+            if idx + 1 < token_offsets.len() {
+                token_offsets[idx + 1] as i32 - token_offsets[idx] as i32
+            } else {
+                input.len() as i32 - token_offsets[idx] as i32
+            }
+        });
+
+        builder.set_treebank_id(treebank_id(&sentence.sentence)? as i64);
+        builder.add_without_labels(
+            input.view(),
+            token_offsets.view(),
+            token_lens.view(),
+            token_mask.view(),
+        );
     }
+
+    Ok(builder.into())
 }
 
 impl<'a, I> Iterator for TensorIter<'a, I>
 where
-    I: Iterator<Item = Result<SentenceWithPieces, SyntaxDotError>>,
+    I: Iterator<Item = Result<Vec<SentenceWithPieces>, SyntaxDotError>>,
 {
     type Item = Result<Tensors, SyntaxDotError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut batch_sentences = Vec::with_capacity(self.batch_size);
-        for sentence in &mut self.sentences {
-            let sentence = match sentence {
-                Ok(sentence) => sentence,
+        loop {
+            let batch_sentences = match self.batches.next()? {
+                Ok(batch_sentences) => batch_sentences,
                 Err(err) => return Some(Err(err)),
             };
-            batch_sentences.push(sentence);
-            if batch_sentences.len() == self.batch_size {
-                break;
-            }
-        }
-
-        // Check whether the reader is exhausted.
-        if batch_sentences.is_empty() {
-            return None;
-        }
 
-        let max_seq_len = batch_sentences
-            .iter()
-            .map(|s| s.pieces.len())
-            .max()
-            .unwrap_or(0);
-
-        let max_tokens_len = batch_sentences
-            .iter()
-            .map(|s| s.token_offsets.len())
-            .max()
-            .unwrap_or(0);
-
-        Some(match self.encoders {
-            Some(encoders) => self.next_with_labels(
+            match batch_tensors_with_malformed(
                 batch_sentences,
-                max_seq_len,
-                max_tokens_len,
                 self.biaffine_encoder,
-                encoders,
-            ),
-            None => Ok(self.next_without_labels(batch_sentences, max_seq_len, max_tokens_len)),
-        })
+                self.encoders,
+                self.malformed,
+            ) {
+                Ok(Some(tensors)) => return Some(Ok(tensors)),
+                // All sentences in the batch were skipped.
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
     }
 }
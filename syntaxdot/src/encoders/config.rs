@@ -1,15 +1,18 @@
 use std::ops::Deref;
 
 use serde::{Deserialize, Serialize};
-use syntaxdot_encoders::depseq::PosLayer;
+use syntaxdot_encoders::depseq::{DecodingStrategy, PosLayer};
 use syntaxdot_encoders::layer::Layer;
 use syntaxdot_encoders::lemma::BackoffStrategy;
+use syntaxdot_transformers::activations::Activation;
+
+use crate::config::LossConfig;
 
 /// Configuration of a set of encoders.
 ///
 /// The configuration is a mapping from encoder name to
 /// encoder configuration.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct EncodersConfig(pub Vec<NamedEncoderConfig>);
 
@@ -25,10 +28,20 @@ impl Deref for EncodersConfig {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum EncoderType {
+    /// Encoder for sentence labels stored in comments.
+    ///
+    /// The value is the attribute of the comment, e.g. `label` for
+    /// comments of the form `# label = value`.
+    Comment(String),
+
     /// Encoder for syntactical dependencies.
     Dependency {
         encoder: DependencyEncoder,
         root_relation: String,
+
+        /// Strategy for decoding dependency trees.
+        #[serde(default)]
+        decoder: DecodingStrategy,
     },
 
     /// Lemma encoder using edit trees.
@@ -54,9 +67,95 @@ pub enum DependencyEncoder {
 }
 
 /// Configuration of an encoder with a name.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct NamedEncoderConfig {
+    /// Configuration of the classifier head of the encoder.
+    #[serde(default)]
+    pub classifier: ClassifierConfig,
+
     pub encoder: EncoderType,
+
+    /// Configuration of the encoder's loss.
+    #[serde(default)]
+    pub loss: LossConfig,
+
+    /// Weight of the encoder's loss.
+    #[serde(default = "crate::config::default_loss_weight")]
+    pub loss_weight: f64,
+
     pub name: String,
 }
+
+/// Configuration of an encoder's classifier head.
+///
+/// Options that are not set use the defaults of the pretrained model.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassifierConfig {
+    /// Activation function of the hidden layers.
+    #[serde(default = "default_classifier_activation")]
+    pub activation: Activation,
+
+    /// Dropout probability of the hidden layers.
+    ///
+    /// Defaults to the hidden dropout probability of the pretrained model.
+    pub dropout: Option<f64>,
+
+    /// The number of hidden layers.
+    #[serde(default = "default_classifier_hidden_layers")]
+    pub hidden_layers: usize,
+
+    /// Size of the hidden layers.
+    ///
+    /// Defaults to the hidden size of the pretrained model.
+    pub hidden_size: Option<i64>,
+
+    /// Transformer layers that are used in scalar weighting.
+    ///
+    /// Layer 0 is the embedding layer. All layers are used when this
+    /// option is absent.
+    pub layers: Option<Vec<usize>>,
+}
+
+impl Default for ClassifierConfig {
+    fn default() -> Self {
+        ClassifierConfig {
+            activation: default_classifier_activation(),
+            dropout: None,
+            hidden_layers: default_classifier_hidden_layers(),
+            hidden_size: None,
+            layers: None,
+        }
+    }
+}
+
+fn default_classifier_activation() -> Activation {
+    Activation::Relu
+}
+
+fn default_classifier_hidden_layers() -> usize {
+    1
+}
+
+impl EncodersConfig {
+    /// Get the classifier configuration of the encoder with the given name.
+    ///
+    /// Returns the default configuration if there is no such encoder.
+    pub fn classifier(&self, name: &str) -> ClassifierConfig {
+        self.iter()
+            .find(|encoder| encoder.name == name)
+            .map(|encoder| encoder.classifier.clone())
+            .unwrap_or_default()
+    }
+
+    /// Get the loss configuration of the encoder with the given name.
+    ///
+    /// Returns the default configuration if there is no such encoder.
+    pub fn loss(&self, name: &str) -> LossConfig {
+        self.iter()
+            .find(|encoder| encoder.name == name)
+            .map(|encoder| encoder.loss.clone())
+            .unwrap_or_default()
+    }
+}
@@ -4,6 +4,7 @@ use std::ops::Deref;
 use numberer::Numberer;
 use serde::{Deserialize, Serialize};
 use syntaxdot_encoders::categorical::{ImmutableCategoricalEncoder, MutableCategoricalEncoder};
+use syntaxdot_encoders::comment::CommentEncoder;
 use syntaxdot_encoders::depseq::{
     DependencyEncoding, RelativePos, RelativePosEncoder, RelativePosition, RelativePositionEncoder,
 };
@@ -69,6 +70,16 @@ where
             CategoricalEncoderWrap::Mutable(encoder) => encoder.encode(sentence),
         }
     }
+
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        match self {
+            CategoricalEncoderWrap::Immutable(encoder) => encoder.encode_partial(sentence),
+            CategoricalEncoderWrap::Mutable(encoder) => encoder.encode_partial(sentence),
+        }
+    }
 }
 
 impl<E, V> CategoricalEncoderWrap<E, V>
@@ -86,6 +97,9 @@ where
 /// Wrapper of encoder error types.
 #[derive(Debug, Error)]
 pub enum DecoderError {
+    #[error(transparent)]
+    Comment(<CommentEncoder as SentenceDecoder>::Error),
+
     #[error(transparent)]
     Lemma(<EditTreeEncoder as SentenceDecoder>::Error),
 
@@ -105,6 +119,9 @@ pub enum DecoderError {
 /// Wrapper of encoder error types.
 #[derive(Debug, Error)]
 pub enum EncoderError {
+    #[error(transparent)]
+    Comment(<CommentEncoder as SentenceEncoder>::Error),
+
     #[error(transparent)]
     Lemma(<EditTreeEncoder as SentenceEncoder>::Error),
 
@@ -124,6 +141,7 @@ pub enum EncoderError {
 /// Wrapper of the various supported encoders.
 #[derive(Deserialize, Serialize)]
 pub enum Encoder {
+    Comment(CategoricalEncoderWrap<CommentEncoder, String>),
     Lemma(CategoricalEncoderWrap<EditTreeEncoder, EditTree>),
     Layer(CategoricalEncoderWrap<LayerEncoder, String>),
     RelativePos(CategoricalEncoderWrap<RelativePosEncoder, DependencyEncoding<RelativePos>>),
@@ -137,6 +155,7 @@ pub enum Encoder {
 impl Encoder {
     pub fn len(&self) -> usize {
         match self {
+            Encoder::Comment(encoder) => encoder.len(),
             Encoder::Layer(encoder) => encoder.len(),
             Encoder::Lemma(encoder) => encoder.len(),
             Encoder::RelativePos(encoder) => encoder.len(),
@@ -144,6 +163,15 @@ impl Encoder {
             Encoder::TdzLemma(encoder) => encoder.len(),
         }
     }
+
+    /// Returns `true` if the encoder assigns a single label to a sentence.
+    ///
+    /// Sentence-level encoders are trained on the representation of the
+    /// sentence root (e.g. the `[CLS]` piece), other encoders assign a
+    /// label to every token.
+    pub fn is_sentence_level(&self) -> bool {
+        matches!(self, Encoder::Comment(_))
+    }
 }
 
 impl SentenceDecoder for Encoder {
@@ -156,6 +184,9 @@ impl SentenceDecoder for Encoder {
         S: AsRef<[EncodingProb<Self::Encoding>]>,
     {
         match self {
+            Encoder::Comment(decoder) => decoder
+                .decode(labels, sentence)
+                .map_err(DecoderError::Comment),
             Encoder::Layer(decoder) => decoder
                 .decode(labels, sentence)
                 .map_err(DecoderError::Layer),
@@ -182,6 +213,7 @@ impl SentenceEncoder for Encoder {
 
     fn encode(&self, sentence: &Sentence) -> Result<Vec<Self::Encoding>, Self::Error> {
         match self {
+            Encoder::Comment(encoder) => encoder.encode(sentence).map_err(EncoderError::Comment),
             Encoder::Layer(encoder) => encoder.encode(sentence).map_err(EncoderError::Layer),
             Encoder::Lemma(encoder) => encoder.encode(sentence).map_err(EncoderError::Lemma),
             Encoder::RelativePos(encoder) => {
@@ -193,18 +225,49 @@ impl SentenceEncoder for Encoder {
             Encoder::TdzLemma(encoder) => encoder.encode(sentence).map_err(EncoderError::TdzLemma),
         }
     }
+
+    fn encode_partial(
+        &self,
+        sentence: &Sentence,
+    ) -> Result<Vec<Option<Self::Encoding>>, Self::Error> {
+        match self {
+            Encoder::Comment(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::Comment),
+            Encoder::Layer(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::Layer),
+            Encoder::Lemma(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::Lemma),
+            Encoder::RelativePos(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::RelativePos),
+            Encoder::RelativePosition(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::RelativePosition),
+            Encoder::TdzLemma(encoder) => encoder
+                .encode_partial(sentence)
+                .map_err(EncoderError::TdzLemma),
+        }
+    }
 }
 
 impl From<&EncoderType> for Encoder {
     fn from(encoder_type: &EncoderType) -> Self {
         // We start labeling at 2. 0 is reserved for padding, 1 for continuations.
         match encoder_type {
+            EncoderType::Comment(attr) => Encoder::Comment(
+                MutableCategoricalEncoder::new(CommentEncoder::new(attr.clone()), Numberer::new(2))
+                    .into(),
+            ),
             EncoderType::Dependency {
                 encoder: DependencyEncoder::RelativePos(pos_layer),
                 root_relation,
+                decoder,
             } => Encoder::RelativePos(
                 MutableCategoricalEncoder::new(
-                    RelativePosEncoder::new(*pos_layer, root_relation),
+                    RelativePosEncoder::new(*pos_layer, root_relation).with_decoding(*decoder),
                     Numberer::new(2),
                 )
                 .into(),
@@ -212,9 +275,10 @@ impl From<&EncoderType> for Encoder {
             EncoderType::Dependency {
                 encoder: DependencyEncoder::RelativePosition,
                 root_relation,
+                decoder,
             } => Encoder::RelativePosition(
                 MutableCategoricalEncoder::new(
-                    RelativePositionEncoder::new(root_relation),
+                    RelativePositionEncoder::new(root_relation).with_decoding(*decoder),
                     Numberer::new(2),
                 )
                 .into(),
@@ -1,7 +1,9 @@
 //! Encoder configuration and construction.
 
 mod config;
-pub use config::{DependencyEncoder, EncoderType, EncodersConfig, NamedEncoderConfig};
+pub use config::{
+    ClassifierConfig, DependencyEncoder, EncoderType, EncodersConfig, NamedEncoderConfig,
+};
 
 #[allow(clippy::module_inception)]
 mod encoders;
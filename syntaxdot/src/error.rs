@@ -15,6 +15,12 @@ pub enum SyntaxDotError {
     #[error(transparent)]
     BertError(#[from] TransformerError),
 
+    #[error("Invalid pre-processed data set: {0}")]
+    CachedDataSetError(String),
+
+    #[error("Cannot read column data on line {line}: {message}")]
+    ColumnFormatError { line: usize, message: String },
+
     #[error(transparent)]
     ConlluIoError(#[from] conllu::IOError),
 
@@ -33,6 +39,15 @@ pub enum SyntaxDotError {
     #[error(transparent)]
     IoError(#[from] io::Error),
 
+    #[error("Cannot encode sentence on {position}: {source}")]
+    MalformedSentence {
+        position: String,
+        source: Box<SyntaxDotError>,
+    },
+
+    #[error("Optimizer state is missing: {0}")]
+    MissingOptimizerState(String),
+
     #[error("The optimizer does not have any associated trainable variables")]
     NoTrainableVariables,
 
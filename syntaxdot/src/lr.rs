@@ -1,6 +1,9 @@
 //! Learning rate functions.
 
 use std::f32;
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
 
 /// Trait for learning rate schedules.
 ///
@@ -21,6 +24,7 @@ pub trait LearningRateSchedule {
 /// Constant learning rate schedule.
 ///
 /// This schedule uses the same learning rate for every epoch.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConstantLearningRate {
     lr: f32,
     warmup_steps: usize,
@@ -63,7 +67,7 @@ impl LearningRateSchedule for ConstantLearningRate {
 /// calculated as follows:
 ///
 /// *lr = initial_lr * decay_rate ^ (global_step / decay_steps)*
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ExponentialDecay {
     initial_lr: f32,
     lr: f32,
@@ -142,6 +146,255 @@ impl LearningRateSchedule for ExponentialDecay {
     }
 }
 
+/// Linear decay learning rate schedule.
+///
+/// This schedule starts at an initial learning rate, which decays
+/// linearly to zero in `decay_steps` steps after warmup. To be specific,
+/// the learning rate is calculated as follows:
+///
+/// *lr = initial_lr * (1 - min(step, decay_steps) / decay_steps)*
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LinearDecay {
+    initial_lr: f32,
+    lr: f32,
+    decay_steps: usize,
+    warmup_steps: usize,
+}
+
+impl LinearDecay {
+    /// Construct a linear decay schedule.
+    ///
+    /// If `warmup_steps` > 0, the learning rate is linearly scaled
+    /// for `warmup_steps` from 0 -> `initial_lr`.
+    pub fn new(initial_lr: f32, decay_steps: usize, warmup_steps: usize) -> Self {
+        assert!(
+            initial_lr > 0.0,
+            "The initial learning rate must be a positive value."
+        );
+        assert!(
+            decay_steps > 0,
+            "The number decay steps should be non-zero."
+        );
+
+        LinearDecay {
+            initial_lr,
+            lr: initial_lr,
+            decay_steps,
+            warmup_steps,
+        }
+    }
+}
+
+impl LearningRateSchedule for LinearDecay {
+    fn compute_step_learning_rate(&mut self, global_step: usize) -> f32 {
+        if global_step < self.warmup_steps {
+            return (self.initial_lr / (self.warmup_steps as f32)) * global_step as f32;
+        }
+
+        let step = (global_step - self.warmup_steps).min(self.decay_steps);
+        self.lr = self.initial_lr * (1. - step as f32 / self.decay_steps as f32);
+        self.lr
+    }
+
+    fn compute_epoch_learning_rate(&mut self, _epoch: usize, _last_score: f32) -> f32 {
+        self.lr
+    }
+
+    fn initial_lr(&self) -> f32 {
+        self.initial_lr
+    }
+
+    fn set_initial_lr(&mut self, lr: f32) {
+        self.initial_lr = lr;
+    }
+}
+
+/// Cosine decay learning rate schedule.
+///
+/// This schedule starts at an initial learning rate, which is annealed
+/// to zero in `decay_steps` steps after warmup, following half a cosine
+/// period:
+///
+/// *lr = initial_lr * 0.5 * (1 + cos(pi * min(step, decay_steps) / decay_steps))*
+///
+/// With restarts, the learning rate is reset to the initial learning
+/// rate every `decay_steps` steps (Loshchilov & Hutter, 2017).
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CosineDecay {
+    initial_lr: f32,
+    lr: f32,
+    decay_steps: usize,
+    restarts: bool,
+    warmup_steps: usize,
+}
+
+impl CosineDecay {
+    /// Construct a cosine decay schedule.
+    ///
+    /// If `restarts` is true, the schedule is restarted every
+    /// `decay_steps` steps. If `warmup_steps` > 0, the learning rate is
+    /// linearly scaled for `warmup_steps` from 0 -> `initial_lr`.
+    pub fn new(initial_lr: f32, decay_steps: usize, restarts: bool, warmup_steps: usize) -> Self {
+        assert!(
+            initial_lr > 0.0,
+            "The initial learning rate must be a positive value."
+        );
+        assert!(
+            decay_steps > 0,
+            "The number decay steps should be non-zero."
+        );
+
+        CosineDecay {
+            initial_lr,
+            lr: initial_lr,
+            decay_steps,
+            restarts,
+            warmup_steps,
+        }
+    }
+}
+
+impl LearningRateSchedule for CosineDecay {
+    fn compute_step_learning_rate(&mut self, global_step: usize) -> f32 {
+        if global_step < self.warmup_steps {
+            return (self.initial_lr / (self.warmup_steps as f32)) * global_step as f32;
+        }
+
+        let step = global_step - self.warmup_steps;
+        let step = if self.restarts {
+            step % self.decay_steps
+        } else {
+            step.min(self.decay_steps)
+        };
+
+        let progress = step as f32 / self.decay_steps as f32;
+        self.lr = self.initial_lr * 0.5 * (1. + (PI * progress).cos());
+        self.lr
+    }
+
+    fn compute_epoch_learning_rate(&mut self, _epoch: usize, _last_score: f32) -> f32 {
+        self.lr
+    }
+
+    fn initial_lr(&self) -> f32 {
+        self.initial_lr
+    }
+
+    fn set_initial_lr(&mut self, lr: f32) {
+        self.initial_lr = lr;
+    }
+}
+
+/// Inverse square root learning rate schedule.
+///
+/// After warmup, the learning rate decays proportionally to the inverse
+/// square root of the step (Vaswani et al., 2017):
+///
+/// *lr = initial_lr * sqrt(warmup_steps / global_step)*
+///
+/// Without warmup, the learning rate is computed as
+/// *initial_lr * sqrt(1 / (global_step + 1))*.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct InverseSqrtDecay {
+    initial_lr: f32,
+    lr: f32,
+    warmup_steps: usize,
+}
+
+impl InverseSqrtDecay {
+    /// Construct an inverse square root decay schedule.
+    ///
+    /// If `warmup_steps` > 0, the learning rate is linearly scaled
+    /// for `warmup_steps` from 0 -> `initial_lr`.
+    pub fn new(initial_lr: f32, warmup_steps: usize) -> Self {
+        assert!(
+            initial_lr > 0.0,
+            "The initial learning rate must be a positive value."
+        );
+
+        InverseSqrtDecay {
+            initial_lr,
+            lr: initial_lr,
+            warmup_steps,
+        }
+    }
+}
+
+impl LearningRateSchedule for InverseSqrtDecay {
+    fn compute_step_learning_rate(&mut self, global_step: usize) -> f32 {
+        if global_step < self.warmup_steps {
+            return (self.initial_lr / (self.warmup_steps as f32)) * global_step as f32;
+        }
+
+        self.lr = if self.warmup_steps == 0 {
+            self.initial_lr * (1. / (global_step + 1) as f32).sqrt()
+        } else {
+            self.initial_lr * (self.warmup_steps as f32 / global_step as f32).sqrt()
+        };
+        self.lr
+    }
+
+    fn compute_epoch_learning_rate(&mut self, _epoch: usize, _last_score: f32) -> f32 {
+        self.lr
+    }
+
+    fn initial_lr(&self) -> f32 {
+        self.initial_lr
+    }
+
+    fn set_initial_lr(&mut self, lr: f32) {
+        self.initial_lr = lr;
+    }
+}
+
+/// Step-based learning rate schedule that is selected at run time.
+#[derive(Clone, Deserialize, Serialize)]
+pub enum DecaySchedule {
+    Cosine(CosineDecay),
+    Exponential(ExponentialDecay),
+    InverseSqrt(InverseSqrtDecay),
+    Linear(LinearDecay),
+}
+
+impl DecaySchedule {
+    fn inner(&self) -> &dyn LearningRateSchedule {
+        match self {
+            DecaySchedule::Cosine(schedule) => schedule,
+            DecaySchedule::Exponential(schedule) => schedule,
+            DecaySchedule::InverseSqrt(schedule) => schedule,
+            DecaySchedule::Linear(schedule) => schedule,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn LearningRateSchedule {
+        match self {
+            DecaySchedule::Cosine(schedule) => schedule,
+            DecaySchedule::Exponential(schedule) => schedule,
+            DecaySchedule::InverseSqrt(schedule) => schedule,
+            DecaySchedule::Linear(schedule) => schedule,
+        }
+    }
+}
+
+impl LearningRateSchedule for DecaySchedule {
+    fn compute_epoch_learning_rate(&mut self, epoch: usize, last_score: f32) -> f32 {
+        self.inner_mut()
+            .compute_epoch_learning_rate(epoch, last_score)
+    }
+
+    fn compute_step_learning_rate(&mut self, global_step: usize) -> f32 {
+        self.inner_mut().compute_step_learning_rate(global_step)
+    }
+
+    fn initial_lr(&self) -> f32 {
+        self.inner().initial_lr()
+    }
+
+    fn set_initial_lr(&mut self, lr: f32) {
+        self.inner_mut().set_initial_lr(lr)
+    }
+}
+
 /// Plateau learning rate schedule.
 ///
 /// This schedule scales the learning rate by some factor when a
@@ -150,7 +403,7 @@ impl LearningRateSchedule for ExponentialDecay {
 /// The plateau learning rate schedule wraps another learning rate
 /// schedule. This schedule can be used in conjunction with
 /// `ConstantLearningRate` for a traditional plateau schedule.
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct PlateauLearningRate<I> {
     scale: f32,
     best_score: f32,
@@ -217,7 +470,8 @@ mod tests {
     use approx::assert_relative_eq;
 
     use super::{
-        ConstantLearningRate, ExponentialDecay, LearningRateSchedule, PlateauLearningRate,
+        ConstantLearningRate, CosineDecay, ExponentialDecay, InverseSqrtDecay,
+        LearningRateSchedule, LinearDecay, PlateauLearningRate,
     };
 
     #[test]
@@ -267,6 +521,47 @@ mod tests {
         assert_relative_eq!(decay1.compute_step_learning_rate(31), 0.004);
     }
 
+    #[test]
+    fn linear_decay_lr_warmup() {
+        let mut decay = LinearDecay::new(0.1, 10, 5);
+        assert_relative_eq!(decay.compute_step_learning_rate(0), 0.0);
+        assert_relative_eq!(decay.compute_step_learning_rate(1), 0.02);
+        assert_relative_eq!(decay.compute_step_learning_rate(5), 0.1);
+        assert_relative_eq!(decay.compute_step_learning_rate(10), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(14), 0.01);
+        assert_relative_eq!(decay.compute_step_learning_rate(15), 0.0);
+        assert_relative_eq!(decay.compute_step_learning_rate(25), 0.0);
+    }
+
+    #[test]
+    fn cosine_decay_lr() {
+        let mut decay = CosineDecay::new(0.1, 10, false, 0);
+        assert_relative_eq!(decay.compute_step_learning_rate(0), 0.1);
+        assert_relative_eq!(decay.compute_step_learning_rate(5), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(10), 0.0);
+        assert_relative_eq!(decay.compute_step_learning_rate(15), 0.0);
+    }
+
+    #[test]
+    fn cosine_decay_lr_restarts() {
+        let mut decay = CosineDecay::new(0.1, 10, true, 2);
+        assert_relative_eq!(decay.compute_step_learning_rate(1), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(2), 0.1);
+        assert_relative_eq!(decay.compute_step_learning_rate(7), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(12), 0.1);
+        assert_relative_eq!(decay.compute_step_learning_rate(17), 0.05);
+    }
+
+    #[test]
+    fn inverse_sqrt_decay_lr() {
+        let mut decay = InverseSqrtDecay::new(0.1, 4);
+        assert_relative_eq!(decay.compute_step_learning_rate(0), 0.0);
+        assert_relative_eq!(decay.compute_step_learning_rate(2), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(4), 0.1);
+        assert_relative_eq!(decay.compute_step_learning_rate(16), 0.05);
+        assert_relative_eq!(decay.compute_step_learning_rate(64), 0.025);
+    }
+
     #[test]
     fn plateau_lr() {
         let mut plateau = PlateauLearningRate::new(ConstantLearningRate::new(0.1, 0), 0.5, 2);
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::time::Instant;
 
 use syntaxdot_tch_ext::PathExt;
-use syntaxdot_transformers::layers::Dropout;
+use syntaxdot_transformers::layers::{Dropout, Embedding};
 use syntaxdot_transformers::models::albert::{AlbertConfig, AlbertEmbeddings, AlbertEncoder};
 use syntaxdot_transformers::models::bert::{BertConfig, BertEmbeddings, BertEncoder};
 use syntaxdot_transformers::models::roberta::RobertaEmbeddings;
@@ -12,19 +12,27 @@ use syntaxdot_transformers::models::squeeze_albert::SqueezeAlbertEncoder;
 use syntaxdot_transformers::models::squeeze_bert::SqueezeBertEncoder;
 use syntaxdot_transformers::models::Encoder as _;
 use syntaxdot_transformers::models::LayerOutput;
-use syntaxdot_transformers::module::FallibleModuleT;
+use syntaxdot_transformers::module::{FallibleModule, FallibleModuleT};
 use syntaxdot_transformers::TransformerError;
+use tch::nn::Init;
 use tch::{self, Tensor};
 
-use crate::config::{BiaffineParserConfig, PositionEmbeddings, PretrainConfig};
-use crate::encoders::Encoders;
+use crate::config::{
+    BiaffineParserConfig, PositionEmbeddings, PretrainConfig, Regularization, TaskWeighting,
+};
+use crate::encoders::{Encoders, EncodersConfig};
 use crate::error::SyntaxDotError;
 use crate::model::biaffine_dependency_layer::{
     BiaffineDependencyLayer, BiaffineLoss, BiaffineScoreLogits,
 };
-use crate::model::pooling::PiecePooler;
+use crate::model::pooling::{PiecePooler, Pooler, PoolingPosition};
+use crate::model::sentence_classifiers::{SentenceClassifiers, SentenceClassifiersLoss};
 use crate::model::seq_classifiers::{SequenceClassifiers, SequenceClassifiersLoss, TopK};
-use crate::tensor::{BiaffineTensors, TokenMask, TokenSpans};
+use crate::model::task_weights::TaskWeights;
+use crate::tensor::{BiaffineTensors, TokenMask, TokenSpans, TokenSpansWithRoot};
+
+/// Name of the biaffine parser in task weighting.
+const BIAFFINE_TASK: &str = "biaffine";
 
 pub trait PretrainBertConfig {
     fn bert_config(&self) -> Cow<BertConfig>;
@@ -195,9 +203,59 @@ impl Encoder {
     }
 }
 
+/// Layer outputs of the encoder.
+///
+/// When pieces are pooled before scalar weighting, the layer outputs
+/// are token representations. Otherwise, the layer outputs are piece
+/// representations and the classifiers pool their scalar-weighted
+/// representations using `pool_weighted`.
+pub struct Encoding<'a> {
+    deferred_pooling: Option<(&'a Pooler, TokenSpansWithRoot)>,
+    layers: Vec<LayerOutput>,
+}
+
+impl<'a> Encoding<'a> {
+    /// Get the layer outputs.
+    pub fn layers(&self) -> &[LayerOutput] {
+        &self.layers
+    }
+
+    /// Get the layer outputs as token representations.
+    ///
+    /// The layers are pooled if pooling was deferred.
+    pub fn pooled_layers(&self) -> Result<Vec<LayerOutput>, SyntaxDotError> {
+        Ok(self
+            .layers
+            .iter()
+            .map(|layer| {
+                layer.map_output(|output| match &self.deferred_pooling {
+                    Some((pooler, token_spans)) => pooler.pool_layer(token_spans, output),
+                    None => Ok(output.shallow_clone()),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Pool a scalar-weighted representation if pooling was deferred.
+    pub fn pool_weighted(&self, weighted: Tensor) -> Result<Tensor, SyntaxDotError> {
+        match &self.deferred_pooling {
+            Some((pooler, token_spans)) => Ok(pooler.pool_layer(token_spans, &weighted)?),
+            None => Ok(weighted),
+        }
+    }
+}
+
 pub struct BertLoss {
     pub biaffine: Option<BiaffineLoss>,
+
+    /// The weighted sum of the task losses.
+    pub loss: Tensor,
+
     pub seq_classifiers: SequenceClassifiersLoss,
+    pub sentence_classifiers: SentenceClassifiersLoss,
+
+    /// The effective weight of each task loss.
+    pub task_weights: HashMap<String, f32>,
 }
 
 /// Multi-task classifier using the BERT architecture with scalar weighting.
@@ -206,16 +264,26 @@ pub struct BertModel {
     biaffine: Option<BiaffineDependencyLayer>,
     embeddings: BertEmbeddingLayer,
     encoder: Encoder,
-    pooler: PiecePooler,
+    pooler: Pooler,
     seq_classifiers: SequenceClassifiers,
+    sentence_classifiers: SentenceClassifiers,
     layers_dropout: Dropout,
+    task_weights: TaskWeights,
+    treebank_embeddings: Option<Embedding>,
 }
 
 impl BertModel {
     /// Construct a fresh model.
     ///
-    /// `layer_dropout` is the probability with which layers should
-    /// be dropped out in scalar weighting during training.
+    /// `encoders_config` configures the classifier heads of the
+    /// `encoders`. `pooler` and `pooling_position` configure how piece
+    /// representations are pooled into token representations.
+    /// `regularization` configures the dropout of the layer outputs and
+    /// the heads. Transformer dropout is configured through
+    /// `pretrain_config`. `task_weighting` configures how the losses of
+    /// the encoders and the biaffine parser are combined. If
+    /// `n_treebanks` is non-zero, the model has treebank embeddings for
+    /// the treebank identifiers `1..=n_treebanks`.
     #[allow(clippy::too_many_arguments)]
     pub fn new<'a>(
         vs: impl Borrow<PathExt<'a>>,
@@ -223,63 +291,179 @@ impl BertModel {
         biaffine_config: Option<&BiaffineParserConfig>,
         n_relations: usize,
         encoders: &Encoders,
+        encoders_config: &EncodersConfig,
         pooler: PiecePooler,
-        layers_dropout: f64,
+        pooling_position: PoolingPosition,
+        regularization: &Regularization,
         position_embeddings: PositionEmbeddings,
+        task_weighting: TaskWeighting,
+        n_treebanks: usize,
     ) -> Result<Self, SyntaxDotError> {
         let vs = vs.borrow();
 
         let embeddings = BertEmbeddingLayer::new(vs, pretrain_config, position_embeddings)?;
 
+        // Treebank embeddings are initialized to zero, so that a fresh
+        // model starts out with the pretrained piece embeddings. The
+        // identifier 0 is used for sentences from unknown treebanks.
+        let treebank_embeddings = if n_treebanks == 0 {
+            None
+        } else {
+            Some(Embedding::new(
+                vs / "treebank_embeddings",
+                "embeddings",
+                n_treebanks as i64 + 1,
+                pretrain_config.bert_config().hidden_size,
+                Init::Const(0.),
+            )?)
+        };
+
         let encoder = Encoder::new(vs, pretrain_config)?;
 
+        let pooler = Pooler::new(
+            vs,
+            pooler,
+            pooling_position,
+            pretrain_config.bert_config().hidden_size,
+        )?;
+
         let biaffine = biaffine_config
             .map(|config| {
                 BiaffineDependencyLayer::new(
                     vs,
                     pretrain_config,
                     config,
+                    regularization,
                     encoder.n_layers(),
                     n_relations as i64,
                 )
             })
             .transpose()?;
 
-        let seq_classifiers =
-            SequenceClassifiers::new(vs, pretrain_config, encoder.n_layers(), encoders)?;
+        let seq_classifiers = SequenceClassifiers::new(
+            vs,
+            pretrain_config,
+            encoder.n_layers(),
+            encoders,
+            encoders_config,
+            regularization,
+        )?;
+
+        let sentence_classifiers = SentenceClassifiers::new(
+            vs,
+            pretrain_config,
+            encoder.n_layers(),
+            encoders,
+            encoders_config,
+            regularization,
+        )?;
+
+        let mut loss_weights: HashMap<_, _> = encoders_config
+            .iter()
+            .map(|config| (config.name.clone(), config.loss_weight))
+            .collect();
+        if let Some(biaffine_config) = biaffine_config {
+            loss_weights.insert(BIAFFINE_TASK.to_string(), biaffine_config.loss_weight);
+        }
+        let task_weights = TaskWeights::new(vs, loss_weights, task_weighting)?;
 
         Ok(BertModel {
             embeddings,
             encoder,
-            layers_dropout: Dropout::new(layers_dropout),
+            layers_dropout: Dropout::new(regularization.layers_dropout.unwrap_or(0.0)),
             pooler,
             biaffine,
             seq_classifiers,
+            sentence_classifiers,
+            task_weights,
+            treebank_embeddings,
         })
     }
 
+    /// Read the class weights of the losses.
+    ///
+    /// Class weights are only used by the losses, so this only needs
+    /// to be called when the model is trained. This keeps class weight
+    /// files out of the requirements for annotation.
+    pub fn load_class_weights(
+        &mut self,
+        biaffine_config: Option<&BiaffineParserConfig>,
+        encoders_config: &EncodersConfig,
+    ) -> Result<(), SyntaxDotError> {
+        if let (Some(biaffine), Some(biaffine_config)) = (&mut self.biaffine, biaffine_config) {
+            biaffine.load_class_weights(biaffine_config)?;
+        }
+
+        self.seq_classifiers.load_class_weights(encoders_config)?;
+        self.sentence_classifiers
+            .load_class_weights(encoders_config)?;
+
+        Ok(())
+    }
+
+    /// Get the number of encoder layers, including the embedding layer.
+    pub fn n_layers(&self) -> i64 {
+        self.encoder.n_layers()
+    }
+
     /// Compute the biaffine logits for a batch of inputs from the transformer's encoding.
     pub fn biaffine_logits_from_encoding(
         &self,
-        layer_outputs: &[LayerOutput],
+        encoding: &Encoding,
         token_mask: &TokenMask,
         train: bool,
     ) -> Result<Option<BiaffineScoreLogits>, SyntaxDotError> {
         self.biaffine
             .as_ref()
-            .map(|biaffine| biaffine.forward(layer_outputs, token_mask, true, train))
+            .map(|biaffine| biaffine.forward(encoding, token_mask, true, train))
             .transpose()
     }
 
+    /// Add treebank embeddings to piece embeddings.
+    ///
+    /// If the model has treebank embeddings, the embedding of the
+    /// treebank of each sequence is added to its piece embeddings.
+    /// Returns an error if a treebank identifier is not in
+    /// `0..=n_treebanks`.
+    fn add_treebank_embeddings(
+        &self,
+        embeds: Tensor,
+        treebank_ids: &Tensor,
+    ) -> Result<Tensor, SyntaxDotError> {
+        match &self.treebank_embeddings {
+            Some(treebank_embeddings) => {
+                let n_treebanks = treebank_embeddings.0.size()[0] - 1;
+                if treebank_ids.numel() != 0 {
+                    let min_id = treebank_ids.f_min()?.int64_value(&[]);
+                    let max_id = treebank_ids.f_max()?.int64_value(&[]);
+                    if min_id < 0 || max_id > n_treebanks {
+                        return Err(SyntaxDotError::IllegalConfigurationError(format!(
+                            "Treebank identifier should be in 0..={}, was: {}",
+                            n_treebanks,
+                            if min_id < 0 { min_id } else { max_id }
+                        )));
+                    }
+                }
+
+                let treebank_embeds = treebank_embeddings.forward(treebank_ids)?;
+                Ok(embeds.f_add(&treebank_embeds.f_unsqueeze(1)?)?)
+            }
+            None => Ok(embeds),
+        }
+    }
+
     /// Encode an input.
+    ///
+    /// `treebank_ids` contains the treebank identifier of each sequence.
     pub fn encode(
         &self,
         inputs: &Tensor,
         attention_mask: &Tensor,
         token_spans: &TokenSpans,
+        treebank_ids: &Tensor,
         train: bool,
         freeze_layers: FreezeLayers,
-    ) -> Result<Vec<LayerOutput>, SyntaxDotError> {
+    ) -> Result<Encoding, SyntaxDotError> {
         let start = Instant::now();
 
         let embeds = if freeze_layers.embeddings {
@@ -288,15 +472,26 @@ impl BertModel {
             self.embeddings.forward_t(inputs, train)?
         };
 
+        // Treebank embeddings are not pretrained, so they are also
+        // trained when the piece embeddings are frozen.
+        let embeds = self.add_treebank_embeddings(embeds, treebank_ids)?;
+
         let encoded = if freeze_layers.encoder {
             tch::no_grad(|| self.encoder.encode(&embeds, Some(attention_mask), train))?
         } else {
             self.encoder.encode(&embeds, Some(attention_mask), train)?
         };
 
-        let mut pooled = self.pooler.pool(token_spans, &encoded)?;
+        let (mut layers, deferred_pooling) = match self.pooler.position() {
+            PoolingPosition::BeforeScalarWeighting => {
+                (self.pooler.pool(token_spans, &encoded)?, None)
+            }
+            PoolingPosition::AfterScalarWeighting => {
+                (encoded, Some((&self.pooler, token_spans.with_root()?)))
+            }
+        };
 
-        for layer in &mut pooled {
+        for layer in &mut layers {
             *layer.output_mut() = if freeze_layers.classifiers {
                 tch::no_grad(|| self.layers_dropout.forward_t(layer.output(), train))?
             } else {
@@ -312,13 +507,17 @@ impl BertModel {
             start.elapsed().as_millis()
         );
 
-        Ok(pooled)
+        Ok(Encoding {
+            deferred_pooling,
+            layers,
+        })
     }
 
     /// Compute the logits for a batch of inputs.
     ///
     /// * `attention_mask`: specifies which sequence elements should
     ///    be masked when applying the encoder.
+    /// * `treebank_ids`: the treebank identifier of each sequence.
     /// * `train`: indicates whether this forward pass will be used
     ///   for backpropagation.
     /// * `freeze_embeddings`: exclude embeddings from backpropagation.
@@ -328,10 +527,18 @@ impl BertModel {
         inputs: &Tensor,
         attention_mask: &Tensor,
         token_spans: &TokenSpans,
+        treebank_ids: &Tensor,
         train: bool,
         freeze_layers: FreezeLayers,
     ) -> Result<HashMap<String, Tensor>, SyntaxDotError> {
-        let encoding = self.encode(inputs, attention_mask, token_spans, train, freeze_layers)?;
+        let encoding = self.encode(
+            inputs,
+            attention_mask,
+            token_spans,
+            treebank_ids,
+            train,
+            freeze_layers,
+        )?;
         self.seq_classifiers.forward_t(&encoding, train)
     }
 
@@ -345,10 +552,23 @@ impl BertModel {
     /// * `freeze_encoder`: exclude the encoder from backpropagation.
     pub fn encoder_logits_from_encoding(
         &self,
-        layer_outputs: &[LayerOutput],
+        encoding: &Encoding,
+        train: bool,
+    ) -> Result<HashMap<String, Tensor>, SyntaxDotError> {
+        self.seq_classifiers.forward_t(encoding, train)
+    }
+
+    /// Compute the sentence classifier logits for a batch of inputs from
+    /// the transformer's encoding.
+    ///
+    /// The logits of each sentence-level encoder have the shape
+    /// `[batch_size, 1, n_labels]`.
+    pub fn sentence_logits_from_encoding(
+        &self,
+        encoding: &Encoding,
         train: bool,
     ) -> Result<HashMap<String, Tensor>, SyntaxDotError> {
-        self.seq_classifiers.forward_t(layer_outputs, train)
+        self.sentence_classifiers.forward_t(encoding, train)
     }
 
     /// Compute the loss given a batch of inputs and target labels.
@@ -358,6 +578,7 @@ impl BertModel {
     /// * `token_mask`: specifies which sequence elements should be
     ///    masked when computing the loss. Typically, this is used
     ///    to exclude padding and continuation word pieces.
+    /// * `treebank_ids`: the treebank identifier of each sequence.
     /// * `targets`: the labels to be predicted, per encoder name.
     /// * `label_smoothing`: apply label smoothing, redistributing
     ///   the given probability to non-target labels.
@@ -371,46 +592,25 @@ impl BertModel {
         inputs: &Tensor,
         attention_mask: &Tensor,
         token_spans: &TokenSpans,
+        treebank_ids: &Tensor,
         biaffine_tensors: Option<BiaffineTensors<Tensor>>,
         targets: &HashMap<String, Tensor>,
         label_smoothing: Option<f64>,
         train: bool,
         freeze_layers: FreezeLayers,
     ) -> Result<BertLoss, SyntaxDotError> {
-        let encoding = self.encode(inputs, attention_mask, token_spans, train, freeze_layers)?;
+        let encoding = self.encode(
+            inputs,
+            attention_mask,
+            token_spans,
+            treebank_ids,
+            train,
+            freeze_layers,
+        )?;
 
         let token_mask = token_spans.token_mask()?;
 
-        if freeze_layers.classifiers {
-            tch::no_grad(|| {
-                let biaffine_loss = self
-                    .biaffine
-                    .as_ref()
-                    .map(|biaffine| {
-                        biaffine.loss(
-                            &encoding,
-                            &token_mask,
-                            biaffine_tensors.as_ref().unwrap(),
-                            label_smoothing,
-                            train,
-                        )
-                    })
-                    .transpose()?;
-
-                let seq_classifiers_loss = self.seq_classifiers.loss(
-                    &encoding,
-                    targets,
-                    label_smoothing,
-                    &token_mask,
-                    train,
-                )?;
-
-                Ok(BertLoss {
-                    biaffine: biaffine_loss,
-                    seq_classifiers: seq_classifiers_loss,
-                })
-            })
-        } else {
+        let loss = || {
             let biaffine_loss = self
                 .biaffine
                 .as_ref()
@@ -433,10 +633,39 @@ impl BertModel {
                 train,
             )?;
 
+            let sentence_classifiers_loss =
+                self.sentence_classifiers
+                    .loss(&encoding, targets, label_smoothing, train)?;
+
+            let biaffine_task_loss = biaffine_loss
+                .as_ref()
+                .map(|loss| loss.head_loss.f_add(&loss.relation_loss))
+                .transpose()?;
+
+            let task_losses = seq_classifiers_loss
+                .encoder_losses
+                .iter()
+                .chain(&sentence_classifiers_loss.encoder_losses)
+                .map(|(name, loss)| (name.as_str(), loss))
+                .chain(biaffine_task_loss.iter().map(|loss| (BIAFFINE_TASK, loss)));
+
+            let (loss, task_weights) = self
+                .task_weights
+                .weighted_loss(task_losses, token_mask.device())?;
+
             Ok(BertLoss {
                 biaffine: biaffine_loss,
+                loss,
                 seq_classifiers: seq_classifiers_loss,
+                sentence_classifiers: sentence_classifiers_loss,
+                task_weights,
             })
+        };
+
+        if freeze_layers.classifiers {
+            tch::no_grad(loss)
+        } else {
+            loss()
         }
     }
 
@@ -446,16 +675,19 @@ impl BertModel {
     ///    tokens.
     /// * `attention_mask`: specifies which sequence elements should
     ///    be masked when applying the encoder.
+    /// * `treebank_ids`: the treebank identifier of each sequence.
     pub fn predict(
         &self,
         inputs: &Tensor,
         attention_mask: &Tensor,
         token_spans: &TokenSpans,
+        treebank_ids: &Tensor,
     ) -> Result<Predictions, SyntaxDotError> {
         let encoding = self.encode(
             inputs,
             attention_mask,
             token_spans,
+            treebank_ids,
             false,
             FreezeLayers {
                 embeddings: true,
@@ -470,10 +702,12 @@ impl BertModel {
             .map(|biaffine| biaffine.forward(&encoding, &token_spans.token_mask()?, false, false))
             .transpose()?;
         let sequences_top_k = self.seq_classifiers.top_k(&encoding, 3)?;
+        let sentences_top_k = self.sentence_classifiers.top_k(&encoding, 3)?;
 
         Ok(Predictions {
             biaffine_score_logits,
             sequences_top_k,
+            sentences_top_k,
         })
     }
 }
@@ -489,4 +723,5 @@ pub struct FreezeLayers {
 pub struct Predictions {
     pub biaffine_score_logits: Option<BiaffineScoreLogits>,
     pub sequences_top_k: HashMap<String, TopK>,
+    pub sentences_top_k: HashMap<String, TopK>,
 }
@@ -6,15 +6,16 @@ use syntaxdot_transformers::layers::{
     PairwiseBilinear, PairwiseBilinearConfig, VariationalDropout,
 };
 use syntaxdot_transformers::loss::CrossEntropyLoss;
-use syntaxdot_transformers::models::LayerOutput;
 use syntaxdot_transformers::module::{FallibleModule, FallibleModuleT};
 use syntaxdot_transformers::scalar_weighting::ScalarWeight;
+use syntaxdot_transformers::TransformerError;
 use tch::nn::{Init, Linear, Module};
 use tch::{Kind, Reduction, Tensor};
 
-use crate::config::{BiaffineParserConfig, PretrainConfig};
+use crate::config::{BiaffineParserConfig, PretrainConfig, Regularization};
 use crate::error::SyntaxDotError;
-use crate::model::bert::PretrainBertConfig;
+use crate::model::bert::{Encoding, PretrainBertConfig};
+use crate::model::masked_mean;
 use crate::tensor::{BiaffineTensors, TokenMask};
 
 /// Accuracy of a biaffine parsing layer.
@@ -76,6 +77,9 @@ pub struct BiaffineDependencyLayer {
 
     dropout: VariationalDropout,
     n_relations: i64,
+
+    relation_class_weights: Option<Tensor>,
+    relation_focal_gamma: Option<f64>,
 }
 
 impl BiaffineDependencyLayer {
@@ -84,6 +88,7 @@ impl BiaffineDependencyLayer {
         vs: impl Borrow<PathExt<'a>>,
         pretrain_config: &PretrainConfig,
         biaffine_config: &BiaffineParserConfig,
+        regularization: &Regularization,
         n_layers: i64,
         n_relations: i64,
     ) -> Result<BiaffineDependencyLayer, SyntaxDotError> {
@@ -92,7 +97,13 @@ impl BiaffineDependencyLayer {
         let vs = vs.borrow() / "biaffine";
         let vs = vs.borrow();
 
-        let scalar_weight = ScalarWeight::new(vs, n_layers, bert_config.hidden_dropout_prob)?;
+        let scalar_weight = ScalarWeight::new(
+            vs,
+            n_layers,
+            regularization
+                .scalar_weight_dropout
+                .unwrap_or(bert_config.hidden_dropout_prob),
+        )?;
 
         let arc_dependent = Self::affine(
             vs / "arc_dependent",
@@ -152,7 +163,11 @@ impl BiaffineDependencyLayer {
             },
         )?;
 
-        let dropout = VariationalDropout::new(bert_config.hidden_dropout_prob);
+        let dropout = VariationalDropout::new(
+            regularization
+                .biaffine_dropout
+                .unwrap_or(bert_config.hidden_dropout_prob),
+        );
 
         Ok(BiaffineDependencyLayer {
             scalar_weight,
@@ -168,9 +183,39 @@ impl BiaffineDependencyLayer {
 
             dropout,
             n_relations,
+
+            relation_class_weights: None,
+            relation_focal_gamma: biaffine_config.loss.focal_gamma,
         })
     }
 
+    /// Read the relation class weights of the loss.
+    ///
+    /// Class weights are only used by the loss, so they are only read
+    /// when the model is trained.
+    pub fn load_class_weights(
+        &mut self,
+        biaffine_config: &BiaffineParserConfig,
+    ) -> Result<(), SyntaxDotError> {
+        let class_weights = match &biaffine_config.loss.class_weights {
+            Some(class_weights) => class_weights.read()?,
+            None => return Ok(()),
+        };
+
+        if class_weights.len() as i64 != self.n_relations {
+            return Err(TransformerError::IncorrectNumberOfClassWeights {
+                n_class_weights: class_weights.len(),
+                n_classes: self.n_relations,
+            }
+            .into());
+        }
+
+        self.relation_class_weights =
+            Some(Tensor::of_slice(&class_weights).f_to_device(self.arc_dependent.ws.device())?);
+
+        Ok(())
+    }
+
     fn affine<'a>(
         vs: impl Borrow<PathExt<'a>>,
         in_features: i64,
@@ -198,14 +243,14 @@ impl BiaffineDependencyLayer {
     ///
     /// The required arguments are:
     ///
-    /// * `layers`: encoder output.
+    /// * `encoding`: encoder output.
     /// * `token_mask`: mask of tokens with shape `[batch_size, seq_len]`.
     /// * `train`: should be `true` when the layer is used in backprop, or `false` otherwise.
     ///
     /// Returns the unnormalized head and label probabilities (logits).
     pub fn forward(
         &self,
-        layers: &[LayerOutput],
+        encoding: &Encoding,
         token_mask: &TokenMask,
         remove_root: bool,
         train: bool,
@@ -219,7 +264,8 @@ impl BiaffineDependencyLayer {
         let _ = logits_mask.f_slice(1, 0, 1, 1)?.f_fill_(0)?;
 
         // Get weighted hidden representation.
-        let hidden = self.scalar_weight.forward(layers, train)?;
+        let hidden =
+            encoding.pool_weighted(self.scalar_weight.forward(encoding.layers(), train)?)?;
 
         // Compute dependent/head arc representations of each token.
         let arc_dependent = self.dropout.forward_t(
@@ -276,9 +322,10 @@ impl BiaffineDependencyLayer {
     ///
     /// The required arguments are:
     ///
-    /// * `layers`: encoder output.
+    /// * `encoding`: encoder output.
     /// * `token_mask`: mask of tokens with shape `[batch_size, seq_len]`.
     /// * `targets`: the gold-standard dependency heads and dependency relations.
+    ///   Heads and relations with the value `-1` are excluded from the loss.
     /// * `label_smoothing`: label smoothing for dependency relations, the given probability
     ///   is distributed among incorrect labels.
     /// * `train`: should be `true` when the layer is used in backprop, or `false` otherwise.
@@ -286,7 +333,7 @@ impl BiaffineDependencyLayer {
     /// Returns the loss and greedy decoding LAS/UAS.
     pub fn loss(
         &self,
-        layers: &[LayerOutput],
+        encoding: &Encoding,
         token_mask: &TokenMask,
         targets: &BiaffineTensors<Tensor>,
         label_smoothing: Option<f64>,
@@ -311,7 +358,7 @@ impl BiaffineDependencyLayer {
             token_mask.dim()
         );
 
-        let biaffine_logits = self.forward(layers, token_mask, true, train)?;
+        let biaffine_logits = self.forward(encoding, token_mask, true, train)?;
 
         let (batch_size, seq_len) = targets.heads.size2()?;
 
@@ -356,11 +403,10 @@ impl BiaffineDependencyLayer {
             .f_view_(&[-1, self.n_relations])?;
 
         let relation_targets = targets.relations.f_view_(&[-1])?;
-        let relation_loss = CrossEntropyLoss::new(-1, label_smoothing, Reduction::Mean).forward(
-            &label_score_logits,
-            &relation_targets,
-            None,
-        )?;
+        let relation_loss = CrossEntropyLoss::new(-1, label_smoothing, Reduction::Mean)
+            .with_class_weights(self.relation_class_weights.as_ref())
+            .with_focal_gamma(self.relation_focal_gamma)
+            .forward(&label_score_logits, &relation_targets, None)?;
 
         // Compute greedy decoding accuracy.
         let acc =
@@ -397,18 +443,13 @@ impl BiaffineDependencyLayer {
 
         let head_and_relations_correct = head_correct.f_logical_and(&relations_correct)?;
 
-        let las = head_and_relations_correct
-            .f_masked_select(token_mask)?
-            .f_to_kind(Kind::Float)?
-            .f_mean(Kind::Float)?;
-        let ls = relations_correct
-            .f_masked_select(token_mask)?
-            .f_to_kind(Kind::Float)?
-            .f_mean(Kind::Float)?;
-        let uas = head_correct
-            .f_masked_select(token_mask)?
-            .f_to_kind(Kind::Float)?
-            .f_mean(Kind::Float)?;
+        // Exclude tokens with masked heads or relations.
+        let head_mask = token_mask.f_logical_and(&targets.heads.f_ne(-1)?)?;
+        let relation_mask = token_mask.f_logical_and(&targets.relations.f_ne(-1)?)?;
+
+        let las = masked_mean(&head_and_relations_correct, &relation_mask)?;
+        let ls = masked_mean(&relations_correct, &relation_mask)?;
+        let uas = masked_mean(&head_correct, &head_mask)?;
 
         Ok(BiaffineAccuracy { las, ls, uas })
     }
@@ -4,4 +4,24 @@ pub mod bert;
 
 pub(crate) mod pooling;
 
+pub mod sentence_classifiers;
+
 pub mod seq_classifiers;
+
+pub mod task_weights;
+
+use tch::{Kind, Tensor};
+
+use crate::error::SyntaxDotError;
+
+/// Compute the mean of the elements of `t` that are selected by `mask`.
+///
+/// Returns zero when no elements are selected, for instance when all
+/// labels of a batch are masked.
+pub(crate) fn masked_mean(t: &Tensor, mask: &Tensor) -> Result<Tensor, SyntaxDotError> {
+    let selected = t.f_masked_select(mask)?.f_to_kind(Kind::Float)?;
+    let n_selected = selected.size()[0];
+    Ok(selected
+        .f_sum(Kind::Float)?
+        .f_div_scalar(n_selected.max(1) as f64)?)
+}
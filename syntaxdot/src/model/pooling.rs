@@ -1,6 +1,10 @@
+use std::borrow::Borrow;
+
 use serde::{Deserialize, Serialize};
+use syntaxdot_tch_ext::PathExt;
 use syntaxdot_transformers::models::LayerOutput;
 use syntaxdot_transformers::TransformerError;
+use tch::nn::{Init, Linear, Module};
 use tch::{Kind, Tensor};
 
 use crate::error::SyntaxDotError;
@@ -19,20 +23,124 @@ use crate::tensor::{TokenSpans, TokenSpansWithRoot};
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PiecePooler {
+    /// Learned attention over the piece representations.
+    Attention,
+
     /// Discard continuation pieces.
     Discard,
 
+    /// Concatenate the first and last piece representations and
+    /// project the result to the hidden size.
+    FirstLast,
+
+    /// Use the element-wise maximum of the piece representations.
+    Max,
+
     /// Use the mean of the piece representations as token representations.
     Mean,
 }
 
-impl PiecePooler {
+/// Position of piece pooling in the model.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolingPosition {
+    /// Pool the pieces of every layer, before scalar weighting.
+    BeforeScalarWeighting,
+
+    /// Pool the pieces after scalar weighting.
+    ///
+    /// Each classifier pools its own scalar-weighted piece
+    /// representations.
+    AfterScalarWeighting,
+}
+
+impl Default for PoolingPosition {
+    fn default() -> Self {
+        PoolingPosition::BeforeScalarWeighting
+    }
+}
+
+/// Piece pooling layer.
+///
+/// This layer applies the pooling of a `PiecePooler` and stores the
+/// parameters of the poolers that are learned.
+#[derive(Debug)]
+pub struct Pooler {
+    attention: Option<Linear>,
+    first_last_projection: Option<Linear>,
+    pooler: PiecePooler,
+    position: PoolingPosition,
+}
+
+impl Pooler {
+    /// Construct a pooling layer.
+    ///
+    /// Parameters are only created for the `Attention` and `FirstLast`
+    /// poolers, so that models that use other poolers do not change.
+    pub fn new<'a>(
+        vs: impl Borrow<PathExt<'a>>,
+        pooler: PiecePooler,
+        position: PoolingPosition,
+        hidden_size: i64,
+    ) -> Result<Self, SyntaxDotError> {
+        let vs = vs.borrow() / "pooler";
+
+        let attention = match pooler {
+            // Zero-initialized weights start out as mean pooling.
+            PiecePooler::Attention => Some(Self::affine(
+                vs.sub("attention"),
+                hidden_size,
+                1,
+                Init::Const(0.),
+            )?),
+            _ => None,
+        };
+
+        let first_last_projection = match pooler {
+            PiecePooler::FirstLast => Some(Self::affine(
+                vs.sub("first_last_projection"),
+                2 * hidden_size,
+                hidden_size,
+                Init::KaimingUniform,
+            )?),
+            _ => None,
+        };
+
+        Ok(Pooler {
+            attention,
+            first_last_projection,
+            pooler,
+            position,
+        })
+    }
+
+    fn affine<'a>(
+        vs: impl Borrow<PathExt<'a>>,
+        in_features: i64,
+        out_features: i64,
+        init: Init,
+    ) -> Result<Linear, SyntaxDotError> {
+        let vs = vs.borrow();
+
+        Ok(Linear {
+            ws: vs.var("weight", &[out_features, in_features], init)?,
+            bs: Some(vs.var("bias", &[out_features], Init::Const(0.))?),
+        })
+    }
+
+    /// Get the position of pooling in the model.
+    pub fn position(&self) -> PoolingPosition {
+        self.position
+    }
+
     /// Pool pieces in all layers.
     pub fn pool(
         &self,
         token_spans: &TokenSpans,
         layer_outputs: &[LayerOutput],
     ) -> Result<Vec<LayerOutput>, SyntaxDotError> {
+        let token_spans = token_spans.with_root()?;
+
         let mut new_layer_outputs = Vec::with_capacity(layer_outputs.len());
 
         // Note: it would seem more efficient to stack all layers and pool all layers
@@ -45,7 +153,7 @@ impl PiecePooler {
         // per layer.
         for layer_output in layer_outputs {
             let new_layer_output = layer_output
-                .map_output(|output| self.pool_layer(&token_spans.with_root()?, output))
+                .map_output(|output| self.pool_layer(&token_spans, output))
                 .map_err(SyntaxDotError::BertError)?;
 
             new_layer_outputs.push(new_layer_output);
@@ -55,21 +163,48 @@ impl PiecePooler {
     }
 
     /// Pool the pieces in a single layer.
-    fn pool_layer(
+    pub fn pool_layer(
         &self,
         token_spans: &TokenSpansWithRoot,
         layer: &Tensor,
     ) -> Result<Tensor, TransformerError> {
         let token_embeddings = token_spans.embeddings_per_token(layer)?;
 
-        let pooled_layer = match self {
+        let pooled_layer = match self.pooler {
+            PiecePooler::Attention => self.pool_attention(&token_embeddings)?,
             PiecePooler::Discard => Self::pool_discard(&token_embeddings)?,
+            PiecePooler::FirstLast => self.pool_first_last(&token_embeddings)?,
+            PiecePooler::Max => Self::pool_max(&token_embeddings)?,
             PiecePooler::Mean => Self::pool_mean(&token_embeddings)?,
         };
 
         Ok(pooled_layer)
     }
 
+    /// Attention pooling.
+    fn pool_attention(
+        &self,
+        token_embeddings: &TokenEmbeddings,
+    ) -> Result<Tensor, TransformerError> {
+        let attention = self
+            .attention
+            .as_ref()
+            .expect("Attention pooler without attention parameters");
+
+        // Piece scores with shape [batch_size, tokens_len, max_token_len].
+        let scores = attention
+            .forward(&token_embeddings.embeddings)
+            .f_squeeze_dim(-1)?
+            .f_masked_fill(&token_embeddings.mask.f_logical_not()?, -10_000.)?;
+
+        let weights = scores.f_softmax(-1, Kind::Float)?.f_unsqueeze(-1)?;
+
+        Ok(token_embeddings
+            .embeddings
+            .f_mul(&weights)?
+            .f_sum_dim_intlist(&[2], false, Kind::Float)?)
+    }
+
     /// Discard pooling.
     fn pool_discard(token_embeddings: &TokenEmbeddings) -> Result<Tensor, TransformerError> {
         Ok(token_embeddings
@@ -78,6 +213,54 @@ impl PiecePooler {
             .f_squeeze_dim(2)?)
     }
 
+    /// First-last pooling.
+    fn pool_first_last(
+        &self,
+        token_embeddings: &TokenEmbeddings,
+    ) -> Result<Tensor, TransformerError> {
+        let projection = self
+            .first_last_projection
+            .as_ref()
+            .expect("First-last pooler without projection parameters");
+
+        let (_, _, _, hidden_size) = token_embeddings.embeddings.size4()?;
+
+        let first = token_embeddings
+            .embeddings
+            .f_slice(2, 0, 1, 1)?
+            .f_squeeze_dim(2)?;
+
+        let last_indices = token_embeddings
+            .mask
+            .f_sum_dim_intlist(&[2], true, Kind::Int64)?
+            .f_sub_scalar(1)?
+            .f_clamp_min(0)?
+            .f_unsqueeze(-1)?
+            .f_expand(&[-1, -1, 1, hidden_size], true)?;
+        let last = token_embeddings
+            .embeddings
+            .f_gather(2, &last_indices, false)?
+            .f_squeeze_dim(2)?;
+
+        Ok(projection.forward(&Tensor::f_cat(&[first, last], -1)?))
+    }
+
+    /// Max pooling.
+    fn pool_max(token_embeddings: &TokenEmbeddings) -> Result<Tensor, TransformerError> {
+        let (max, _) = token_embeddings
+            .embeddings
+            .f_masked_fill(
+                &token_embeddings.mask.f_logical_not()?.f_unsqueeze(-1)?,
+                -10_000.,
+            )?
+            .f_max_dim(2, false)?;
+
+        // Tokens without pieces (padding) would otherwise get large negative values.
+        let has_pieces = token_embeddings.mask.f_any_dim(2, false)?.f_unsqueeze(-1)?;
+
+        Ok(max.f_mul(&has_pieces)?)
+    }
+
     /// Mean pooling
     fn pool_mean(token_embeddings: &TokenEmbeddings) -> Result<Tensor, TransformerError> {
         let pieces_per_token = token_embeddings
@@ -154,23 +337,65 @@ impl EmbeddingsPerToken for TokenSpansWithRoot {
 
 #[cfg(test)]
 mod tests {
+    use syntaxdot_tch_ext::RootExt;
+    use tch::nn::VarStore;
     use tch::{Device, Kind, Tensor};
 
-    use crate::model::pooling::{EmbeddingsPerToken, PiecePooler};
+    use crate::model::pooling::{EmbeddingsPerToken, PiecePooler, Pooler, PoolingPosition};
     use crate::tensor::{TokenSpans, TokenSpansWithRoot};
 
-    #[test]
-    fn discard_pooler_works_correctly() {
-        let spans = TokenSpans::new(
+    fn pooler(vs: &VarStore, pooler: PiecePooler) -> Pooler {
+        Pooler::new(
+            vs.root_ext(|_| 0),
+            pooler,
+            PoolingPosition::BeforeScalarWeighting,
+            2,
+        )
+        .unwrap()
+    }
+
+    fn test_spans() -> TokenSpans {
+        TokenSpans::new(
             Tensor::of_slice2(&[[1, 3, 4, -1, -1], [1, 3, 4, 6, 7]]),
             Tensor::of_slice2(&[[2, 1, 1, -1, -1], [2, 1, 2, 1, 1]]),
+        )
+    }
+
+    #[test]
+    fn attention_pooler_is_initialized_as_mean_pooler() {
+        let spans = test_spans();
+
+        let hidden = Tensor::arange_start_step(36, 0, -1, (Kind::Int64, Device::Cpu))
+            .view([2, 9, 2])
+            .to_kind(Kind::Float);
+
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::Attention);
+
+        let token_embeddings = pooler
+            .pool_layer(&spans.with_root().unwrap(), &hidden)
+            .unwrap();
+
+        assert_eq!(
+            token_embeddings,
+            Tensor::of_slice2(&[
+                &[36, 35, 33, 32, 30, 29, 28, 27, 0, 0, 0, 0,],
+                &[18, 17, 15, 14, 12, 11, 9, 8, 6, 5, 4, 3]
+            ])
+            .view([2, 6, 2])
         );
+    }
+
+    #[test]
+    fn discard_pooler_works_correctly() {
+        let spans = test_spans();
 
         let hidden = Tensor::arange_start_step(36, 0, -1, (Kind::Int64, Device::Cpu))
             .view([2, 9, 2])
             .to_kind(Kind::Float);
 
-        let pooler = PiecePooler::Discard;
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::Discard);
 
         let token_embeddings = pooler
             .pool_layer(&spans.with_root().unwrap(), &hidden)
@@ -186,6 +411,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn first_last_pooler_projects_to_hidden_size() {
+        let spans = test_spans();
+
+        let hidden = Tensor::arange_start_step(36, 0, -1, (Kind::Int64, Device::Cpu))
+            .view([2, 9, 2])
+            .to_kind(Kind::Float);
+
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::FirstLast);
+
+        let token_embeddings = pooler
+            .pool_layer(&spans.with_root().unwrap(), &hidden)
+            .unwrap();
+
+        assert_eq!(token_embeddings.size(), &[2, 6, 2]);
+        assert_eq!(vs.variables().len(), 2);
+    }
+
+    #[test]
+    fn first_last_pooler_projects_first_and_last_piece() {
+        let spans = test_spans();
+
+        let hidden = Tensor::arange_start_step(36, 0, -1, (Kind::Int64, Device::Cpu))
+            .view([2, 9, 2])
+            .to_kind(Kind::Float);
+
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::FirstLast);
+
+        // Project to the first dimension of the first piece and the
+        // second dimension of the last piece.
+        let mut weight = vs.variables()["pooler.first_last_projection.weight"].shallow_clone();
+        tch::no_grad(|| weight.copy_(&Tensor::of_slice2(&[[1f32, 0., 0., 0.], [0., 0., 0., 1.]])));
+
+        let token_embeddings = pooler
+            .pool_layer(&spans.with_root().unwrap(), &hidden)
+            .unwrap();
+
+        assert_eq!(
+            token_embeddings,
+            Tensor::of_slice2(&[
+                &[36f32, 35., 34., 31., 30., 29., 28., 27., 0., 0., 0., 0.],
+                &[18., 17., 16., 13., 12., 11., 10., 7., 6., 5., 4., 3.]
+            ])
+            .view([2, 6, 2])
+        );
+    }
+
+    #[test]
+    fn max_pooler_works_correctly() {
+        let spans = test_spans();
+
+        let hidden = Tensor::arange(36, (Kind::Int64, Device::Cpu))
+            .view([2, 9, 2])
+            .to_kind(Kind::Float);
+
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::Max);
+
+        let token_embeddings = pooler
+            .pool_layer(&spans.with_root().unwrap(), &hidden)
+            .unwrap();
+
+        assert_eq!(
+            token_embeddings,
+            Tensor::of_slice2(&[
+                &[0, 1, 4, 5, 6, 7, 8, 9, 0, 0, 0, 0],
+                &[18, 19, 22, 23, 24, 25, 28, 29, 30, 31, 32, 33]
+            ])
+            .view([2, 6, 2])
+        );
+    }
+
     #[test]
     fn embeddings_are_returned_per_token() {
         let spans = TokenSpansWithRoot::new(
@@ -219,16 +518,14 @@ mod tests {
 
     #[test]
     fn mean_pooler_works_correctly() {
-        let spans = TokenSpans::new(
-            Tensor::of_slice2(&[[1, 3, 4, -1, -1], [1, 3, 4, 6, 7]]),
-            Tensor::of_slice2(&[[2, 1, 1, -1, -1], [2, 1, 2, 1, 1]]),
-        );
+        let spans = test_spans();
 
         let hidden = Tensor::arange_start_step(36, 0, -1, (Kind::Int64, Device::Cpu))
             .view([2, 9, 2])
             .to_kind(Kind::Float);
 
-        let pooler = PiecePooler::Mean;
+        let vs = VarStore::new(Device::Cpu);
+        let pooler = pooler(&vs, PiecePooler::Mean);
 
         let token_embeddings = pooler
             .pool_layer(&spans.with_root().unwrap(), &hidden)
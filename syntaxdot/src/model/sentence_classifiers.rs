@@ -0,0 +1,200 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+
+use syntaxdot_tch_ext::PathExt;
+use syntaxdot_transformers::scalar_weighting::ScalarWeightClassifier;
+use tch::{Kind, Tensor};
+
+use crate::config::{PretrainConfig, Regularization};
+use crate::encoders::{Encoders, EncodersConfig};
+use crate::error::SyntaxDotError;
+use crate::model::bert::{Encoding, PretrainBertConfig};
+use crate::model::masked_mean;
+use crate::model::seq_classifiers::{scalar_weight_classifier_config, TopK};
+
+/// A set of sentence classifiers.
+///
+/// This data type stores a set of scalar weight-based classifiers for
+/// sentence-level encoders. The classifiers make their predictions
+/// using the representation of the sentence root, which corresponds to
+/// the beginning of sentence piece (e.g. `[CLS]` or `<s>`).
+#[derive(Debug)]
+pub struct SentenceClassifiers {
+    classifiers: HashMap<String, ScalarWeightClassifier>,
+}
+
+impl SentenceClassifiers {
+    /// Create a set of sentence classifiers.
+    ///
+    /// Classifiers are only constructed for sentence-level encoders.
+    pub fn new<'a>(
+        vs: impl Borrow<PathExt<'a>>,
+        pretrain_config: &PretrainConfig,
+        n_layers: i64,
+        encoders: &Encoders,
+        encoders_config: &EncodersConfig,
+        regularization: &Regularization,
+    ) -> Result<SentenceClassifiers, SyntaxDotError> {
+        let vs = vs.borrow();
+
+        let bert_config = pretrain_config.bert_config();
+
+        let classifiers = encoders
+            .iter()
+            .filter(|encoder| encoder.encoder().is_sentence_level())
+            .map(|encoder| {
+                Ok((
+                    encoder.name().to_owned(),
+                    ScalarWeightClassifier::new(
+                        vs.sub("sentence_classifiers")
+                            .sub(format!("{}_classifier", encoder.name())),
+                        &scalar_weight_classifier_config(
+                            &encoders_config.classifier(encoder.name()),
+                            &encoders_config.loss(encoder.name()),
+                            &bert_config,
+                            regularization,
+                            n_layers,
+                            encoder.encoder().len() as i64,
+                        ),
+                    )?,
+                ))
+            })
+            .collect::<Result<_, SyntaxDotError>>()?;
+
+        Ok(SentenceClassifiers { classifiers })
+    }
+
+    /// Read the class weights of the classifier losses.
+    ///
+    /// Class weights are only used by the loss, so they are only read
+    /// when the model is trained.
+    pub fn load_class_weights(
+        &mut self,
+        encoders_config: &EncodersConfig,
+    ) -> Result<(), SyntaxDotError> {
+        for (name, classifier) in &mut self.classifiers {
+            if let Some(class_weights) = encoders_config.loss(name).class_weights {
+                classifier.set_class_weights(&class_weights.read()?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if there are no sentence classifiers.
+    pub fn is_empty(&self) -> bool {
+        self.classifiers.is_empty()
+    }
+
+    /// Perform a forward pass of sentence classifiers.
+    ///
+    /// The logits of each classifier have shape `[batch_size, 1, n_labels]`.
+    pub fn forward_t(
+        &self,
+        encoding: &Encoding,
+        train: bool,
+    ) -> Result<HashMap<String, Tensor>, SyntaxDotError> {
+        self.classifiers
+            .iter()
+            .map(|(encoder_name, classifier)| {
+                let weighted = Self::weighted_root(encoding, classifier, train)?;
+                Ok((
+                    encoder_name.to_string(),
+                    classifier.logits_from_weighted(&weighted, train)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Compute the loss of each sentence classifier.
+    ///
+    /// This method computes the loss of each sentence classifier, using
+    /// the targets of shape `[batch_size, 1]`. Targets with the value
+    /// `-1` are masked labels, they are excluded from the loss and
+    /// accuracy.
+    ///
+    /// If `label_smoothing` is enabled, a the given amount of probability
+    /// mass of `targets` is redistributed among other classes than the
+    /// targets.
+    pub fn loss(
+        &self,
+        encoding: &Encoding,
+        targets: &HashMap<String, Tensor>,
+        label_smoothing: Option<f64>,
+        train: bool,
+    ) -> Result<SentenceClassifiersLoss, SyntaxDotError> {
+        let mut encoder_losses = HashMap::with_capacity(self.classifiers.len());
+        let mut encoder_accuracies = HashMap::with_capacity(self.classifiers.len());
+        for (encoder_name, classifier) in &self.classifiers {
+            let weighted = Self::weighted_root(encoding, classifier, train)?;
+            let encoder_targets = &targets[encoder_name];
+            let (loss, correct) = classifier.losses_from_weighted(
+                &weighted,
+                encoder_targets,
+                label_smoothing,
+                train,
+            )?;
+
+            let label_mask = encoder_targets.f_ne(-1)?;
+            let loss = masked_mean(&loss, &label_mask)?;
+            let acc = masked_mean(&correct, &label_mask)?;
+
+            encoder_losses.insert(encoder_name.clone(), loss);
+            encoder_accuracies.insert(encoder_name.clone(), acc);
+        }
+
+        let summed_loss = encoder_losses.values().try_fold(
+            Tensor::f_zeros(&[], (Kind::Float, encoding.layers()[0].output().device()))?,
+            |summed_loss, loss| summed_loss.f_add(loss),
+        )?;
+
+        Ok(SentenceClassifiersLoss {
+            summed_loss,
+            encoder_losses,
+            encoder_accuracies,
+        })
+    }
+
+    /// Predict for each classifier the top-K labels and their probabilities.
+    ///
+    /// The labels and probabilities of each classifier have shape
+    /// `[batch_size, 1, k]`.
+    pub fn top_k(
+        &self,
+        encoding: &Encoding,
+        k: usize,
+    ) -> Result<HashMap<String, TopK>, SyntaxDotError> {
+        self.classifiers
+            .iter()
+            .map(|(encoder_name, classifier)| {
+                let weighted = Self::weighted_root(encoding, classifier, false)?;
+                let (probs, mut labels) = classifier
+                    .forward_from_weighted(&weighted, false)?
+                    // Exclude first two classes (padding and continuation).
+                    .f_slice(-1, 2, i64::MAX, 1)?
+                    .f_topk(k as i64, -1, true, true)?;
+
+                // Fix label offsets.
+                let _ = labels.f_add_scalar_(2)?;
+
+                Ok((encoder_name.to_string(), TopK { labels, probs }))
+            })
+            .collect()
+    }
+
+    /// Get the scalar-weighted root representation of a classifier.
+    fn weighted_root(
+        encoding: &Encoding,
+        classifier: &ScalarWeightClassifier,
+        train: bool,
+    ) -> Result<Tensor, SyntaxDotError> {
+        let weighted = classifier.weighted_layers(encoding.layers(), train)?;
+        Ok(encoding.pool_weighted(weighted)?.f_slice(1, 0, 1, 1)?)
+    }
+}
+
+pub struct SentenceClassifiersLoss {
+    pub summed_loss: Tensor,
+    pub encoder_losses: HashMap<String, Tensor>,
+    pub encoder_accuracies: HashMap<String, Tensor>,
+}
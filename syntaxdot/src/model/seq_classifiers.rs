@@ -2,16 +2,17 @@ use std::borrow::Borrow;
 use std::collections::HashMap;
 
 use syntaxdot_tch_ext::PathExt;
-use syntaxdot_transformers::models::LayerOutput;
+use syntaxdot_transformers::models::bert::BertConfig;
 use syntaxdot_transformers::scalar_weighting::{
     ScalarWeightClassifier, ScalarWeightClassifierConfig,
 };
 use tch::{Kind, Tensor};
 
-use crate::config::PretrainConfig;
-use crate::encoders::Encoders;
+use crate::config::{LossConfig, PretrainConfig, Regularization};
+use crate::encoders::{ClassifierConfig, Encoders, EncodersConfig};
 use crate::error::SyntaxDotError;
-use crate::model::bert::PretrainBertConfig;
+use crate::model::bert::{Encoding, PretrainBertConfig};
+use crate::model::masked_mean;
 use crate::tensor::TokenMask;
 use std::time::Instant;
 
@@ -27,11 +28,16 @@ pub struct SequenceClassifiers {
 
 impl SequenceClassifiers {
     /// Create a set of sequence classifiers.
+    ///
+    /// Sentence-level encoders are skipped, these are handled by
+    /// `SentenceClassifiers`.
     pub fn new<'a>(
         vs: impl Borrow<PathExt<'a>>,
         pretrain_config: &PretrainConfig,
         n_layers: i64,
         encoders: &Encoders,
+        encoders_config: &EncodersConfig,
+        regularization: &Regularization,
     ) -> Result<SequenceClassifiers, SyntaxDotError> {
         let vs = vs.borrow();
 
@@ -39,21 +45,21 @@ impl SequenceClassifiers {
 
         let classifiers = encoders
             .iter()
+            .filter(|encoder| !encoder.encoder().is_sentence_level())
             .map(|encoder| {
                 Ok((
                     encoder.name().to_owned(),
                     ScalarWeightClassifier::new(
                         vs.sub("classifiers")
                             .sub(format!("{}_classifier", encoder.name())),
-                        &ScalarWeightClassifierConfig {
-                            dropout_prob: bert_config.hidden_dropout_prob,
-                            hidden_size: bert_config.hidden_size,
-                            input_size: bert_config.hidden_size,
-                            layer_dropout_prob: 0.1,
-                            layer_norm_eps: bert_config.layer_norm_eps,
+                        &scalar_weight_classifier_config(
+                            &encoders_config.classifier(encoder.name()),
+                            &encoders_config.loss(encoder.name()),
+                            &bert_config,
+                            regularization,
                             n_layers,
-                            n_labels: encoder.encoder().len() as i64,
-                        },
+                            encoder.encoder().len() as i64,
+                        ),
                     )?,
                 ))
             })
@@ -62,23 +68,36 @@ impl SequenceClassifiers {
         Ok(SequenceClassifiers { classifiers })
     }
 
+    /// Read the class weights of the classifier losses.
+    ///
+    /// Class weights are only used by the loss, so they are only read
+    /// when the model is trained.
+    pub fn load_class_weights(
+        &mut self,
+        encoders_config: &EncodersConfig,
+    ) -> Result<(), SyntaxDotError> {
+        for (name, classifier) in &mut self.classifiers {
+            if let Some(class_weights) = encoders_config.loss(name).class_weights {
+                classifier.set_class_weights(&class_weights.read()?)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Perform a forward pass of sequence classifiers.
     pub fn forward_t(
         &self,
-        layers: &[LayerOutput],
+        encoding: &Encoding,
         train: bool,
     ) -> Result<HashMap<String, Tensor>, SyntaxDotError> {
-        let layers_without_root = layers
-            .iter()
-            .map(|layer| layer.map_output(|output| Ok(output.f_slice(1, 1, i64::MAX, 1)?)))
-            .collect::<Result<Vec<_>, _>>()?;
-
         self.classifiers
             .iter()
             .map(|(encoder_name, classifier)| {
+                let weighted = Self::weighted_tokens(encoding, classifier, train)?;
                 Ok((
                     encoder_name.to_string(),
-                    classifier.logits(&layers_without_root, train)?,
+                    classifier.logits_from_weighted(&weighted, train)?,
                 ))
             })
             .collect()
@@ -97,46 +116,40 @@ impl SequenceClassifiers {
     ///
     /// If `include_continuations` is set to `true`, the loss is also
     /// computed over continuation pieces.
+    ///
+    /// Targets with the value `-1` are masked labels, they are excluded
+    /// from the loss and accuracy.
     #[allow(clippy::too_many_arguments)]
     pub fn loss(
         &self,
-        layers: &[LayerOutput],
+        encoding: &Encoding,
         targets: &HashMap<String, Tensor>,
         label_smoothing: Option<f64>,
         token_mask: &TokenMask,
         train: bool,
     ) -> Result<SequenceClassifiersLoss, SyntaxDotError> {
-        // Remove root token representation.
-        let layers_without_root = layers
-            .iter()
-            .map(|layer| layer.map_output(|output| Ok(output.f_slice(1, 1, i64::MAX, 1)?)))
-            .collect::<Result<Vec<_>, _>>()?;
-
         let mut encoder_losses = HashMap::with_capacity(self.classifiers.len());
         let mut encoder_accuracies = HashMap::with_capacity(self.classifiers.len());
         for (encoder_name, classifier) in &self.classifiers {
-            let (loss, correct) = classifier.losses(
-                &layers_without_root,
-                &targets[encoder_name],
+            let weighted = Self::weighted_tokens(encoding, classifier, train)?;
+            let encoder_targets = &targets[encoder_name];
+            let (loss, correct) = classifier.losses_from_weighted(
+                &weighted,
+                encoder_targets,
                 label_smoothing,
                 train,
             )?;
 
-            let loss = loss
-                .f_masked_select(token_mask)?
-                .f_to_kind(Kind::Float)?
-                .f_mean(Kind::Float)?;
-            let acc = correct
-                .f_masked_select(token_mask)?
-                .f_to_kind(Kind::Float)?
-                .f_mean(Kind::Float)?;
+            let label_mask = token_mask.f_logical_and(&encoder_targets.f_ne(-1)?)?;
+            let loss = masked_mean(&loss, &label_mask)?;
+            let acc = masked_mean(&correct, &label_mask)?;
 
             encoder_losses.insert(encoder_name.clone(), loss);
             encoder_accuracies.insert(encoder_name.clone(), acc);
         }
 
         let summed_loss = encoder_losses.values().try_fold(
-            Tensor::f_zeros(&[], (Kind::Float, layers_without_root[0].output().device()))?,
+            Tensor::f_zeros(&[], (Kind::Float, token_mask.device()))?,
             |summed_loss, loss| summed_loss.f_add(loss),
         )?;
 
@@ -154,22 +167,18 @@ impl SequenceClassifiers {
     /// returns a mapping for the classifier name to `(probabilities, labels)`.
     pub fn top_k(
         &self,
-        layers: &[LayerOutput],
+        encoding: &Encoding,
         k: usize,
     ) -> Result<HashMap<String, TopK>, SyntaxDotError> {
         let start = Instant::now();
 
-        let layers_without_root = layers
-            .iter()
-            .map(|layer| layer.map_output(|output| Ok(output.f_slice(1, 1, i64::MAX, 1)?)))
-            .collect::<Result<Vec<_>, _>>()?;
-
         let top_k = self
             .classifiers
             .iter()
             .map(|(encoder_name, classifier)| {
+                let weighted = Self::weighted_tokens(encoding, classifier, false)?;
                 let (probs, mut labels) = classifier
-                    .forward(&layers_without_root, false)?
+                    .forward_from_weighted(&weighted, false)?
                     // Exclude first two classes (padding and continuation).
                     .f_slice(-1, 2, i64::MAX, 1)?
                     .f_topk(k as i64, -1, true, true)?;
@@ -181,7 +190,7 @@ impl SequenceClassifiers {
             })
             .collect();
 
-        let (batch_size, seq_len, _) = layers_without_root[0].output().size3()?;
+        let (batch_size, seq_len, _) = encoding.layers()[0].output().size3()?;
         log::debug!(
             "Predicted top-{} labels for {} inputs with length {} in {}ms",
             k,
@@ -192,6 +201,49 @@ impl SequenceClassifiers {
 
         top_k
     }
+
+    /// Get the scalar-weighted token representations of a classifier.
+    ///
+    /// The representation of the root is removed.
+    fn weighted_tokens(
+        encoding: &Encoding,
+        classifier: &ScalarWeightClassifier,
+        train: bool,
+    ) -> Result<Tensor, SyntaxDotError> {
+        let weighted = classifier.weighted_layers(encoding.layers(), train)?;
+        Ok(encoding
+            .pool_weighted(weighted)?
+            .f_slice(1, 1, i64::MAX, 1)?)
+    }
+}
+
+/// Construct the configuration of an encoder's scalar weight classifier.
+///
+/// Options that are not set in the classifier configuration are taken
+/// from the pretrained model configuration. Class weights are not read
+/// here, they are set with `load_class_weights` for training.
+pub(crate) fn scalar_weight_classifier_config(
+    config: &ClassifierConfig,
+    loss: &LossConfig,
+    bert_config: &BertConfig,
+    regularization: &Regularization,
+    n_layers: i64,
+    n_labels: i64,
+) -> ScalarWeightClassifierConfig {
+    ScalarWeightClassifierConfig {
+        activation: config.activation,
+        class_weights: None,
+        dropout_prob: config.dropout.unwrap_or(bert_config.hidden_dropout_prob),
+        focal_gamma: loss.focal_gamma,
+        hidden_size: config.hidden_size.unwrap_or(bert_config.hidden_size),
+        input_size: bert_config.hidden_size,
+        layer_dropout_prob: regularization.scalar_weight_dropout_prob(),
+        layer_norm_eps: bert_config.layer_norm_eps,
+        layers: config.layers.clone(),
+        n_hidden_layers: config.hidden_layers,
+        n_layers,
+        n_labels,
+    }
 }
 
 pub struct SequenceClassifiersLoss {
@@ -0,0 +1,132 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+
+use syntaxdot_tch_ext::PathExt;
+use tch::nn::Init;
+use tch::{Device, Kind, Tensor};
+
+use crate::config::TaskWeighting;
+use crate::error::SyntaxDotError;
+
+/// Weights of the task losses of a multi-task model.
+///
+/// This data type combines the losses of the tasks of a model into
+/// a single loss. Each task has a static loss weight. When uncertainty
+/// weighting is used, each task additionally has a learned log variance
+/// that scales its loss.
+#[derive(Debug)]
+pub struct TaskWeights {
+    log_variances: HashMap<String, Tensor>,
+    weights: HashMap<String, f64>,
+}
+
+impl TaskWeights {
+    /// Construct the task weights.
+    ///
+    /// `weights` maps each task to its static loss weight.
+    pub fn new<'a>(
+        vs: impl Borrow<PathExt<'a>>,
+        weights: HashMap<String, f64>,
+        weighting: TaskWeighting,
+    ) -> Result<Self, SyntaxDotError> {
+        let vs = vs.borrow();
+
+        let log_variances = match weighting {
+            TaskWeighting::Static => HashMap::new(),
+            TaskWeighting::Uncertainty => weights
+                .keys()
+                .map(|task| {
+                    Ok((
+                        task.clone(),
+                        vs.sub("task_weights").var(
+                            &format!("{}_log_variance", task),
+                            &[],
+                            Init::Const(0.),
+                        )?,
+                    ))
+                })
+                .collect::<Result<_, SyntaxDotError>>()?,
+        };
+
+        Ok(TaskWeights {
+            log_variances,
+            weights,
+        })
+    }
+
+    /// Compute the weighted sum of the task losses.
+    ///
+    /// Returns the weighted loss and the effective weight of each task.
+    pub fn weighted_loss<'a>(
+        &self,
+        losses: impl IntoIterator<Item = (&'a str, &'a Tensor)>,
+        device: Device,
+    ) -> Result<(Tensor, HashMap<String, f32>), SyntaxDotError> {
+        let mut weighted_loss = Tensor::f_zeros(&[], (Kind::Float, device))?;
+        let mut effective_weights = HashMap::with_capacity(self.weights.len());
+
+        for (task, loss) in losses {
+            let weight = self.weights.get(task).copied().unwrap_or(1.0);
+
+            let task_loss = match self.log_variances.get(task) {
+                Some(log_variance) => {
+                    let precision = log_variance.f_neg()?.f_exp()?;
+                    effective_weights
+                        .insert(task.to_string(), weight as f32 * f32::from(&precision));
+                    precision.f_mul(loss)?.f_add(log_variance)?
+                }
+                None => {
+                    effective_weights.insert(task.to_string(), weight as f32);
+                    loss.shallow_clone()
+                }
+            };
+
+            weighted_loss = weighted_loss.f_add(&task_loss.f_mul_scalar(weight)?)?;
+        }
+
+        Ok((weighted_loss, effective_weights))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use approx::assert_abs_diff_eq;
+    use syntaxdot_tch_ext::RootExt;
+    use tch::nn::VarStore;
+    use tch::{Device, Tensor};
+
+    use super::TaskWeights;
+    use crate::config::TaskWeighting;
+
+    #[test]
+    fn uncertainty_weighting_scales_losses_by_precision() {
+        let vs = VarStore::new(Device::Cpu);
+        let weights = vec![("pos".to_string(), 2.0), ("lemma".to_string(), 1.0)]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let task_weights =
+            TaskWeights::new(vs.root_ext(|_| 0), weights, TaskWeighting::Uncertainty).unwrap();
+
+        let mut pos_log_variance = vs
+            .variables()
+            .remove("task_weights.pos_log_variance")
+            .unwrap();
+        tch::no_grad(|| pos_log_variance.fill_(2f64.ln()));
+
+        let pos_loss = Tensor::from(3f32);
+        let lemma_loss = Tensor::from(1f32);
+        let (loss, effective_weights) = task_weights
+            .weighted_loss(
+                vec![("pos", &pos_loss), ("lemma", &lemma_loss)],
+                Device::Cpu,
+            )
+            .unwrap();
+
+        // pos: 2 * (0.5 * 3 + ln 2), lemma: 1 * (1 * 1 + 0)
+        assert_abs_diff_eq!(f32::from(loss), 4. + 2. * 2f32.ln(), epsilon = 1e-6);
+        assert_abs_diff_eq!(effective_weights["pos"], 1.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(effective_weights["lemma"], 1.0, epsilon = 1e-6);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use tch::nn::VarStore;
+use tch::Tensor;
+
+use super::params::{named_trainable_variables, GroupHyperparameters, ParameterGroups};
+use super::{Optimizer, OptimizerState, ZeroGrad};
+use crate::error::SyntaxDotError;
+
+/// Adafactor optimizer configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct AdafactorConfig {
+    /// Threshold of the root mean square of an update.
+    ///
+    /// Updates with a larger root mean square are scaled down.
+    pub clip_threshold: f64,
+
+    /// Exponent of the second moment decay schedule.
+    ///
+    /// The decay rate of the second moment estimates in step `t` is
+    /// `1 - t^decay_rate`.
+    pub decay_rate: f64,
+
+    /// Term that is added to the squared gradients for numerical stability.
+    pub eps: f64,
+
+    /// Default learning rate of parameter groups.
+    pub lr: f64,
+
+    /// Default weight decay of parameter groups.
+    pub weight_decay: f64,
+}
+
+impl Default for AdafactorConfig {
+    fn default() -> Self {
+        AdafactorConfig {
+            clip_threshold: 1.0,
+            decay_rate: -0.8,
+            eps: 1e-30,
+            lr: 1e-3,
+            weight_decay: 0.,
+        }
+    }
+}
+
+/// Second moment estimates of a variable.
+enum SecondMoment {
+    /// Row and column estimates of a matrix (or batch of matrices).
+    Factored { row: Tensor, col: Tensor },
+
+    /// Estimates of a vector.
+    Full(Tensor),
+}
+
+struct AdafactorParameter {
+    name: String,
+    group: usize,
+    tensor: Tensor,
+    second_moment: SecondMoment,
+}
+
+/// Adafactor optimizer (Shazeer & Stern, 2018).
+///
+/// Adafactor does not store first moment estimates. For variables with
+/// two or more dimensions, the second moment estimates are factored
+/// into row and column estimates. This makes the optimizer state far
+/// smaller than that of Adam-style optimizers. This implementation uses
+/// the provided learning rate rather than relative step sizes. Weight
+/// decay is decoupled from the gradient update.
+///
+/// The optimizer updates the trainable variables that are in the
+/// variable store at construction time.
+pub struct Adafactor {
+    config: AdafactorConfig,
+    groups: ParameterGroups,
+    parameters: Vec<AdafactorParameter>,
+    step: i64,
+}
+
+impl Adafactor {
+    /// Construct an Adafactor optimizer for the trainable variables of `vs`.
+    pub fn new(vs: &VarStore, config: AdafactorConfig) -> Result<Self, SyntaxDotError> {
+        let parameters = named_trainable_variables(vs)
+            .into_iter()
+            .map(|var| {
+                let kind = var.tensor.kind();
+                let second_moment = if var.tensor.dim() >= 2 {
+                    SecondMoment::Factored {
+                        row: var.tensor.f_mean_dim(&[-1], false, kind)?.f_zeros_like()?,
+                        col: var.tensor.f_mean_dim(&[-2], false, kind)?.f_zeros_like()?,
+                    }
+                } else {
+                    SecondMoment::Full(var.tensor.f_zeros_like()?)
+                };
+
+                Ok(AdafactorParameter {
+                    name: var.name,
+                    group: var.group,
+                    tensor: var.tensor,
+                    second_moment,
+                })
+            })
+            .collect::<Result<_, SyntaxDotError>>()?;
+
+        Ok(Adafactor {
+            config,
+            groups: ParameterGroups::new(config.lr, config.weight_decay),
+            parameters,
+            step: 0,
+        })
+    }
+
+    fn update_parameter(
+        parameter: &mut AdafactorParameter,
+        config: &AdafactorConfig,
+        hyperparameters: GroupHyperparameters,
+        step: i64,
+    ) -> Result<(), SyntaxDotError> {
+        let grad = parameter.tensor.grad();
+        if !grad.defined() {
+            return Ok(());
+        }
+
+        let kind = grad.kind();
+        let beta2 = 1. - (step as f64).powf(config.decay_rate);
+        let grad_sq = grad.f_square()?.f_add_scalar(config.eps)?;
+
+        let update = match &mut parameter.second_moment {
+            SecondMoment::Factored { row, col } => {
+                let _ = row.f_mul_scalar_(beta2)?.f_add_(
+                    &grad_sq
+                        .f_mean_dim(&[-1], false, kind)?
+                        .f_mul_scalar(1. - beta2)?,
+                )?;
+                let _ = col.f_mul_scalar_(beta2)?.f_add_(
+                    &grad_sq
+                        .f_mean_dim(&[-2], false, kind)?
+                        .f_mul_scalar(1. - beta2)?,
+                )?;
+
+                let row_factor = row
+                    .f_div(&row.f_mean_dim(&[-1], true, kind)?)?
+                    .f_rsqrt()?
+                    .f_unsqueeze(-1)?;
+                let col_factor = col.f_unsqueeze(-2)?.f_rsqrt()?;
+
+                row_factor.f_mul(&col_factor)?.f_mul(&grad)?
+            }
+            SecondMoment::Full(second_moment) => {
+                let _ = second_moment
+                    .f_mul_scalar_(beta2)?
+                    .f_add_(&grad_sq.f_mul_scalar(1. - beta2)?)?;
+
+                second_moment.f_rsqrt()?.f_mul(&grad)?
+            }
+        };
+
+        // Scale down updates with a root mean square above the threshold.
+        let rms = update
+            .f_norm()?
+            .f_div_scalar((update.numel() as f64).sqrt())?;
+        let update = update.f_div(&rms.f_div_scalar(config.clip_threshold)?.f_clamp_min(1.)?)?;
+
+        if hyperparameters.weight_decay != 0. {
+            let _ = parameter
+                .tensor
+                .f_mul_scalar_(1. - hyperparameters.lr * hyperparameters.weight_decay)?;
+        }
+
+        let _ = parameter
+            .tensor
+            .f_sub_(&update.f_mul_scalar(hyperparameters.lr)?)?;
+
+        Ok(())
+    }
+}
+
+impl Optimizer for Adafactor {
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.trainable_variables().zero_grad();
+        loss.backward();
+        tch::no_grad(|| self.step());
+        Ok(())
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.groups.get_mut(group).lr = learning_rate;
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.groups.get_mut(group).weight_decay = weight_decay;
+    }
+
+    fn step(&mut self) {
+        self.step += 1;
+
+        for parameter in &mut self.parameters {
+            let hyperparameters = self.groups.get(parameter.group);
+            Self::update_parameter(parameter, &self.config, hyperparameters, self.step)
+                .expect("Cannot update parameter");
+        }
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.parameters
+            .iter()
+            .map(|parameter| parameter.tensor.shallow_clone())
+            .collect()
+    }
+}
+
+impl OptimizerState for Adafactor {
+    fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = Vec::with_capacity(2 * self.parameters.len() + 1);
+        state.push(("step".to_string(), Tensor::from(self.step)));
+
+        for parameter in &self.parameters {
+            match &parameter.second_moment {
+                SecondMoment::Factored { row, col } => {
+                    state.push((
+                        format!("exp_avg_sq_row.{}", parameter.name),
+                        row.shallow_clone(),
+                    ));
+                    state.push((
+                        format!("exp_avg_sq_col.{}", parameter.name),
+                        col.shallow_clone(),
+                    ));
+                }
+                SecondMoment::Full(second_moment) => state.push((
+                    format!("exp_avg_sq.{}", parameter.name),
+                    second_moment.shallow_clone(),
+                )),
+            }
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        let get = |name: String| {
+            state
+                .get(&name)
+                .ok_or(SyntaxDotError::MissingOptimizerState(name))
+        };
+
+        self.step = i64::from(get("step".to_string())?);
+
+        tch::no_grad(|| {
+            for parameter in &mut self.parameters {
+                match &mut parameter.second_moment {
+                    SecondMoment::Factored { row, col } => {
+                        row.f_copy_(get(format!("exp_avg_sq_row.{}", parameter.name))?)?;
+                        col.f_copy_(get(format!("exp_avg_sq_col.{}", parameter.name))?)?;
+                    }
+                    SecondMoment::Full(second_moment) => {
+                        second_moment.f_copy_(get(format!("exp_avg_sq.{}", parameter.name))?)?;
+                    }
+                }
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tch::nn::{Init, VarStore};
+    use tch::Device;
+
+    use super::{Adafactor, AdafactorConfig};
+    use crate::optimizers::tests::{check_restored_state_gives_identical_updates, quadratic_loss};
+    use crate::optimizers::Optimizer;
+
+    #[test]
+    fn factored_second_moment_update() {
+        let vs = VarStore::new(Device::Cpu);
+        let w = vs.root().var("w", &[2, 3], Init::Const(0.));
+        let mut optimizer = Adafactor::new(
+            &vs,
+            AdafactorConfig {
+                lr: 0.1,
+                ..AdafactorConfig::default()
+            },
+        )
+        .unwrap();
+
+        // The gradient is [[-2, 4, -6], [8, -10, 12]]. In the first step,
+        // the second moment estimates are the row means [56/3, 308/3] and
+        // the column means [34, 58, 90] of the squared gradient. The
+        // update is the gradient divided by the square root of
+        // row * col / mean(row). Its root mean square is below the
+        // clipping threshold.
+        optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        for (&v, &expected) in Vec::<f32>::from(&w.view([-1])).iter().zip(&[
+            0.061_835, -0.094_686, 0.114_018, -0.105_466, 0.100_936, -0.097_234,
+        ]) {
+            assert_abs_diff_eq!(v, expected, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn restored_optimizer_state_gives_identical_updates() {
+        let config = AdafactorConfig {
+            weight_decay: 0.01,
+            ..AdafactorConfig::default()
+        };
+
+        check_restored_state_gives_identical_updates(&[2, 3], |vs| {
+            Adafactor::new(vs, config).unwrap()
+        });
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use tch::nn::VarStore;
+use tch::Tensor;
+
+use super::params::{named_trainable_variables, GroupHyperparameters, ParameterGroups};
+use super::{Optimizer, OptimizerState, ZeroGrad};
+use crate::error::SyntaxDotError;
+
+/// AdamW optimizer configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct AdamWConfig {
+    /// Exponential decay rate of the first moment estimates.
+    pub beta1: f64,
+
+    /// Exponential decay rate of the second moment estimates.
+    pub beta2: f64,
+
+    /// Term that is added to the denominator for numerical stability.
+    pub eps: f64,
+
+    /// Default learning rate of parameter groups.
+    pub lr: f64,
+
+    /// Default weight decay of parameter groups.
+    pub weight_decay: f64,
+}
+
+impl Default for AdamWConfig {
+    fn default() -> Self {
+        AdamWConfig {
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            lr: 1e-3,
+            weight_decay: 0.,
+        }
+    }
+}
+
+struct AdamWParameter {
+    name: String,
+    group: usize,
+    tensor: Tensor,
+    exp_avg: Tensor,
+    exp_avg_sq: Tensor,
+}
+
+/// AdamW optimizer (Loshchilov & Hutter, 2019).
+///
+/// In contrast to the optimizers in `tch`, the moment estimates of this
+/// optimizer are accessible through the `OptimizerState` trait. This
+/// makes it possible to save the optimizer state and resume training.
+///
+/// The optimizer updates the trainable variables that are in the
+/// variable store at construction time.
+pub struct AdamW {
+    config: AdamWConfig,
+    groups: ParameterGroups,
+    parameters: Vec<AdamWParameter>,
+    step: i64,
+}
+
+impl AdamW {
+    /// Construct an AdamW optimizer for the trainable variables of `vs`.
+    pub fn new(vs: &VarStore, config: AdamWConfig) -> Result<Self, SyntaxDotError> {
+        let parameters = named_trainable_variables(vs)
+            .into_iter()
+            .map(|var| {
+                Ok(AdamWParameter {
+                    exp_avg: var.tensor.f_zeros_like()?,
+                    exp_avg_sq: var.tensor.f_zeros_like()?,
+                    name: var.name,
+                    group: var.group,
+                    tensor: var.tensor,
+                })
+            })
+            .collect::<Result<_, SyntaxDotError>>()?;
+
+        Ok(AdamW {
+            config,
+            groups: ParameterGroups::new(config.lr, config.weight_decay),
+            parameters,
+            step: 0,
+        })
+    }
+
+    fn update_parameter(
+        parameter: &mut AdamWParameter,
+        config: &AdamWConfig,
+        hyperparameters: GroupHyperparameters,
+        step: i64,
+    ) -> Result<(), SyntaxDotError> {
+        let grad = parameter.tensor.grad();
+        if !grad.defined() {
+            return Ok(());
+        }
+
+        let bias_correction1 = 1. - config.beta1.powi(step as i32);
+        let bias_correction2 = 1. - config.beta2.powi(step as i32);
+
+        // Decoupled weight decay.
+        if hyperparameters.weight_decay != 0. {
+            let _ = parameter
+                .tensor
+                .f_mul_scalar_(1. - hyperparameters.lr * hyperparameters.weight_decay)?;
+        }
+
+        let _ = parameter
+            .exp_avg
+            .f_mul_scalar_(config.beta1)?
+            .f_add_(&grad.f_mul_scalar(1. - config.beta1)?)?;
+        let _ = parameter
+            .exp_avg_sq
+            .f_mul_scalar_(config.beta2)?
+            .f_add_(&grad.f_mul(&grad)?.f_mul_scalar(1. - config.beta2)?)?;
+
+        let denom = parameter
+            .exp_avg_sq
+            .f_sqrt()?
+            .f_div_scalar(bias_correction2.sqrt())?
+            .f_add_scalar(config.eps)?;
+
+        let update = parameter
+            .exp_avg
+            .f_div(&denom)?
+            .f_mul_scalar(hyperparameters.lr / bias_correction1)?;
+
+        let _ = parameter.tensor.f_sub_(&update)?;
+
+        Ok(())
+    }
+}
+
+impl Optimizer for AdamW {
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.trainable_variables().zero_grad();
+        loss.backward();
+        tch::no_grad(|| self.step());
+        Ok(())
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.groups.get_mut(group).lr = learning_rate;
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.groups.get_mut(group).weight_decay = weight_decay;
+    }
+
+    fn step(&mut self) {
+        self.step += 1;
+
+        let hyperparameters = self
+            .parameters
+            .iter()
+            .map(|parameter| self.groups.get(parameter.group))
+            .collect::<Vec<_>>();
+
+        for (parameter, hyperparameters) in self.parameters.iter_mut().zip(hyperparameters) {
+            Self::update_parameter(parameter, &self.config, hyperparameters, self.step)
+                .expect("Cannot update parameter");
+        }
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.parameters
+            .iter()
+            .map(|parameter| parameter.tensor.shallow_clone())
+            .collect()
+    }
+}
+
+impl OptimizerState for AdamW {
+    fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = Vec::with_capacity(2 * self.parameters.len() + 1);
+        state.push(("step".to_string(), Tensor::from(self.step)));
+
+        for parameter in &self.parameters {
+            state.push((
+                format!("exp_avg.{}", parameter.name),
+                parameter.exp_avg.shallow_clone(),
+            ));
+            state.push((
+                format!("exp_avg_sq.{}", parameter.name),
+                parameter.exp_avg_sq.shallow_clone(),
+            ));
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        let get = |name: String| {
+            state
+                .get(&name)
+                .ok_or(SyntaxDotError::MissingOptimizerState(name))
+        };
+
+        self.step = i64::from(get("step".to_string())?);
+
+        tch::no_grad(|| {
+            for parameter in &mut self.parameters {
+                parameter
+                    .exp_avg
+                    .f_copy_(get(format!("exp_avg.{}", parameter.name))?)?;
+                parameter
+                    .exp_avg_sq
+                    .f_copy_(get(format!("exp_avg_sq.{}", parameter.name))?)?;
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdamW, AdamWConfig};
+    use crate::optimizers::tests::check_restored_state_gives_identical_updates;
+
+    #[test]
+    fn restored_optimizer_state_gives_identical_updates() {
+        let config = AdamWConfig {
+            weight_decay: 0.01,
+            ..AdamWConfig::default()
+        };
+
+        check_restored_state_gives_identical_updates(&[3], |vs| AdamW::new(vs, config).unwrap());
+    }
+}
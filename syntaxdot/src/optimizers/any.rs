@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use tch::Tensor;
+
+use super::{Adafactor, AdamW, Lamb, Optimizer, OptimizerState, Sgd};
+use crate::error::SyntaxDotError;
+
+/// Optimizer that is selected at run time.
+pub enum AnyOptimizer {
+    Adafactor(Adafactor),
+    AdamW(AdamW),
+    Lamb(Lamb),
+    Sgd(Sgd),
+}
+
+impl AnyOptimizer {
+    fn inner(&self) -> &dyn OptimizerAndState {
+        match self {
+            AnyOptimizer::Adafactor(optimizer) => optimizer,
+            AnyOptimizer::AdamW(optimizer) => optimizer,
+            AnyOptimizer::Lamb(optimizer) => optimizer,
+            AnyOptimizer::Sgd(optimizer) => optimizer,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn OptimizerAndState {
+        match self {
+            AnyOptimizer::Adafactor(optimizer) => optimizer,
+            AnyOptimizer::AdamW(optimizer) => optimizer,
+            AnyOptimizer::Lamb(optimizer) => optimizer,
+            AnyOptimizer::Sgd(optimizer) => optimizer,
+        }
+    }
+}
+
+trait OptimizerAndState: Optimizer + OptimizerState {}
+
+impl<T> OptimizerAndState for T where T: Optimizer + OptimizerState {}
+
+impl Optimizer for AnyOptimizer {
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.inner_mut().backward_step(loss)
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.inner_mut().set_lr_group(group, learning_rate)
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.inner_mut().set_weight_decay_group(group, weight_decay)
+    }
+
+    fn step(&mut self) {
+        self.inner_mut().step()
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.inner().trainable_variables()
+    }
+}
+
+impl OptimizerState for AnyOptimizer {
+    fn state(&self) -> Vec<(String, Tensor)> {
+        self.inner().state()
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        self.inner_mut().load_state(state)
+    }
+}
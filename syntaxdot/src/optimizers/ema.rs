@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use tch::Tensor;
+
+use super::{Optimizer, OptimizerState};
+use crate::error::SyntaxDotError;
+
+/// Exponential moving average of parameters.
+///
+/// This data type wraps an optimizer and keeps an exponential moving
+/// average (EMA) of the trainable variables. The average is updated
+/// after every optimizer step. The averaged parameters often generalize
+/// better than the parameters of the last step. `swap_average` swaps the
+/// variables and their averages, so that the averaged parameters can be
+/// used for validation and saving.
+///
+/// `ExponentialMovingAverage` implements the `Optimizer` trait, so that
+/// it can be used in the same contexts as an optimizer can be used. When
+/// the average is disabled, all calls are passed through to the wrapped
+/// optimizer.
+pub struct ExponentialMovingAverage<O> {
+    enabled: bool,
+    decay: f64,
+
+    optimizer: O,
+
+    averages: Vec<Tensor>,
+    variables: Vec<Tensor>,
+}
+
+impl<O> ExponentialMovingAverage<O>
+where
+    O: Optimizer,
+{
+    /// Construct a new exponential moving average.
+    ///
+    /// The average is initialized with the current values of the
+    /// trainable variables of `optimizer`. In each step, the averages
+    /// are updated as `decay * average + (1 - decay) * variable`.
+    pub fn new(enabled: bool, optimizer: O, decay: f64) -> Result<Self, SyntaxDotError> {
+        let variables = if enabled {
+            optimizer.trainable_variables()
+        } else {
+            Vec::new()
+        };
+
+        let averages = tch::no_grad(|| {
+            variables
+                .iter()
+                .map(|variable| {
+                    let mut average = variable.f_zeros_like()?;
+                    average.f_copy_(variable)?;
+                    Ok(average)
+                })
+                .collect::<Result<Vec<_>, SyntaxDotError>>()
+        })?;
+
+        Ok(ExponentialMovingAverage {
+            enabled,
+            decay,
+            optimizer,
+            averages,
+            variables,
+        })
+    }
+
+    /// Get a reference to the wrapped optimizer.
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    /// Get a mutable reference to the wrapped optimizer.
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    /// Swap the trainable variables and their averages.
+    ///
+    /// After a call to this method, the variables contain the averaged
+    /// parameters. Calling this method again restores the variables.
+    /// This method does nothing when the average is disabled.
+    pub fn swap_average(&mut self) -> Result<(), SyntaxDotError> {
+        tch::no_grad(|| {
+            for (variable, average) in self.variables.iter_mut().zip(&mut self.averages) {
+                let mut tmp = variable.f_zeros_like()?;
+                tmp.f_copy_(variable)?;
+                variable.f_copy_(average)?;
+                average.f_copy_(&tmp)?;
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })
+    }
+
+    /// Update the averages with the current values of the variables.
+    fn update(&mut self) -> Result<(), SyntaxDotError> {
+        for (variable, average) in self.variables.iter().zip(&mut self.averages) {
+            let _ = average.f_lerp_(variable, 1. - self.decay)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<O> Optimizer for ExponentialMovingAverage<O>
+where
+    O: Optimizer,
+{
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.optimizer.backward_step(loss)?;
+        if self.enabled {
+            tch::no_grad(|| self.update())?;
+        }
+        Ok(())
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.optimizer.set_lr_group(group, learning_rate)
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.optimizer.set_weight_decay_group(group, weight_decay)
+    }
+
+    fn step(&mut self) {
+        self.optimizer.step();
+        if self.enabled {
+            tch::no_grad(|| self.update()).expect("Cannot update parameter averages");
+        }
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.optimizer.trainable_variables()
+    }
+}
+
+impl<O> OptimizerState for ExponentialMovingAverage<O>
+where
+    O: OptimizerState,
+{
+    fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = self.optimizer.state();
+
+        state.extend(
+            self.averages
+                .iter()
+                .enumerate()
+                .map(|(idx, average)| (format!("ema.{}", idx), average.shallow_clone())),
+        );
+
+        state
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        tch::no_grad(|| {
+            for (idx, average) in self.averages.iter_mut().enumerate() {
+                let name = format!("ema.{}", idx);
+                let saved = state
+                    .get(&name)
+                    .ok_or(SyntaxDotError::MissingOptimizerState(name))?;
+                average.f_copy_(saved)?;
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })?;
+
+        self.optimizer.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tch::nn::{Init, VarStore};
+    use tch::{Device, Kind};
+
+    use super::ExponentialMovingAverage;
+    use crate::optimizers::{Optimizer, Sgd, SgdConfig};
+
+    #[test]
+    fn swap_average_swaps_in_averaged_parameters() {
+        let vs = VarStore::new(Device::Cpu);
+        let w = vs.root().var("w", &[1], Init::Const(0.));
+        let optimizer = Sgd::new(
+            &vs,
+            SgdConfig {
+                lr: 1.,
+                momentum: 0.,
+                weight_decay: 0.,
+            },
+        )
+        .unwrap();
+        let mut ema = ExponentialMovingAverage::new(true, optimizer, 0.5).unwrap();
+
+        // Each step decreases w by 1: w = -1, -2, so the averages are
+        // -0.5 and -1.25.
+        for _ in 0..2 {
+            ema.backward_step(&w.sum(Kind::Float)).unwrap();
+        }
+
+        assert_abs_diff_eq!(Vec::<f32>::from(&w)[0], -2.);
+        ema.swap_average().unwrap();
+        assert_abs_diff_eq!(Vec::<f32>::from(&w)[0], -1.25);
+        ema.swap_average().unwrap();
+        assert_abs_diff_eq!(Vec::<f32>::from(&w)[0], -2.);
+    }
+}
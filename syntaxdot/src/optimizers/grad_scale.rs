@@ -1,6 +1,8 @@
+use std::collections::HashMap;
+
 use tch::{Kind, Tensor};
 
-use super::{Optimizer, ZeroGrad};
+use super::{Optimizer, OptimizerState, ZeroGrad};
 use crate::error::SyntaxDotError;
 
 /// Gradient scaler
@@ -16,6 +18,10 @@ use crate::error::SyntaxDotError;
 /// more infinite gradients are found, the optimizer step is skipped and
 /// the scale is reduced for the next step.
 ///
+/// The gradient scaler also computes the global norm of the (unscaled)
+/// gradients in every step. When a maximum gradient norm is set, the
+/// gradients are clipped to that norm before the optimizer step.
+///
 /// `GradientScaler` wraps an optimizer and implements the `Optimizer`
 /// trait, so that it can be used in the same contexts as an optimizer
 /// can be used.
@@ -24,9 +30,13 @@ pub struct GradScaler<O> {
     growth_factor: f64,
     backoff_factor: f64,
     growth_interval: i64,
+    max_grad_norm: Option<f64>,
 
     optimizer: O,
 
+    last_grad_norm: f32,
+    last_step_skipped: bool,
+
     found_inf: Tensor,
     growth_tracker: Tensor,
     scale: Tensor,
@@ -54,9 +64,13 @@ where
             growth_factor,
             backoff_factor,
             growth_interval,
+            max_grad_norm: None,
 
             optimizer,
 
+            last_grad_norm: 0.,
+            last_step_skipped: false,
+
             found_inf: Tensor::full(&[1], 0.0, (Kind::Float, device)),
             growth_tracker: Tensor::full(&[1], 0, (Kind::Int, device)),
             scale: Tensor::full(&[1], init_scale, (Kind::Float, device)),
@@ -70,6 +84,44 @@ where
         GradScaler::new(enabled, optimizer, 2f64.powi(16), 2., 0.5, 2000)
     }
 
+    /// Accumulate the gradients of the given loss.
+    ///
+    /// The loss is scaled and its gradients are added to the gradients of
+    /// the trainable variables. Use `step_accumulated` to perform an
+    /// optimizer step with the accumulated gradients.
+    pub fn backward(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.scale(loss)?.backward();
+        Ok(())
+    }
+
+    /// Perform an optimizer step with the accumulated gradients.
+    ///
+    /// The gradients are cleared after the step.
+    pub fn step_accumulated(&mut self) {
+        tch::no_grad(|| self.step());
+        self.update();
+        self.optimizer.trainable_variables().zero_grad();
+    }
+
+    /// Clip gradients to the given global norm.
+    ///
+    /// Clipping is disabled when `max_grad_norm` is `None`.
+    pub fn set_max_grad_norm(&mut self, max_grad_norm: Option<f64>) {
+        self.max_grad_norm = max_grad_norm;
+    }
+
+    /// Get the global gradient norm of the last step.
+    ///
+    /// This is the norm before clipping.
+    pub fn last_grad_norm(&self) -> f32 {
+        self.last_grad_norm
+    }
+
+    /// Check whether the last step was skipped due to infinite gradients.
+    pub fn last_step_skipped(&self) -> bool {
+        self.last_step_skipped
+    }
+
     /// Get the current scale.
     pub fn current_scale(&self) -> f32 {
         Vec::<f32>::from(&self.scale)[0]
@@ -85,6 +137,38 @@ where
         &mut self.optimizer
     }
 
+    /// Compute the global gradient norm and clip the gradients.
+    fn clip_grad_norm(&mut self) -> Result<f32, SyntaxDotError> {
+        let mut grads = self
+            .optimizer
+            .trainable_variables()
+            .iter()
+            .map(Tensor::grad)
+            .filter(Tensor::defined)
+            .collect::<Vec<_>>();
+
+        if grads.is_empty() {
+            return Ok(0.);
+        }
+
+        let norms = grads
+            .iter()
+            .map(|grad| grad.f_norm())
+            .collect::<Result<Vec<_>, _>>()?;
+        let grad_norm = f32::from(Tensor::f_stack(&norms, 0)?.f_norm()?);
+
+        if let Some(max_grad_norm) = self.max_grad_norm {
+            if f64::from(grad_norm) > max_grad_norm {
+                let clip_coef = max_grad_norm / (f64::from(grad_norm) + 1e-6);
+                for grad in &mut grads {
+                    let _ = grad.f_mul_scalar_(clip_coef)?;
+                }
+            }
+        }
+
+        Ok(grad_norm)
+    }
+
     /// Scale the given tensor.
     fn scale(&mut self, t: &Tensor) -> Result<Tensor, SyntaxDotError> {
         Ok(if !self.enabled {
@@ -134,31 +218,119 @@ where
     }
 
     fn step(&mut self) {
-        if !self.enabled {
-            return self.optimizer.step();
-        }
+        if self.enabled {
+            let inv_scale = self.scale.reciprocal().to_kind(Kind::Float);
 
-        let inv_scale = self.scale.reciprocal().to_kind(Kind::Float);
+            for tensor in &mut self.optimizer.trainable_variables() {
+                if !tensor.grad().defined() {
+                    continue;
+                }
 
-        for tensor in &mut self.optimizer.trainable_variables() {
-            if !tensor.grad().defined() {
-                continue;
+                tensor
+                    .grad()
+                    .internal_amp_non_finite_check_and_unscale(&mut self.found_inf, &inv_scale);
             }
 
-            tensor
-                .grad()
-                .internal_amp_non_finite_check_and_unscale(&mut self.found_inf, &inv_scale);
+            let found_inf = (f32::from(&self.found_inf) - 1.0).abs() < f32::EPSILON;
+
+            // Only step when there are no infinite gradients.
+            if found_inf {
+                self.last_grad_norm = f32::INFINITY;
+                self.last_step_skipped = true;
+                return;
+            }
         }
 
-        let found_inf = (f32::from(&self.found_inf) - 1.0).abs() < f32::EPSILON;
+        self.last_grad_norm = self.clip_grad_norm().expect("Cannot compute gradient norm");
+        self.last_step_skipped = false;
 
-        // Only step when there are no infinite gradients.
-        if !found_inf {
-            self.optimizer.step()
-        }
+        self.optimizer.step()
     }
 
     fn trainable_variables(&self) -> Vec<Tensor> {
         self.optimizer.trainable_variables()
     }
 }
+
+impl<O> OptimizerState for GradScaler<O>
+where
+    O: OptimizerState,
+{
+    fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = vec![
+            (
+                "grad_scaler.growth_tracker".to_string(),
+                self.growth_tracker.shallow_clone(),
+            ),
+            ("grad_scaler.scale".to_string(), self.scale.shallow_clone()),
+        ];
+
+        state.extend(
+            self.optimizer
+                .state()
+                .into_iter()
+                .map(|(name, tensor)| (format!("optimizer.{}", name), tensor)),
+        );
+
+        state
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        for (name, tensor) in &mut [
+            ("grad_scaler.growth_tracker", &mut self.growth_tracker),
+            ("grad_scaler.scale", &mut self.scale),
+        ] {
+            let saved = state
+                .get(*name)
+                .ok_or_else(|| SyntaxDotError::MissingOptimizerState(name.to_string()))?;
+            tensor.f_copy_(saved)?;
+        }
+
+        let optimizer_state = state
+            .iter()
+            .filter_map(|(name, tensor)| {
+                name.strip_prefix("optimizer.")
+                    .map(|name| (name.to_string(), tensor.shallow_clone()))
+            })
+            .collect();
+
+        self.optimizer.load_state(&optimizer_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tch::nn::{Init, VarStore};
+    use tch::{Device, Tensor};
+
+    use super::GradScaler;
+    use crate::optimizers::{Optimizer, Sgd, SgdConfig};
+
+    #[test]
+    fn gradients_are_clipped_to_max_norm() {
+        let vs = VarStore::new(Device::Cpu);
+        let w = vs.root().var("w", &[2], Init::Const(0.));
+        let optimizer = Sgd::new(
+            &vs,
+            SgdConfig {
+                lr: 1.,
+                momentum: 0.,
+                weight_decay: 0.,
+            },
+        )
+        .unwrap();
+        let mut grad_scaler = GradScaler::new_with_defaults(false, optimizer).unwrap();
+        grad_scaler.set_max_grad_norm(Some(1.));
+
+        // The gradient of the loss is [3, 4], with norm 5.
+        let loss = w.dot(&Tensor::of_slice(&[3f32, 4.]));
+        grad_scaler.backward_step(&loss).unwrap();
+
+        assert_abs_diff_eq!(grad_scaler.last_grad_norm(), 5., epsilon = 1e-6);
+        assert!(!grad_scaler.last_step_skipped());
+        let w = Vec::<f32>::from(&w);
+        assert_abs_diff_eq!(w[0], -0.6, epsilon = 1e-5);
+        assert_abs_diff_eq!(w[1], -0.8, epsilon = 1e-5);
+    }
+}
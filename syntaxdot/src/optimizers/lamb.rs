@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use tch::nn::VarStore;
+use tch::Tensor;
+
+use super::params::{named_trainable_variables, GroupHyperparameters, ParameterGroups};
+use super::{Optimizer, OptimizerState, ZeroGrad};
+use crate::error::SyntaxDotError;
+
+/// LAMB optimizer configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct LambConfig {
+    /// Exponential decay rate of the first moment estimates.
+    pub beta1: f64,
+
+    /// Exponential decay rate of the second moment estimates.
+    pub beta2: f64,
+
+    /// Term that is added to the denominator for numerical stability.
+    pub eps: f64,
+
+    /// Default learning rate of parameter groups.
+    pub lr: f64,
+
+    /// Default weight decay of parameter groups.
+    pub weight_decay: f64,
+}
+
+impl Default for LambConfig {
+    fn default() -> Self {
+        LambConfig {
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-6,
+            lr: 1e-3,
+            weight_decay: 0.,
+        }
+    }
+}
+
+struct LambParameter {
+    name: String,
+    group: usize,
+    tensor: Tensor,
+    exp_avg: Tensor,
+    exp_avg_sq: Tensor,
+}
+
+/// LAMB optimizer (You et al., 2020).
+///
+/// LAMB computes the same update direction as AdamW, but scales the
+/// update of each variable by the trust ratio: the ratio of the norm
+/// of the variable and the norm of the update. This makes training
+/// with large batch sizes more stable.
+///
+/// The optimizer updates the trainable variables that are in the
+/// variable store at construction time.
+pub struct Lamb {
+    config: LambConfig,
+    groups: ParameterGroups,
+    parameters: Vec<LambParameter>,
+    step: i64,
+}
+
+impl Lamb {
+    /// Construct a LAMB optimizer for the trainable variables of `vs`.
+    pub fn new(vs: &VarStore, config: LambConfig) -> Result<Self, SyntaxDotError> {
+        let parameters = named_trainable_variables(vs)
+            .into_iter()
+            .map(|var| {
+                Ok(LambParameter {
+                    exp_avg: var.tensor.f_zeros_like()?,
+                    exp_avg_sq: var.tensor.f_zeros_like()?,
+                    name: var.name,
+                    group: var.group,
+                    tensor: var.tensor,
+                })
+            })
+            .collect::<Result<_, SyntaxDotError>>()?;
+
+        Ok(Lamb {
+            config,
+            groups: ParameterGroups::new(config.lr, config.weight_decay),
+            parameters,
+            step: 0,
+        })
+    }
+
+    fn update_parameter(
+        parameter: &mut LambParameter,
+        config: &LambConfig,
+        hyperparameters: GroupHyperparameters,
+        step: i64,
+    ) -> Result<(), SyntaxDotError> {
+        let grad = parameter.tensor.grad();
+        if !grad.defined() {
+            return Ok(());
+        }
+
+        let bias_correction1 = 1. - config.beta1.powi(step as i32);
+        let bias_correction2 = 1. - config.beta2.powi(step as i32);
+
+        let _ = parameter
+            .exp_avg
+            .f_mul_scalar_(config.beta1)?
+            .f_add_(&grad.f_mul_scalar(1. - config.beta1)?)?;
+        let _ = parameter
+            .exp_avg_sq
+            .f_mul_scalar_(config.beta2)?
+            .f_add_(&grad.f_mul(&grad)?.f_mul_scalar(1. - config.beta2)?)?;
+
+        let denom = parameter
+            .exp_avg_sq
+            .f_div_scalar(bias_correction2)?
+            .f_sqrt()?
+            .f_add_scalar(config.eps)?;
+
+        let mut update = parameter
+            .exp_avg
+            .f_div_scalar(bias_correction1)?
+            .f_div(&denom)?;
+
+        if hyperparameters.weight_decay != 0. {
+            update = update.f_add(
+                &parameter
+                    .tensor
+                    .f_mul_scalar(hyperparameters.weight_decay)?,
+            )?;
+        }
+
+        // The trust ratio is 1 when the variable or update norm is zero.
+        let weight_norm = parameter.tensor.f_norm()?;
+        let update_norm = update.f_norm()?;
+        let trust_ratio = weight_norm.f_div(&update_norm)?.f_where_self(
+            &weight_norm
+                .f_gt(0.)?
+                .f_logical_and(&update_norm.f_gt(0.)?)?,
+            &weight_norm.f_ones_like()?,
+        )?;
+
+        let _ = parameter.tensor.f_sub_(
+            &update
+                .f_mul(&trust_ratio)?
+                .f_mul_scalar(hyperparameters.lr)?,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Optimizer for Lamb {
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.trainable_variables().zero_grad();
+        loss.backward();
+        tch::no_grad(|| self.step());
+        Ok(())
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.groups.get_mut(group).lr = learning_rate;
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.groups.get_mut(group).weight_decay = weight_decay;
+    }
+
+    fn step(&mut self) {
+        self.step += 1;
+
+        for parameter in &mut self.parameters {
+            let hyperparameters = self.groups.get(parameter.group);
+            Self::update_parameter(parameter, &self.config, hyperparameters, self.step)
+                .expect("Cannot update parameter");
+        }
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.parameters
+            .iter()
+            .map(|parameter| parameter.tensor.shallow_clone())
+            .collect()
+    }
+}
+
+impl OptimizerState for Lamb {
+    fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = Vec::with_capacity(2 * self.parameters.len() + 1);
+        state.push(("step".to_string(), Tensor::from(self.step)));
+
+        for parameter in &self.parameters {
+            state.push((
+                format!("exp_avg.{}", parameter.name),
+                parameter.exp_avg.shallow_clone(),
+            ));
+            state.push((
+                format!("exp_avg_sq.{}", parameter.name),
+                parameter.exp_avg_sq.shallow_clone(),
+            ));
+        }
+
+        state
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        let get = |name: String| {
+            state
+                .get(&name)
+                .ok_or(SyntaxDotError::MissingOptimizerState(name))
+        };
+
+        self.step = i64::from(get("step".to_string())?);
+
+        tch::no_grad(|| {
+            for parameter in &mut self.parameters {
+                parameter
+                    .exp_avg
+                    .f_copy_(get(format!("exp_avg.{}", parameter.name))?)?;
+                parameter
+                    .exp_avg_sq
+                    .f_copy_(get(format!("exp_avg_sq.{}", parameter.name))?)?;
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tch::nn::{Init, VarStore};
+    use tch::Device;
+
+    use super::{Lamb, LambConfig};
+    use crate::optimizers::tests::{check_restored_state_gives_identical_updates, quadratic_loss};
+    use crate::optimizers::Optimizer;
+
+    #[test]
+    fn update_is_scaled_by_trust_ratio() {
+        let vs = VarStore::new(Device::Cpu);
+        let w = vs.root().var("w", &[3], Init::Const(1.));
+        let mut optimizer = Lamb::new(
+            &vs,
+            LambConfig {
+                lr: 0.1,
+                ..LambConfig::default()
+            },
+        )
+        .unwrap();
+
+        // After bias correction, the first update is the sign of the
+        // gradient [0, 6, -4]. The trust ratio is the ratio of the
+        // parameter norm and the update norm, sqrt(3) / sqrt(2).
+        optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        let trust_ratio = 3f32.sqrt() / 2f32.sqrt();
+        for (&v, &expected) in
+            Vec::<f32>::from(&w)
+                .iter()
+                .zip(&[1., 1. - 0.1 * trust_ratio, 1. + 0.1 * trust_ratio])
+        {
+            assert_abs_diff_eq!(v, expected, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn restored_optimizer_state_gives_identical_updates() {
+        let config = LambConfig {
+            weight_decay: 0.01,
+            ..LambConfig::default()
+        };
+
+        check_restored_state_gives_identical_updates(&[3], |vs| Lamb::new(vs, config).unwrap());
+    }
+}
@@ -1,6 +1,20 @@
+use std::collections::HashMap;
+
 use tch::nn::{self};
 use tch::Tensor;
 
+mod adafactor;
+pub use adafactor::{Adafactor, AdafactorConfig};
+
+mod adamw;
+pub use adamw::{AdamW, AdamWConfig};
+
+mod any;
+pub use any::AnyOptimizer;
+
+mod ema;
+pub use ema::ExponentialMovingAverage;
+
 mod grad;
 pub use grad::ZeroGrad;
 
@@ -8,6 +22,14 @@ mod grad_scale;
 use crate::error::SyntaxDotError;
 pub use grad_scale::GradScaler;
 
+mod lamb;
+pub use lamb::{Lamb, LambConfig};
+
+mod params;
+
+mod sgd;
+pub use sgd::{Sgd, SgdConfig};
+
 pub trait Optimizer {
     /// Perform a backward step on the given loss.
     fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError>;
@@ -29,6 +51,15 @@ pub trait Optimizer {
     fn trainable_variables(&self) -> Vec<Tensor>;
 }
 
+/// Optimizers with a state that can be saved and restored.
+pub trait OptimizerState {
+    /// Get the optimizer state as named tensors.
+    fn state(&self) -> Vec<(String, Tensor)>;
+
+    /// Restore the optimizer state from named tensors.
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError>;
+}
+
 impl Optimizer for nn::Optimizer {
     fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
         nn::Optimizer::backward_step(self, loss);
@@ -51,3 +82,64 @@ impl Optimizer for nn::Optimizer {
         nn::Optimizer::trainable_variables(self)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use tch::nn::{Init, VarStore};
+    use tch::{Device, Kind, Tensor};
+
+    use super::{Optimizer, OptimizerState};
+
+    const TARGET: [f32; 6] = [1., -2., 3., -4., 5., -6.];
+
+    /// Squared distance of the variable `w` to a fixed target.
+    ///
+    /// The target has the shape of `w` and consists of the first
+    /// elements of `TARGET`.
+    pub fn quadratic_loss(vs: &VarStore) -> Tensor {
+        let w = &vs.variables()["w"];
+        let target = Tensor::of_slice(&TARGET[..w.numel()]).view(w.size().as_slice());
+        w.f_sub(&target).unwrap().square().sum(Kind::Float)
+    }
+
+    /// Check that an optimizer with restored state gives the same update.
+    ///
+    /// A variable `w` with the given `shape` is trained for a few steps
+    /// using the optimizer constructed by `new_optimizer`. The parameters
+    /// and optimizer state are then restored in a new variable store and
+    /// optimizer, which should make the same update as the original.
+    pub fn check_restored_state_gives_identical_updates<O>(
+        shape: &[i64],
+        new_optimizer: impl Fn(&VarStore) -> O,
+    ) where
+        O: Optimizer + OptimizerState,
+    {
+        let vs = VarStore::new(Device::Cpu);
+        vs.root().var("w", shape, Init::Const(0.));
+        let mut optimizer = new_optimizer(&vs);
+        for _ in 0..3 {
+            optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        }
+
+        // Copy the parameters and optimizer state.
+        let mut vs_restored = VarStore::new(Device::Cpu);
+        vs_restored.root().var("w", shape, Init::Const(0.));
+        vs_restored.copy(&vs).unwrap();
+        let mut optimizer_restored = new_optimizer(&vs_restored);
+        let state = optimizer
+            .state()
+            .into_iter()
+            .map(|(name, tensor)| (name, tensor.copy()))
+            .collect::<HashMap<_, _>>();
+        optimizer_restored.load_state(&state).unwrap();
+
+        optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        optimizer_restored
+            .backward_step(&quadratic_loss(&vs_restored))
+            .unwrap();
+
+        assert_eq!(vs.variables()["w"], vs_restored.variables()["w"]);
+    }
+}
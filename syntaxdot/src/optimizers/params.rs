@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use tch::nn::VarStore;
+use tch::Tensor;
+
+/// Hyperparameters of a parameter group.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GroupHyperparameters {
+    pub lr: f64,
+    pub weight_decay: f64,
+}
+
+/// Hyperparameters of parameter groups.
+///
+/// Groups for which no hyperparameters were set use the defaults.
+pub(crate) struct ParameterGroups {
+    defaults: GroupHyperparameters,
+    groups: HashMap<usize, GroupHyperparameters>,
+}
+
+impl ParameterGroups {
+    pub fn new(lr: f64, weight_decay: f64) -> Self {
+        ParameterGroups {
+            defaults: GroupHyperparameters { lr, weight_decay },
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Get the hyperparameters of a group.
+    pub fn get(&self, group: usize) -> GroupHyperparameters {
+        self.groups.get(&group).copied().unwrap_or(self.defaults)
+    }
+
+    /// Get the hyperparameters of a group mutably.
+    pub fn get_mut(&mut self, group: usize) -> &mut GroupHyperparameters {
+        let defaults = self.defaults;
+        self.groups.entry(group).or_insert(defaults)
+    }
+}
+
+/// Trainable variable with its name and parameter group.
+pub(crate) struct NamedVariable {
+    pub name: String,
+    pub group: usize,
+    pub tensor: Tensor,
+}
+
+/// Get the trainable variables of a variable store.
+///
+/// The variables are sorted by name, so that optimizers update
+/// variables and store their state in a deterministic order.
+pub(crate) fn named_trainable_variables(vs: &VarStore) -> Vec<NamedVariable> {
+    let named_variables = vs.variables();
+    let variables = vs.variables_.lock().unwrap();
+
+    let mut trainable = variables
+        .trainable_variables
+        .iter()
+        .map(|var| {
+            let name = named_variables
+                .iter()
+                .find(|(_, tensor)| tensor.data_ptr() == var.tensor.data_ptr())
+                .map(|(name, _)| name.clone())
+                .expect("Trainable variable is not in the variable store");
+
+            NamedVariable {
+                name,
+                group: var.group,
+                tensor: var.tensor.shallow_clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    trainable.sort_by(|v1, v2| v1.name.cmp(&v2.name));
+
+    trainable
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use tch::nn::VarStore;
+use tch::Tensor;
+
+use super::params::{named_trainable_variables, GroupHyperparameters, ParameterGroups};
+use super::{Optimizer, OptimizerState, ZeroGrad};
+use crate::error::SyntaxDotError;
+
+/// SGD optimizer configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct SgdConfig {
+    /// Default learning rate of parameter groups.
+    pub lr: f64,
+
+    /// Momentum factor.
+    pub momentum: f64,
+
+    /// Default weight decay of parameter groups.
+    pub weight_decay: f64,
+}
+
+impl Default for SgdConfig {
+    fn default() -> Self {
+        SgdConfig {
+            lr: 1e-3,
+            momentum: 0.9,
+            weight_decay: 0.,
+        }
+    }
+}
+
+struct SgdParameter {
+    name: String,
+    group: usize,
+    tensor: Tensor,
+    momentum_buffer: Tensor,
+}
+
+/// Stochastic gradient descent with momentum.
+///
+/// Weight decay is applied as an L2 penalty, by adding the decayed
+/// parameters to the gradient before the momentum update.
+///
+/// The optimizer updates the trainable variables that are in the
+/// variable store at construction time.
+pub struct Sgd {
+    config: SgdConfig,
+    groups: ParameterGroups,
+    parameters: Vec<SgdParameter>,
+}
+
+impl Sgd {
+    /// Construct an SGD optimizer for the trainable variables of `vs`.
+    pub fn new(vs: &VarStore, config: SgdConfig) -> Result<Self, SyntaxDotError> {
+        let parameters = named_trainable_variables(vs)
+            .into_iter()
+            .map(|var| {
+                Ok(SgdParameter {
+                    momentum_buffer: var.tensor.f_zeros_like()?,
+                    name: var.name,
+                    group: var.group,
+                    tensor: var.tensor,
+                })
+            })
+            .collect::<Result<_, SyntaxDotError>>()?;
+
+        Ok(Sgd {
+            config,
+            groups: ParameterGroups::new(config.lr, config.weight_decay),
+            parameters,
+        })
+    }
+
+    fn update_parameter(
+        parameter: &mut SgdParameter,
+        config: &SgdConfig,
+        hyperparameters: GroupHyperparameters,
+    ) -> Result<(), SyntaxDotError> {
+        let mut grad = parameter.tensor.grad();
+        if !grad.defined() {
+            return Ok(());
+        }
+
+        if hyperparameters.weight_decay != 0. {
+            grad = grad.f_add(
+                &parameter
+                    .tensor
+                    .f_mul_scalar(hyperparameters.weight_decay)?,
+            )?;
+        }
+
+        let _ = parameter
+            .momentum_buffer
+            .f_mul_scalar_(config.momentum)?
+            .f_add_(&grad)?;
+
+        let _ = parameter
+            .tensor
+            .f_sub_(&parameter.momentum_buffer.f_mul_scalar(hyperparameters.lr)?)?;
+
+        Ok(())
+    }
+}
+
+impl Optimizer for Sgd {
+    fn backward_step(&mut self, loss: &Tensor) -> Result<(), SyntaxDotError> {
+        self.trainable_variables().zero_grad();
+        loss.backward();
+        tch::no_grad(|| self.step());
+        Ok(())
+    }
+
+    fn set_lr_group(&mut self, group: usize, learning_rate: f64) {
+        self.groups.get_mut(group).lr = learning_rate;
+    }
+
+    fn set_weight_decay_group(&mut self, group: usize, weight_decay: f64) {
+        self.groups.get_mut(group).weight_decay = weight_decay;
+    }
+
+    fn step(&mut self) {
+        for parameter in &mut self.parameters {
+            let hyperparameters = self.groups.get(parameter.group);
+            Self::update_parameter(parameter, &self.config, hyperparameters)
+                .expect("Cannot update parameter");
+        }
+    }
+
+    fn trainable_variables(&self) -> Vec<Tensor> {
+        self.parameters
+            .iter()
+            .map(|parameter| parameter.tensor.shallow_clone())
+            .collect()
+    }
+}
+
+impl OptimizerState for Sgd {
+    fn state(&self) -> Vec<(String, Tensor)> {
+        self.parameters
+            .iter()
+            .map(|parameter| {
+                (
+                    format!("momentum_buffer.{}", parameter.name),
+                    parameter.momentum_buffer.shallow_clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), SyntaxDotError> {
+        tch::no_grad(|| {
+            for parameter in &mut self.parameters {
+                let name = format!("momentum_buffer.{}", parameter.name);
+                let buffer = state
+                    .get(&name)
+                    .ok_or(SyntaxDotError::MissingOptimizerState(name))?;
+                parameter.momentum_buffer.f_copy_(buffer)?;
+            }
+
+            Ok::<_, SyntaxDotError>(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tch::nn::{Init, VarStore};
+    use tch::Device;
+
+    use super::{Sgd, SgdConfig};
+    use crate::optimizers::tests::{check_restored_state_gives_identical_updates, quadratic_loss};
+    use crate::optimizers::Optimizer;
+
+    #[test]
+    fn momentum_accumulates_gradients() {
+        let vs = VarStore::new(Device::Cpu);
+        let w = vs.root().var("w", &[3], Init::Const(0.));
+        let mut optimizer = Sgd::new(
+            &vs,
+            SgdConfig {
+                lr: 0.1,
+                momentum: 0.9,
+                weight_decay: 0.,
+            },
+        )
+        .unwrap();
+
+        // The gradient of the first step is [-2, 4, -6].
+        optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        for (&v, &expected) in Vec::<f32>::from(&w).iter().zip(&[0.2, -0.4, 0.6]) {
+            assert_abs_diff_eq!(v, expected, epsilon = 1e-6);
+        }
+
+        // The gradient of the second step is [-1.6, 3.2, -4.8], the
+        // momentum buffer becomes 0.9 * [-2, 4, -6] + [-1.6, 3.2, -4.8].
+        optimizer.backward_step(&quadratic_loss(&vs)).unwrap();
+        for (&v, &expected) in Vec::<f32>::from(&w).iter().zip(&[0.54, -1.08, 1.62]) {
+            assert_abs_diff_eq!(v, expected, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn restored_optimizer_state_gives_identical_updates() {
+        let config = SgdConfig {
+            weight_decay: 0.01,
+            ..SgdConfig::default()
+        };
+
+        check_restored_state_gives_identical_updates(&[3], |vs| Sgd::new(vs, config).unwrap());
+    }
+}
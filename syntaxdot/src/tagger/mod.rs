@@ -8,6 +8,7 @@ use syntaxdot_encoders::{EncodingProb, SentenceDecoder};
 use syntaxdot_tokenizers::SentenceWithPieces;
 use tch::Device;
 
+use crate::dataset::treebank_id;
 use crate::encoders::Encoders;
 use crate::error::SyntaxDotError;
 use crate::model::bert::BertModel;
@@ -21,21 +22,28 @@ pub struct Tagger {
     device: Device,
     encoders: Encoders,
     model: BertModel,
+    treebank_id: Option<usize>,
 }
 
 impl Tagger {
     /// Construct a new tagger.
+    ///
+    /// If `treebank_id` is given, all sentences are tagged as sentences
+    /// of that treebank. Otherwise, the treebank identifier is read from
+    /// the sentence comments.
     pub fn new(
         device: Device,
         model: BertModel,
         biaffine_encoder: Option<ImmutableDependencyEncoder>,
         encoders: Encoders,
+        treebank_id: Option<usize>,
     ) -> Self {
         Tagger {
             biaffine_encoder,
             device,
             encoders,
             model,
+            treebank_id,
         }
     }
 
@@ -44,7 +52,7 @@ impl Tagger {
         &self,
         sentences: &mut [impl BorrowMut<SentenceWithPieces>],
     ) -> Result<(), SyntaxDotError> {
-        let tensors = self.prepare_batch(sentences);
+        let tensors = self.prepare_batch(sentences)?;
 
         // Get model predictions.
         let attention_mask = tensors.seq_lens.attention_mask()?;
@@ -52,6 +60,7 @@ impl Tagger {
             &tensors.inputs.to_device(self.device),
             &attention_mask.to_device(self.device),
             &tensors.token_spans.to_device(self.device),
+            &tensors.treebank_ids.to_device(self.device),
         )?;
 
         assert_eq!(
@@ -72,13 +81,23 @@ impl Tagger {
             tch::no_grad(|| self.decode_biaffine(encoder, sentences, biaffine_score_logits))?
         }
 
-        self.decode_sequence_labels(sentences, predictions.sequences_top_k)?;
+        self.decode_sequence_labels(
+            sentences,
+            predictions
+                .sequences_top_k
+                .into_iter()
+                .chain(predictions.sentences_top_k)
+                .collect(),
+        )?;
 
         Ok(())
     }
 
     /// Construct the tensor representations of a batch of sentences.
-    fn prepare_batch(&self, sentences: &[impl Borrow<SentenceWithPieces>]) -> Tensors {
+    fn prepare_batch(
+        &self,
+        sentences: &[impl Borrow<SentenceWithPieces>],
+    ) -> Result<Tensors, SyntaxDotError> {
         let max_seq_len = sentences
             .iter()
             .map(|sentence| sentence.borrow().pieces.len())
@@ -117,6 +136,11 @@ impl Tagger {
                     }
                 });
 
+            let treebank_id = match self.treebank_id {
+                Some(treebank_id) => treebank_id,
+                None => treebank_id(&sentence.sentence)?,
+            };
+            builder.set_treebank_id(treebank_id as i64);
             builder.add_without_labels(
                 input.view(),
                 token_offsets.view(),
@@ -125,7 +149,7 @@ impl Tagger {
             );
         }
 
-        builder.into()
+        Ok(builder.into())
     }
 
     /// Decode biaffine score matrices.
@@ -188,7 +212,7 @@ impl Tagger {
         S: BorrowMut<SentenceWithPieces>,
     {
         // For each encoder, we get a tensor of shape [batch_size, seq_len, k].
-        // Convert the tensors to ndarray tensors, since they are easier to work with
+        // For sentence-level encoders, seq_len is 1. Convert the tensors to ndarray tensors, since they are easier to work with
         // in Rust.
         let mut top_k_tensors = HashMap::new();
         for (encoder_name, top_k) in sequences_top_k {
@@ -206,14 +230,20 @@ impl Tagger {
                 let (top_k_labels, top_k_probs) = &top_k_tensors[encoder.name()];
 
                 // Get the sentence and within the sentence the sequence elements
-                // that represent tokens.
+                // that represent tokens. Sentence-level encoders have a single
+                // label per sentence.
+                let n_labels = if encoder.encoder().is_sentence_level() {
+                    1
+                } else {
+                    sentence.token_offsets.len()
+                };
                 let sent_top_k_labels = top_k_labels
                     .index_axis(Axis(0), idx)
-                    .slice(s![..sentence.token_offsets.len(), ..])
+                    .slice(s![..n_labels, ..])
                     .to_owned();
                 let sent_top_k_probs = &top_k_probs
                     .index_axis(Axis(0), idx)
-                    .slice(s![..sentence.token_offsets.len(), ..])
+                    .slice(s![..n_labels, ..])
                     .to_owned();
 
                 // Collect sentence top-k
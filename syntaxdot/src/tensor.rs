@@ -8,6 +8,13 @@ use tch::{Device, Kind, Tensor};
 use crate::error::SyntaxDotError;
 use syntaxdot_transformers::TransformerError;
 
+/// Label of targets that are unknown.
+///
+/// This is the `ignore_index` of the losses, so unknown labels do not
+/// contribute to the loss or to the accuracy. Padding of dependency
+/// heads and relations also uses this label.
+pub const UNKNOWN_LABEL: i64 = -1;
+
 /// Tensors for biaffine encodings.
 #[derive(Debug, PartialEq)]
 pub struct BiaffineTensors<T> {
@@ -18,8 +25,8 @@ pub struct BiaffineTensors<T> {
 impl BiaffineTensors<Array2<i64>> {
     fn from_shape(batch_size: usize, time_steps: usize) -> Self {
         BiaffineTensors {
-            heads: Array2::from_elem((batch_size, time_steps), -1),
-            relations: Array2::from_elem((batch_size, time_steps), -1),
+            heads: Array2::from_elem((batch_size, time_steps), UNKNOWN_LABEL),
+            relations: Array2::from_elem((batch_size, time_steps), UNKNOWN_LABEL),
         }
     }
 }
@@ -74,10 +81,12 @@ pub struct TensorBuilder {
     current_sequence: usize,
     inputs: Array2<i64>,
     labels: Option<LabelTensor>,
+    sentence_labels: Option<LabelTensor>,
     token_offsets: Array2<i32>,
     token_len: Array2<i32>,
     token_mask: Array2<i32>,
     seq_lens: Array1<i32>,
+    treebank_ids: Array1<i64>,
 }
 
 impl TensorBuilder {
@@ -98,20 +107,25 @@ impl TensorBuilder {
             token_len: Array2::from_elem((batch_size, max_tokens_len), -1),
             token_mask: Array2::zeros((batch_size, max_seq_len)),
             labels: None,
+            sentence_labels: None,
             seq_lens: Array1::zeros((batch_size,)),
+            treebank_ids: Array1::zeros((batch_size,)),
         }
     }
 
     /// Create a new `TensorBuilder` with labels.
     ///
     /// Creates a new builder with the given batch size, number of time steps,
-    /// and encoder names.
+    /// and encoder names. `encoder_names` are the names of encoders that
+    /// assign a label to every token, `sentence_encoder_names` the names
+    /// of encoders that assign a single label to a sentence.
     pub fn new_with_labels(
         batch_size: usize,
         max_seq_len: usize,
         max_tokens_len: usize,
         biaffine_encoder: bool,
         encoder_names: impl IntoIterator<Item = impl Into<String>>,
+        sentence_encoder_names: impl IntoIterator<Item = impl Into<String>>,
     ) -> Self {
         let biaffine_encodings = if biaffine_encoder {
             Some(BiaffineTensors::from_shape(batch_size, max_tokens_len))
@@ -131,12 +145,31 @@ impl TensorBuilder {
                 batch_size,
                 max_tokens_len,
             )),
+            sentence_labels: Some(LabelTensor::from_shape(
+                sentence_encoder_names,
+                batch_size,
+                1,
+            )),
             seq_lens: Array1::zeros((batch_size,)),
+            treebank_ids: Array1::zeros((batch_size,)),
         }
     }
 }
 
 impl TensorBuilder {
+    /// Set the treebank identifier of the next instance.
+    ///
+    /// Instances without an explicit treebank identifier use the
+    /// identifier `0`.
+    pub fn set_treebank_id(&mut self, treebank_id: i64) {
+        assert!(
+            self.current_sequence < self.inputs.shape()[0],
+            "TensorBuilder is already filled."
+        );
+
+        self.treebank_ids[self.current_sequence] = treebank_id;
+    }
+
     /// Add an instance without labels.
     ///
     /// The `token_mask` should be a mask which is set to `true` for
@@ -180,11 +213,16 @@ impl TensorBuilder {
     }
 
     /// Add an instance with labels.
+    ///
+    /// Dependency heads and relations, token labels, and sentence
+    /// labels can be `UNKNOWN_LABEL` for instances that are not
+    /// annotated with that label. Such labels are ignored in the loss.
     pub fn add_with_labels(
         &mut self,
         input: ArrayView1<i64>,
         biaffine_labels: Option<(Array1<i64>, Array1<i64>)>,
         sequence_labels: HashMap<&str, Array1<i64>>,
+        sentence_labels: HashMap<&str, i64>,
         token_offsets: ArrayView1<i32>,
         token_lens: ArrayView1<i32>,
         token_mask: ArrayView1<i32>,
@@ -194,6 +232,14 @@ impl TensorBuilder {
             "TensorBuilder is already filled."
         );
 
+        assert_eq!(
+            self.sentence_labels.as_ref().unwrap().len(),
+            sentence_labels.len(),
+            "Expected labels for {} sentence encoders, got labels for {}",
+            self.sentence_labels.as_ref().unwrap().len(),
+            sentence_labels.len(),
+        );
+
         assert_eq!(
             self.labels.as_ref().unwrap().len(),
             sequence_labels.len(),
@@ -225,6 +271,9 @@ impl TensorBuilder {
                 token_offsets.len()
             );
 
+            assert_known_or_unknown("biaffine heads", &instance_biaffine_encodings.0);
+            assert_known_or_unknown("biaffine relations", &instance_biaffine_encodings.1);
+
             biaffine_encodings
                 .heads
                 .row_mut(self.current_sequence)
@@ -247,6 +296,7 @@ impl TensorBuilder {
                 labels.len(),
                 token_offsets.len()
             );
+            assert_known_or_unknown(encoder_name, &labels);
 
             #[allow(clippy::deref_addrof)]
             self.labels
@@ -259,10 +309,33 @@ impl TensorBuilder {
                 .assign(&labels)
         }
 
+        for (encoder_name, label) in sentence_labels {
+            assert!(
+                label >= UNKNOWN_LABEL,
+                "Invalid label for sentence encoder {}: {}",
+                encoder_name,
+                label
+            );
+
+            self.sentence_labels
+                .as_mut()
+                .unwrap()
+                .get_mut(encoder_name)
+                .unwrap_or_else(|| panic!("Undefined sentence encoder: {}", encoder_name))
+                [[self.current_sequence, 0]] = label;
+        }
+
         self.add_without_labels(input, token_offsets, token_lens, token_mask);
     }
 }
 
+/// Check that labels are either known or `UNKNOWN_LABEL`.
+fn assert_known_or_unknown(name: &str, labels: &Array1<i64>) {
+    if let Some(label) = labels.iter().find(|&&label| label < UNKNOWN_LABEL) {
+        panic!("Invalid label for {}: {}", name, label);
+    }
+}
+
 /// Tensors constructed by `TensorBuilder`.
 #[derive(Debug)]
 pub struct Tensors {
@@ -273,6 +346,10 @@ pub struct Tensors {
     pub biaffine_encodings: Option<BiaffineTensors<Tensor>>,
 
     /// Labels.
+    ///
+    /// Labels of token-level encoders have the shape
+    /// `[batch_size, max_tokens_len]`, labels of sentence-level
+    /// encoders have the shape `[batch_size, 1]`.
     pub labels: Option<HashMap<String, Tensor>>,
 
     /// Sequence lengths.
@@ -280,14 +357,21 @@ pub struct Tensors {
 
     /// Token offsets.
     pub token_spans: TokenSpans,
+
+    /// Treebank identifiers of the sequences.
+    ///
+    /// Shape: `[batch_size]`.
+    pub treebank_ids: Tensor,
 }
 
 impl From<TensorBuilder> for Tensors {
     fn from(builder: TensorBuilder) -> Self {
+        let sentence_labels = builder.sentence_labels;
         let labels = builder.labels.map(|labels| {
             labels
                 .inner
                 .into_iter()
+                .chain(sentence_labels.into_iter().flat_map(|labels| labels.inner))
                 .map(|(encoder_name, matrix)| (encoder_name, matrix.try_into().unwrap()))
                 .collect()
         });
@@ -310,6 +394,7 @@ impl From<TensorBuilder> for Tensors {
                     .unwrap()
                     .to_kind(Kind::Int64),
             ),
+            treebank_ids: builder.treebank_ids.try_into().unwrap(),
         }
     }
 }
@@ -500,10 +585,12 @@ impl Deref for TokenMaskWithRoot {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use ndarray::arr1;
     use tch::Tensor;
 
-    use super::{TensorBuilder, Tensors};
+    use super::{TensorBuilder, Tensors, UNKNOWN_LABEL};
     use crate::tensor::{BiaffineTensors, SequenceLengths, TokenSpans};
 
     #[test]
@@ -529,6 +616,7 @@ mod tests {
             arr1(&[1]).view(),
             arr1(&[1, 0]).view(),
         );
+        builder.set_treebank_id(2);
         builder.add_without_labels(
             arr1(&[3, 4, 5]).view(),
             arr1(&[0, 2]).view(),
@@ -541,6 +629,8 @@ mod tests {
         // No labels.
         assert_eq!(tensors.labels, None);
 
+        assert_eq!(tensors.treebank_ids, Tensor::of_slice(&[0, 2]));
+
         assert_eq!(*tensors.seq_lens, Tensor::of_slice(&[2, 3]));
         assert_eq!(
             tensors.inputs,
@@ -551,13 +641,14 @@ mod tests {
     #[test]
     fn instances_are_added_with_labels() {
         let mut builder: TensorBuilder =
-            TensorBuilder::new_with_labels(2, 3, 2, true, vec!["a", "b"]);
+            TensorBuilder::new_with_labels(2, 3, 2, true, vec!["a", "b"], vec!["c"]);
         builder.add_with_labels(
             arr1(&[1, 2]).view(),
             Some((arr1(&[1]), arr1(&[2]))),
             vec![("a", arr1(&[12])), ("b", arr1(&[21]))]
                 .into_iter()
                 .collect(),
+            vec![("c", 5)].into_iter().collect(),
             arr1(&[0]).view(),
             arr1(&[1]).view(),
             arr1(&[1, 0]).view(),
@@ -568,6 +659,7 @@ mod tests {
             vec![("a", arr1(&[13, 15])), ("b", arr1(&[24, 25]))]
                 .into_iter()
                 .collect(),
+            vec![("c", 7)].into_iter().collect(),
             arr1(&[0, 2]).view(),
             arr1(&[2, 1]).view(),
             arr1(&[1, 0, 1]).view(),
@@ -596,7 +688,8 @@ mod tests {
                     (
                         "b".to_string(),
                         Tensor::of_slice(&[21, 0, 24, 25]).reshape(&[2, 2])
-                    )
+                    ),
+                    ("c".to_string(), Tensor::of_slice(&[5, 7]).reshape(&[2, 1]))
                 ]
                 .into_iter()
                 .collect()
@@ -610,17 +703,79 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unknown_labels_are_added() {
+        let mut builder: TensorBuilder =
+            TensorBuilder::new_with_labels(1, 3, 3, true, vec!["a"], vec!["c"]);
+        builder.add_with_labels(
+            arr1(&[1, 2, 3]).view(),
+            Some((
+                arr1(&[2, UNKNOWN_LABEL, 0]),
+                arr1(&[4, UNKNOWN_LABEL, UNKNOWN_LABEL]),
+            )),
+            vec![("a", arr1(&[UNKNOWN_LABEL, 12, UNKNOWN_LABEL]))]
+                .into_iter()
+                .collect(),
+            vec![("c", UNKNOWN_LABEL)].into_iter().collect(),
+            arr1(&[0, 1, 2]).view(),
+            arr1(&[1, 1, 1]).view(),
+            arr1(&[1, 1, 1]).view(),
+        );
+
+        let tensors: Tensors = builder.into();
+
+        assert_eq!(
+            tensors.biaffine_encodings,
+            Some(BiaffineTensors {
+                heads: Tensor::of_slice(&[2, -1, 0]).reshape(&[1, 3]),
+                relations: Tensor::of_slice(&[4, -1, -1]).reshape(&[1, 3])
+            })
+        );
+
+        assert_eq!(
+            tensors.labels,
+            Some(
+                vec![
+                    (
+                        "a".to_string(),
+                        Tensor::of_slice(&[-1, 12, -1]).reshape(&[1, 3])
+                    ),
+                    ("c".to_string(), Tensor::of_slice(&[-1]).reshape(&[1, 1]))
+                ]
+                .into_iter()
+                .collect()
+            )
+        );
+    }
+
+    #[should_panic]
+    #[test]
+    fn panics_on_invalid_labels() {
+        let mut builder: TensorBuilder =
+            TensorBuilder::new_with_labels(1, 2, 1, false, vec!["a"], Vec::<String>::new());
+        builder.add_with_labels(
+            arr1(&[1, 2]).view(),
+            None,
+            vec![("a", arr1(&[-2]))].into_iter().collect(),
+            HashMap::new(),
+            arr1(&[0]).view(),
+            arr1(&[1]).view(),
+            arr1(&[1, 0]).view(),
+        );
+    }
+
     #[should_panic]
     #[test]
     fn panics_when_labels_and_mask_len_differ() {
         let mut builder: TensorBuilder =
-            TensorBuilder::new_with_labels(2, 3, 1, false, vec!["a", "b"]);
+            TensorBuilder::new_with_labels(2, 3, 1, false, vec!["a", "b"], Vec::<String>::new());
         builder.add_with_labels(
             arr1(&[1, 2]).view(),
             None,
             vec![("a", arr1(&[11])), ("b", arr1(&[21, 22]))]
                 .into_iter()
                 .collect(),
+            HashMap::new(),
             arr1(&[0]).view(),
             arr1(&[1]).view(),
             arr1(&[1, 0]).view(),
@@ -649,11 +804,12 @@ mod tests {
     #[test]
     fn panics_when_labels_for_encoder_missing() {
         let mut builder: TensorBuilder =
-            TensorBuilder::new_with_labels(2, 3, 1, false, vec!["a", "b"]);
+            TensorBuilder::new_with_labels(2, 3, 1, false, vec!["a", "b"], Vec::<String>::new());
         builder.add_with_labels(
             arr1(&[1, 2]).view(),
             None,
             vec![("b", arr1(&[21, 22]))].into_iter().collect(),
+            HashMap::new(),
             arr1(&[0]).view(),
             arr1(&[1]).view(),
             arr1(&[1, 0]).view(),